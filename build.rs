@@ -0,0 +1,20 @@
+// Expose build information to `keep-it-focused version`.
+fn main() {
+    println!(
+        "cargo:rustc-env=KEEP_IT_FOCUSED_TARGET={}",
+        std::env::var("TARGET").expect("cargo should set TARGET")
+    );
+
+    // The git hash is optional, e.g. when building from a source tarball.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=KEEP_IT_FOCUSED_GIT_HASH={}", hash.trim());
+    }
+}
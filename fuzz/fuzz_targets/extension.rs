@@ -0,0 +1,10 @@
+//! Feed arbitrary data to the parser for temporary rules.
+#![no_main]
+
+use keep_it_focused::config::Extension;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    // We only care about panics, not about errors.
+    let _ = serde_yaml::from_str::<Extension>(data);
+});
@@ -0,0 +1,128 @@
+//! Keeping track of the CPU time of processes, for rules that defer stopping busy processes.
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use log::debug;
+
+/// The share of a CPU above which a process is considered busy, e.g. because the user is
+/// interacting with it.
+const ACTIVE_CPU_SHARE: f64 = 0.05;
+
+/// How long we remember a process that we haven't seen since.
+const MAX_SAMPLE_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// A process, as its pid and start time, so that a reused pid is a different process.
+pub type ProcessKey = (i32, u64);
+
+#[derive(Debug)]
+struct Sample {
+    /// The CPU time of the process (user and system), in clock ticks.
+    cpu_ticks: u64,
+
+    /// When the sample was taken.
+    at: Instant,
+
+    /// Whether stopping the process has already been deferred once.
+    deferred: bool,
+}
+
+/// The latest CPU time of the processes watched by rules with `defer_if_active`.
+#[derive(Debug)]
+pub struct Activity {
+    samples: HashMap<ProcessKey, Sample>,
+
+    /// The number of clock ticks per second, as used by `/proc/<pid>/stat`.
+    ticks_per_second: u64,
+}
+impl Default for Activity {
+    fn default() -> Self {
+        Activity::new(procfs::ticks_per_second())
+    }
+}
+impl Activity {
+    pub fn new(ticks_per_second: u64) -> Self {
+        Activity {
+            samples: HashMap::new(),
+            ticks_per_second: ticks_per_second.max(1),
+        }
+    }
+
+    /// Record the CPU time of a process at `now`, and return whether the process has been busy
+    /// since the previous sample.
+    ///
+    /// A process without a previous sample, e.g. one that has just been launched, is not busy.
+    pub fn sample(&mut self, process: ProcessKey, cpu_ticks: u64, now: Instant) -> bool {
+        let sample = self.samples.entry(process).or_insert(Sample {
+            cpu_ticks,
+            at: now,
+            deferred: false,
+        });
+        let elapsed = now.saturating_duration_since(sample.at).as_secs_f64();
+        let cpu = cpu_ticks.saturating_sub(sample.cpu_ticks) as f64 / self.ticks_per_second as f64;
+        sample.cpu_ticks = cpu_ticks;
+        sample.at = now;
+        let busy = elapsed > 0. && cpu / elapsed > ACTIVE_CPU_SHARE;
+        if busy {
+            debug!("process {} used {:.0}% of a CPU", process.0, 100. * cpu / elapsed);
+        }
+        busy
+    }
+
+    /// Whether to defer stopping a process, busy or not.
+    ///
+    /// Stopping a process is deferred at most once: the next time, it's stopped regardless.
+    pub fn defer(&mut self, process: ProcessKey, busy: bool) -> bool {
+        let Some(sample) = self.samples.get_mut(&process) else {
+            return false;
+        };
+        if !busy || sample.deferred {
+            return false;
+        }
+        sample.deferred = true;
+        true
+    }
+
+    /// Forget the processes that we haven't seen for a while, presumably because they're gone.
+    pub fn prune(&mut self, now: Instant) {
+        self.samples
+            .retain(|_, sample| now.saturating_duration_since(sample.at) < MAX_SAMPLE_AGE);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Activity;
+
+    #[test]
+    fn test_activity() {
+        let mut activity = Activity::new(100);
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+        let game = (1234, 42);
+
+        // Nothing to compare to.
+        assert!(!activity.sample(game, 1000, at(0)));
+        assert!(!activity.defer(game, false));
+        // 1 second of CPU time over a minute, that's idle.
+        assert!(!activity.sample(game, 1100, at(60)));
+        // 30 seconds over a minute, that's busy.
+        assert!(activity.sample(game, 4100, at(120)));
+        // Deferred once, not twice.
+        assert!(activity.defer(game, true));
+        assert!(activity.sample(game, 7100, at(180)));
+        assert!(!activity.defer(game, true));
+
+        // A reused pid is another process.
+        let other = (1234, 4242);
+        assert!(!activity.sample(other, 0, at(180)));
+        assert!(activity.sample(other, 3000, at(240)));
+        assert!(activity.defer(other, true));
+
+        // Processes we haven't seen for a while are forgotten.
+        activity.prune(at(180 + 10 * 60));
+        assert!(!activity.sample(game, 100_000, at(180 + 10 * 60)));
+        assert!(activity.sample(other, 60_000, at(180 + 10 * 60)));
+    }
+}
@@ -0,0 +1,239 @@
+//! Keeping track of the time spent running binaries during the week, for rules with a weekly
+//! budget.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Local, NaiveDate};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::uid_resolver::Uid;
+
+/// The longest delay between two sightings of a running binary that we count as use.
+///
+/// Sweeps normally happen every minute, so a longer delay means that the binary (or the daemon)
+/// was stopped in the meantime, and we don't know for how long it ran.
+const MAX_GAP_SECONDS: i64 = 5 * 60;
+
+/// The time spent running a (user, binary) during the week.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Usage {
+    uid: u32,
+
+    /// The binary, as specified in the configuration (may be a glob).
+    binary: PathBuf,
+
+    /// How long the binary ran this week, in seconds.
+    seconds: u64,
+
+    /// When the binary was last seen running.
+    last_seen: DateTime<Local>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Persisted {
+    /// The ISO week, as (year, week number).
+    week: Option<(i32, u32)>,
+    usages: Vec<Usage>,
+}
+
+/// The time spent running binaries during the current ISO week, which starts on Monday.
+///
+/// Usage is persisted to disk, so that restarting the daemon doesn't reset budgets.
+#[derive(Debug)]
+pub struct Budgets {
+    /// The ISO week to which the usage applies, as (year, week number).
+    week: (i32, u32),
+
+    usages: HashMap<(Uid, PathBuf), Usage>,
+
+    /// Whether `usages` has changed since it was last persisted.
+    dirty: bool,
+
+    /// Where to persist usage. If `None`, usage is not persisted.
+    path: Option<PathBuf>,
+}
+impl Budgets {
+    /// Load the usage recorded this week from `path`, if any.
+    ///
+    /// Usage recorded during previous weeks is ignored.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let week = iso_week(Local::now().date_naive());
+        let persisted = match path.as_deref().map(Self::read) {
+            None => Persisted::default(),
+            Some(Ok(persisted)) => persisted,
+            Some(Err(err)) => {
+                warn!("failed to read weekly budgets, starting from scratch: {err:#}");
+                Persisted::default()
+            }
+        };
+        let usages = if persisted.week == Some(week) {
+            persisted
+                .usages
+                .into_iter()
+                .map(|usage| ((Uid(usage.uid), usage.binary.clone()), usage))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Budgets {
+            week,
+            usages,
+            dirty: false,
+            path,
+        }
+    }
+
+    fn read(path: &Path) -> Result<Persisted, anyhow::Error> {
+        let file = match std::fs::File::open(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Persisted::default())
+            }
+            result => result.with_context(|| format!("Failed to open {}", path.display()))?,
+        };
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Persist usage, if it has changed since it was last persisted.
+    ///
+    /// Usage is written to a temporary file, then renamed, so that a crash while writing
+    /// doesn't lose the usage of the week.
+    pub fn save(&mut self) -> Result<(), anyhow::Error> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let persisted = Persisted {
+            week: Some(self.week),
+            usages: self.usages.values().cloned().collect(),
+        };
+        let data = serde_json::to_string(&persisted).expect("error during serialization");
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let result = std::fs::write(&temp_path, data)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))
+            .and_then(|()| {
+                std::fs::rename(&temp_path, path)
+                    .with_context(|| format!("Failed to move usage to {}", path.display()))
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Forget all usage if `today` is not in the week to which it applies.
+    pub fn rollover(&mut self, today: NaiveDate) {
+        let week = iso_week(today);
+        if week != self.week {
+            self.week = week;
+            self.usages.clear();
+            self.dirty = true;
+        }
+    }
+
+    /// Record that `binary` is running for `uid` at `now`, and return how long it has run this
+    /// week, in seconds.
+    ///
+    /// The time since the binary was last seen running is counted as use, unless it's longer
+    /// than `MAX_GAP_SECONDS`.
+    pub fn record(&mut self, uid: Uid, binary: &Path, now: DateTime<Local>) -> u64 {
+        let usage = self
+            .usages
+            .entry((uid, binary.to_path_buf()))
+            .or_insert_with(|| Usage {
+                uid: uid.0,
+                binary: binary.to_path_buf(),
+                seconds: 0,
+                last_seen: now,
+            });
+        let gap = (now - usage.last_seen).num_seconds();
+        if (0..=MAX_GAP_SECONDS).contains(&gap) {
+            usage.seconds += gap as u64;
+        }
+        usage.last_seen = now;
+        self.dirty = true;
+        debug!(
+            "binary {} for user {} ran for {}s this week",
+            binary.display(),
+            uid.0,
+            usage.seconds
+        );
+        usage.seconds
+    }
+}
+
+fn iso_week(day: NaiveDate) -> (i32, u32) {
+    let week = day.iso_week();
+    (week.year(), week.week())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ops::Not, path::Path};
+
+    use chrono::{Duration, Local, NaiveDate, TimeZone};
+
+    use crate::uid_resolver::Uid;
+
+    use super::Budgets;
+
+    #[test]
+    fn test_budgets() {
+        let path = std::env::temp_dir().join(format!(
+            "keep-it-focused-test-budgets-{}.json",
+            std::process::id()
+        ));
+        let tetris = Path::new("**/tetris");
+        let user = Uid(1000);
+        let start = Local::now();
+        let at = |seconds: i64| start + Duration::seconds(seconds);
+
+        let mut budgets = Budgets::load(Some(path.clone()));
+        // Nothing counted until we have seen the binary twice.
+        assert_eq!(budgets.record(user, tetris, at(0)), 0);
+        assert_eq!(budgets.record(user, tetris, at(60)), 60);
+        // Several processes during the same sweep count once.
+        assert_eq!(budgets.record(user, tetris, at(60)), 60);
+        assert_eq!(budgets.record(user, tetris, at(120)), 120);
+        // After a long gap, we don't know how long the binary ran.
+        assert_eq!(budgets.record(user, tetris, at(3600)), 120);
+        assert_eq!(budgets.record(user, tetris, at(3660)), 180);
+        // Other users are tracked separately.
+        assert_eq!(budgets.record(Uid(1001), tetris, at(3660)), 0);
+
+        // Restarting doesn't reset budgets.
+        budgets.save().unwrap();
+        assert!(path.with_extension("json.tmp").exists().not());
+        let mut budgets = Budgets::load(Some(path.clone()));
+        assert_eq!(budgets.record(user, tetris, at(3720)), 240);
+
+        // Changing days within the week doesn't reset budgets, a new week does.
+        let monday = |week: u32| NaiveDate::from_isoywd_opt(2026, week, chrono::Weekday::Mon).unwrap();
+        let mut budgets = Budgets::load(None);
+        budgets.rollover(monday(10));
+        let noon = |day: NaiveDate| Local.from_local_datetime(&day.and_hms_opt(12, 0, 0).unwrap()).unwrap();
+        budgets.record(user, tetris, noon(monday(10)));
+        assert_eq!(budgets.record(user, tetris, noon(monday(10)) + Duration::seconds(60)), 60);
+        budgets.rollover(monday(10) + Duration::days(6));
+        assert_eq!(budgets.record(user, tetris, noon(monday(10)) + Duration::seconds(120)), 120);
+        budgets.rollover(monday(11));
+        assert_eq!(budgets.record(user, tetris, noon(monday(11))), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
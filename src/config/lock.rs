@@ -0,0 +1,91 @@
+//! Advisory locking of the main configuration file.
+//!
+//! Commands editing the main file (e.g. `permanently`) hold an exclusive lock from the moment
+//! they read it until they have replaced it, so that concurrent edits are serialized rather than
+//! lost. The daemon holds a shared lock while it reads the main file and, if an edit is in
+//! progress, keeps the previous version until its next reload.
+//!
+//! Edits replace the main file by renaming, so the lock is taken on a sidecar file,
+//! `<main file>.lock` (e.g. `/etc/keep-it-focused.yaml.lock`), which is never removed.
+
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+/// A lock on the main configuration file, released when dropped.
+#[derive(Debug)]
+pub struct ConfigLock {
+    _file: File,
+}
+
+impl ConfigLock {
+    /// Acquire the exclusive lock, waiting for other edits or reads to complete.
+    pub fn exclusive(main_config: &Path) -> Result<Self, std::io::Error> {
+        let file = open(main_config)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                info!("{} is being edited, waiting", main_config.display());
+                file.lock()?;
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+        Ok(ConfigLock { _file: file })
+    }
+
+    /// Acquire the shared lock, unless the file is being edited, in which case `None`.
+    pub fn try_shared(main_config: &Path) -> Result<Option<Self>, std::io::Error> {
+        let file = open(main_config)?;
+        match file.try_lock_shared() {
+            Ok(()) => Ok(Some(ConfigLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+/// The path of the file on which we take the lock for `main_config`.
+pub fn lock_path(main_config: &Path) -> PathBuf {
+    let mut path = OsString::from(main_config);
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+fn open(main_config: &Path) -> Result<File, std::io::Error> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path(main_config))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{lock_path, ConfigLock};
+
+    #[test]
+    fn test_lock() {
+        assert_eq!(
+            lock_path(Path::new("/etc/keep-it-focused.yaml")),
+            Path::new("/etc/keep-it-focused.yaml.lock")
+        );
+
+        let main_config = std::env::temp_dir().join(format!("keep-it-focused-test-lock-{}.yaml", std::process::id()));
+        let edit = ConfigLock::exclusive(&main_config).unwrap();
+        // Reads are refused during an edit...
+        assert!(ConfigLock::try_shared(&main_config).unwrap().is_none());
+        drop(edit);
+        // ... and permitted once it's complete, including concurrently.
+        let read = ConfigLock::try_shared(&main_config).unwrap();
+        assert!(read.is_some());
+        assert!(ConfigLock::try_shared(&main_config).unwrap().is_some());
+        drop(read);
+        std::fs::remove_file(lock_path(&main_config)).unwrap();
+    }
+}
@@ -1,16 +1,18 @@
 use std::{
-    collections::HashMap, ops::Not, path::{Path, PathBuf}, rc::Rc, time::{SystemTime, UNIX_EPOCH}
+    collections::{HashMap, HashSet}, ops::Not, path::{Path, PathBuf}, rc::Rc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}
 };
 
 use anyhow::Context;
-use chrono::{DateTime, Datelike, Local};
+use chrono::{DateTime, Datelike, Local, NaiveDate};
 use itertools::Itertools;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{Binary, Config, Extension},
+    config::{lock::ConfigLock, Binary, Combination, Companion, Config, Error, Escalation, Extension, Hooks, Messages, Nag, Scope},
+    locale::Language,
     types::{
-        is_today, AcceptedInterval, DayOfWeek, Domain, IntervalsDiff, RejectedInterval, Username,
+        is_today, AcceptedInterval, DayOfWeek, Domain, IntervalsDiff, RejectedInterval, TimeOfDay, Username,
     },
     uid_resolver::{self, Uid},
     UserInstructions,
@@ -18,7 +20,22 @@ use crate::{
 
 use super::DayConfig;
 
-#[derive(Debug)]
+/// How long we wait before trying again to remove a file that has expired, after a first
+/// failure. The delay doubles with each failure, up to `REMOVAL_BACKOFF_MAX`.
+const REMOVAL_BACKOFF_START: Duration = Duration::from_secs(60);
+const REMOVAL_BACKOFF_MAX: Duration = Duration::from_secs(3600);
+
+/// After this many failures to remove a file, we consider that it's stuck.
+const REMOVAL_MAX_ATTEMPTS: u32 = 5;
+
+/// How many expired files we remember, see `ConfigManager::expired`.
+const MAX_EXPIRED: usize = 10_000;
+
+/// Beyond this number of files, the directory of temporary rules is probably not cleaned up
+/// as it should be.
+const LARGE_EXTENSIONS_DIR: usize = 1_000;
+
+#[derive(Debug, Serialize, Deserialize)]
 struct CacheEntry {
     /// When the file was last changed and read.
     latest_update: SystemTime,
@@ -28,35 +45,203 @@ struct CacheEntry {
 
     /// Contents last read from that file.
     config: HashMap<Username, DayConfig>,
+
+    /// When the file stops being valid, if it specifies it.
+    expires: Option<SystemTime>,
+}
+impl CacheEntry {
+    /// Whether this temporary file is not valid anymore.
+    ///
+    /// Unless they specify an expiry date, files are only valid on the day they were created.
+    fn is_obsolete(&self) -> bool {
+        match self.expires {
+            Some(expires) => expires <= SystemTime::now(),
+            None => is_today(self.creation_date).not(),
+        }
+    }
+}
+
+/// A temporary file that has expired but could not be removed.
+#[derive(Debug, Clone)]
+struct FailedRemoval {
+    /// How many times we have failed to remove it.
+    attempts: u32,
+
+    /// When to examine it again. Until then, it's skipped.
+    next_attempt: Instant,
+
+    /// Why the latest attempt failed.
+    reason: String,
+}
+impl FailedRemoval {
+    fn new(reason: String, now: Instant) -> Self {
+        let mut failure = FailedRemoval {
+            attempts: 0,
+            next_attempt: now,
+            reason: String::new(),
+        };
+        failure.record(reason, now);
+        failure
+    }
+
+    /// Record one more failure, backing off exponentially.
+    fn record(&mut self, reason: String, now: Instant) {
+        self.attempts += 1;
+        let delay = REMOVAL_BACKOFF_START
+            .saturating_mul(1 << (self.attempts - 1).min(16))
+            .min(REMOVAL_BACKOFF_MAX);
+        self.next_attempt = now + delay;
+        self.reason = reason;
+    }
+
+    /// Whether removal fails persistently, e.g. because of an immutable attribute or a wrong
+    /// owner, so that only an administrator may fix it.
+    fn is_stuck(&self) -> bool {
+        self.attempts >= REMOVAL_MAX_ATTEMPTS
+    }
 }
 
 pub struct Options {
     pub main_config: PathBuf,
     pub extensions_dir: PathBuf,
+
+    /// Where to persist the files read, so that a restart on the same day doesn't need to
+    /// parse again the files that haven't changed.
+    ///
+    /// We persist the rules parsed from each file rather than the compiled configuration: the
+    /// latter resolves user names to uids, which may change between restarts, and would need
+    /// to be invalidated as a whole whenever any temporary rule is added or expires. Compiling
+    /// is cheap compared to parsing.
+    ///
+    /// The entry of the main file only holds the rules of one weekday, the day on which it was
+    /// read, which is why the cache is ignored on another day.
+    ///
+    /// If `None`, files are parsed again on each restart.
+    pub cache: Option<PathBuf>,
+}
+
+/// The files read, as persisted to `Options::cache`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Persisted {
+    /// The day on which the files were read. The entry of the main file only holds the
+    /// rules of that day.
+    day: Option<NaiveDate>,
+
+    entries: HashMap<PathBuf, CacheEntry>,
+
+    /// The settings read from the main file.
+    combine: Combination,
+    messages: Messages,
+    hooks: Hooks,
+    case_insensitive_users: bool,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Precompiled {
     today_per_user: HashMap<Uid, UserInstructions>,
+
+    /// For accounts shared by several people, the web rules of each profile, i.e. those of
+    /// the account followed by those of the profile (see `Username::split_profile`).
+    profiles: HashMap<(Uid, String), UserInstructions>,
 }
 impl Precompiled {
     /// Serialize the web component to JSON, fit for serving.
-    pub fn serialize_web(&self) -> HashMap<Uid, String> {
+    ///
+    /// By default, this is a map `domain => [permitted intervals]`. If `forbidden` is true,
+    /// this is a map `domain => { permitted: [intervals], forbidden: [intervals] }`, where
+    /// `forbidden` lists the intervals blocked by an explicit rule.
+    ///
+    /// Each domain covers its subdomains, except those that have an entry of their own: for
+    /// a given host, the extension must apply the entry of the longest domain suffix of the
+    /// host. To make this explicit, domains are served from the most specific to the least
+    /// specific (see `Domain::specificity`).
+    pub fn serialize_web(&self, forbidden: bool) -> HashMap<Uid, String> {
         debug!("serializing {:?}", self);
         let data = self
             .today_per_user
             .iter()
-            .map(|(uid, instructions)| {
-                (*uid, {
-                    serde_json::to_string(&instructions.web).expect("error during serialization")
-                })
-            })
+            .map(|(uid, instructions)| (*uid, Self::serialize_web_of(instructions, forbidden)))
             .collect();
         data
     }
+    /// Serialize the web component of each profile, as `serialize_web`.
+    pub fn serialize_profiles_web(&self, forbidden: bool) -> HashMap<(Uid, String), String> {
+        self.profiles
+            .iter()
+            .map(|(key, instructions)| (key.clone(), Self::serialize_web_of(instructions, forbidden)))
+            .collect()
+    }
+    fn serialize_web_of(instructions: &UserInstructions, forbidden: bool) -> String {
+        #[derive(Serialize)]
+        struct Detailed<'a> {
+            permitted: &'a [AcceptedInterval],
+            forbidden: &'a [RejectedInterval],
+        }
+        if forbidden {
+            let detailed = BySpecificity::new(instructions.web.iter().map(|(domain, permitted)| {
+                let forbidden = instructions
+                    .web_forbidden
+                    .get(domain)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                (domain, Detailed { permitted, forbidden })
+            }));
+            serde_json::to_string(&detailed)
+        } else {
+            serde_json::to_string(&BySpecificity::new(instructions.web.iter()))
+        }
+        .expect("error during serialization")
+    }
+    /// Serialize everything applying to each user today (processes, web, ips), fit for serving
+    /// to desktop clients.
+    ///
+    /// Unlike `serialize_web`, this is not meant for the browser extension.
+    pub fn serialize_instructions(&self) -> HashMap<Uid, String> {
+        self.today_per_user
+            .iter()
+            .map(|(uid, instructions)| {
+                let data = serde_json::to_string(instructions).expect("error during serialization");
+                (*uid, data)
+            })
+            .collect()
+    }
     pub fn today_per_user(&self) -> &HashMap<Uid, UserInstructions> {
         &self.today_per_user
     }
+    pub fn profiles(&self) -> &HashMap<(Uid, String), UserInstructions> {
+        &self.profiles
+    }
+    /// The next time at which enforcement changes for any user, at the latest midnight,
+    /// when the rules of the next day apply.
+    pub fn next_boundary(&self, now: TimeOfDay) -> TimeOfDay {
+        self.today_per_user
+            .values()
+            .filter_map(|instructions| instructions.next_boundary(now))
+            .min()
+            .unwrap_or(TimeOfDay::END)
+    }
+}
+
+/// A map serialized from its most specific domain to its least specific domain.
+struct BySpecificity<'a, V>(Vec<(&'a Domain, V)>);
+impl<'a, V> BySpecificity<'a, V> {
+    fn new(entries: impl Iterator<Item = (&'a Domain, V)>) -> Self {
+        let mut entries = entries.collect_vec();
+        entries.sort_by(|(a, _), (b, _)| {
+            b.specificity()
+                .cmp(&a.specificity())
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        BySpecificity(entries)
+    }
+}
+impl<V: Serialize> Serialize for BySpecificity<'_, V> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.0.iter().map(|(domain, value)| (domain, value)))
+    }
 }
 
 pub struct ConfigManager {
@@ -70,52 +255,187 @@ pub struct ConfigManager {
     /// When `config` was last computed.
     last_computed: DateTime<Local>,
 
+    /// How many times `config` was computed since startup.
+    generation: u64,
+
+    /// Temporary files that have expired but could not be removed (e.g. because of their
+    /// permissions), so that we don't stat, parse and try to remove them again at each tick.
+    /// At most `MAX_EXPIRED` entries.
+    expired: HashMap<PathBuf, FailedRemoval>,
+
+    /// The day on which we last removed all obsolete files from the directory of temporary
+    /// rules, see `compact`.
+    last_compaction: Option<NaiveDate>,
+
+    /// Whether we have already warned that the directory of temporary rules is large.
+    warned_large: bool,
+
+    /// How rules from several files combine, as specified by the main file.
+    combine: Combination,
+
+    /// The notifications shown to users, as specified by the main file.
+    messages: Messages,
+
+    /// The commands to run after enforcement, as specified by the main file.
+    hooks: Hooks,
+
+    /// Whether user names may match users up to case, as specified by the main file.
+    case_insensitive_users: bool,
+
     options: Options,
 }
 impl ConfigManager {
     pub fn new(options: Options) -> Self {
-        Self {
+        let mut manager = Self {
             cache: HashMap::new(), // Data will be filled once we have executed `load_config()`.
             config: Precompiled::default(), // Data will be filled once we have executed `load_config()`.
             last_computed: DateTime::from_timestamp_micros(0).unwrap().into(), // Expect that we're running *after* the epoch.
+            generation: 0,
+            expired: HashMap::new(),
+            last_compaction: None,
+            warned_large: false,
+            combine: Combination::default(),
+            messages: Messages::default(),
+            hooks: Hooks::default(),
+            case_insensitive_users: false,
             options,
+        };
+        manager.restore();
+        manager
+    }
+
+    /// Restore the files persisted to `Options::cache`, if they were read today and haven't
+    /// changed since.
+    fn restore(&mut self) {
+        let Some(ref path) = self.options.cache else {
+            return;
+        };
+        let persisted = match Self::read_persisted(path) {
+            Ok(persisted) => persisted,
+            Err(err) => {
+                warn!("failed to read config cache, ignoring it: {err:#}");
+                return;
+            }
+        };
+        if persisted.day != Some(Local::now().date_naive()) {
+            debug!("config cache is from another day, ignoring it");
+            return;
+        }
+        for (path, entry) in persisted.entries {
+            match std::fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                Ok(latest_update) if latest_update == entry.latest_update => {
+                    debug!("restoring {} from config cache", path.display());
+                    self.cache.insert(path, entry);
+                }
+                _ => debug!("{} has changed, not restoring it from config cache", path.display()),
+            }
+        }
+        // The settings are only valid if the main file hasn't changed.
+        if self.cache.contains_key(&self.options.main_config) {
+            self.combine = persisted.combine;
+            self.messages = persisted.messages;
+            self.hooks = persisted.hooks;
+            self.case_insensitive_users = persisted.case_insensitive_users;
         }
     }
 
+    fn read_persisted(path: &Path) -> Result<Persisted, anyhow::Error> {
+        let file = match std::fs::File::open(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Persisted::default())
+            }
+            result => result.with_context(|| format!("Failed to open {}", path.display()))?,
+        };
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Persist the files read to `Options::cache`, if specified.
+    ///
+    /// The cache is written to a temporary file, then renamed, so that a crash while writing
+    /// doesn't leave a truncated cache.
+    fn persist(&mut self) -> Result<(), anyhow::Error> {
+        let Some(ref path) = self.options.cache else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let persisted = Persisted {
+            day: Some(Local::now().date_naive()),
+            entries: std::mem::take(&mut self.cache),
+            combine: self.combine,
+            messages: self.messages.clone(),
+            hooks: self.hooks.clone(),
+            case_insensitive_users: self.case_insensitive_users,
+        };
+        let data = serde_json::to_string(&persisted);
+        self.cache = persisted.entries;
+        let data = data.context("Failed to serialize config cache")?;
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let result = std::fs::write(&temp_path, data)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))
+            .and_then(|()| {
+                std::fs::rename(&temp_path, path)
+                    .with_context(|| format!("Failed to move config cache to {}", path.display()))
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
+
     pub fn config(&self) -> &Precompiled {
         &self.config
     }
 
+    pub fn messages(&self) -> &Messages {
+        &self.messages
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// How many times the configuration was compiled since startup, starting at 1 once
+    /// `load_config` has succeeded.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// When the configuration was last compiled.
+    pub fn last_computed(&self) -> DateTime<Local> {
+        self.last_computed
+    }
+
+    /// The modification date of the main file, as of the version currently loaded, if any.
+    pub fn main_config_modified(&self) -> Option<SystemTime> {
+        self.cache
+            .get(&self.options.main_config)
+            .map(|entry| entry.latest_update)
+    }
+
     fn fetch_and_cache<F>(
         &mut self,
         path: PathBuf,
         today_only: bool,
         read: F,
-    ) -> Result<bool, anyhow::Error>
+    ) -> Result<bool, Error>
     where
-        F: FnOnce(std::fs::File) -> Result<HashMap<Username, DayConfig>, anyhow::Error>,
+        F: FnOnce(std::fs::File) -> Result<(HashMap<Username, DayConfig>, Option<SystemTime>), serde_yaml::Error>,
     {
-        let metadata = std::fs::metadata(&path)
-            .with_context(|| format!("could not access configuration at {}", path.display()))?;
-        let latest_update = metadata
-            .modified()
-            .with_context(|| format!("no latest modification time for {}", path.display()))?;
-        if today_only && is_today(latest_update).not() {
-            // This file has been modified before today, so it's obsolete, remove from cache.
-            debug!(
-                "File {} was modified before today, removing from cache and disk",
-                path.display()
-            );
-            self.cache.remove(&path);
-            if let Err(err) = std::fs::remove_file(&path) {
-                warn!("failed to remove file {}: {err}", path.display());
-            }
-            return Ok(true);
-        }
-
-        let creation_date = metadata
-            .created()
-            .with_context(|| format!("no creation time for {}", path.display()))?;
+        let io_error = |source| Error::Io {
+            path: path.clone(),
+            source,
+        };
+        let metadata = std::fs::metadata(&path).map_err(io_error)?;
+        let latest_update = metadata.modified().map_err(io_error)?;
+        // Not all filesystems record the creation date.
+        let creation_date = metadata.created().unwrap_or(latest_update);
+        let cached = self.cache.contains_key(&path);
         let entry = self
             .cache
             .entry(path.clone())
@@ -123,47 +443,201 @@ impl ConfigManager {
                 latest_update: UNIX_EPOCH,
                 creation_date,
                 config: HashMap::default(),
+                expires: None,
             });
-        if latest_update <= entry.latest_update {
-            // No change, keep cache.
-            return Ok(false);
+        let has_changes = latest_update > entry.latest_update;
+        if has_changes {
+            let reader = std::fs::File::open(&path).map_err(io_error)?;
+            match read(reader) {
+                Ok((data, expires)) => {
+                    entry.config = data;
+                    entry.expires = expires;
+                    entry.latest_update = latest_update;
+                }
+                Err(source) => {
+                    return Err(Error::Parse {
+                        path: path.clone(),
+                        source,
+                    })
+                }
+            }
+        }
+        if today_only && entry.is_obsolete() {
+            // This file is not valid anymore, remove from cache.
+            debug!(
+                "File {} has expired, removing from cache and disk",
+                path.display()
+            );
+            self.cache.remove(&path);
+            self.remove_expired(&path);
+            // A file that was never taken into account doesn't change the config.
+            return Ok(cached);
+        }
+        // The file has been replaced since we failed to remove it.
+        self.expired.remove(&path);
+        Ok(has_changes)
+    }
+
+    /// Remove a temporary file that has expired, remembering it if this fails, so that we
+    /// don't examine it again at each tick.
+    fn remove_expired(&mut self, path: &Path) {
+        let Err(err) = std::fs::remove_file(path) else {
+            self.expired.remove(path);
+            return;
+        };
+        if err.kind() == std::io::ErrorKind::NotFound {
+            self.expired.remove(path);
+            return;
+        }
+        let now = Instant::now();
+        let reason = err.to_string();
+        match self.expired.get_mut(path) {
+            Some(failure) => {
+                let was_stuck = failure.is_stuck();
+                failure.record(reason, now);
+                if failure.is_stuck() && was_stuck.not() {
+                    error!(
+                        "cannot remove expired file {} after {} attempts: {}, giving up until it's fixed, please remove it manually",
+                        path.display(),
+                        failure.attempts,
+                        failure.reason
+                    );
+                } else {
+                    debug!(
+                        "failed again to remove file {}: {}, attempt {}",
+                        path.display(),
+                        failure.reason,
+                        failure.attempts
+                    );
+                }
+            }
+            None => {
+                if self.expired.len() >= MAX_EXPIRED {
+                    // Forget the file due to be examined the soonest, we'll examine it again.
+                    if let Some(soonest) = self
+                        .expired
+                        .iter()
+                        .min_by_key(|(_, failure)| failure.next_attempt)
+                        .map(|(path, _)| path.clone())
+                    {
+                        self.expired.remove(&soonest);
+                    }
+                }
+                let failure = FailedRemoval::new(reason, now);
+                warn!(
+                    "failed to remove expired file {}: {}, will try again in {}s",
+                    path.display(),
+                    failure.reason,
+                    (failure.next_attempt - now).as_secs()
+                );
+                self.expired.insert(path.to_path_buf(), failure);
+            }
+        }
+    }
+
+    /// The temporary files that have expired but which we persistently fail to remove, with
+    /// the reason of the latest failure.
+    pub fn stuck_files(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.expired
+            .iter()
+            .filter(|(_, failure)| failure.is_stuck())
+            .map(|(path, failure)| (path.as_path(), failure.reason.as_str()))
+    }
+
+    /// Remove from the directory of temporary rules all the files that are not valid today
+    /// in one pass: expired files and hidden files left behind by an interrupted write before
+    /// today.
+    ///
+    /// Files that are valid, or may still become valid, are left alone, as are files that
+    /// cannot be read, lest we delete rules that the admin could still fix.
+    fn compact(&mut self) {
+        info!("reading config: removing obsolete temporary rules");
+        let dir = match std::fs::read_dir(&self.options.extensions_dir) {
+            Ok(dir) => dir,
+            Err(err) => {
+                debug!("failed to open directory {}, skipping compaction: {err}", self.options.extensions_dir.display());
+                return;
+            }
+        };
+        let mut removed = 0;
+        for entry in dir.flatten() {
+            let path = entry.path();
+            if self.cache.get(&path).is_some_and(|entry| entry.is_obsolete().not()) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else { continue };
+            if metadata.is_file().not() {
+                continue;
+            }
+            let Ok(latest_update) = metadata.modified() else { continue };
+            let creation_date = metadata.created().unwrap_or(latest_update);
+            let hidden = entry.file_name().as_encoded_bytes().starts_with(b".");
+            let obsolete = if hidden {
+                is_today(creation_date).not()
+            } else {
+                match Self::read_extension(&path) {
+                    Ok(entry) => entry.is_obsolete(),
+                    Err(err) => {
+                        warn!("cannot read {}, leaving it alone: {:#}", path.display(), anyhow::Error::from(err));
+                        false
+                    }
+                }
+            };
+            if obsolete {
+                debug!("removing obsolete file {}", path.display());
+                self.cache.remove(&path);
+                self.remove_expired(&path);
+                removed += 1;
+            }
         }
-        let reader = std::fs::File::open(&path)
-            .with_context(|| format!("could not open file {}", path.to_string_lossy()))?;
-        let data = read(reader)
-            .with_context(|| format!("could not parse file {}", path.to_string_lossy()))?;
-        entry.config = data;
-        entry.latest_update = latest_update;
-        Ok(true)
+        info!("reading config: removed {removed} obsolete temporary rules");
     }
 
-    pub fn load_config(&mut self) -> Result<bool, anyhow::Error> {
+    /// (Re)load the configuration files, returning `true` if the configuration was recompiled.
+    pub fn load_config(&mut self) -> Result<bool, Error> {
         let today = DayOfWeek::now();
 
         let mut has_changes = false;
 
-        // 1. Load main file.
+        // 1. Load main file, unless it's being edited (see `lock`) and we already have a version.
         info!("reading config: loading main file");
-        has_changes |= self.fetch_and_cache(self.options.main_config.clone(), false, |file| {
-            let config: Config = serde_yaml::from_reader(file).context("Invalid format")?;
-            let mut result = HashMap::new();
-            for (user, mut week) in config.users {
-                if let Some(day_config) = week.0.remove(&today) {
-                    debug!(
-                        "processing user {user} - we have a rule for today {:?}",
-                        day_config
-                    );
-                    result.insert(user, day_config);
-                } else {
-                    debug!("processing user {user} - no rule for today");
+        let lock = ConfigLock::try_shared(&self.options.main_config);
+        match lock {
+            Ok(None) if self.cache.contains_key(&self.options.main_config) => {
+                info!("reading config: main file is being edited, keeping the previous version for now");
+            }
+            _ => {
+                if let Err(ref err) = lock {
+                    warn!("failed to lock main file, reading it anyway: {err}");
                 }
+                let mut combine = self.combine;
+                let mut messages = None;
+                let mut hooks = None;
+                let mut case_insensitive_users = self.case_insensitive_users;
+                has_changes |= self.fetch_and_cache(self.options.main_config.clone(), false, |file| {
+                    let config: Config = serde_yaml::from_reader(file)?;
+                    combine = config.combine;
+                    let language = config.language.unwrap_or_else(Language::from_env);
+                    messages = Some(config.messages.localized(language));
+                    hooks = Some(config.hooks.clone());
+                    case_insensitive_users = config.case_insensitive_users;
+                    Ok((Self::rules_for_day(&config, today), None))
+                })?;
+                self.combine = combine;
+                self.case_insensitive_users = case_insensitive_users;
+                if let Some(messages) = messages {
+                    self.messages = messages;
+                }
+                if let Some(hooks) = hooks {
+                    self.hooks = hooks;
+                }
+                debug!(
+                    "reading config: loading main file, {}",
+                    if has_changes { "changed" } else { "unchanged" }
+                );
             }
-            Ok(result)
-        })?;
-        debug!(
-            "reading config: loading main file, {}",
-            if has_changes { "changed" } else { "unchanged" }
-        );
+        }
+        drop(lock);
 
         // 2. Load other files from the directory, ignoring any error
         // (along the way, we purge from the cache directory files that are now old).
@@ -177,32 +651,52 @@ impl ConfigManager {
                 );
             }
             Ok(dir) => {
+                let mut files = 0;
                 for entry in dir {
+                    files += 1;
                     match entry {
                         Err(err) => warn!(
                             "failed to access entry in directory {}, skipping: {}",
                             self.options.extensions_dir.display(),
                             err
                         ),
+                        // Hidden files are temporary rules still being written, skip them.
+                        Ok(entry) if entry.file_name().as_encoded_bytes().starts_with(b".") => {
+                            debug!("skipping hidden file {:?}", entry.file_name());
+                        }
                         Ok(entry) => {
                             let path = Path::join(&self.options.extensions_dir, entry.file_name());
+                            if self.expired.get(&path).is_some_and(|failure| failure.next_attempt > Instant::now()) {
+                                // Known to have expired, don't bother until the next attempt.
+                                continue;
+                            }
                             match self.fetch_and_cache(path.clone(), true, |file| {
-                                let config: Extension = serde_yaml::from_reader(file)
-                                    .context("Error reading/parsing file")?;
-                                Ok(config.users)
+                                let config: Extension = serde_yaml::from_reader(file)?;
+                                Ok((config.users, config.expires_at.map(SystemTime::from)))
                             }) {
                                 Ok(changes) => has_changes |= changes,
                                 Err(err) => {
                                     warn!(
-                                        "error while reading {}, skipping: {}",
+                                        "error while reading {}, skipping: {:#}",
                                         path.display(),
-                                        err
+                                        anyhow::Error::from(err)
                                     );
                                 }
                             }
                         }
                     }
                 }
+                if files > LARGE_EXTENSIONS_DIR {
+                    if self.warned_large.not() {
+                        warn!(
+                            "directory {} contains {files} files, obsolete temporary rules are probably not removed, check its permissions",
+                            self.options.extensions_dir.display()
+                        );
+                        self.warned_large = true;
+                    }
+                } else {
+                    self.warned_large = false;
+                }
             }
         }
         debug!(
@@ -210,11 +704,11 @@ impl ConfigManager {
             if has_changes { "changed" } else { "unchanged" }
         );
 
-        // 3. Purge from memory any file that hasn't been modified today (except for the main file).
+        // 3. Purge from memory any file that has expired (except for the main file).
         debug!("reading config: purging old content");
         let before = self.cache.len();
         self.cache.retain(|path, entry| {
-            is_today(entry.latest_update) || path == &self.options.main_config
+            entry.is_obsolete().not() || path == &self.options.main_config
         });
         let after = self.cache.len();
         if after != before {
@@ -224,37 +718,291 @@ impl ConfigManager {
         } else {
             debug!("reading config: purging old content, no old content to purge");
         }
+        let date = Local::now().date_naive();
+        if self.last_compaction != Some(date) {
+            // Once a day, clean up the files that we couldn't remove or that we skipped.
+            self.compact();
+            self.last_compaction = Some(date);
+        }
 
         // 4. Compile all these files.
         info!("reading config: resolving {:?}", self.cache);
         let now = Local::now();
-        if has_changes || self.last_computed.day() != now.day() {
+        let recompile = has_changes || self.last_computed.day() != now.day();
+        if recompile {
             // We need to recompile today's config if there have been changes or whenever a new day starts.
-            self.config =
-                Self::compile(&self.cache).context("error while compiling the configuration")?;
+            self.config = Self::compile(&self.cache, self.combine, self.case_insensitive_users)?;
             self.last_computed = now;
+            self.generation += 1;
         }
-        Ok(has_changes)
+
+        // 5. Persist the files read, for the next restart.
+        if has_changes {
+            if let Err(err) = self.persist() {
+                warn!("failed to write config cache: {err:#}");
+            }
+        }
+        // On startup, files restored from the cache are unchanged, but the config is new.
+        Ok(recompile)
+    }
+
+    /// Extract the rules applying on `day` from the main configuration.
+    fn rules_for_day(config: &Config, day: DayOfWeek) -> HashMap<Username, DayConfig> {
+        let mut result = HashMap::new();
+        for (user, week) in &config.users {
+            if let Some(day_config) = week.0.get(&day) {
+                debug!("processing user {user} - we have a rule for {day} {:?}", day_config);
+                result.insert(user.clone(), day_config.clone());
+            } else {
+                debug!("processing user {user} - no rule for {day}");
+            }
+        }
+        result
+    }
+
+    /// Check that a main configuration file can be parsed and compiled, for every day of
+    /// the week.
+    ///
+    /// Unlike `load_config`, this doesn't touch the directory of temporary rules, and nothing
+    /// is enforced.
+    pub fn validate(main_config: &Path) -> Result<(), Error> {
+        let config = Self::read_main(main_config)?;
+        for day in DayOfWeek::all() {
+            debug!("validating rules for {day}");
+            let entry = CacheEntry {
+                latest_update: UNIX_EPOCH,
+                creation_date: UNIX_EPOCH,
+                config: Self::rules_for_day(&config, day),
+                expires: None,
+            };
+            Self::compile(
+                &HashMap::from([(main_config.to_path_buf(), entry)]),
+                config.combine,
+                config.case_insensitive_users,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Compile today's configuration, from the main file and the temporary rules.
+    ///
+    /// Unlike `load_config`, this has no side effects: obsolete temporary rules are ignored
+    /// rather than removed, so this doesn't require any privileges beyond reading the files.
+    /// Temporary rules that cannot be read are skipped.
+    pub fn compile_only(options: &Options) -> Result<Precompiled, Error> {
+        let config = Self::read_main(&options.main_config)?;
+        let mut cache = HashMap::from([(
+            options.main_config.clone(),
+            CacheEntry {
+                latest_update: UNIX_EPOCH,
+                creation_date: UNIX_EPOCH,
+                config: Self::rules_for_day(&config, DayOfWeek::now()),
+                expires: None,
+            },
+        )]);
+        match std::fs::read_dir(&options.extensions_dir) {
+            Err(err) => warn!(
+                "failed to open directory {}, skipping extensions: {}",
+                options.extensions_dir.display(),
+                err
+            ),
+            Ok(dir) => {
+                for entry in dir.flatten() {
+                    if entry.file_name().as_encoded_bytes().starts_with(b".") {
+                        continue;
+                    }
+                    let path = entry.path();
+                    match Self::read_extension(&path) {
+                        Ok(entry) if entry.is_obsolete() => {
+                            debug!("File {} has expired, ignoring", path.display());
+                        }
+                        Ok(entry) => {
+                            cache.insert(path, entry);
+                        }
+                        Err(err) => warn!(
+                            "error while reading {}, skipping: {:#}",
+                            path.display(),
+                            anyhow::Error::from(err)
+                        ),
+                    }
+                }
+            }
+        }
+        Self::compile(&cache, config.combine, config.case_insensitive_users)
+    }
+
+    /// The number of temporary rules created today in `extensions_dir` for `user`, whether
+    /// they target the account or one of its profiles.
+    ///
+    /// Files that cannot be read are ignored, as the daemon would.
+    pub fn count_extensions_today(extensions_dir: &Path, user: &Username) -> Result<usize, Error> {
+        let dir = std::fs::read_dir(extensions_dir).map_err(|source| Error::Io {
+            path: extensions_dir.to_path_buf(),
+            source,
+        })?;
+        let mut count = 0;
+        for entry in dir.flatten() {
+            if entry.file_name().as_encoded_bytes().starts_with(b".") {
+                // Still being written.
+                continue;
+            }
+            let path = entry.path();
+            match Self::read_extension(&path) {
+                Ok(entry) if is_today(entry.creation_date) => {
+                    if entry.config.keys().any(|name| &name.split_profile().0 == user) {
+                        count += 1;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => debug!(
+                    "error while reading {}, not counting it: {:#}",
+                    path.display(),
+                    anyhow::Error::from(err)
+                ),
+            }
+        }
+        Ok(count)
+    }
+
+    fn read_main(main_config: &Path) -> Result<Config, Error> {
+        let file = std::fs::File::open(main_config).map_err(|source| Error::Io {
+            path: main_config.to_path_buf(),
+            source,
+        })?;
+        serde_yaml::from_reader(file).map_err(|source| Error::Parse {
+            path: main_config.to_path_buf(),
+            source,
+        })
+    }
+
+    fn read_extension(path: &Path) -> Result<CacheEntry, Error> {
+        let io_error = |source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let metadata = std::fs::metadata(path).map_err(io_error)?;
+        let latest_update = metadata.modified().map_err(io_error)?;
+        let file = std::fs::File::open(path).map_err(io_error)?;
+        let extension: Extension = serde_yaml::from_reader(file).map_err(|source| Error::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(CacheEntry {
+            latest_update,
+            creation_date: metadata.created().unwrap_or(latest_update),
+            config: extension.users,
+            expires: extension.expires_at.map(SystemTime::from),
+        })
     }
 
     /// Resolve the cache
     ///
     /// - restrict to the current day of the week;
     /// - restrict to
-    fn compile(cache: &HashMap<PathBuf, CacheEntry>) -> Result<Precompiled, anyhow::Error> {
-        let mut resolver = uid_resolver::Resolver::new();
+    /// - combine rules from several files as per `combine`;
+    /// - resolve user names, up to case if `case_insensitive_users`.
+    fn compile(
+        cache: &HashMap<PathBuf, CacheEntry>,
+        combine: Combination,
+        case_insensitive_users: bool,
+    ) -> Result<Precompiled, Error> {
+        let compute_accepted_intervals = match combine {
+            Combination::Union => IntervalsDiff::compute_accepted_intervals,
+            Combination::Intersection => IntervalsDiff::compute_intersected_accepted_intervals,
+        };
+        let mut resolver = uid_resolver::Resolver::new().case_insensitive(case_insensitive_users);
         #[derive(Default)]
         struct TodayPerUser {
+            /// The user name as spelled in the configuration, or the name of the system
+            /// account if several spellings resolve to the same user.
+            user_name: Option<Rc<Username>>,
             processes: HashMap<Binary, Vec<IntervalsDiff>>,
+            escalations: HashMap<Binary, Escalation>,
+            match_inode: HashSet<Binary>,
+            local_only: HashSet<Binary>,
+            focused_only: HashSet<Binary>,
+            defer_if_active: HashSet<Binary>,
+            cooldowns: HashMap<Binary, u16>,
+            nags: HashMap<Binary, Nag>,
+            companions: HashMap<Binary, Companion>,
+            weekly_budgets: HashMap<Binary, u16>,
             ips: HashMap<Domain, Vec<IntervalsDiff>>,
+            network: HashMap<Binary, Vec<IntervalsDiff>>,
             web: HashMap<Domain, Vec<IntervalsDiff>>,
         }
-        let mut today_per_user: HashMap</* user */ Rc<Username>, TodayPerUser> = HashMap::new();
+        impl TodayPerUser {
+            /// Add the rules of a file, later than those already added.
+            ///
+            /// With `Combination::Intersection`, the rules of the file for each target are
+            /// combined with one another first, so that they're only intersected with the
+            /// rules of other files.
+            fn add_file(&mut self, file: TodayPerUser, combine: Combination) {
+                fn add<K: Eq + std::hash::Hash>(
+                    target: &mut HashMap<K, Vec<IntervalsDiff>>,
+                    file: HashMap<K, Vec<IntervalsDiff>>,
+                    combine: Combination,
+                ) {
+                    for (key, diffs) in file {
+                        let target = target.entry(key).or_default();
+                        match combine {
+                            Combination::Union => target.extend(diffs),
+                            Combination::Intersection => target.push(IntervalsDiff::merge(diffs)),
+                        }
+                    }
+                }
+                add(&mut self.processes, file.processes, combine);
+                add(&mut self.ips, file.ips, combine);
+                add(&mut self.network, file.network, combine);
+                add(&mut self.web, file.web, combine);
+                // Later rules override earlier rules.
+                self.escalations.extend(file.escalations);
+                self.cooldowns.extend(file.cooldowns);
+                self.nags.extend(file.nags);
+                self.companions.extend(file.companions);
+                self.weekly_budgets.extend(file.weekly_budgets);
+                self.match_inode.extend(file.match_inode);
+                self.local_only.extend(file.local_only);
+                self.focused_only.extend(file.focused_only);
+                self.defer_if_active.extend(file.defer_if_active);
+            }
+        }
+        // Users are resolved before merging rules, as several spellings may resolve to the
+        // same user if `case_insensitive_users`.
+        let mut today_per_user: HashMap<(Uid, /* profile */ Option<String>), TodayPerUser> = HashMap::new();
+        let mut unresolved = HashSet::new();
         let entries = cache.values().sorted_by_key(|entry| entry.creation_date);
         for entry in entries {
-            for (user, day_config) in &entry.config {
-                let user_name = Rc::new(user.clone());
-                let user_entry = today_per_user.entry(user_name.clone()).or_default();
+            // Sort users within a file, so that spellings of the same user are merged
+            // in a deterministic order.
+            let users = entry.config.iter().sorted_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+            let mut file_per_user: HashMap<(Uid, Option<String>), TodayPerUser> = HashMap::new();
+            for (user, day_config) in users {
+                let (account, profile) = user.split_profile();
+                let uid = match resolver.resolve(&account) {
+                    Ok(uid) => uid,
+                    Err(_) => {
+                        if unresolved.insert(user.clone()) {
+                            warn!("failed to resolve user name {user}");
+                        }
+                        continue;
+                    }
+                };
+                let key = (uid, profile.map(str::to_string));
+                let user_name = &mut today_per_user.entry(key.clone()).or_default().user_name;
+                match user_name {
+                    None => *user_name = Some(Rc::new(user.clone())),
+                    Some(known) if **known != *user => {
+                        if let Ok(name) = uid.name() {
+                            let name = match profile {
+                                Some(profile) => format!("{name}:{profile}"),
+                                None => name,
+                            };
+                            *user_name = Some(Rc::new(Username(name)));
+                        }
+                    }
+                    Some(_) => {}
+                }
+                let user_entry = file_per_user.entry(key).or_default();
                 for proc in &day_config.processes {
                     let accepted = proc
                         .permitted
@@ -273,6 +1021,38 @@ impl ConfigManager {
                         .entry(proc.binary.clone())
                         .or_default()
                         .push(IntervalsDiff { accepted, rejected });
+                    if let Some(escalation) = proc.escalation {
+                        // Later rules override earlier rules.
+                        user_entry.escalations.insert(proc.binary.clone(), escalation);
+                    }
+                    if proc.match_inode {
+                        user_entry.match_inode.insert(proc.binary.clone());
+                    }
+                    if proc.scope == Scope::Local {
+                        user_entry.local_only.insert(proc.binary.clone());
+                    }
+                    if proc.focused_only {
+                        user_entry.focused_only.insert(proc.binary.clone());
+                    }
+                    if proc.defer_if_active {
+                        user_entry.defer_if_active.insert(proc.binary.clone());
+                    }
+                    if let Some(cooldown) = proc.cooldown_minutes {
+                        // Later rules override earlier rules.
+                        user_entry.cooldowns.insert(proc.binary.clone(), cooldown);
+                    }
+                    if let Some(nag) = proc.nag {
+                        // Later rules override earlier rules.
+                        user_entry.nags.insert(proc.binary.clone(), nag);
+                    }
+                    if let Some(companion) = &proc.companion {
+                        // Later rules override earlier rules.
+                        user_entry.companions.insert(proc.binary.clone(), companion.clone());
+                    }
+                    if let Some(budget) = proc.weekly_budget_minutes {
+                        // Later rules override earlier rules.
+                        user_entry.weekly_budgets.insert(proc.binary.clone(), budget);
+                    }
                 }
                 for ip in &day_config.ip {
                     let accepted = ip
@@ -293,6 +1073,25 @@ impl ConfigManager {
                         .or_default()
                         .push(IntervalsDiff { accepted, rejected });
                 }
+                for network in &day_config.network {
+                    let accepted = network
+                        .permitted
+                        .iter()
+                        .cloned()
+                        .map(AcceptedInterval)
+                        .collect_vec();
+                    let rejected = network
+                        .forbidden
+                        .iter()
+                        .cloned()
+                        .map(RejectedInterval)
+                        .collect_vec();
+                    user_entry
+                        .network
+                        .entry(network.binary.clone())
+                        .or_default()
+                        .push(IntervalsDiff { accepted, rejected });
+                }
                 for web in &day_config.web {
                     let accepted = web
                         .permitted
@@ -308,36 +1107,92 @@ impl ConfigManager {
                         .collect_vec();
                     user_entry
                         .web
-                        .entry(web.domain.clone())
+                        .entry(web.domain.fold_www())
                         .or_default()
                         .push(IntervalsDiff { accepted, rejected });
                 }
             }
+            for (key, file_entry) in file_per_user {
+                today_per_user.entry(key).or_default().add_file(file_entry, combine);
+            }
         }
 
         // Now resolve intervals and usernames.
         let mut resolved = Precompiled {
             today_per_user: HashMap::new(),
+            profiles: HashMap::new(),
         };
-        for (user_name, user_entry) in today_per_user {
-            let Ok(uid) = resolver.resolve(&user_name) else {
-                warn!("failed to resolve user name {user_name}");
+        let resolve_web = |per_user: &mut UserInstructions, web: HashMap<Domain, Vec<IntervalsDiff>>| {
+            for (domain, intervals) in web {
+                // Keep track of the intervals explicitly forbidden, minus whatever has been
+                // permitted again by a later rule.
+                let resolved = compute_accepted_intervals(intervals.clone());
+                debug!("domain {domain}: resolving intervals => {resolved:?}");
+                let forbidden = IntervalsDiff::compute_explicitly_rejected_intervals(&intervals, &resolved);
+                if forbidden.is_empty().not() {
+                    per_user.web_forbidden.insert(domain.clone(), forbidden);
+                }
+                per_user.web.insert(domain, resolved);
+            }
+        };
+        // The web rules of a profile apply on top of those of its account.
+        let accounts_web: HashMap<Uid, HashMap<Domain, Vec<IntervalsDiff>>> = today_per_user
+            .iter()
+            .filter(|((_, profile), _)| profile.is_none())
+            .map(|((uid, _), user_entry)| (*uid, user_entry.web.clone()))
+            .collect();
+        for ((uid, profile), mut user_entry) in today_per_user {
+            let user_name = user_entry
+                .user_name
+                .take()
+                .expect("every entry is created along with its user name");
+            if let Some(profile) = profile {
+                // Only the browser extension knows about profiles.
+                if user_entry.processes.is_empty().not() || user_entry.ips.is_empty().not() || user_entry.network.is_empty().not() {
+                    warn!("profile {user_name} has rules other than web rules, ignoring them");
+                }
+                let mut web = accounts_web.get(&uid).cloned().unwrap_or_default();
+                for (domain, intervals) in user_entry.web {
+                    web.entry(domain).or_default().extend(intervals);
+                }
+                let mut per_profile = UserInstructions::new(user_name.clone());
+                resolve_web(&mut per_profile, web);
+                resolved.profiles.insert((uid, profile), per_profile);
                 continue;
-            };
+            }
             let mut per_user = UserInstructions::new(user_name);
             for (domain, intervals) in user_entry.ips {
-                let resolved = IntervalsDiff::compute_rejected_intervals(intervals);
+                let resolved = RejectedInterval::complement(compute_accepted_intervals(intervals));
                 per_user.ips.insert(domain, resolved);
             }
+            for (binary, intervals) in user_entry.network {
+                let resolved = RejectedInterval::complement(compute_accepted_intervals(intervals));
+                per_user.network.push((binary, resolved));
+            }
             for (binary, intervals) in user_entry.processes {
-                let resolved = IntervalsDiff::compute_accepted_intervals(intervals);
+                let resolved = compute_accepted_intervals(intervals.clone());
+                let forbidden = IntervalsDiff::compute_explicitly_rejected_intervals(&intervals, &resolved);
+                // Only labels are useful here, see `UserInstructions::forbidden_label`.
+                if forbidden.iter().any(|interval| interval.0.label.is_some()) {
+                    per_user.process_forbidden.insert(binary.clone(), forbidden);
+                }
                 per_user.processes.push((binary, resolved));
             }
-            for (domain, intervals) in user_entry.web {
-                let resolved = IntervalsDiff::compute_accepted_intervals(intervals);
-                debug!("domain {domain}: resolving intervals => {resolved:?}");
-                per_user.web.insert(domain, resolved);
+            per_user.escalations = user_entry.escalations;
+            for binary in &user_entry.match_inode {
+                if let Err(err) = std::fs::metadata(&binary.path) {
+                    warn!("binary {binary} should be matched by inode, but it cannot be accessed, matching by path only: {err}");
+                }
             }
+            per_user.match_inode = user_entry.match_inode;
+            per_user.local_only = user_entry.local_only;
+            per_user.focused_only = user_entry.focused_only;
+            per_user.defer_if_active = user_entry.defer_if_active;
+            per_user.cooldowns = user_entry.cooldowns;
+            per_user.nags = user_entry.nags;
+            per_user.companions = user_entry.companions;
+            per_user.weekly_budgets = user_entry.weekly_budgets;
+            resolve_web(&mut per_user, user_entry.web);
             resolved.today_per_user.insert(uid, per_user);
         }
         info!("reading config: {}", "complete");
@@ -348,3 +1203,656 @@ impl ConfigManager {
         &self.config.today_per_user
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, ops::{Deref, Not}, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+    use crate::{
+        config::{Binary, Combination, DayConfig, ProcessFilter, WebFilter},
+        types::{AcceptedInterval, Domain, Interval, RejectedInterval, TimeOfDay, Username},
+        uid_resolver::Uid,
+    };
+
+    use super::{CacheEntry, ConfigManager, Error, FailedRemoval, Options, REMOVAL_BACKOFF_MAX, REMOVAL_BACKOFF_START};
+
+    fn time(hours: u8, minutes: u8) -> TimeOfDay {
+        TimeOfDay { hours, minutes }
+    }
+
+    /// A directory for the files of a test, removed along with its contents once the test is
+    /// complete, whether it passes or not.
+    struct TempDir(PathBuf);
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("keep-it-focused-test-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+    impl Deref for TempDir {
+        type Target = Path;
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry(created_s: u64, web: Vec<WebFilter>) -> CacheEntry {
+        let date = UNIX_EPOCH + Duration::from_secs(created_s);
+        CacheEntry {
+            latest_update: date,
+            creation_date: date,
+            expires: None,
+            config: HashMap::from([(
+                Username("root".to_string()),
+                DayConfig {
+                    web,
+                    ..Default::default()
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let dir = TempDir::new("parse");
+        let main_config = dir.join("main.yaml");
+        std::fs::write(&main_config, "users:\n  mickey:\n    monday:\n      web: 12\n").unwrap();
+        let mut manager = ConfigManager::new(Options {
+            main_config: main_config.clone(),
+            extensions_dir: dir.join("extensions"),
+            cache: None,
+        });
+        let err = manager.load_config().unwrap_err();
+        assert!(matches!(err, Error::Parse { ref path, .. } if path == &main_config));
+        assert_eq!(err.line(), Some(3));
+        let err = ConfigManager::validate(&main_config).unwrap_err();
+        assert_eq!(err.line(), Some(3));
+
+        std::fs::write(&main_config, "users:\n  root:\n    monday:\n      web: []\n").unwrap();
+        ConfigManager::validate(&main_config).unwrap();
+
+        std::fs::remove_file(&main_config).unwrap();
+        let err = manager.load_config().unwrap_err();
+        assert!(matches!(err, Error::Io { .. }));
+    }
+
+    #[test]
+    fn test_persisted() {
+        let dir = TempDir::new("persisted");
+        let main_config = dir.join("main.yaml");
+        std::fs::write(&main_config, "combine: intersection\nusers: {}\n").unwrap();
+        let options = || Options {
+            main_config: main_config.clone(),
+            extensions_dir: dir.join("extensions"),
+            cache: Some(dir.join("cache.json")),
+        };
+        let mut manager = ConfigManager::new(options());
+        assert_eq!(manager.generation(), 0);
+        assert!(manager.load_config().unwrap());
+        assert!(dir.join("cache.json").exists());
+        assert!(dir.join("cache.json.tmp").exists().not());
+        assert_eq!(manager.generation(), 1);
+        assert_eq!(manager.main_config_modified(), Some(std::fs::metadata(&main_config).unwrap().modified().unwrap()));
+        // Nothing has changed, nothing to compile.
+        assert!(manager.load_config().unwrap().not());
+        assert_eq!(manager.generation(), 1);
+
+        // Break the main file without changing its modification date: a restart doesn't
+        // parse it again.
+        let latest_update = std::fs::metadata(&main_config).unwrap().modified().unwrap();
+        std::fs::write(&main_config, "users: 12\n").unwrap();
+        std::fs::File::options().write(true).open(&main_config).unwrap().set_modified(latest_update).unwrap();
+        let mut manager = ConfigManager::new(options());
+        assert_eq!(manager.combine, Combination::Intersection);
+        assert!(manager.load_config().unwrap());
+
+        // Once it has changed, it is parsed again.
+        std::fs::File::options()
+            .write(true)
+            .open(&main_config)
+            .unwrap()
+            .set_modified(latest_update + Duration::from_secs(1))
+            .unwrap();
+        let mut manager = ConfigManager::new(options());
+        assert_eq!(manager.combine, Combination::Union);
+        assert!(manager.load_config().is_err());
+    }
+
+    #[test]
+    fn test_extension_expiry() {
+        let dir = TempDir::new("expiry");
+        let extensions_dir = dir.join("extensions");
+        std::fs::create_dir_all(&extensions_dir).unwrap();
+        let main_config = dir.join("main.yaml");
+        std::fs::write(&main_config, "users: {}\n").unwrap();
+        let mut manager = ConfigManager::new(Options {
+            main_config: main_config.clone(),
+            extensions_dir: extensions_dir.clone(),
+            cache: None,
+        });
+
+        // A file created today is valid all day, even if it was last modified yesterday.
+        let fresh = extensions_dir.join("fresh.yaml");
+        std::fs::write(&fresh, "users:\n  root:\n    web: []\n").unwrap();
+        let yesterday = SystemTime::now() - Duration::from_secs(24 * 3600);
+        std::fs::File::options().write(true).open(&fresh).unwrap().set_modified(yesterday).unwrap();
+        let supports_creation_date = std::fs::metadata(&fresh).unwrap().created().is_ok();
+
+        // A file created yesterday is obsolete, even if it was modified today.
+        let obsolete = extensions_dir.join("obsolete.yaml");
+        manager.cache.insert(obsolete.clone(), CacheEntry {
+            latest_update: SystemTime::now(),
+            creation_date: yesterday,
+            expires: None,
+            config: HashMap::new(),
+        });
+
+        // A file with an explicit expiry date is valid until then, whenever it was created.
+        let extended = extensions_dir.join("extended.yaml");
+        manager.cache.insert(extended.clone(), CacheEntry {
+            latest_update: SystemTime::now(),
+            creation_date: yesterday,
+            expires: Some(SystemTime::now() + Duration::from_secs(3600)),
+            config: HashMap::new(),
+        });
+        let expired = extensions_dir.join("expired.yaml");
+        let expires_at = chrono::Local::now() - chrono::Duration::minutes(1);
+        std::fs::write(&expired, format!("users: {{}}\nexpires_at: {}\n", expires_at.to_rfc3339())).unwrap();
+
+        // Compiling without loading ignores expired files but leaves them alone.
+        let compiled = ConfigManager::compile_only(&manager.options).unwrap();
+        assert!(expired.exists());
+        if supports_creation_date {
+            assert!(compiled.today_per_user().is_empty().not());
+        }
+
+        manager.load_config().unwrap();
+        assert!(manager.cache.contains_key(&obsolete).not());
+        assert!(manager.cache.contains_key(&extended));
+        assert!(manager.cache.contains_key(&expired).not());
+        assert!(expired.exists().not());
+        if supports_creation_date {
+            assert!(manager.cache.contains_key(&fresh));
+            assert!(fresh.exists());
+        }
+    }
+
+    #[test]
+    fn test_expired_files() {
+        let dir = TempDir::new("expired");
+        let extensions_dir = dir.join("extensions");
+        std::fs::create_dir_all(&extensions_dir).unwrap();
+        let main_config = dir.join("main.yaml");
+        std::fs::write(&main_config, "users: {}\n").unwrap();
+        let mut manager = ConfigManager::new(Options {
+            main_config: main_config.clone(),
+            extensions_dir: extensions_dir.clone(),
+            cache: None,
+        });
+        manager.load_config().unwrap();
+        assert_eq!(manager.last_compaction, Some(chrono::Local::now().date_naive()));
+
+        // A file that we failed to remove is skipped for a while...
+        let skipped = extensions_dir.join("skipped.yaml");
+        std::fs::write(&skipped, "users:\n  root:\n    web: []\n").unwrap();
+        let failure = FailedRemoval::new("Permission denied (os error 13)".to_string(), Instant::now());
+        manager.expired.insert(skipped.clone(), failure.clone());
+        assert!(manager.load_config().unwrap().not());
+        assert!(manager.cache.contains_key(&skipped).not());
+
+        // ... then examined again, in case it has been replaced.
+        manager.expired.insert(skipped.clone(), FailedRemoval {
+            next_attempt: Instant::now(),
+            ..failure.clone()
+        });
+        assert!(manager.load_config().unwrap());
+        assert!(manager.cache.contains_key(&skipped));
+        assert!(manager.expired.contains_key(&skipped).not());
+
+        // An expired file that was never taken into account doesn't cause a recompilation.
+        let expired = extensions_dir.join("expired.yaml");
+        let expires_at = chrono::Local::now() - chrono::Duration::minutes(1);
+        std::fs::write(&expired, format!("users:\n  root:\n    web: []\nexpires_at: {}\n", expires_at.to_rfc3339())).unwrap();
+        assert!(manager.load_config().unwrap().not());
+        assert!(expired.exists().not());
+
+        // Compaction removes obsolete files, including those we skipped, and leaves the
+        // others alone.
+        std::fs::write(&expired, format!("users: {{}}\nexpires_at: {}\n", expires_at.to_rfc3339())).unwrap();
+        manager.expired.insert(expired.clone(), failure);
+        manager.compact();
+        assert!(expired.exists().not());
+        assert!(skipped.exists());
+        assert!(manager.expired.is_empty());
+
+        // Files that cannot be read are left alone.
+        let broken = extensions_dir.join("broken.yaml");
+        std::fs::write(&broken, "users: 12\n").unwrap();
+        manager.compact();
+        assert!(broken.exists());
+    }
+
+    #[test]
+    fn test_failed_removal() {
+        let start = Instant::now();
+        let mut failure = FailedRemoval::new("Permission denied (os error 13)".to_string(), start);
+        assert_eq!(failure.next_attempt - start, REMOVAL_BACKOFF_START);
+        // Each failure doubles the delay...
+        failure.record("Permission denied (os error 13)".to_string(), start);
+        assert_eq!(failure.next_attempt - start, REMOVAL_BACKOFF_START * 2);
+        assert!(failure.is_stuck().not());
+        for _ in 0..3 {
+            failure.record("Operation not permitted (os error 1)".to_string(), start);
+        }
+        // ... until removal is considered stuck...
+        assert!(failure.is_stuck());
+        assert_eq!(failure.reason, "Operation not permitted (os error 1)");
+        // ... up to a maximum.
+        for _ in 0..100 {
+            failure.record("Operation not permitted (os error 1)".to_string(), start);
+        }
+        assert_eq!(failure.next_attempt - start, REMOVAL_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn test_count_extensions_today() {
+        let dir = TempDir::new("count");
+        let alice = Username("alice".to_string());
+        assert_eq!(ConfigManager::count_extensions_today(&dir, &alice).unwrap(), 0);
+
+        std::fs::write(dir.join("1.yaml"), "users:\n  alice:\n    web: []\n").unwrap();
+        // Profiles count towards their account.
+        std::fs::write(dir.join("2.yaml"), "users:\n  alice:kids:\n    web: []\n  bob:\n    web: []\n").unwrap();
+        std::fs::write(dir.join("3.yaml"), "users:\n  bob:\n    web: []\n").unwrap();
+        // Files being written or that the daemon cannot read don't count.
+        std::fs::write(dir.join(".4.yaml"), "users:\n  alice:\n    web: []\n").unwrap();
+        std::fs::write(dir.join("5.yaml"), "users:\n  alice: [\n").unwrap();
+        assert_eq!(ConfigManager::count_extensions_today(&dir, &alice).unwrap(), 2);
+        assert_eq!(ConfigManager::count_extensions_today(&dir, &Username("bob".to_string())).unwrap(), 2);
+        assert_eq!(ConfigManager::count_extensions_today(&dir, &Username("carol".to_string())).unwrap(), 0);
+
+        std::fs::remove_dir_all(&*dir).unwrap();
+        assert!(ConfigManager::count_extensions_today(&dir, &alice).is_err());
+    }
+
+    #[test]
+    fn test_web_forbidden() {
+        let youtube = Domain("youtube.com".to_string());
+        let cache = HashMap::from([
+            (
+                PathBuf::from("main"),
+                entry(
+                    0,
+                    vec![WebFilter {
+                        domain: youtube.clone(),
+                        permitted: vec![Interval {
+                            start: time(16, 0),
+                            end: time(20, 0),
+                            label: None,
+                        }],
+                        forbidden: vec![Interval {
+                            start: time(17, 0),
+                            end: time(19, 0),
+                            label: None,
+                        }],
+                    }],
+                ),
+            ),
+            (
+                PathBuf::from("extension"),
+                entry(
+                    1,
+                    vec![WebFilter {
+                        domain: youtube.clone(),
+                        permitted: vec![Interval {
+                            start: time(18, 0),
+                            end: time(18, 30),
+                            label: None,
+                        }],
+                        forbidden: vec![],
+                    }],
+                ),
+            ),
+        ]);
+        let compiled = ConfigManager::compile(&cache, Combination::Union, false).unwrap();
+        let root = compiled.today_per_user().values().next().unwrap();
+        assert_eq!(
+            root.web_forbidden().get(&youtube).unwrap(),
+            &vec![
+                RejectedInterval(Interval {
+                    start: time(17, 0),
+                    end: time(18, 0),
+                    label: None,
+                }),
+                RejectedInterval(Interval {
+                    start: time(18, 30),
+                    end: time(19, 0),
+                    label: None,
+                }),
+            ]
+        );
+
+        // By default, only permitted intervals are served.
+        let served = compiled.serialize_web(false);
+        let served = served.values().next().unwrap();
+        assert!(served.starts_with(r#"{"youtube.com":[{"#));
+        let served = compiled.serialize_web(true);
+        let served = served.values().next().unwrap();
+        assert!(served.contains(r#""forbidden":[{"start":"1700","end":"1800"}"#));
+    }
+
+    #[test]
+    fn test_extension_punches_hole() {
+        // The main file blocks youtube.com all day, every day, an `exceptionally allow` permits it
+        // from 16:00 to 16:30.
+        let dir = TempDir::new("hole");
+        let extensions_dir = dir.join("extensions");
+        std::fs::create_dir_all(&extensions_dir).unwrap();
+        let main_config = dir.join("main.yaml");
+        let days = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"]
+            .map(|day| {
+                format!("    {day}:\n      web:\n        - domain: youtube.com\n          forbidden:\n            - start: 0000\n              end: 2400\n")
+            })
+            .concat();
+        std::fs::write(&main_config, format!("users:\n  root:\n{days}")).unwrap();
+        let expires_at = chrono::Local::now() + chrono::Duration::hours(1);
+        std::fs::write(
+            extensions_dir.join("root-allow-domain.yaml"),
+            format!("users:\n  root:\n    web:\n      - domain: youtube.com\n        permitted:\n          - start: 1600\n            end: 1630\nexpires_at: {}\n", expires_at.to_rfc3339()),
+        )
+        .unwrap();
+        let options = Options {
+            main_config,
+            extensions_dir,
+            cache: None,
+        };
+
+        let youtube = Domain("youtube.com".to_string());
+        let compiled = ConfigManager::compile_only(&options).unwrap();
+        let root = &compiled.today_per_user()[&Uid(0)];
+        assert_eq!(root.web()[&youtube], vec![AcceptedInterval(Interval::new(time(16, 0), time(16, 30)).unwrap())]);
+        assert_eq!(
+            root.web_forbidden()[&youtube],
+            vec![
+                RejectedInterval(Interval::new(time(0, 0), time(16, 0)).unwrap()),
+                RejectedInterval(Interval::new(time(16, 30), TimeOfDay::END).unwrap()),
+            ]
+        );
+
+        // Without the extension, youtube.com is never permitted.
+        std::fs::remove_dir_all(&options.extensions_dir).unwrap();
+        let compiled = ConfigManager::compile_only(&options).unwrap();
+        let root = &compiled.today_per_user()[&Uid(0)];
+        assert!(root.web()[&youtube].is_empty());
+    }
+
+    #[test]
+    fn test_web_specificity() {
+        let filter = |domain: &str, permitted: Vec<Interval>| WebFilter {
+            domain: Domain(domain.to_string()),
+            permitted,
+            forbidden: vec![],
+        };
+        // Block google.com, except for docs.google.com, except for secret.docs.google.com.
+        let cache = HashMap::from([(
+            PathBuf::from("main"),
+            entry(
+                0,
+                vec![
+                    filter("google.com", vec![]),
+                    filter("secret.docs.google.com", vec![]),
+                    filter("docs.google.com", vec![Interval { start: time(0, 0), end: TimeOfDay::END, label: None }]),
+                    filter("youtube.com", vec![]),
+                ],
+            ),
+        )]);
+        let compiled = ConfigManager::compile(&cache, Combination::Union, false).unwrap();
+        for forbidden in [false, true] {
+            let served = compiled.serialize_web(forbidden);
+            let served = served.values().next().unwrap();
+            // Most specific domains first.
+            let positions = ["secret.docs.google.com", "docs.google.com", "google.com", "youtube.com"]
+                .map(|domain| served.find(&format!("\"{domain}\"")).unwrap());
+            assert!(positions.is_sorted(), "{served}");
+        }
+    }
+
+    #[test]
+    fn test_combination() {
+        let filter = |start: u8, end: u8| WebFilter {
+            domain: Domain("youtube.com".to_string()),
+            permitted: vec![Interval { start: time(start, 0), end: time(end, 0), label: None }],
+            forbidden: vec![],
+        };
+        let cache = HashMap::from([
+            (PathBuf::from("main"), entry(0, vec![filter(16, 19)])),
+            (PathBuf::from("extension"), entry(1, vec![filter(9, 18)])),
+        ]);
+        let permitted = |combine| {
+            let compiled = ConfigManager::compile(&cache, combine, false).unwrap();
+            let root = compiled.today_per_user().values().next().unwrap();
+            root.web()[&Domain("youtube.com".to_string())]
+                .iter()
+                .map(|interval| (interval.0.start, interval.0.end))
+                .collect::<Vec<_>>()
+        };
+        // By default, the extension permits more.
+        assert_eq!(permitted(Combination::Union), vec![(time(9, 0), time(19, 0))]);
+        // With intersection, it may only restrict.
+        assert_eq!(permitted(Combination::Intersection), vec![(time(16, 0), time(18, 0))]);
+    }
+
+    #[test]
+    fn test_intersection_within_file() {
+        let filter = |start: u8, end: u8| WebFilter {
+            domain: Domain("youtube.com".to_string()),
+            permitted: vec![Interval { start: time(start, 0), end: time(end, 0), label: None }],
+            forbidden: vec![],
+        };
+        // Two rules for the same domain in the same file add up, even with intersection.
+        let mut cache = HashMap::from([(PathBuf::from("main"), entry(0, vec![filter(9, 10), filter(16, 19)]))]);
+        let permitted = |cache: &HashMap<PathBuf, CacheEntry>| {
+            let compiled = ConfigManager::compile(cache, Combination::Intersection, false).unwrap();
+            let root = compiled.today_per_user().values().next().unwrap();
+            root.web()[&Domain("youtube.com".to_string())]
+                .iter()
+                .map(|interval| (interval.0.start, interval.0.end))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(permitted(&cache), vec![(time(9, 0), time(10, 0)), (time(16, 0), time(19, 0))]);
+
+        // Other files still restrict them.
+        cache.insert(PathBuf::from("extension"), entry(1, vec![filter(9, 17)]));
+        assert_eq!(permitted(&cache), vec![(time(9, 0), time(10, 0)), (time(16, 0), time(17, 0))]);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let filter = |domain: &str, start: u8, end: u8| WebFilter {
+            domain: Domain(domain.to_string()),
+            permitted: vec![Interval { start: time(start, 0), end: time(end, 0), label: None }],
+            forbidden: vec![],
+        };
+        let mut main = entry(0, vec![filter("youtube.com", 16, 19)]);
+        main.config.insert(
+            Username("root:kids".to_string()),
+            DayConfig {
+                web: vec![filter("twitch.tv", 17, 18)],
+                ..Default::default()
+            },
+        );
+        let cache = HashMap::from([(PathBuf::from("main"), main)]);
+        let compiled = ConfigManager::compile(&cache, Combination::Intersection, false).unwrap();
+
+        // The account doesn't see the rules of the profile.
+        let root = &compiled.today_per_user()[&Uid(0)];
+        assert_eq!(root.web().len(), 1);
+        assert_eq!(compiled.profiles().len(), 1);
+
+        // The profile sees the rules of the account and its own.
+        let kids = &compiled.profiles()[&(Uid(0), "kids".to_string())];
+        assert_eq!(kids.web().len(), 2);
+        assert!(kids.web().contains_key(&Domain("youtube.com".to_string())));
+        assert!(kids.web().contains_key(&Domain("twitch.tv".to_string())));
+        let served = compiled.serialize_profiles_web(false);
+        assert!(served[&(Uid(0), "kids".to_string())].contains("twitch.tv"));
+    }
+
+    #[test]
+    fn test_case_insensitive_spellings() {
+        let filter = |domain: &str, start: u8, end: u8| WebFilter {
+            domain: Domain(domain.to_string()),
+            permitted: vec![Interval { start: time(start, 0), end: time(end, 0), label: None }],
+            forbidden: vec![],
+        };
+        let mut main = entry(0, vec![filter("youtube.com", 16, 19)]);
+        main.config.insert(
+            Username("ROOT".to_string()),
+            DayConfig {
+                web: vec![filter("twitch.tv", 17, 18)],
+                ..Default::default()
+            },
+        );
+        let cache = HashMap::from([(PathBuf::from("main"), main)]);
+
+        // Both spellings resolve to the same user, their rules are merged.
+        let compiled = ConfigManager::compile(&cache, Combination::Union, true).unwrap();
+        assert_eq!(compiled.today_per_user().len(), 1);
+        let root = &compiled.today_per_user()[&Uid(0)];
+        assert_eq!(root.user_name(), &Username("root".to_string()));
+        assert_eq!(root.web().len(), 2);
+        assert!(root.web().contains_key(&Domain("youtube.com".to_string())));
+        assert!(root.web().contains_key(&Domain("twitch.tv".to_string())));
+
+        // Otherwise, only the exact spelling applies.
+        let compiled = ConfigManager::compile(&cache, Combination::Union, false).unwrap();
+        let root = &compiled.today_per_user()[&Uid(0)];
+        assert_eq!(root.web().len(), 1);
+        assert!(root.web().contains_key(&Domain("youtube.com".to_string())));
+    }
+
+    #[test]
+    fn test_next_boundary() {
+        let filter = |domain: &str, start: u8, end: u8| WebFilter {
+            domain: Domain(domain.to_string()),
+            permitted: vec![Interval { start: time(start, 0), end: time(end, 0), label: None }],
+            forbidden: vec![],
+        };
+        let cache = HashMap::from([(
+            PathBuf::from("main"),
+            entry(0, vec![filter("youtube.com", 17, 18), filter("twitch.tv", 9, 12)]),
+        )]);
+        let compiled = ConfigManager::compile(&cache, Combination::Union, false).unwrap();
+        assert_eq!(compiled.next_boundary(time(8, 0)), time(9, 0));
+        assert_eq!(compiled.next_boundary(time(9, 0)), time(12, 0));
+        assert_eq!(compiled.next_boundary(time(12, 30)), time(17, 0));
+        // Nothing else today, the rules change at midnight.
+        assert_eq!(compiled.next_boundary(time(18, 0)), TimeOfDay::END);
+    }
+
+    #[test]
+    fn test_forbidden_labels() {
+        let homework = Interval::builder()
+            .start(time(16, 0))
+            .end(time(18, 0))
+            .label("homework time")
+            .build();
+        let cache = HashMap::from([(
+            PathBuf::from("main"),
+            CacheEntry {
+                latest_update: UNIX_EPOCH,
+                creation_date: UNIX_EPOCH,
+                expires: None,
+                config: HashMap::from([(
+                    Username("root".to_string()),
+                    DayConfig::builder()
+                        .processes(vec![ProcessFilter::builder()
+                            .binary(Binary::try_new("/usr/games/tetris").unwrap())
+                            .permitted(vec![Interval::new(time(0, 0), TimeOfDay::END).unwrap()])
+                            .forbidden(vec![homework.clone()])
+                            .build()])
+                        .web(vec![WebFilter::builder()
+                            .domain(Domain("youtube.com".to_string()))
+                            .forbidden(vec![homework.clone()])
+                            .build()])
+                        .build(),
+                )]),
+            },
+        )]);
+        let compiled = ConfigManager::compile(&cache, Combination::Union, false).unwrap();
+        let root = compiled.today_per_user().values().next().unwrap();
+        let (tetris, _) = root.process_rule(Path::new("/usr/games/tetris"), |_| false).unwrap();
+        assert_eq!(root.forbidden_label(tetris, time(16, 0)), Some("homework time"));
+        assert_eq!(root.forbidden_label(tetris, time(18, 0)), None);
+
+        let served = compiled.serialize_web(true);
+        let served = served.values().next().unwrap();
+        assert!(served.contains(r#""forbidden":[{"start":"1600","end":"1800","label":"homework time"}]"#), "{served}");
+
+        // Desktop clients get processes along with the web.
+        let served = compiled.serialize_instructions();
+        let served: serde_json::Value = serde_json::from_str(served.values().next().unwrap()).unwrap();
+        assert_eq!(served["processes"][0][0], "/usr/games/tetris");
+        assert_eq!(served["web"]["youtube.com"], serde_json::json!([]));
+        assert_eq!(served["process_forbidden"]["/usr/games/tetris"][0]["label"], "homework time");
+    }
+
+    #[test]
+    fn test_process_rule_precedence() {
+        let rule = |binary: &str, start: u8| {
+            ProcessFilter::builder()
+                .binary(Binary::try_new(binary).unwrap())
+                .permitted(vec![Interval::new(time(start, 0), time(start + 1, 0)).unwrap()])
+                .build()
+        };
+        for processes in [
+            vec![rule("/usr/games/**", 17), rule("/usr/games/tetris", 18), rule("**/tetris", 19)],
+            vec![rule("**/tetris", 19), rule("/usr/games/tetris", 18), rule("/usr/games/**", 17)],
+        ] {
+            let cache = HashMap::from([(
+                PathBuf::from("main"),
+                CacheEntry {
+                    latest_update: UNIX_EPOCH,
+                    creation_date: UNIX_EPOCH,
+                    expires: None,
+                    config: HashMap::from([(
+                        Username("root".to_string()),
+                        DayConfig {
+                            processes,
+                            ..Default::default()
+                        },
+                    )]),
+                },
+            )]);
+            let compiled = ConfigManager::compile(&cache, Combination::Union, false).unwrap();
+            let root = compiled.today_per_user().values().next().unwrap();
+            let no_inode = |_: &Path| false;
+
+            // The path without wildcards wins, whatever the order of rules.
+            let (binary, intervals) = root.process_rule(Path::new("/usr/games/tetris"), no_inode).unwrap();
+            assert_eq!(binary.path, Path::new("/usr/games/tetris"));
+            assert_eq!(intervals[0].0.start, time(18, 0));
+
+            // Between two globs, the longest wins.
+            let (binary, _) = root.process_rule(Path::new("/usr/games/tetris-2"), no_inode).unwrap();
+            assert_eq!(binary.path, Path::new("/usr/games/**"));
+            let (binary, _) = root.process_rule(Path::new("/opt/tetris"), no_inode).unwrap();
+            assert_eq!(binary.path, Path::new("**/tetris"));
+            assert_eq!(root.forbidden_label(binary, time(12, 0)), None);
+
+            assert!(root.process_rule(Path::new("/usr/bin/firefox"), no_inode).is_none());
+
+            let minutes = |m: u64| Some(Duration::from_secs(m * 60));
+            assert_eq!(root.remaining_for_binary(Path::new("/usr/games/tetris"), time(18, 15)), minutes(45));
+            assert_eq!(root.remaining_for_binary(Path::new("/usr/games/tetris"), time(17, 15)), None);
+            // Not watched, permitted until rules change.
+            assert_eq!(root.remaining_for_binary(Path::new("/usr/bin/firefox"), time(23, 0)), minutes(60));
+        }
+    }
+}
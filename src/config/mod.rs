@@ -1,32 +1,112 @@
+pub mod lock;
 pub mod manager;
 
 use core::fmt;
-use std::{collections::HashMap, fmt::Display, hash::Hash, ops::Not, path::PathBuf};
+use std::{collections::{BTreeMap, HashMap}, fmt::Display, hash::Hash, ops::{Not, RangeInclusive}, path::{Path, PathBuf}};
 
-use crate::types::{DayOfWeek, Domain, Interval, Username};
-use anyhow::anyhow;
+use crate::{
+    locale::Language,
+    types::{DayOfWeek, Domain, Interval, TimeOfDay, Username},
+};
+use chrono::{DateTime, Local};
 use globset::{Glob, GlobMatcher};
-use log::trace;
+use itertools::Itertools;
+use log::{trace, warn};
 use serde::{
     de::{Unexpected, Visitor},
     Deserialize, Serialize,
 };
+use typed_builder::TypedBuilder;
+
+/// An error while loading the configuration.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A binary is not a valid glob.
+    #[error("invalid glob {glob}")]
+    InvalidGlob {
+        glob: String,
+        #[source]
+        source: globset::Error,
+    },
+
+    /// A configuration file could not be accessed, e.g. it doesn't exist or we don't have the permissions.
+    #[error("could not access {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A configuration file is not valid.
+    #[error("could not parse {}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: serde_yaml::Error,
+    },
+}
+impl Error {
+    /// If this error was caused by a syntax error, the line (starting at 1) at which it occurred.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Error::Parse { source, .. } => source.location().map(|location| location.line()),
+            _ => None,
+        }
+    }
+}
 
-/// The absolute path to a binary (may be a glob).
+/// The path to a binary (may be a glob).
+///
+/// A relative path, e.g. `chromium`, matches the end of the path of the binary, i.e. it is
+/// equivalent to `**/chromium`.
 #[derive(Clone)]
 pub struct Binary {
     pub path: PathBuf,
     pub matcher: GlobMatcher,
 }
 impl Binary {
-    pub fn try_new(path: &str) -> Result<Self, anyhow::Error> {
-        let glob = Glob::new(path).map_err(|_| anyhow!("invalid glob {path}"))?;
+    pub fn try_new(path: &str) -> Result<Self, Error> {
+        let matcher = Self::compile(path).map_err(|source| Error::InvalidGlob {
+            glob: path.to_string(),
+            source,
+        })?;
 
         Ok(Binary {
             path: PathBuf::from(path),
-            matcher: glob.compile_matcher(),
+            matcher,
         })
     }
+
+    fn compile(path: &str) -> Result<GlobMatcher, globset::Error> {
+        let glob = if Self::is_relative(path) {
+            Glob::new(&format!("**/{path}"))?
+        } else {
+            Glob::new(path)?
+        };
+        Ok(glob.compile_matcher())
+    }
+
+    /// Whether `path` is matched against the end of the path of binaries.
+    fn is_relative(path: &str) -> bool {
+        path.starts_with('/').not() && path.starts_with("**").not()
+    }
+
+    /// How specific this binary is, for precedence between rules matching the same process.
+    ///
+    /// A path without wildcards is more specific than any glob. Among globs, the one with
+    /// the most characters outside of wildcards is the most specific. A relative path is
+    /// a glob.
+    pub fn specificity(&self) -> (bool, usize) {
+        let mut pattern = self.path.to_string_lossy().to_string();
+        if Self::is_relative(&pattern) {
+            pattern.insert_str(0, "**/");
+        }
+        let wildcards = pattern
+            .chars()
+            .filter(|c| matches!(c, '*' | '?' | '[' | ']' | '{' | '}'))
+            .count();
+        (wildcards == 0, pattern.chars().count() - wildcards)
+    }
 }
 impl fmt::Debug for Binary {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -64,10 +144,9 @@ impl<'de> Deserialize<'de> for Binary {
             {
                 trace!("Binary <- {v}");
                 let path = PathBuf::from(v);
-                let glob = Glob::new(v).map_err(|err| {
+                let matcher = Binary::compile(v).map_err(|err| {
                     E::invalid_value(Unexpected::Other(&format!("{}", err)), &"glob string")
                 })?;
-                let matcher = glob.compile_matcher();
                 trace!("Binary -> {path:?}");
                 Ok(Binary { path, matcher })
             }
@@ -90,7 +169,11 @@ impl Display for Binary {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+/// A rule on a binary.
+///
+/// `ProcessFilter::builder()` may be used to build it programmatically: only `binary` is
+/// required.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, TypedBuilder)]
 pub struct ProcessFilter {
     /// The full path to the binary being watched.
     pub binary: Binary,
@@ -98,6 +181,7 @@ pub struct ProcessFilter {
     /// Intervals during which the binary is permitted.
     ///
     /// If empty, the binary is never permitted.
+    #[builder(default)]
     #[serde(default)]
     pub permitted: Vec<Interval>,
 
@@ -106,17 +190,349 @@ pub struct ProcessFilter {
     /// This are subtracted from `permitted`. If empty,
     /// the binary is permitted exactly during the
     /// intervals specified by `permitted`.
+    #[builder(default)]
     #[serde(default)]
     pub forbidden: Vec<Interval>,
+
+    /// If specified, the first time the binary is caught outside of its
+    /// permitted intervals, only warn the user.
+    #[builder(default, setter(strip_option))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<Escalation>,
+
+    /// If true, also match processes running the same file as `binary` (same
+    /// device and inode), whatever the path through which they were launched.
+    ///
+    /// This catches a binary that has been renamed, moved within the same filesystem
+    /// or hard-linked, but not a copy. `binary` must be a path to an existing file,
+    /// not a glob. If it cannot be found, we only match by path.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub match_inode: bool,
+
+    /// The sessions in which the rule applies.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Scope::is_all")]
+    pub scope: Scope,
+
+    /// If true, only enforce the rule while the process owns the focused window, e.g. to
+    /// let a game run in the background.
+    ///
+    /// Best effort: if we cannot find out which window is focused (e.g. under Wayland),
+    /// the rule is enforced regardless.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub focused_only: bool,
+
+    /// If specified, once a permitted interval during which the binary ran has ended, whether
+    /// or not it was still running then, the binary may not be relaunched for this many minutes,
+    /// even if another permitted interval starts in the meantime.
+    #[builder(default, setter(strip_option))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown_minutes: Option<u16>,
+
+    /// If specified, the binary is never stopped: outside of its permitted intervals, the
+    /// user is only reminded to close it, from time to time.
+    #[builder(default, setter(strip_option))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nag: Option<Nag>,
+
+    /// If specified, the binary is only permitted during its permitted intervals if the other
+    /// binaries run by the same user satisfy this condition, e.g. no game is running.
+    #[builder(default, setter(strip_option))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub companion: Option<Companion>,
+
+    /// If specified, the binary may only run this many minutes per week (starting on Monday)
+    /// during its permitted intervals, however the user spreads them.
+    #[builder(default, setter(strip_option))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weekly_budget_minutes: Option<u16>,
+
+    /// If true, a process that has used the CPU since the previous check, e.g. because the
+    /// user is in the middle of something, isn't stopped right away: the user receives a last
+    /// warning, and the process is stopped at the next check, busy or not.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub defer_if_active: bool,
+}
+
+/// The sessions in which a rule applies.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// All processes, wherever they were launched from.
+    #[default]
+    All,
+
+    /// Only processes of local sessions (e.g. the desktop or a virtual console), not those
+    /// of remote sessions (e.g. SSH). Processes outside of any login session are considered
+    /// local, and so are sessions opened from this machine (e.g. `ssh localhost`).
+    Local,
 }
+impl Scope {
+    pub fn is_all(&self) -> bool {
+        *self == Scope::All
+    }
+}
+
+/// How the rules of several sources (the main configuration, temporary rules) on the same
+/// binary or domain combine.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Combination {
+    /// Rules apply in the order in which their files were created: each permits its
+    /// `permitted` intervals, then forbids its `forbidden` intervals. So a temporary rule
+    /// may permit more than the main configuration.
+    #[default]
+    Union,
+
+    /// Permitted only if every rule permits it. A rule without `permitted` intervals
+    /// permits everything but its `forbidden` intervals. So a temporary rule may only
+    /// restrict the main configuration.
+    Intersection,
+}
+impl Combination {
+    pub fn is_union(&self) -> bool {
+        *self == Combination::Union
+    }
+}
+
+/// The templates of the notifications shown to users, e.g. to translate them or change their tone.
+///
+/// Templates may use the placeholders `{binary}` (the path to the binary), `{user}`, `{label}`
+/// (the label of the `forbidden` interval, if any, otherwise nothing), `{reason}` (the label
+/// between parentheses, if any, otherwise nothing), as well as `{minutes}` for `warning`,
+/// `cooldown`, `budget_warning` and `budget_left`, `{budget_left}` for `warning` (`budget_left`,
+/// if a weekly budget applies, otherwise nothing), `{launcher}` for `stopped_launcher`, `{companion}` for
+/// `companion_running` and `companion_missing` and `{budget}` for `budget_exhausted`. Other placeholders are left as is.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Messages {
+    /// A binary is about to be stopped.
+    pub warning: String,
+
+    /// A binary isn't permitted, but it's the first offense, see `escalation`.
+    pub first_offense: String,
+
+    /// A binary isn't permitted and is being stopped.
+    pub stopped: String,
+
+    /// A binary keeps restarting, so we're stopping the process that launches it.
+    pub stopped_launcher: String,
+
+    /// A binary keeps restarting, but we may not stop the process that launches it.
+    pub keeps_restarting: String,
 
+    /// A binary was relaunched too soon after the end of a permitted interval, see `cooldown_minutes`.
+    pub cooldown: String,
+
+    /// A binary isn't permitted, but its rule only reminds the user, see `nag`.
+    pub nag: String,
+
+    /// A binary is stopped because another binary is running, see `companion`.
+    pub companion_running: String,
+
+    /// A binary is stopped because another binary isn't running, see `companion`.
+    pub companion_missing: String,
+
+    /// A binary is about to use up its weekly budget, see `weekly_budget_minutes`.
+    pub budget_warning: String,
+
+    /// The weekly budget left to a binary, appended to `warning`, see `weekly_budget_minutes`.
+    pub budget_left: String,
+
+    /// A binary has used up its weekly budget, see `weekly_budget_minutes`.
+    pub budget_exhausted: String,
+
+    /// A binary isn't permitted, but it's busy, so it will only be stopped at the next check,
+    /// see `defer_if_active`.
+    pub deferred: String,
+}
+impl Default for Messages {
+    fn default() -> Self {
+        Messages {
+            warning: "{binary} will quit in {minutes} minutes{reason}{budget_left}".to_string(),
+            first_offense: "{binary} is not permitted at this time{reason}, please close it or it will be stopped".to_string(),
+            stopped: "{binary} is not permitted at this time{reason}, stopping it".to_string(),
+            stopped_launcher: "{binary} keeps restarting{reason}, stopping {launcher} which launches it".to_string(),
+            keeps_restarting: "{binary} keeps restarting{reason}, it will be stopped each time".to_string(),
+            cooldown: "{binary} needs a break, it may be relaunched in {minutes} minutes".to_string(),
+            nag: "{binary} is not permitted at this time{reason}, please close it".to_string(),
+            companion_running: "{binary} is not permitted while {companion} is running, stopping it".to_string(),
+            companion_missing: "{binary} is only permitted while {companion} is running, stopping it".to_string(),
+            budget_warning: "{binary} will quit in {minutes} minutes, its weekly budget is almost used up".to_string(),
+            budget_left: ", {minutes} minutes left this week".to_string(),
+            budget_exhausted: "{binary} has used up its weekly budget of {budget} minutes, stopping it".to_string(),
+            deferred: "{binary} is not permitted at this time{reason}, please save your work, it will be stopped shortly".to_string(),
+        }
+    }
+}
+impl Messages {
+    pub fn is_default(&self) -> bool {
+        *self == Messages::default()
+    }
+
+    /// Translate the templates that haven't been customized.
+    pub fn localized(&self, language: Language) -> Self {
+        Messages {
+            warning: language.translate(&self.warning).to_string(),
+            first_offense: language.translate(&self.first_offense).to_string(),
+            stopped: language.translate(&self.stopped).to_string(),
+            stopped_launcher: language.translate(&self.stopped_launcher).to_string(),
+            keeps_restarting: language.translate(&self.keeps_restarting).to_string(),
+            cooldown: language.translate(&self.cooldown).to_string(),
+            nag: language.translate(&self.nag).to_string(),
+            companion_running: language.translate(&self.companion_running).to_string(),
+            companion_missing: language.translate(&self.companion_missing).to_string(),
+            budget_warning: language.translate(&self.budget_warning).to_string(),
+            budget_left: language.translate(&self.budget_left).to_string(),
+            budget_exhausted: language.translate(&self.budget_exhausted).to_string(),
+            deferred: language.translate(&self.deferred).to_string(),
+        }
+    }
+
+    /// Replace the placeholders of `template` with `values`.
+    ///
+    /// Values are inserted as is, placeholders within values are not replaced.
+    pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+        let mut result = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest.find('}').and_then(|end| {
+                let (_, value) = values.iter().find(|(key, _)| *key == &rest[1..end])?;
+                Some((value, end))
+            });
+            match value {
+                Some((value, end)) => {
+                    result.push_str(value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    result.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+/// Commands run by the daemon after enforcing a rule, e.g. to log to a home-automation system.
+///
+/// Commands are run with `sh -c`, as the same user as the daemon, i.e. generally root. They
+/// receive `KIF_USER`, `KIF_BINARY` and `KIF_ACTION` (`warning`, `first_offense` or `kill`) as
+/// environment variables, see `hooks`.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// Run when a user is warned that a binary is about to be stopped, or on a first offense.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_warn: Option<String>,
+
+    /// Run when a binary is stopped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_kill: Option<String>,
+}
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.on_warn.is_none() && self.on_kill.is_none()
+    }
+}
+
+/// A "warn on first offense, kill on repeat" policy.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Escalation {
+    /// How long an offense is remembered, in minutes.
+    ///
+    /// If the binary is caught again within this delay, it is stopped.
+    /// Otherwise, this counts as a new first offense.
+    pub cooldown: u16,
+}
+
+/// A "remind, never stop" policy.
+///
+/// Reminders are spaced by a random delay between `min_minutes` and `max_minutes`, so that
+/// they don't become background noise.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Nag {
+    pub min_minutes: u16,
+    pub max_minutes: u16,
+}
+impl Nag {
+    /// The delays between two reminders, in minutes, at least 1.
+    pub fn minutes(&self) -> RangeInclusive<u16> {
+        let min = self.min_minutes.max(1);
+        min..=self.max_minutes.max(min)
+    }
+}
+
+/// A condition on the binaries run by a user, see `ProcessFilter::companion`.
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Companion {
+    /// The condition only holds while none of these binaries is running, e.g. "no game".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_running: Vec<Binary>,
+
+    /// If non-empty, the condition only holds while at least one of these binaries is
+    /// running, e.g. "the homework app".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub running: Vec<Binary>,
+}
+impl Companion {
+    /// Why the condition doesn't hold, given the binaries currently running, if it doesn't.
+    pub fn unsatisfied<'a>(&'a self, running: &'a [PathBuf]) -> Option<Unsatisfied<'a>> {
+        let is_running = |binary: &Binary| running.iter().any(|exe| binary.matcher.is_match(exe));
+        if let Some(exe) = running
+            .iter()
+            .find(|exe| self.not_running.iter().any(|binary| binary.matcher.is_match(exe)))
+        {
+            return Some(Unsatisfied::Running(exe));
+        }
+        if self.running.is_empty().not() && self.running.iter().any(is_running).not() {
+            return Some(Unsatisfied::Missing(&self.running));
+        }
+        None
+    }
+}
+
+/// Why a `Companion` condition doesn't hold.
+#[derive(Debug, PartialEq)]
+pub enum Unsatisfied<'a> {
+    /// This binary is running, but is listed in `not_running`.
+    Running(&'a Path),
+
+    /// None of these binaries, listed in `running`, is running.
+    Missing(&'a [Binary]),
+}
+impl Display for Unsatisfied<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unsatisfied::Running(exe) => write!(f, "{}", exe.display()),
+            Unsatisfied::Missing(binaries) => {
+                write!(f, "{}", binaries.iter().map(|binary| binary.path.display()).join(", "))
+            }
+        }
+    }
+}
+
+/// A rule on a domain.
+///
+/// `WebFilter::builder()` may be used to build it programmatically: only `domain` is required.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, TypedBuilder)]
 pub struct WebFilter {
     pub domain: Domain,
 
     /// Intervals during which the domain is permitted.
     ///
     /// If empty, the domain is never permitted.
+    #[builder(default)]
     #[serde(default)]
     pub permitted: Vec<Interval>,
 
@@ -125,134 +541,469 @@ pub struct WebFilter {
     /// This are subtracted from `permitted`. If empty,
     /// the domain is permitted exactly during the
     /// intervals specified by `permitted`.
+    #[builder(default)]
+    #[serde(default)]
+    pub forbidden: Vec<Interval>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct NetworkFilter {
+    /// The binary whose network access is blocked (may be a glob).
+    pub binary: Binary,
+
+    /// Intervals during which the binary may access the network.
+    ///
+    /// If empty, the binary may never access the network.
+    #[serde(default)]
+    pub permitted: Vec<Interval>,
+
+    /// Intervals during which the binary may not access the network.
+    ///
+    /// This are subtracted from `permitted`.
     #[serde(default)]
     pub forbidden: Vec<Interval>,
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
 enum DayConfigParser {
-    Copy {
-        /// Copy the configuration of another day of the week.
-        like: DayOfWeek,
-    },
-    Instructions {
-        /// Block certain processes during given time periods.
-        #[serde(default)]
-        processes: Vec<ProcessFilter>,
-
-        /// Block certain IPs during given time periods.
-        ///
-        /// Note: This doesn't work with e.g. youtube.com, as they
-        /// load-balance between millions of IPs.
-        #[serde(default)]
-        ip: Vec<WebFilter>,
-
-        /// Block certain domains during given time periods.
-        ///
-        /// Note: This requires the companion browser extension.
-        #[serde(default)]
-        web: Vec<WebFilter>,
-    },
+    /// Copy the configuration of another day of the week or of a template.
+    Copy { like: String },
+    Instructions(DayConfig),
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Debug, Default)]
+impl<'de> Deserialize<'de> for DayConfigParser {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Copy {
+            like: String,
+        }
+        // untagged enum parsers are really bad for error messages, so we use an intermediate
+        // yaml parser
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+        if value.get("like").is_some() {
+            let copy: Copy = serde_yaml::from_value(value).map_err(D::Error::custom)?;
+            Ok(DayConfigParser::Copy { like: copy.like })
+        } else {
+            let instructions: DayConfig = serde_yaml::from_value(value).map_err(D::Error::custom)?;
+            Ok(DayConfigParser::Instructions(instructions))
+        }
+    }
+}
+
+/// The rules of a day.
+///
+/// `DayConfig::builder()` may be used to build it programmatically, e.g.
+/// `DayConfig::builder().processes(vec![filter]).build()`.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default, TypedBuilder)]
 pub struct DayConfig {
+    /// Block certain processes during given time periods.
+    #[builder(default)]
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub processes: Vec<ProcessFilter>,
 
+    /// Block certain IPs during given time periods.
+    ///
+    /// Note: This doesn't work with e.g. youtube.com, as they
+    /// load-balance between millions of IPs.
+    #[builder(default)]
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ip: Vec<WebFilter>,
 
+    /// Block certain domains during given time periods.
+    ///
+    /// Note: This requires the companion browser extension.
+    #[builder(default)]
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub web: Vec<WebFilter>,
+
+    /// Block the network access of certain binaries during given time periods, without
+    /// stopping them.
+    ///
+    /// Note: This requires iptables and cgroups v2, and only applies to binaries running in
+    /// a cgroup of their own, e.g. applications launched from a desktop environment.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network: Vec<NetworkFilter>,
 }
+impl DayConfig {
+    /// All the intervals mentioned in this configuration, permitted or forbidden.
+    pub fn intervals(&self) -> impl Iterator<Item = &Interval> {
+        let processes = self
+            .processes
+            .iter()
+            .flat_map(|filter| filter.permitted.iter().chain(&filter.forbidden));
+        let domains = self
+            .ip
+            .iter()
+            .chain(&self.web)
+            .flat_map(|filter| filter.permitted.iter().chain(&filter.forbidden));
+        let network = self
+            .network
+            .iter()
+            .flat_map(|filter| filter.permitted.iter().chain(&filter.forbidden));
+        processes.chain(domains).chain(network)
+    }
 
-#[derive(Serialize, Default, Debug)]
+    /// The `forbidden` intervals that cannot forbid anything, as they end when or before they
+    /// start, along with the binary or domain to which they apply.
+    pub fn ineffective_forbidden(&self) -> Vec<(String, &Interval)> {
+        let processes = self
+            .processes
+            .iter()
+            .flat_map(|filter| filter.forbidden.iter().map(|interval| (filter.binary.path.display().to_string(), interval)));
+        let domains = self
+            .ip
+            .iter()
+            .chain(&self.web)
+            .flat_map(|filter| filter.forbidden.iter().map(|interval| (filter.domain.to_string(), interval)));
+        let network = self
+            .network
+            .iter()
+            .flat_map(|filter| filter.forbidden.iter().map(|interval| (filter.binary.path.display().to_string(), interval)));
+        processes
+            .chain(domains)
+            .chain(network)
+            .filter(|(_, interval)| interval.end <= interval.start)
+            .collect()
+    }
+}
+
+#[derive(Default, Debug)]
 pub struct Week(pub HashMap<DayOfWeek, DayConfig>);
 
+impl Serialize for Week {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // Serialize days in order, so that the output is stable and readable.
+        let sorted: BTreeMap<&DayOfWeek, &DayConfig> = self.0.iter().collect();
+        sorted.serialize(serializer)
+    }
+}
+
 impl<'de> Deserialize<'de> for Week {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        use serde::de::{Error, Unexpected};
+        use serde::de::Error;
         trace!("attempting to parse week");
-        let mut parse_map = HashMap::<DayOfWeek, DayConfigParser>::deserialize(deserializer)?;
-        let mut build_map = HashMap::<DayOfWeek, DayConfig>::new();
+        let parse_map = HashMap::<DayOfWeek, DayConfigParser>::deserialize(deserializer)?;
+        Week::resolve(parse_map, &HashMap::new()).map_err(D::Error::custom)
+    }
+}
 
+impl Week {
+    /// Resolve the `like:` references of a week.
+    ///
+    /// A `like:` may reference either one of `templates` or another day of the same week, by
+    /// its full name (see `DayOfWeek::from_name`). If a template has the name of a day, the
+    /// template takes precedence.
+    fn resolve(
+        parse_map: HashMap<DayOfWeek, DayConfigParser>,
+        templates: &HashMap<String, DayConfig>,
+    ) -> Result<Self, String> {
         trace!("attempting to normalize week");
-        // Let's be a bit hackish here. As there are exactly 7 per week, we need at most 7 steps to flatten any reference.
-        for _ in 0..7 {
-            for day in [
-                DayOfWeek::monday(),
-                DayOfWeek::tuesday(),
-                DayOfWeek::wednesday(),
-                DayOfWeek::thursday(),
-                DayOfWeek::friday(),
-                DayOfWeek::saturday(),
-                DayOfWeek::sunday(),
-            ] {
-                match parse_map.get(&day) {
-                    None => continue,
-                    Some(DayConfigParser::Copy { like: other }) => {
-                        // Attempt to resolve.
-                        let Some(d) = build_map.get(other) else {
-                            continue;
-                        };
-                        build_map.insert(
-                            day,
-                            DayConfig {
-                                processes: d.processes.clone(),
-                                ip: d.ip.clone(),
-                                web: d.web.clone(),
-                            },
-                        );
-                    }
-                    Some(DayConfigParser::Instructions { processes, ip, web }) => {
-                        build_map.insert(
-                            day,
-                            DayConfig {
-                                processes: processes.clone(),
-                                ip: ip.clone(),
-                                web: web.clone(),
-                            },
-                        );
+        let mut build_map = HashMap::new();
+        let mut copies = BTreeMap::new();
+        for (day, parsed) in parse_map {
+            match parsed {
+                DayConfigParser::Instructions(config) => {
+                    build_map.insert(day, config);
+                }
+                DayConfigParser::Copy { like } => {
+                    if let Some(template) = templates.get(&like) {
+                        build_map.insert(day, template.clone());
+                    } else if let Some(other) = DayOfWeek::from_name(&like) {
+                        copies.insert(day, other);
+                    } else {
+                        return Err(format!(
+                            "{day} is defined as like {like}, but {like} is neither a day nor a template"
+                        ));
                     }
                 }
-                parse_map.remove(&day);
             }
         }
-        if parse_map.is_empty().not() {
-            return Err(D::Error::invalid_value(
-                Unexpected::Other("cycle within day definitions"),
-                &"a DAG of day definitions",
-            ));
-        }
-        Ok(Week(build_map))
+        Ok(Week(resolve_copies(build_map, copies)?))
     }
 }
 
+/// Resolve chains of `like:`, where `copies` maps keys to the key they copy.
+///
+/// Each chain must end with a key in `build_map`. As we stop as soon as we revisit a key,
+/// each chain is at most as long as `copies`.
+fn resolve_copies<K>(
+    mut build_map: HashMap<K, DayConfig>,
+    copies: BTreeMap<K, K>,
+) -> Result<HashMap<K, DayConfig>, String>
+where
+    K: Clone + Ord + Hash + Display,
+{
+    for day in copies.keys() {
+        let mut chain = vec![day];
+        let mut current = day;
+        let config = loop {
+            let target = &copies[current];
+            if let Some(config) = build_map.get(target) {
+                break config.clone();
+            }
+            if chain.contains(&target) {
+                chain.push(target);
+                return Err(format!(
+                    "cycle within day definitions: {}",
+                    chain.iter().join(" -> ")
+                ));
+            }
+            if copies.contains_key(target).not() {
+                return Err(format!(
+                    "{current} is defined as like {target}, but {target} is not defined"
+                ));
+            }
+            chain.push(target);
+            current = target;
+        };
+        build_map.insert(day.clone(), config);
+    }
+    Ok(build_map)
+}
+
 /// The contents of /etc/keep-it-focused.yaml, covering the entire week.
-#[derive(Deserialize, Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug)]
 pub struct Config {
+    /// Named day configurations, which days may reference with `like: name`.
+    ///
+    /// Useful e.g. to share the same rules between several users.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub templates: HashMap<String, DayConfig>,
+
     #[serde(default)]
     pub users: HashMap<Username, Week>,
+
+    /// How rules from several files combine.
+    #[serde(default, skip_serializing_if = "Combination::is_union")]
+    pub combine: Combination,
+
+    /// The notifications shown to users.
+    #[serde(default, skip_serializing_if = "Messages::is_default")]
+    pub messages: Messages,
+
+    /// The language of notifications. If unspecified, that of the daemon's locale.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<Language>,
+
+    /// Commands to run after enforcement.
+    ///
+    /// Only taken into account in the main file, as they are run as root.
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+
+    /// If true, a user name that doesn't match any user exactly may match a user up to
+    /// case, e.g. `Mickey` applies to user `mickey`. Applies to all files, but only taken
+    /// into account in the main file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub case_insensitive_users: bool,
+
+    /// If specified, the maximal number of temporary rules that may be written for a user
+    /// on a given day, by `exceptionally` or `apply`.
+    ///
+    /// Only taken into account in the main file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_extensions_per_day: Option<u16>,
+
+    /// If specified, the maximal duration of a rule added by `exceptionally` to allow
+    /// something, in minutes. Longer rules are shortened.
+    ///
+    /// Only taken into account in the main file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_exceptional_minutes: Option<u16>,
+}
+
+impl Config {
+    /// The binaries referenced by a rule as a path without wildcards, but which don't exist,
+    /// which is probably a typo or the wrong path.
+    ///
+    /// Globs (including relative paths) are skipped, as they may legitimately match nothing.
+    pub fn missing_binaries(&self) -> Vec<&Binary> {
+        self.users
+            .values()
+            .flat_map(|week| week.0.values())
+            .flat_map(|day| {
+                day.processes
+                    .iter()
+                    .map(|proc| &proc.binary)
+                    .chain(day.network.iter().map(|network| &network.binary))
+            })
+            .filter(|binary| binary.specificity().0 && binary.path.exists().not())
+            .unique()
+            .sorted_by(|a, b| a.path.cmp(&b.path))
+            .collect()
+    }
+
+    /// The `forbidden` intervals that cannot forbid anything, for each user and day, see
+    /// `DayConfig::ineffective_forbidden`.
+    pub fn ineffective_forbidden(&self) -> Vec<(&Username, DayOfWeek, String, &Interval)> {
+        self.users
+            .iter()
+            .sorted_by(|a, b| a.0 .0.cmp(&b.0 .0))
+            .flat_map(|(user, week)| {
+                DayOfWeek::all().filter_map(move |day| week.0.get(&day).map(|config| (user, day, config)))
+            })
+            .flat_map(|(user, day, config)| {
+                config
+                    .ineffective_forbidden()
+                    .into_iter()
+                    .map(move |(target, interval)| (user, day, target, interval))
+            })
+            .collect()
+    }
+
+    /// How long a rule added by `exceptionally` lasts, in minutes, if it starts at `start`
+    /// and lasts `minutes` or until `end` (by default, until the end of the day).
+    ///
+    /// Rules that allow something are shortened to `max_exceptional_minutes`, with a warning.
+    /// Rules that forbid something are not, as that would permit it earlier than requested.
+    pub fn exceptional_minutes(&self, allow: bool, start: TimeOfDay, minutes: Option<u16>, end: Option<TimeOfDay>) -> u16 {
+        let requested = minutes.unwrap_or_else(|| {
+            end.unwrap_or(TimeOfDay::END)
+                .as_minutes()
+                .saturating_sub(start.as_minutes())
+        });
+        match self.max_exceptional_minutes {
+            Some(max) if allow && requested > max => {
+                warn!("rule would last {requested} minutes, the maximum is {max} minutes, shortening it");
+                max
+            }
+            _ => requested,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        #[derive(Deserialize)]
+        struct ConfigParser {
+            #[serde(default)]
+            templates: HashMap<String, DayConfigParser>,
+            #[serde(default)]
+            users: HashMap<Username, HashMap<DayOfWeek, DayConfigParser>>,
+            #[serde(default)]
+            combine: Combination,
+            #[serde(default)]
+            messages: Messages,
+            #[serde(default)]
+            language: Option<Language>,
+            #[serde(default)]
+            hooks: Hooks,
+            #[serde(default)]
+            case_insensitive_users: bool,
+            #[serde(default)]
+            max_extensions_per_day: Option<u16>,
+            #[serde(default)]
+            max_exceptional_minutes: Option<u16>,
+        }
+        let parsed = ConfigParser::deserialize(deserializer)?;
+
+        // Templates may only reference other templates.
+        let mut build_map = HashMap::new();
+        let mut copies = BTreeMap::new();
+        for (name, parsed) in parsed.templates {
+            match parsed {
+                DayConfigParser::Instructions(config) => {
+                    build_map.insert(name, config);
+                }
+                DayConfigParser::Copy { like } => {
+                    copies.insert(name, like);
+                }
+            }
+        }
+        let templates = resolve_copies(build_map, copies)
+            .map_err(|err| D::Error::custom(format!("templates: {err}")))?;
+
+        let mut users = HashMap::new();
+        for (user, parse_map) in parsed.users {
+            let week = Week::resolve(parse_map, &templates)
+                .map_err(|err| D::Error::custom(format!("users.{user}: {err}")))?;
+            users.insert(user, week);
+        }
+        Ok(Config {
+            templates,
+            users,
+            combine: parsed.combine,
+            messages: parsed.messages,
+            language: parsed.language,
+            hooks: parsed.hooks,
+            case_insensitive_users: parsed.case_insensitive_users,
+            max_extensions_per_day: parsed.max_extensions_per_day,
+            max_exceptional_minutes: parsed.max_exceptional_minutes,
+        })
+    }
 }
 
 /// The contents of a patch file, valid only for one day.
 #[derive(Deserialize, Serialize, Default, Debug)]
 pub struct Extension {
     pub users: HashMap<Username, DayConfig>,
+
+    /// If specified, the file is valid until this date, rather than until the end of the
+    /// day on which it was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Local>>,
+}
+impl Extension {
+    /// The intervals of a rule that starts at `start` and lasts `minutes`, as added at `now`
+    /// by `exceptionally`. Sets `expires_at` to the end of the rule.
+    ///
+    /// A rule that extends past midnight is split into `[start, 24:00)` and `[00:00, end)`.
+    /// As a file applies to whichever day it's read, the latter also applies today, so this
+    /// is rejected if it would apply later than `now` today, e.g. if it extends past tomorrow.
+    pub fn schedule(&mut self, start: TimeOfDay, minutes: u16, label: Option<String>, now: DateTime<Local>) -> Result<Vec<Interval>, anyhow::Error> {
+        let today = now.date_naive();
+        let day_ends = TimeOfDay::END.as_minutes() as u32;
+        let end = start.as_minutes() as u32 + minutes as u32;
+        if end <= day_ends {
+            let end = TimeOfDay::from_minutes(end as u16);
+            self.expires_at = end.on(today);
+            return Ok(vec![Interval { start, end, label }]);
+        }
+        let overflow = TimeOfDay::from_minutes((end - day_ends).min(day_ends) as u16);
+        if overflow > TimeOfDay::from(now) {
+            return Err(anyhow::anyhow!(
+                "rule would extend until {overflow} tomorrow, which would also permit or forbid it until {overflow} today, add the rest tomorrow"
+            ));
+        }
+        self.expires_at = overflow.on(today.succ_opt().unwrap_or(today));
+        Ok(vec![
+            Interval { start, end: TimeOfDay::END, label: label.clone() },
+            Interval { start: TimeOfDay::START, end: overflow, label },
+        ])
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
-    use crate::types::{TimeOfDay, Username};
+    use chrono::{Local, TimeZone};
 
-    use super::{Config, DayOfWeek};
+    use crate::types::{Domain, Interval, TimeOfDay, Username};
+
+    use std::ops::Not;
+
+    use crate::locale::Language;
+
+    use super::{
+        Binary, Combination, Config, DayConfig, DayOfWeek, Escalation, Extension, Messages, ProcessFilter, Scope, Unsatisfied,
+        WebFilter,
+    };
 
     #[test]
     fn test_config_syntax_v2() {
@@ -265,6 +1016,20 @@ mod test {
                               permitted:
                                 - start: 0911
                                   end: 0923
+                              forbidden:
+                                - start: 0915
+                                  end: 0920
+                                  label: homework time
+                              escalation:
+                                cooldown: 15
+                              scope: local
+                              focused_only: true
+                              cooldown_minutes: 30
+                              nag:
+                                min_minutes: 3
+                                max_minutes: 7
+                              weekly_budget_minutes: 300
+                              defer_if_active: true
                     tuesday:
                         like: monday
                     WEDanythinggoes:
@@ -302,8 +1067,484 @@ mod test {
                 minutes: 11
             }
         );
+        assert_eq!(
+            mickey_monday.processes[0].forbidden[0].label.as_deref(),
+            Some("homework time")
+        );
+        assert_eq!(mickey_monday.processes[0].permitted[0].label, None);
+        assert_eq!(
+            mickey_monday.processes[0].escalation,
+            Some(Escalation { cooldown: 15 })
+        );
+        assert_eq!(mickey_monday.processes[0].scope, Scope::Local);
+        assert!(mickey_monday.processes[0].focused_only);
+        assert_eq!(mickey_monday.processes[0].cooldown_minutes, Some(30));
+        assert_eq!(mickey_monday.processes[0].nag.unwrap().minutes(), 3..=7);
+        assert_eq!(mickey_monday.processes[0].weekly_budget_minutes, Some(300));
+        assert!(mickey_monday.processes[0].defer_if_active);
         assert_eq!(mickey_monday, mickey_tuesday);
         assert_eq!(mickey_wed, mickey_tuesday);
         assert_eq!(mickey.0.len(), 3);
+
+        let mouse = config
+            .users
+            .get(&Username("mouse".to_string()))
+            .expect("missing user mouse");
+        assert_eq!(mouse.0[&DayOfWeek::monday()].processes[0].escalation, None);
+        assert_eq!(mouse.0[&DayOfWeek::monday()].processes[0].scope, Scope::All);
+        assert!(mouse.0[&DayOfWeek::monday()].processes[0].focused_only.not());
+        assert_eq!(config.combine, Combination::Union);
+
+        assert!(config.case_insensitive_users.not());
+
+        let config: Config = serde_yaml::from_str("combine: intersection").expect("invalid config");
+        assert_eq!(config.combine, Combination::Intersection);
+
+        let config: Config = serde_yaml::from_str("case_insensitive_users: true").expect("invalid config");
+        assert!(config.case_insensitive_users);
+    }
+
+    #[test]
+    fn test_config_cycles() {
+        let sample = r#"
+            users:
+                mickey:
+                    monday:
+                        like: monday
+        "#;
+        let err = serde_yaml::from_str::<Config>(sample).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("cycle within day definitions: monday -> monday"));
+
+        let sample = r#"
+            users:
+                mickey:
+                    monday:
+                        like: tuesday
+                    tuesday:
+                        like: wednesday
+                    wednesday:
+                        like: monday
+                    thursday:
+                        like: monday
+        "#;
+        let err = serde_yaml::from_str::<Config>(sample).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("cycle within day definitions: monday -> tuesday -> wednesday -> monday"));
+
+        let sample = r#"
+            users:
+                mickey:
+                    monday:
+                        like: tuesday
+        "#;
+        let err = serde_yaml::from_str::<Config>(sample).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("monday is defined as like tuesday, but tuesday is not defined"));
+    }
+
+    #[test]
+    fn test_config_templates() {
+        let sample = r#"
+            templates:
+                school-day:
+                    processes:
+                        - binary: /usr/games/**
+                          permitted:
+                            - start: 1700
+                              end: 1800
+                weekend:
+                    like: school-day
+            users:
+                mickey:
+                    monday:
+                        like: school-day
+                    tuesday:
+                        like: monday
+                    saturday:
+                        like: weekend
+                minnie:
+                    monday:
+                        like: school-day
+        "#;
+        let config: Config = serde_yaml::from_str(sample).expect("invalid config");
+        let template = config.templates.get("school-day").unwrap();
+        let mickey = config.users.get(&Username("mickey".to_string())).unwrap();
+        let minnie = config.users.get(&Username("minnie".to_string())).unwrap();
+        assert_eq!(mickey.0.get(&DayOfWeek::monday()).unwrap(), template);
+        assert_eq!(mickey.0.get(&DayOfWeek::tuesday()).unwrap(), template);
+        assert_eq!(mickey.0.get(&DayOfWeek::saturday()).unwrap(), template);
+        assert_eq!(minnie.0.get(&DayOfWeek::monday()).unwrap(), template);
+
+        let sample = r#"
+            templates:
+                a:
+                    like: b
+                b:
+                    like: a
+        "#;
+        let err = serde_yaml::from_str::<Config>(sample).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("templates: cycle within day definitions: a -> b -> a"));
+
+        let sample = r#"
+            users:
+                mickey:
+                    monday:
+                        like: school-day
+        "#;
+        let err = serde_yaml::from_str::<Config>(sample).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("monday is defined as like school-day, but school-day is neither a day nor a template"));
+
+        // Days are referenced by their full name, so that a typo isn't mistaken for a day.
+        let sample = r#"
+            users:
+                mickey:
+                    monday:
+                        processes: []
+                    tuesday:
+                        like: Montag
+                    wednesday:
+                        like: mondy
+        "#;
+        let err = serde_yaml::from_str::<Config>(sample).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("wednesday is defined as like mondy, but mondy is neither a day nor a template"));
+        let sample = r#"
+            users:
+                mickey:
+                    monday:
+                        processes: []
+                    tuesday:
+                        like: Montag
+        "#;
+        let config = serde_yaml::from_str::<Config>(sample).unwrap();
+        let mickey = config.users.get(&Username("mickey".to_string())).unwrap();
+        assert!(mickey.0.contains_key(&DayOfWeek::tuesday()));
+    }
+
+    #[test]
+    fn test_binary_relative() {
+        let matches = |binary: &str, exe: &str| Binary::try_new(binary).unwrap().matcher.is_match(exe);
+
+        // A relative path matches the end of the path.
+        assert!(matches("chromium", "/usr/bin/chromium"));
+        assert!(matches("chromium", "/snap/chromium/current/usr/lib/chromium"));
+        assert!(matches("chromium", "/usr/bin/chromium-browser").not());
+        assert!(matches("bin/chromium", "/usr/bin/chromium"));
+
+        assert!(matches("/usr/bin/chromium", "/usr/bin/chromium"));
+        assert!(matches("/usr/bin/chromium", "/opt/bin/chromium").not());
+
+        assert!(matches("**/chromium", "/usr/bin/chromium"));
+        assert!(matches("**/chromium", "/opt/chromium"));
+
+        // Serialized as written.
+        let binary: Binary = serde_yaml::from_str("chromium").unwrap();
+        assert!(binary.matcher.is_match("/usr/bin/chromium"));
+        assert_eq!(serde_yaml::to_string(&binary).unwrap(), "chromium\n");
+
+        // A relative path is as specific as the equivalent glob.
+        assert_eq!(
+            Binary::try_new("chromium").unwrap().specificity(),
+            Binary::try_new("**/chromium").unwrap().specificity()
+        );
+        assert!(Binary::try_new("/usr/bin/chromium").unwrap().specificity() > Binary::try_new("chromium").unwrap().specificity());
+    }
+
+    #[test]
+    fn test_messages() {
+        let config: Config = serde_yaml::from_str("users: {}").unwrap();
+        assert!(config.messages.is_default());
+
+        let sample = r#"
+            messages:
+                warning: "{user}, {binary} s'arrête dans {minutes} minutes{reason}"
+        "#;
+        let config: Config = serde_yaml::from_str(sample).unwrap();
+        // Other templates keep their default.
+        assert_eq!(config.messages.stopped, Messages::default().stopped);
+        let values = [
+            ("binary", "/usr/games/tetris"),
+            ("user", "alice"),
+            ("minutes", "5"),
+            ("reason", " (devoirs)"),
+        ];
+        assert_eq!(
+            Messages::render(&config.messages.warning, &values),
+            "alice, /usr/games/tetris s'arrête dans 5 minutes (devoirs)"
+        );
+
+        // Unknown placeholders and lone braces are kept, values are not expanded.
+        assert_eq!(
+            Messages::render("{binary} {unknown} {label} {", &[("binary", "{label}"), ("label", "")]),
+            "{label} {unknown}  {"
+        );
+
+        assert!(serde_yaml::from_str::<Config>("messages: { warnings: oops }").is_err());
+
+        // Customized templates are not translated.
+        let config: Config = serde_yaml::from_str("language: fr\nmessages: { stopped: Stop }").unwrap();
+        assert_eq!(config.language, Some(Language::French));
+        let messages = config.messages.localized(Language::French);
+        assert_eq!(messages.stopped, "Stop");
+        assert_eq!(messages.warning, "{binary} va se fermer dans {minutes} minutes{reason}{budget_left}");
+        assert_eq!(messages.budget_left, ", il reste {minutes} minutes cette semaine");
+    }
+
+    #[test]
+    fn test_network() {
+        let sample = r#"
+            users:
+                alice:
+                    saturday:
+                        network:
+                            - binary: "**/supertuxkart"
+                              permitted:
+                                - start: 1000
+                                  end: 1100
+        "#;
+        let config: Config = serde_yaml::from_str(sample).unwrap();
+        let saturday = &config.users[&Username("alice".to_string())].0[&DayOfWeek::saturday()];
+        assert_eq!(saturday.network.len(), 1);
+        assert!(saturday.network[0].binary.matcher.is_match("/usr/games/supertuxkart"));
+        assert!(saturday.network[0].forbidden.is_empty());
+        assert_eq!(saturday.intervals().count(), 1);
+    }
+
+    #[test]
+    fn test_missing_binaries() {
+        let sample = r#"
+            users:
+                alice:
+                    monday:
+                        processes:
+                            - binary: /bin/sh
+                            - binary: /usr/bin/keep-it-focused-does-not-exist
+                            - binary: "/usr/bin/keep-it-focused-*"
+                            - binary: keep-it-focused-does-not-exist
+                    tuesday:
+                        like: monday
+                    wednesday:
+                        network:
+                            - binary: /usr/games/keep-it-focused-does-not-exist
+        "#;
+        let config: Config = serde_yaml::from_str(sample).unwrap();
+        let missing = config.missing_binaries().into_iter().map(|binary| binary.path.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            missing,
+            [
+                PathBuf::from("/usr/bin/keep-it-focused-does-not-exist"),
+                PathBuf::from("/usr/games/keep-it-focused-does-not-exist"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_companion() {
+        let sample = r#"
+            users:
+                alice:
+                    monday:
+                        processes:
+                            - binary: /usr/bin/firefox
+                              permitted:
+                                - start: 1600
+                                  end: 2000
+                              companion:
+                                not_running: ["**/steam", "/usr/games/**"]
+                            - binary: /usr/bin/discord
+                              companion:
+                                running: [/usr/bin/firefox, /usr/bin/chromium]
+                                not_running: ["/usr/games/**"]
+        "#;
+        let config: Config = serde_yaml::from_str(sample).unwrap();
+        let monday = &config.users[&Username("alice".to_string())].0[&DayOfWeek::monday()];
+        let no_game = monday.processes[0].companion.as_ref().unwrap();
+        let with_browser = monday.processes[1].companion.as_ref().unwrap();
+
+        let running = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(no_game.unsatisfied(&running(&[])), None);
+        assert_eq!(no_game.unsatisfied(&running(&["/usr/bin/firefox", "/usr/bin/bash"])), None);
+        let steam = running(&["/usr/bin/firefox", "/home/alice/.steam/bin/steam"]);
+        assert_eq!(no_game.unsatisfied(&steam), Some(Unsatisfied::Running(Path::new("/home/alice/.steam/bin/steam"))));
+        assert_eq!(no_game.unsatisfied(&running(&["/usr/games/tetris"])).unwrap().to_string(), "/usr/games/tetris");
+
+        let nothing = running(&["/usr/bin/bash"]);
+        let missing = with_browser.unsatisfied(&nothing).unwrap();
+        assert_eq!(missing, Unsatisfied::Missing(&with_browser.running));
+        assert_eq!(missing.to_string(), "/usr/bin/firefox, /usr/bin/chromium");
+        assert_eq!(with_browser.unsatisfied(&running(&["/usr/bin/chromium"])), None);
+        // Both conditions must hold.
+        let tetris = running(&["/usr/bin/chromium", "/usr/games/tetris"]);
+        assert_eq!(with_browser.unsatisfied(&tetris), Some(Unsatisfied::Running(Path::new("/usr/games/tetris"))));
+    }
+
+    #[test]
+    fn test_builders() {
+        let sample = r#"
+            processes:
+                - binary: /usr/games/tetris
+                  permitted:
+                    - start: 1600
+                      end: 1800
+                  forbidden:
+                    - start: 1630
+                      end: 1700
+                      label: homework time
+                  cooldown_minutes: 30
+            web:
+                - domain: youtube.com
+                  permitted:
+                    - start: 1700
+                      end: 1800
+        "#;
+        let parsed: DayConfig = serde_yaml::from_str(sample).unwrap();
+        let time = |hours, minutes| TimeOfDay { hours, minutes };
+        let built = DayConfig::builder()
+            .processes(vec![ProcessFilter::builder()
+                .binary(Binary::try_new("/usr/games/tetris").unwrap())
+                .permitted(vec![Interval::new(time(16, 0), time(18, 0)).unwrap()])
+                .forbidden(vec![Interval::builder()
+                    .start(time(16, 30))
+                    .end(time(17, 0))
+                    .label("homework time")
+                    .build()])
+                .cooldown_minutes(30)
+                .build()])
+            .web(vec![WebFilter::builder()
+                .domain(Domain("youtube.com".to_string()))
+                .permitted(vec![Interval::new(time(17, 0), time(18, 0)).unwrap()])
+                .build()])
+            .build();
+        assert_eq!(built, parsed);
+        assert_eq!(DayConfig::builder().build(), DayConfig::default());
+    }
+
+    #[test]
+    fn test_ineffective_forbidden() {
+        let sample = r#"
+            users:
+                bob:
+                    tuesday:
+                        web:
+                            - domain: youtube.com
+                              forbidden:
+                                - start: 1700
+                                  end: 1700
+                alice:
+                    monday:
+                        processes:
+                            - binary: /usr/games/tetris
+                              forbidden:
+                                - start: 0900
+                                  end: 1000
+                                - start: 1800
+                                  end: 1700
+                              permitted:
+                                - start: 1800
+                                  end: 1700
+                    wednesday:
+                        network:
+                            - binary: /usr/bin/firefox
+                              forbidden:
+                                - end: 0000
+        "#;
+        let config: Config = serde_yaml::from_str(sample).unwrap();
+        let ineffective = config
+            .ineffective_forbidden()
+            .into_iter()
+            .map(|(user, day, target, interval)| (user.0.as_str(), day, target, interval.start.to_string(), interval.end.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ineffective,
+            [
+                ("alice", DayOfWeek::monday(), "/usr/games/tetris".to_string(), "18:00".to_string(), "17:00".to_string()),
+                ("alice", DayOfWeek::wednesday(), "/usr/bin/firefox".to_string(), "00:00".to_string(), "00:00".to_string()),
+                ("bob", DayOfWeek::tuesday(), "youtube.com".to_string(), "17:00".to_string(), "17:00".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hooks() {
+        let config: Config = serde_yaml::from_str("users: {}").unwrap();
+        assert!(config.hooks.is_empty());
+
+        let config: Config = serde_yaml::from_str("hooks: { on_kill: \"logger killed $KIF_BINARY\" }").unwrap();
+        assert_eq!(config.hooks.on_kill.as_deref(), Some("logger killed $KIF_BINARY"));
+        assert_eq!(config.hooks.on_warn, None);
+
+        assert!(serde_yaml::from_str::<Config>("hooks: { on_stop: oops }").is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let time = |hours, minutes| TimeOfDay { hours, minutes };
+        let at = |hours, minutes| Local.with_ymd_and_hms(2026, 3, 10, hours, minutes, 0).unwrap();
+        let interval = |start, end| Interval { start, end, label: None };
+
+        // Within the day.
+        let mut extension = Extension::default();
+        let intervals = extension.schedule(time(16, 0), 30, None, at(15, 0)).unwrap();
+        assert_eq!(intervals, vec![interval(time(16, 0), time(16, 30))]);
+        assert_eq!(extension.expires_at, Some(at(16, 30)));
+
+        // Until midnight.
+        let intervals = extension.schedule(time(23, 30), 30, None, at(23, 30)).unwrap();
+        assert_eq!(intervals, vec![interval(time(23, 30), TimeOfDay::END)]);
+        assert_eq!(extension.expires_at, Some(at(23, 59) + chrono::Duration::minutes(1)));
+
+        // Past midnight, the rule is split and lasts until tomorrow.
+        let intervals = extension.schedule(time(23, 50), 30, None, at(23, 50)).unwrap();
+        assert_eq!(intervals, vec![interval(time(23, 50), TimeOfDay::END), interval(TimeOfDay::START, time(0, 20))]);
+        assert_eq!(extension.expires_at, Some(at(0, 20) + chrono::Duration::days(1)));
+
+        // Unless the part past midnight would also apply later today.
+        assert!(extension.schedule(time(23, 0), 10 * 60, None, at(8, 0)).is_err());
+        assert!(extension.schedule(time(23, 0), 48 * 60, None, at(23, 0)).is_err());
+    }
+
+    #[test]
+    fn test_exceptional_minutes() {
+        let time = |hours, minutes| TimeOfDay { hours, minutes };
+        let config: Config = serde_yaml::from_str("max_exceptional_minutes: 60").unwrap();
+
+        // Rules that allow something are capped, whether specified with `--minutes` or `--end`.
+        assert_eq!(config.exceptional_minutes(true, time(16, 0), Some(30), None), 30);
+        assert_eq!(config.exceptional_minutes(true, time(16, 0), Some(90), None), 60);
+        assert_eq!(config.exceptional_minutes(true, time(16, 0), None, Some(time(16, 45))), 45);
+        assert_eq!(config.exceptional_minutes(true, time(16, 0), None, Some(time(18, 0))), 60);
+        assert_eq!(config.exceptional_minutes(true, time(16, 0), None, None), 60);
+
+        // Rules that forbid something are not.
+        assert_eq!(config.exceptional_minutes(false, time(16, 0), Some(90), None), 90);
+        assert_eq!(config.exceptional_minutes(false, time(16, 0), None, None), 8 * 60);
+
+        // Without a limit, nothing is capped.
+        let config = Config::default();
+        assert_eq!(config.exceptional_minutes(true, time(16, 0), Some(90), None), 90);
+        assert_eq!(config.exceptional_minutes(true, time(16, 0), None, Some(time(18, 0))), 120);
+    }
+
+    /// Inputs found by `cargo fuzz`, which used to cause panics.
+    #[test]
+    fn test_fuzz_regressions() {
+        let samples = [
+            // Day names shorter than 3 bytes.
+            "users:\n  david:\n    \"\":\n      like: monday\n",
+            // Day names that are not ASCII.
+            "users:\n  david:\n    \"\u{9e}\u{96}\":\n      like: monday\n",
+        ];
+        for sample in samples {
+            assert!(serde_yaml::from_str::<Config>(sample).is_err());
+        }
     }
 }
@@ -0,0 +1,101 @@
+//! Keeping track of the end of permitted intervals, for rules with a cooldown.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Local, NaiveDate};
+use log::debug;
+
+use crate::{types::TimeOfDay, uid_resolver::Uid};
+
+/// When the permitted interval of a (user, binary) ends or ended, as observed while the binary
+/// was running at some point during that interval.
+#[derive(Debug)]
+pub struct Cooldowns {
+    /// The day to which the intervals apply.
+    day: NaiveDate,
+
+    ends: HashMap<(Uid, PathBuf), TimeOfDay>,
+}
+impl Default for Cooldowns {
+    fn default() -> Self {
+        Cooldowns::new()
+    }
+}
+impl Cooldowns {
+    pub fn new() -> Self {
+        Cooldowns {
+            day: Local::now().date_naive(),
+            ends: HashMap::new(),
+        }
+    }
+
+    /// Forget all intervals if `today` is not the day to which they apply.
+    pub fn rollover(&mut self, today: NaiveDate) {
+        if today != self.day {
+            self.day = today;
+            self.ends.clear();
+        }
+    }
+
+    /// Record that `binary` is running for `uid` during a permitted interval ending at `end`.
+    pub fn record(&mut self, uid: Uid, binary: &Path, end: TimeOfDay) {
+        self.ends.insert((uid, binary.to_path_buf()), end);
+    }
+
+    /// If the permitted interval of `binary` for `uid` ended less than `cooldown` minutes
+    /// before `now`, the number of minutes until the end of the cooldown.
+    pub fn remaining(&self, uid: Uid, binary: &Path, cooldown: u16, now: TimeOfDay) -> Option<u16> {
+        let end = self.ends.get(&(uid, binary.to_path_buf()))?;
+        let elapsed = now.as_minutes().checked_sub(end.as_minutes())?;
+        let remaining = cooldown.checked_sub(elapsed).filter(|remaining| *remaining > 0)?;
+        debug!(
+            "binary {} for user {} is in cooldown for {remaining} minutes",
+            binary.display(),
+            uid.0
+        );
+        Some(remaining)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use chrono::Local;
+
+    use crate::{types::TimeOfDay, uid_resolver::Uid};
+
+    use super::Cooldowns;
+
+    fn time(hours: u8, minutes: u8) -> TimeOfDay {
+        TimeOfDay { hours, minutes }
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let tetris = Path::new("**/tetris");
+        let user = Uid(1000);
+        let mut cooldowns = Cooldowns::new();
+
+        // Never seen running, no cooldown.
+        assert_eq!(cooldowns.remaining(user, tetris, 30, time(17, 0)), None);
+
+        cooldowns.record(user, tetris, time(17, 0));
+        // Before the end of the interval.
+        assert_eq!(cooldowns.remaining(user, tetris, 30, time(16, 55)), None);
+        // During the cooldown.
+        assert_eq!(cooldowns.remaining(user, tetris, 30, time(17, 0)), Some(30));
+        assert_eq!(cooldowns.remaining(user, tetris, 30, time(17, 20)), Some(10));
+        // After the cooldown.
+        assert_eq!(cooldowns.remaining(user, tetris, 30, time(17, 30)), None);
+        // Other users are tracked separately.
+        assert_eq!(cooldowns.remaining(Uid(1001), tetris, 30, time(17, 10)), None);
+
+        // A new day forgets intervals.
+        cooldowns.rollover(Local::now().date_naive().succ_opt().unwrap());
+        assert_eq!(cooldowns.remaining(user, tetris, 30, time(17, 10)), None);
+    }
+}
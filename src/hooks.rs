@@ -0,0 +1,150 @@
+//! Running the commands configured in `config::Hooks` after enforcement.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::uid_resolver::Uid;
+
+/// How long a hook may run before it is stopped.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Once a hook has run for a (user, binary, action), how long before it may run again, so
+/// that e.g. `on_warn` runs once per warning rather than at each sweep.
+pub const HOOK_DEBOUNCE: Duration = Duration::from_secs(5 * 60);
+
+/// How often we check whether a hook is complete.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The hooks run recently, per (user, binary, action).
+#[derive(Debug, Default)]
+pub struct Debounce {
+    latest: HashMap<(Uid, PathBuf, &'static str), Instant>,
+}
+impl Debounce {
+    /// Whether the hook for `action` should run for `binary` of `uid` at `now`, i.e. whether it
+    /// hasn't run within `HOOK_DEBOUNCE`. If so, record that it runs.
+    ///
+    /// `now` should be the start of the current sweep, so that several processes of the same
+    /// binary only run the hook once.
+    pub fn should_run(&mut self, uid: Uid, binary: &Path, action: &'static str, now: Instant) -> bool {
+        self.latest
+            .retain(|_, latest| now.saturating_duration_since(*latest) < HOOK_DEBOUNCE);
+        match self.latest.entry((uid, binary.to_path_buf(), action)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+/// Run `command` with `sh -c` on its own thread, so that a slow hook doesn't delay enforcement.
+///
+/// The command receives `KIF_USER`, `KIF_BINARY` and `KIF_ACTION` as environment variables.
+pub fn spawn(command: &str, user: &str, binary: &Path, action: &str) -> JoinHandle<()> {
+    spawn_with_timeout(command, user, binary, action, HOOK_TIMEOUT)
+}
+
+fn spawn_with_timeout(command: &str, user: &str, binary: &Path, action: &str, timeout: Duration) -> JoinHandle<()> {
+    let mut child = Command::new("sh");
+    child
+        .arg("-c")
+        .arg(command)
+        .env("KIF_USER", user)
+        .env("KIF_BINARY", binary)
+        .env("KIF_ACTION", action)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let command = command.to_string();
+    std::thread::spawn(move || {
+        debug!("running hook {command}");
+        let mut child = match child.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                warn!("failed to launch hook {command}: {err}");
+                return;
+            }
+        };
+        let start = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => {
+                    debug!("hook {command} complete");
+                    return;
+                }
+                Ok(Some(status)) => {
+                    warn!("hook {command} failed with {status}");
+                    return;
+                }
+                Ok(None) if start.elapsed() < timeout => std::thread::sleep(POLL_INTERVAL),
+                Ok(None) => {
+                    warn!("hook {command} still running after {}s, stopping it", timeout.as_secs());
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return;
+                }
+                Err(err) => {
+                    warn!("failed to wait for hook {command}: {err}");
+                    return;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ops::Not,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use crate::uid_resolver::Uid;
+
+    use super::{spawn, spawn_with_timeout, Debounce, HOOK_DEBOUNCE};
+
+    #[test]
+    fn test_hook() {
+        let path = std::env::temp_dir().join(format!("keep-it-focused-test-hook-{}", std::process::id()));
+        let command = format!("echo \"$KIF_USER $KIF_BINARY $KIF_ACTION\" > {}", path.display());
+        spawn(&command, "alice", Path::new("/usr/games/tetris"), "kill")
+            .join()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "alice /usr/games/tetris kill\n");
+        std::fs::remove_file(&path).unwrap();
+
+        // Slow hooks are stopped.
+        let start = Instant::now();
+        spawn_with_timeout("sleep 10", "alice", Path::new("/usr/games/tetris"), "kill", Duration::from_millis(200))
+            .join()
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_debounce() {
+        let tetris = Path::new("/usr/games/tetris");
+        let mut debounce = Debounce::default();
+        let start = Instant::now();
+        assert!(debounce.should_run(Uid(1000), tetris, "warning", start));
+        // Other processes of the same sweep, later sweeps.
+        assert!(debounce.should_run(Uid(1000), tetris, "warning", start).not());
+        assert!(debounce.should_run(Uid(1000), tetris, "warning", start + Duration::from_secs(5)).not());
+        // Other actions, binaries and users are independent.
+        assert!(debounce.should_run(Uid(1000), tetris, "kill", start + Duration::from_secs(5)));
+        assert!(debounce.should_run(Uid(1000), Path::new("/usr/bin/chromium"), "warning", start));
+        assert!(debounce.should_run(Uid(1001), tetris, "warning", start));
+        // Once the delay has passed, the hook runs again.
+        assert!(debounce.should_run(Uid(1000), tetris, "warning", start + HOOK_DEBOUNCE));
+    }
+}
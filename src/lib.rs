@@ -2,33 +2,119 @@ pub mod config;
 
 #[cfg(target_family = "unix")]
 pub mod unix;
+pub mod locale;
+pub mod observe;
+pub mod quick;
+mod activity;
+mod budgets;
+mod cooldowns;
+mod hooks;
+mod nags;
+mod offenses;
+mod respawn;
 mod server;
 pub mod setup;
+pub mod stats;
 pub mod types;
 
-use std::{collections::HashMap, path::PathBuf, rc::Rc, sync::Arc, ops::Not};
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt::Display, path::{Path, PathBuf}, rc::Rc, sync::Arc, time::Duration};
+use std::os::unix::fs::MetadataExt;
 
 use anyhow::Context;
+use chrono::{DateTime, Datelike, Local};
 use config::manager::ConfigManager;
 use log::{debug, info, warn};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use observe::Observations;
+use activity::{Activity, ProcessKey};
+use budgets::Budgets;
+use cooldowns::Cooldowns;
+use nags::Nags;
+use offenses::{Offenses, Sanction};
+use respawn::{KillAction, RecentKills};
 use server::Server;
+use stats::Stats;
 use typed_builder::TypedBuilder;
 use types::{AcceptedInterval, Domain, RejectedInterval, Username};
 
-use crate::{config::Binary, types::TimeOfDay};
+use crate::{config::{Binary, Companion, Escalation, Messages, Nag, Unsatisfied}, types::TimeOfDay};
 
 #[cfg(target_os = "linux")]
-use crate::unix::linux::notify::{ notify, Urgency };
+use crate::unix::linux::focus::{focused_pid, is_focused};
+#[cfg(target_os = "linux")]
+use crate::unix::linux::notify::{ Notifications, Urgency };
+#[cfg(target_os = "linux")]
+use crate::unix::linux::procfs::{binary_cgroups, display_session, exe_path, is_remote_session, is_self_or_descendant, launcher, runs_file, watched_owner};
 #[cfg(target_family = "unix")]
-use crate::unix::uid_resolver::{self, Uid};
+use crate::unix::uid_resolver;
+#[cfg(feature = "dbus")]
+use crate::unix::linux::dbus::LaunchWatcher;
+#[cfg(feature = "ip_tables")]
+use crate::unix::{
+    linux::iptables::{self, IPTable, IP_TABLES_PREFIX},
+    uid_resolver::Uid,
+};
 
 #[derive(Serialize, Debug, Clone)]
 pub struct UserInstructions {
     user_name: Rc<Username>,
     processes: Vec<(Binary, Vec<AcceptedInterval>)>,
     ips: HashMap<Domain, Vec<RejectedInterval>>,
+
+    /// The binaries whose network access is blocked through iptables today, with the
+    /// intervals during which they're rejected.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    network: Vec<(Binary, Vec<RejectedInterval>)>,
     web: HashMap<Domain, Vec<AcceptedInterval>>,
+
+    /// For each domain in `web`, the intervals during which it is blocked because
+    /// of an explicit `forbidden` rule (rather than because it's outside of `permitted`).
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    web_forbidden: HashMap<Domain, Vec<RejectedInterval>>,
+
+    /// For binaries in `processes`, the escalation policy, if any.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    escalations: HashMap<Binary, Escalation>,
+
+    /// The binaries in `processes` that should also be matched by inode.
+    #[serde(skip_serializing_if = "HashSet::is_empty")]
+    match_inode: HashSet<Binary>,
+
+    /// The binaries in `processes` whose rule only applies to local sessions.
+    #[serde(skip_serializing_if = "HashSet::is_empty")]
+    local_only: HashSet<Binary>,
+
+    /// The binaries in `processes` whose rule only applies while they own the focused window.
+    #[serde(skip_serializing_if = "HashSet::is_empty")]
+    focused_only: HashSet<Binary>,
+
+    /// The binaries in `processes` that aren't stopped right away if they're busy.
+    #[serde(skip_serializing_if = "HashSet::is_empty")]
+    defer_if_active: HashSet<Binary>,
+
+    /// For binaries in `processes`, how long they may not be relaunched after the end of a
+    /// permitted interval, in minutes.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    cooldowns: HashMap<Binary, u16>,
+
+    /// For binaries in `processes`, how often to remind the user to close them, instead of
+    /// stopping them.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    nags: HashMap<Binary, Nag>,
+
+    /// For binaries in `processes`, the condition on the other binaries of the user under
+    /// which their permitted intervals apply.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    companions: HashMap<Binary, Companion>,
+
+    /// For binaries in `processes`, how many minutes per week they may run, if limited.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    weekly_budgets: HashMap<Binary, u16>,
+
+    /// For binaries in `processes`, the intervals during which they're blocked because of an
+    /// explicit `forbidden` rule. Only recorded if some of these intervals have a label.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    process_forbidden: HashMap<Binary, Vec<RejectedInterval>>,
 }
 impl UserInstructions {
     pub fn new(user_name: Rc<Username>) -> Self {
@@ -36,7 +122,273 @@ impl UserInstructions {
             user_name,
             processes: Vec::new(),
             ips: HashMap::new(),
+            network: Vec::new(),
             web: HashMap::new(),
+            web_forbidden: HashMap::new(),
+            escalations: HashMap::new(),
+            match_inode: HashSet::new(),
+            local_only: HashSet::new(),
+            focused_only: HashSet::new(),
+            defer_if_active: HashSet::new(),
+            cooldowns: HashMap::new(),
+            nags: HashMap::new(),
+            companions: HashMap::new(),
+            weekly_budgets: HashMap::new(),
+            process_forbidden: HashMap::new(),
+        }
+    }
+    pub fn user_name(&self) -> &Username {
+        &self.user_name
+    }
+    /// The binaries watched today, with the intervals during which they're permitted.
+    pub fn processes(&self) -> &[(Binary, Vec<AcceptedInterval>)] {
+        &self.processes
+    }
+    /// The domains blocked through iptables today, with the intervals during which they're rejected.
+    pub fn ips(&self) -> &HashMap<Domain, Vec<RejectedInterval>> {
+        &self.ips
+    }
+    /// The binaries whose network access is blocked through iptables today, with the intervals during which they're rejected.
+    pub fn network(&self) -> &[(Binary, Vec<RejectedInterval>)] {
+        &self.network
+    }
+    /// The domains watched by the browser extension today, with the intervals during which they're permitted.
+    pub fn web(&self) -> &HashMap<Domain, Vec<AcceptedInterval>> {
+        &self.web
+    }
+    /// The domains blocked by an explicit `forbidden` rule today, with the intervals during which they're blocked.
+    pub fn web_forbidden(&self) -> &HashMap<Domain, Vec<RejectedInterval>> {
+        &self.web_forbidden
+    }
+    /// The escalation policy for a binary watched today, if any.
+    pub fn escalation(&self, binary: &Binary) -> Option<Escalation> {
+        self.escalations.get(binary).copied()
+    }
+    /// Whether a binary watched today should also be matched by inode.
+    pub fn matches_inode(&self, binary: &Binary) -> bool {
+        self.match_inode.contains(binary)
+    }
+    /// Whether the rule on a binary watched today only applies to local sessions.
+    pub fn is_local_only(&self, binary: &Binary) -> bool {
+        self.local_only.contains(binary)
+    }
+    /// Whether the rule on a binary watched today only applies while it owns the focused window.
+    pub fn is_focused_only(&self, binary: &Binary) -> bool {
+        self.focused_only.contains(binary)
+    }
+    /// Whether stopping a binary watched today is deferred if it's busy.
+    pub fn defers_if_active(&self, binary: &Binary) -> bool {
+        self.defer_if_active.contains(binary)
+    }
+    /// The cooldown of a binary watched today, in minutes, if any.
+    pub fn cooldown(&self, binary: &Binary) -> Option<u16> {
+        self.cooldowns.get(binary).copied()
+    }
+    /// Today's coverage of the rule on a binary watched today, if any.
+    pub fn process_coverage(&self, binary: &Binary) -> Option<Coverage> {
+        self.processes
+            .iter()
+            .find(|(candidate, _)| candidate == binary)
+            .map(|(_, accepted)| Coverage::of_accepted(accepted))
+    }
+    /// Today's coverage of the rule on a domain watched by the browser extension, if any.
+    pub fn web_coverage(&self, domain: &Domain) -> Option<Coverage> {
+        self.web.get(domain).map(|accepted| Coverage::of_accepted(accepted))
+    }
+    /// Today's coverage of the rule on a domain blocked through iptables, if any.
+    pub fn ip_coverage(&self, domain: &Domain) -> Option<Coverage> {
+        self.ips.get(domain).map(|rejected| Coverage::of_rejected(rejected))
+    }
+    /// Today's coverage of the rule on the network access of a binary, if any.
+    pub fn network_coverage(&self, binary: &Binary) -> Option<Coverage> {
+        self.network
+            .iter()
+            .find(|(candidate, _)| candidate == binary)
+            .map(|(_, rejected)| Coverage::of_rejected(rejected))
+    }
+    /// The nag policy of a binary watched today, if any.
+    pub fn nag(&self, binary: &Binary) -> Option<Nag> {
+        self.nags.get(binary).copied()
+    }
+    /// The companion condition of a binary watched today, if any.
+    pub fn companion(&self, binary: &Binary) -> Option<&Companion> {
+        self.companions.get(binary)
+    }
+    /// The weekly budget of a binary watched today, in minutes, if any.
+    pub fn weekly_budget(&self, binary: &Binary) -> Option<u16> {
+        self.weekly_budgets.get(binary).copied()
+    }
+    /// Whether some binary watched today has a companion condition.
+    pub fn has_companions(&self) -> bool {
+        !self.companions.is_empty()
+    }
+    /// The label of an explicit `forbidden` rule blocking `binary` at `time`, if any.
+    pub fn forbidden_label(&self, binary: &Binary, time: TimeOfDay) -> Option<&str> {
+        self.process_forbidden
+            .get(binary)?
+            .iter()
+            .filter(|interval| interval.0.remaining(time).is_some())
+            .find_map(|interval| interval.0.label.as_deref())
+    }
+    /// The rule applying today to a process running `exe`, if any.
+    ///
+    /// If several rules match, the most specific binary wins (see `Binary::specificity`),
+    /// e.g. a rule on `/usr/games/tetris` overrides a rule on `/usr/games/**`, whichever
+    /// intervals they permit. Ties are broken by path, so the result doesn't depend on the
+    /// order of rules.
+    ///
+    /// `runs_file` tells whether the process runs a given file, for rules that match by inode.
+    pub fn process_rule(
+        &self,
+        exe: &Path,
+        runs_file: impl Fn(&Path) -> bool,
+    ) -> Option<&(Binary, Vec<AcceptedInterval>)> {
+        self.processes
+            .iter()
+            .filter(|(binary, _)| {
+                binary.matcher.is_match(exe) || (self.matches_inode(binary) && runs_file(&binary.path))
+            })
+            .max_by(|(a, _), (b, _)| {
+                a.specificity()
+                    .cmp(&b.specificity())
+                    .then_with(|| b.path.cmp(&a.path))
+            })
+    }
+    /// How long a process running `exe` may keep running from `now`, or `None` if it's
+    /// not permitted at `now`.
+    ///
+    /// Back-to-back permitted intervals sum up. A binary without a rule is permitted until the
+    /// end of the day, when rules change. Rules that match by inode are not considered, use
+    /// `process_rule` and `AcceptedInterval::remaining` for these.
+    pub fn remaining_for_binary(&self, exe: &Path, now: TimeOfDay) -> Option<Duration> {
+        match self.process_rule(exe, |_| false) {
+            Some((_, intervals)) => AcceptedInterval::remaining(intervals, now),
+            None => Some(Duration::from(TimeOfDay::END).saturating_sub(now.into())),
+        }
+    }
+    /// The first start or end of an interval strictly after `now`, if any.
+    pub fn next_boundary(&self, now: TimeOfDay) -> Option<TimeOfDay> {
+        let accepted = self
+            .processes
+            .iter()
+            .flat_map(|(_, intervals)| intervals)
+            .chain(self.web.values().flatten())
+            .map(|interval| &interval.0);
+        let rejected = self
+            .ips
+            .values()
+            .chain(self.network.iter().map(|(_, intervals)| intervals))
+            .flatten()
+            .map(|interval| &interval.0);
+        accepted
+            .chain(rejected)
+            .flat_map(|interval| [interval.start, interval.end])
+            .filter(|time| *time > now)
+            .min()
+    }
+}
+
+/// Before this year, the clock has clearly not been set yet, e.g. NTP hasn't synced after boot.
+const EARLIEST_PLAUSIBLE_YEAR: i32 = 2020;
+
+/// How much of today a rule permits and blocks.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coverage {
+    pub permitted_minutes: u16,
+    pub blocked_minutes: u16,
+}
+impl Coverage {
+    pub fn of_accepted(accepted: &[AcceptedInterval]) -> Self {
+        let permitted_minutes = AcceptedInterval::total_minutes(accepted);
+        Coverage {
+            permitted_minutes,
+            blocked_minutes: TimeOfDay::END.as_minutes().saturating_sub(permitted_minutes),
+        }
+    }
+    pub fn of_rejected(rejected: &[RejectedInterval]) -> Self {
+        let blocked_minutes = RejectedInterval::total_minutes(rejected);
+        Coverage {
+            permitted_minutes: TimeOfDay::END.as_minutes().saturating_sub(blocked_minutes),
+            blocked_minutes,
+        }
+    }
+}
+
+/// The status of a running daemon, as served on `/version`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DaemonStatus {
+    /// The version of the daemon.
+    pub version: String,
+
+    /// How many times the configuration was compiled since the daemon started.
+    pub generation: u64,
+
+    /// When the configuration was last compiled.
+    pub compiled_at: DateTime<Local>,
+
+    /// The modification date of the main configuration file, as of the version loaded.
+    pub main_config_modified: Option<DateTime<Local>>,
+
+    /// Temporary files that have expired but that the daemon persistently fails to remove,
+    /// with the reason.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub stuck_files: BTreeMap<PathBuf, String>,
+}
+
+/// How long before the end of a permitted interval we start warning the user.
+const WARNING_WINDOW: Duration = Duration::from_secs(300);
+
+/// The outcome of examining a process matched by a rule.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The process is permitted, with time to spare.
+    Allowed { remaining: Duration },
+
+    /// The process is permitted, but it will be stopped within `WARNING_WINDOW`.
+    Warning { remaining: Duration },
+
+    /// The process is not permitted at this time, but its rule has an escalation
+    /// policy and this is a first offense, so we only warn.
+    FirstOffense,
+
+    /// The process is not permitted at this time, but its rule has a nag policy, so we
+    /// only remind the user to close it.
+    Nag,
+
+    /// The process is not permitted at this time, but it's busy and its rule defers stopping
+    /// busy processes, so we only warn, one last time.
+    Deferred,
+
+    /// The process is not permitted at this time.
+    Killed,
+}
+impl Verdict {
+    fn new(remaining: Option<Duration>) -> Self {
+        match remaining {
+            None => Verdict::Killed,
+            Some(remaining) if remaining < WARNING_WINDOW => Verdict::Warning { remaining },
+            Some(remaining) => Verdict::Allowed { remaining },
+        }
+    }
+
+    /// If this verdict stops a process that has been busy since the previous sweep, defer
+    /// stopping it until the next sweep, at most once per process, see `Activity::defer`.
+    fn defer_if_busy(self, activity: &mut Activity, process: Option<(ProcessKey, bool)>) -> Self {
+        match (self, process) {
+            (Verdict::Killed, Some((process, busy))) if activity.defer(process, busy) => Verdict::Deferred,
+            _ => self,
+        }
+    }
+}
+impl Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Allowed { remaining } => write!(f, "ALLOWED ({}s remaining)", remaining.as_secs()),
+            Verdict::Warning { remaining } => write!(f, "WARNING ({}s remaining)", remaining.as_secs()),
+            Verdict::FirstOffense => write!(f, "FIRST OFFENSE"),
+            Verdict::Nag => write!(f, "NAG"),
+            Verdict::Deferred => write!(f, "DEFERRED"),
+            Verdict::Killed => write!(f, "KILLED"),
         }
     }
 }
@@ -45,10 +397,38 @@ impl UserInstructions {
 pub struct Options {
     #[builder(default = false)]
     pub ip_tables: bool,
+
+    /// The iptables binary, e.g. `iptables`, `/usr/sbin/iptables` or `iptables-legacy`.
+    /// Unless it's a path, it's looked up in `PATH`.
+    #[builder(default = PathBuf::from(unix::linux::DEFAULT_IP_TABLES_BINARY))]
+    pub iptables_binary: PathBuf,
+
     pub port: u16,
 
+    /// If true, serve to the browser extension both the permitted and the explicitly
+    /// forbidden intervals, rather than only the permitted intervals.
+    #[builder(default = false)]
+    pub serve_forbidden: bool,
+
     pub main_config: PathBuf,
     pub extensions_dir: PathBuf,
+
+    /// A directory in which to persist state across restarts.
+    ///
+    /// If `None`, state is not persisted.
+    #[builder(default)]
+    pub state_dir: Option<PathBuf>,
+
+    /// If true, don't enforce anything, only record which watched binaries run when,
+    /// to `observations.json` in `state_dir`.
+    #[builder(default = false)]
+    pub observe: bool,
+
+    /// A file which, if it exists and belongs to root, disables all enforcement (kills and
+    /// iptables), e.g. to recover from a broken configuration. The configuration is still
+    /// reloaded and served.
+    #[builder(default)]
+    pub kill_switch: Option<PathBuf>,
 }
 
 pub struct KeepItFocused {
@@ -60,6 +440,47 @@ pub struct KeepItFocused {
 
     /// A minimal HTTP server running on its own thread to serve web filters to web browsers.
     server: Arc<Server>,
+
+    /// How many times each rule caused enforcement today.
+    stats: Stats,
+
+    /// Offenses recorded today, for rules with an escalation policy.
+    offenses: Offenses,
+
+    /// The end of permitted intervals, for rules with a cooldown.
+    cooldowns: Cooldowns,
+
+    /// The next reminders, for rules with a nag policy.
+    nags: Nags,
+
+    /// The time spent running binaries this week, for rules with a weekly budget.
+    budgets: Budgets,
+
+    /// The CPU time of processes, for rules that defer stopping busy processes.
+    activity: Activity,
+
+    /// Processes stopped recently, to detect processes restarted as soon as they're stopped.
+    recent_kills: RecentKills,
+
+    /// The hooks run recently, so that they don't run at each sweep.
+    hooked: hooks::Debounce,
+
+    /// The cgroups of the binaries whose network access is blocked, as of the latest update
+    /// of iptables.
+    network_cgroups: Vec<(uid_resolver::Uid, Binary, String)>,
+
+    /// In observe mode, which watched binaries ran when.
+    observations: Option<Observations>,
+
+    /// The apps launched by users with rules today, as signaled on D-Bus.
+    #[cfg(feature = "dbus")]
+    launches: LaunchWatcher,
+
+    /// If true, the clock isn't set yet, so we don't know which rules apply.
+    waiting_for_clock: bool,
+
+    /// If true, `Options::kill_switch` exists, so we don't enforce anything.
+    disabled: bool,
 }
 
 impl KeepItFocused {
@@ -67,9 +488,26 @@ impl KeepItFocused {
         debug!("options: {:?}", options);
         let mut me = Self {
             server: Arc::new(Server::new(HashMap::new(), options.port)),
+            stats: Stats::new(),
+            offenses: Offenses::load(options.state_dir.as_ref().map(|dir| dir.join("offenses.json"))),
+            cooldowns: Cooldowns::new(),
+            nags: Nags::new(),
+            budgets: Budgets::load(options.state_dir.as_ref().map(|dir| dir.join("budgets.json"))),
+            activity: Activity::default(),
+            recent_kills: RecentKills::default(),
+            hooked: hooks::Debounce::default(),
+            network_cgroups: Vec::new(),
+            observations: options.observe.then(|| {
+                Observations::load(options.state_dir.as_ref().map(|dir| dir.join("observations.json")))
+            }),
+            waiting_for_clock: false,
+            disabled: false,
+            #[cfg(feature = "dbus")]
+            launches: LaunchWatcher::new(),
             config: ConfigManager::new(config::manager::Options {
                 main_config: options.main_config.clone(),
                 extensions_dir: options.extensions_dir.clone(),
+                cache: options.state_dir.as_ref().map(|dir| dir.join("config-cache.json")),
             }),
             options,
         };
@@ -79,10 +517,33 @@ impl KeepItFocused {
     }
 
     pub fn tick(&mut self) -> Result<(), anyhow::Error> {
+        // Until the clock is set, we'd apply the rules of the wrong day (and purge every
+        // extension as obsolete), so don't even load the configuration.
+        let clock = chrono::Local::now();
+        if clock.year() < EARLIEST_PLAUSIBLE_YEAR {
+            if !self.waiting_for_clock {
+                warn!("clock reads {clock}, waiting for clock sync before enforcing anything");
+                self.waiting_for_clock = true;
+            }
+            return Ok(());
+        }
+        if self.waiting_for_clock {
+            info!("clock synced, now {clock}");
+            self.waiting_for_clock = false;
+        }
+
+        let was_disabled = self.disabled;
+        self.disabled = self.is_kill_switch_on();
+        match (was_disabled, self.disabled) {
+            (false, true) => warn!("kill switch is on, enforcement is disabled until it's removed"),
+            (true, false) => info!("kill switch is off, enforcement is enabled again"),
+            _ => {}
+        }
+
         // Load any change.
         let has_changes = match self.config.load_config() {
             Err(err) => {
-                warn!("Failed to reload config, keeping previous config: {}", err);
+                warn!("Failed to reload config, keeping previous config: {:#}", anyhow::Error::from(err));
                 false
             }
             Ok(has_changes) => has_changes,
@@ -90,16 +551,164 @@ impl KeepItFocused {
 
         // Update server data.
         if has_changes {
-            let data = self.config.config().serialize_web();
+            let (data, profiles) = if self.options.observe {
+                // Let the browser extension permit everything.
+                (HashMap::new(), HashMap::new())
+            } else {
+                let config = self.config.config();
+                (
+                    config.serialize_web(self.options.serve_forbidden),
+                    config.serialize_profiles_web(self.options.serve_forbidden),
+                )
+            };
             self.server
                 .update_data(data)
                 .context("Failed to register data to serve, was the server stopped?")?;
-            if self.options.ip_tables {
-                self.apply_ip_tables()
-                    .context("Failed to update ip tables")?;
+            self.server
+                .update_profiles(profiles)
+                .context("Failed to register profiles to serve, was the server stopped?")?;
+            self.server
+                .update_instructions(self.config.config().serialize_instructions())
+                .context("Failed to register instructions to serve, was the server stopped?")?;
+        }
+        // Files may become stuck without any change to the config.
+        let status = DaemonStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            generation: self.config.generation(),
+            compiled_at: self.config.last_computed(),
+            main_config_modified: self.config.main_config_modified().map(DateTime::from),
+            stuck_files: self
+                .config
+                .stuck_files()
+                .map(|(path, reason)| (path.to_path_buf(), reason.to_string()))
+                .collect(),
+        };
+        self.server
+            .update_status(serde_json::to_string(&status).expect("error during serialization"))
+            .context("Failed to register status to serve, was the server stopped?")?;
+        if self.options.ip_tables && !self.options.observe {
+            if self.disabled {
+                if !was_disabled {
+                    remove_ip_tables(&self.options.iptables_binary)
+                        .context("Failed to remove ip tables")?;
+                }
+            } else {
+                // Binaries whose network access is blocked may have been launched or stopped since
+                // the previous tick.
+                let network_cgroups = self.find_network_cgroups();
+                if has_changes || was_disabled || network_cgroups != self.network_cgroups {
+                    self.network_cgroups = network_cgroups;
+                    self.apply_ip_tables()
+                        .context("Failed to update ip tables")?;
+                }
             }
         }
-        self.find_offending_processes()
+        let today = chrono::Local::now().date_naive();
+        self.stats.rollover(today);
+        self.offenses.rollover(today);
+        self.cooldowns.rollover(today);
+        self.nags.rollover(today);
+        self.budgets.rollover(today);
+        if self.disabled {
+            info!("kill switch is on, not enforcing anything");
+        } else {
+            self.find_offending_processes(None)?;
+        }
+        #[cfg(feature = "dbus")]
+        for (uid, user_config) in self.config.today_per_user() {
+            if !user_config.processes().is_empty() {
+                self.launches.watch(*uid, user_config.user_name().as_str());
+            }
+        }
+        if let Some(next) = self.next_boundary() {
+            let seconds = (next - chrono::Local::now()).num_seconds().max(0);
+            info!(
+                "next enforcement change in {:02}:{:02} at {}",
+                seconds / 60,
+                seconds % 60,
+                next.format("%H:%M")
+            );
+        }
+        self.server
+            .update_stats(self.stats.serialize())
+            .context("Failed to register stats to serve, was the server stopped?")
+    }
+
+    /// Check the processes of a single user, e.g. because they have just launched an app.
+    pub fn check_user(&mut self, uid: uid_resolver::Uid) -> Result<(), anyhow::Error> {
+        if self.waiting_for_clock || self.disabled {
+            return Ok(());
+        }
+        self.find_offending_processes(Some(uid))?;
+        self.server
+            .update_stats(self.stats.serialize())
+            .context("Failed to register stats to serve, was the server stopped?")
+    }
+
+    /// Wait until `deadline`, or until a user launches an app, in which case return that user.
+    ///
+    /// Without the `dbus` feature, this always waits until `deadline`.
+    pub fn wait_until(&mut self, deadline: std::time::Instant) -> Option<uid_resolver::Uid> {
+        #[cfg(feature = "dbus")]
+        {
+            self.launches.wait_until(deadline)
+        }
+        #[cfg(not(feature = "dbus"))]
+        {
+            std::thread::sleep(deadline.saturating_duration_since(std::time::Instant::now()));
+            None
+        }
+    }
+
+    /// Whether we're waiting for the clock to be set before enforcing anything.
+    pub fn is_waiting_for_clock(&self) -> bool {
+        self.waiting_for_clock
+    }
+
+    /// Whether enforcement is disabled by `Options::kill_switch`.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Whether `Options::kill_switch` exists and belongs to root.
+    fn is_kill_switch_on(&self) -> bool {
+        let Some(ref path) = self.options.kill_switch else {
+            return false;
+        };
+        match std::fs::metadata(path) {
+            Err(_) => false,
+            Ok(metadata) if metadata.uid() != 0 => {
+                // Otherwise, anybody able to create the file could disable enforcement.
+                warn!("kill switch {} doesn't belong to root, ignoring it", path.display());
+                false
+            }
+            Ok(_) => true,
+        }
+    }
+
+    /// When enforcement changes next, e.g. because a permitted interval starts or ends.
+    pub fn next_boundary(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        let now = chrono::Local::now();
+        self.config
+            .config()
+            .next_boundary(now.into())
+            .on(now.date_naive())
+    }
+
+    /// The cgroups of the running binaries whose network access may be blocked today.
+    ///
+    /// Sorted, so that we can find out whether they have changed.
+    fn find_network_cgroups(&self) -> Vec<(uid_resolver::Uid, Binary, String)> {
+        let mut cgroups = Vec::new();
+        for (uid, instructions) in self.config.today_per_user() {
+            for (binary, _) in &instructions.network {
+                for cgroup in binary_cgroups(*uid, binary) {
+                    cgroups.push((*uid, binary.clone(), cgroup));
+                }
+            }
+        }
+        cgroups.sort_by(|a, b| (a.0 .0, &a.1.path, &a.2).cmp(&(b.0 .0, &b.1.path, &b.2)));
+        cgroups
     }
 
     #[cfg(not(feature = "ip_tables"))]
@@ -108,7 +717,7 @@ impl KeepItFocused {
             .config
             .today_per_user()
             .values()
-            .any(|user| user.ips.is_empty().not())
+            .any(|user| !user.ips.is_empty() || !user.network.is_empty())
         {
             warn!("this binary was compiled WITHOUT support for ip tables")
         }
@@ -118,45 +727,64 @@ impl KeepItFocused {
     #[cfg(feature = "ip_tables")]
     fn apply_ip_tables(&mut self) -> Result<(), anyhow::Error> {
         #[derive(Debug)]
-        enum Domain {
+        enum Target {
             Source(String),
             Destination(String),
+            Cgroup(String),
         }
         #[derive(Debug)]
         struct Filter {
             uid: Uid,
-            domain: Domain,
+            target: Target,
             rejection: RejectedInterval,
         }
 
         info!("populating web filter: {}", "start");
-        remove_ip_tables(IP_TABLES_PREFIX)?;
+        remove_ip_tables(&self.options.iptables_binary)?;
 
         info!("populating web filter: {}", "compiling chains");
         // Compile to individual chains.
         let mut chains = Vec::new();
-        for (uid, instructions) in &self.config.today_per_user {
+        for (uid, instructions) in self.config.today_per_user() {
             for (domain, rejected) in &instructions.ips {
                 for rejection in rejected {
                     chains.push(Filter {
                         uid: *uid,
-                        domain: Domain::Destination(domain.clone()),
+                        target: Target::Destination(domain.0.clone()),
                         rejection: rejection.clone(),
                     });
                     chains.push(Filter {
                         uid: *uid,
-                        domain: Domain::Source(domain.clone()),
+                        target: Target::Source(domain.0.clone()),
                         rejection: rejection.clone(),
                     });
                 }
             }
         }
+        for (uid, binary, cgroup) in &self.network_cgroups {
+            let Some((_, rejected)) = self
+                .config
+                .today_per_user()
+                .get(uid)
+                .and_then(|instructions| instructions.network.iter().find(|(candidate, _)| candidate == binary))
+            else {
+                continue;
+            };
+            for rejection in rejected {
+                chains.push(Filter {
+                    uid: *uid,
+                    target: Target::Cgroup(cgroup.clone()),
+                    rejection: rejection.clone(),
+                });
+            }
+        }
 
         for (index, filter) in chains.into_iter().enumerate() {
             let chain_name = format!("{IP_TABLES_PREFIX}{index}");
             info!("populating web filter: {}", "inserting chain");
             // Create new chain.
             let mut chain = IPTable::builder()
+                .binary(&self.options.iptables_binary)
                 .build()
                 .create(&chain_name)
                 .with_context(|| format!("failed to create table for {filter:?}"))?;
@@ -176,18 +804,21 @@ impl KeepItFocused {
                 .append(iptables::Filter::Owner { uid: filter.uid })
                 .with_context(|| format!("failed to create user rule for {filter:?}"))?;
 
-            // 3. If this is not a domain we're watching, this chain doesn't apply.
-            match filter.domain {
-                Domain::Source(ref source) => {
+            // 3. If this is not a domain or a binary we're watching, this chain doesn't apply.
+            match filter.target {
+                Target::Source(ref source) => {
                     chain.append(iptables::Filter::Source { domain: source })
                 }
-                Domain::Destination(ref dest) => {
+                Target::Destination(ref dest) => {
                     chain.append(iptables::Filter::Destination { domain: dest })
                 }
+                Target::Cgroup(ref path) => {
+                    chain.append(iptables::Filter::Cgroup { path })
+                }
             }
-            .with_context(|| format!("failed to create domain rule for {filter:?}"))?;
+            .with_context(|| format!("failed to create target rule for {filter:?}"))?;
 
-            // ... If the chain still applies, it means that the domain is currently forbidden for the user!
+            // ... If the chain still applies, it means that the domain or binary is currently forbidden for the user!
             chain
                 .finish(iptables::Finish::Drop)
                 .with_context(|| format!("failed to terminate rule for {filter:?}"))?;
@@ -201,101 +832,354 @@ impl KeepItFocused {
         std::thread::spawn(move || server.serve_blocking());
     }
 
-    fn find_offending_processes(&self) -> Result<(), anyhow::Error> {
+    /// Find and handle the processes running outside of their permitted intervals, only for
+    /// user `only`, if specified.
+    fn find_offending_processes(&mut self, only: Option<uid_resolver::Uid>) -> Result<(), anyhow::Error> {
         if self.config.today_per_user().is_empty() {
             // Nothing to do for today.
             debug!("find offending processes: no configuration for the day, skipping");
             return Ok(());
         }
 
-        let now = TimeOfDay::now();
-        let processes = procfs::process::all_processes()
-            .context("Could not access /proc, is this a Linux machine?")?;
+        let clock = chrono::Local::now();
+        let now = TimeOfDay::from(clock);
+        let today = clock.date_naive();
+        let sweep = std::time::Instant::now();
+        self.recent_kills.begin_sweep(sweep);
+        let mut notifications = Notifications::default();
+        // Per login session, the pid owning the focused window, if we could find out.
+        let mut focused_per_session = HashMap::new();
+        // We may not have access to all processes, e.g. if they're zombies, or being killed while
+        // we look, etc. We don't really care, just skip a process if we can't examine it.
+        let processes: Vec<_> = procfs::process::all_processes()
+            .context("Could not access /proc, is this a Linux machine?")?
+            .filter_map(Result::ok)
+            .collect();
+        let is_watched = |uid| self.config.today_per_user().contains_key(&uid);
+        // Per user, the binaries they're running, for rules with a companion condition.
+        let mut running: HashMap<uid_resolver::Uid, Vec<PathBuf>> = HashMap::new();
+        if self.config.today_per_user().values().any(UserInstructions::has_companions) {
+            for proc in &processes {
+                let (Ok(uid), Ok(exe)) = (watched_owner(proc, is_watched), proc.exe()) else { continue };
+                running.entry(uid).or_default().push(exe_path(exe));
+            }
+        }
 
         for proc in processes {
-            // Examine process. We may not have access to all processes, e.g. if they're zombies,
-            // or being killed while we look, etc. We don't really care, just skip a process if we
-            // can't examine it.
-            let Ok(proc) = proc else { continue };
-            let Ok(uid) = proc.uid() else { continue };
-            let uid = Uid(uid);
+            let Ok(uid) = watched_owner(&proc, is_watched) else { continue };
+            if only.is_some_and(|only| only != uid) {
+                continue;
+            }
             let Some(user_config) = self.config.today_per_user().get(&uid) else {
                 // Nothing to watch for this user.
                 continue;
             };
             let Ok(exe) = proc.exe() else { continue };
+            // Don't let a binary escape by removing its file after launch.
+            let exe = exe_path(exe);
 
-            for (binary, intervals) in &user_config.processes {
-                if !binary.matcher.is_match(&exe) {
+            let rule = user_config.process_rule(&exe, |path| {
+                runs_file(proc.pid, path).unwrap_or_else(|err| {
+                    debug!("could not match binary {} by inode: {err:#}", path.display());
+                    false
+                })
+            });
+            if let Some((binary, intervals)) = rule {
+                if is_self_or_descendant(&proc) {
+                    // Never stop ourselves or our helpers (`systemd-run`, `iptables`, ...).
+                    warn!("rule {binary} matches process {} ({}), which belongs to keep-it-focused, skipping", proc.pid, exe.display());
                     continue;
                 }
-                info!(
-                    "found binary {} for user {}",
+                if user_config.is_local_only(binary) {
+                    match is_remote_session(&proc) {
+                        Ok(false) => {}
+                        Ok(true) => {
+                            debug!("binary {} runs in a remote session, rule {binary} doesn't apply", exe.display());
+                            continue;
+                        }
+                        Err(err) => {
+                            // Err on the side of enforcement.
+                            debug!("could not determine the session of process {}, assuming local: {err:#}", proc.pid);
+                        }
+                    }
+                }
+                let remaining = AcceptedInterval::remaining(intervals, now);
+                if let Some(ref mut observations) = self.observations {
+                    // Record, but don't enforce.
+                    observations.record(today, user_config.user_name.as_str(), &binary.path, now, remaining.is_none());
+                    continue;
+                }
+                // The process and whether it has been busy since the previous sweep.
+                let activity = if user_config.defers_if_active(binary) {
+                    proc.stat().ok().map(|stat| {
+                        let process = (proc.pid, stat.starttime);
+                        (process, self.activity.sample(process, stat.utime + stat.stime, sweep))
+                    })
+                } else {
+                    None
+                };
+                let mut verdict = Verdict::new(remaining);
+                let companion = match (verdict, user_config.companion(binary)) {
+                    (Verdict::Allowed { .. } | Verdict::Warning { .. }, Some(companion)) => {
+                        companion.unsatisfied(running.get(&uid).map(Vec::as_slice).unwrap_or_default())
+                    }
+                    _ => None,
+                };
+                if companion.is_some() {
+                    // Permitted at this time, but not in this company.
+                    verdict = Verdict::Killed;
+                }
+                // If the weekly budget runs out before the permitted interval, the budget.
+                let mut budget_left = None;
+                let budget = match (verdict, user_config.weekly_budget(binary)) {
+                    (Verdict::Allowed { remaining } | Verdict::Warning { remaining }, Some(budget)) => {
+                        let used = self.budgets.record(uid, &binary.path, clock);
+                        let left = Duration::from_secs((budget as u64 * 60).saturating_sub(used));
+                        budget_left = Some(left);
+                        if left < remaining {
+                            verdict = if left.is_zero() { Verdict::Killed } else { Verdict::new(Some(left)) };
+                            Some(budget)
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                let cooldown = user_config
+                    .cooldown(binary)
+                    .and_then(|cooldown| self.cooldowns.remaining(uid, &binary.path, cooldown, now));
+                if cooldown.is_some() {
+                    // Relaunched too soon after the end of a permitted interval.
+                    verdict = Verdict::Killed;
+                }
+                if !matches!(verdict, Verdict::Allowed { .. }) && user_config.is_focused_only(binary) {
+                    let focused = match display_session(&proc, uid) {
+                        Ok(session) => *focused_per_session.entry(session.clone()).or_insert_with(|| match focused_pid(&session) {
+                            Ok(pid) => Some(pid),
+                            Err(err) => {
+                                // Err on the side of enforcement.
+                                debug!("could not find the focused window of session {session}, enforcing regardless: {err:#}");
+                                None
+                            }
+                        }),
+                        Err(err) => {
+                            // Err on the side of enforcement.
+                            debug!("could not find the session of process {}, enforcing regardless: {err:#}", proc.pid);
+                            None
+                        }
+                    };
+                    if focused.is_some_and(|focused| !is_focused(&proc, focused)) {
+                        debug!("binary {} doesn't own the focused window, rule {binary} doesn't apply", exe.display());
+                        continue;
+                    }
+                }
+                if let (Verdict::Killed, Some(escalation), None) = (verdict, user_config.escalation(binary), cooldown) {
+                    if self.offenses.record(uid, &binary.path, escalation, now) == Sanction::Warn {
+                        verdict = Verdict::FirstOffense;
+                    }
+                }
+                if let (Verdict::Killed, Some(nag)) = (verdict, user_config.nag(binary)) {
+                    if !self.nags.is_due(uid, &binary.path, nag, now) {
+                        // Reminded recently, leave the user alone for now.
+                        continue;
+                    }
+                    verdict = Verdict::Nag;
+                }
+                // The user may be in the middle of something, give them a chance to wrap up.
+                verdict = verdict.defer_if_busy(&mut self.activity, activity);
+                if let (Verdict::Allowed { remaining } | Verdict::Warning { remaining }, Some(_)) = (verdict, user_config.cooldown(binary)) {
+                    let end = now.saturating_add_minutes((remaining.as_secs() / 60) as u16);
+                    self.cooldowns.record(uid, &binary.path, end);
+                }
+                self.stats.record(uid, binary, verdict);
+                let messages = self.config.messages();
+                let message = |template: &str, time: TimeOfDay, extra: &[(&str, &str)]| {
+                    let label = user_config.forbidden_label(binary, time);
+                    let reason = reason(label);
+                    let exe = exe.to_string_lossy();
+                    let mut values = vec![
+                        ("binary", exe.as_ref()),
+                        ("user", user_config.user_name.as_str()),
+                        ("label", label.unwrap_or_default()),
+                        ("reason", reason.as_str()),
+                    ];
+                    values.extend_from_slice(extra);
+                    Messages::render(template, &values)
+                };
+                info!(target: "enforcement",
+                    "{verdict}: binary {} for user {}",
                     exe.to_string_lossy(),
                     user_config.user_name
                 );
-                if let Some(duration) = intervals
-                    .iter()
-                    .filter_map(|interval| interval.0.remaining(now))
-                    .next()
-                {
-                    // We're still in permitted territory.
-                    info!("binary is still allowed at this time");
-                    if duration < std::time::Duration::from_secs(300) {
-                        // ...however, we're less than 5 minutes away from shutdown, so let's warn user!
-                        let minutes = duration.as_secs() / 60;
-                        if let Err(err) = notify(
+                match verdict {
+                    Verdict::Allowed { .. } => {
+                        // Nothing to do.
+                    }
+                    Verdict::Warning { remaining } => {
+                        // We're less than 5 minutes away from shutdown, so let's warn user!
+                        let minutes = remaining.as_secs() / 60;
+                        let budget_left = budget_left
+                            .map(|left| Messages::render(&messages.budget_left, &[("minutes", &(left.as_secs() / 60).to_string())]))
+                            .unwrap_or_default();
+                        if let Err(err) = notifications.notify(
                             user_config.user_name.as_str(),
-                            &format!("{} will quit in {} minutes", exe.to_string_lossy(), minutes),
+                            &message(
+                                if budget.is_some() { &messages.budget_warning } else { &messages.warning },
+                                now.saturating_add_minutes(minutes as u16),
+                                &[("minutes", &minutes.to_string()), ("budget_left", &budget_left)],
+                            ),
                             Urgency::Significant,
                         ) {
                             warn!(target: "notify", "failed to notify user {}: {:?}", user_config.user_name, err)
                         }
                     }
-                } else {
-                    info!("let's kill this binary");
-                    // Time to kill the binary.
-                    if let Err(err) = notify(
-                        &user_config.user_name,
-                        &format!(
-                            "{} is not permitted at this time, stopping it",
-                            exe.to_string_lossy()
-                        ),
-                        Urgency::Significant,
-                    ) {
-                        warn!(target: "notify", "failed to notify user {}: {:?}", user_config.user_name, err)
+                    Verdict::FirstOffense => {
+                        if let Err(err) = notifications.notify(
+                            &user_config.user_name,
+                            &message(&messages.first_offense, now, &[]),
+                            Urgency::Significant,
+                        ) {
+                            warn!(target: "notify", "failed to notify user {}: {:?}", user_config.user_name, err)
+                        }
                     }
-                    if let Err(err) = kill_tree::blocking::kill_tree_with_config(
-                        proc.pid as u32,
-                        &kill_tree::Config {
-                            signal: "SIGKILL".to_string(),
-                            ..Default::default()
-                        },
-                    ) {
-                        warn!(target: "notify", "failed to kill process {}: {:?}", exe.to_string_lossy(), err)
+                    Verdict::Nag => {
+                        if let Err(err) = notifications.notify(
+                            &user_config.user_name,
+                            &message(&messages.nag, now, &[]),
+                            Urgency::Significant,
+                        ) {
+                            warn!(target: "notify", "failed to notify user {}: {:?}", user_config.user_name, err)
+                        }
+                    }
+                    Verdict::Deferred => {
+                        if let Err(err) = notifications.notify(
+                            &user_config.user_name,
+                            &message(&messages.deferred, now, &[]),
+                            Urgency::Significant,
+                        ) {
+                            warn!(target: "notify", "failed to notify user {}: {:?}", user_config.user_name, err)
+                        }
+                    }
+                    Verdict::Killed => {
+                        // Time to kill the binary.
+                        let parent = proc.stat().ok().map(|stat| stat.ppid);
+                        let action = self.recent_kills.record(uid, &exe, parent, sweep);
+                        let mut victim = proc.pid;
+                        let message = match action {
+                            KillAction::Kill => Some(match (cooldown, companion, budget) {
+                                (Some(minutes), _, _) => message(&messages.cooldown, now, &[("minutes", &minutes.to_string())]),
+                                (None, Some(unsatisfied @ Unsatisfied::Running(_)), _) => {
+                                    message(&messages.companion_running, now, &[("companion", &unsatisfied.to_string())])
+                                }
+                                (None, Some(unsatisfied @ Unsatisfied::Missing(_)), _) => {
+                                    message(&messages.companion_missing, now, &[("companion", &unsatisfied.to_string())])
+                                }
+                                (None, None, Some(budget)) => {
+                                    message(&messages.budget_exhausted, now, &[("budget", &budget.to_string())])
+                                }
+                                (None, None, None) => message(&messages.stopped, now, &[]),
+                            }),
+                            KillAction::KillLauncher => match launcher(&proc, uid) {
+                                Some(parent) => {
+                                    let parent_exe = parent
+                                        .exe()
+                                        .map(|path| exe_path(path).to_string_lossy().to_string())
+                                        .unwrap_or_else(|_| format!("process {}", parent.pid));
+                                    victim = parent.pid;
+                                    Some(message(&messages.stopped_launcher, now, &[("launcher", &parent_exe)]))
+                                }
+                                None => Some(message(&messages.keeps_restarting, now, &[])),
+                            },
+                            KillAction::KillQuietly => None,
+                        };
+                        if let Some(message) = message {
+                            if let Err(err) = notifications.notify(&user_config.user_name, &message, Urgency::Significant) {
+                                warn!(target: "notify", "failed to notify user {}: {:?}", user_config.user_name, err)
+                            }
+                        }
+                        if let Err(err) = kill_tree::blocking::kill_tree_with_config(
+                            victim as u32,
+                            &kill_tree::Config {
+                                signal: "SIGKILL".to_string(),
+                                ..Default::default()
+                            },
+                        ) {
+                            warn!(target: "notify", "failed to kill process {}: {:?}", exe.to_string_lossy(), err)
+                        }
+                        info!("binary killed");
                     }
-                    info!("binary killed");
                 }
+                let hooks = self.config.hooks();
+                let hook = match verdict {
+                    Verdict::Allowed { .. } => None,
+                    Verdict::Warning { .. } => hooks.on_warn.as_deref().map(|hook| (hook, "warning")),
+                    Verdict::FirstOffense => hooks.on_warn.as_deref().map(|hook| (hook, "first_offense")),
+                    Verdict::Nag => hooks.on_warn.as_deref().map(|hook| (hook, "nag")),
+                    Verdict::Deferred => hooks.on_warn.as_deref().map(|hook| (hook, "deferred")),
+                    Verdict::Killed => hooks.on_kill.as_deref().map(|hook| (hook, "kill")),
+                };
+                if let Some((hook, action)) = hook {
+                    if self.hooked.should_run(uid, &exe, action, sweep) {
+                        hooks::spawn(hook, user_config.user_name.as_str(), &exe, action);
+                    }
+                }
+            }
+        }
+        if let Some(ref mut observations) = self.observations {
+            if let Err(err) = observations.save() {
+                warn!("failed to persist observations: {err:#}");
             }
         }
+        if let Err(err) = self.budgets.save() {
+            warn!("failed to persist weekly budgets: {err:#}");
+        }
+        self.activity.prune(sweep);
         Ok(())
     }
 }
 
-#[cfg(not(feature = "ip_tables"))]
-pub fn remove_ip_tables() -> Result<(), anyhow::Error> {
+/// Fail if this binary was compiled without support for iptables.
+pub fn ensure_ip_tables_supported() -> Result<(), anyhow::Error> {
+    if cfg!(feature = "ip_tables") {
+        return Ok(());
+    }
     Err(anyhow::anyhow!(
-        "this application was compiled without support for iptables"
+        "this application was compiled without support for iptables, rebuild it with `cargo build --release --features ip_tables`"
     ))
 }
 
+/// Format the label of a forbidden interval, if any, for a notification.
+fn reason(label: Option<&str>) -> String {
+    label.map(|label| format!(" ({label})")).unwrap_or_default()
+}
+
+/// Fail if `binary`, the iptables binary, cannot be found.
+#[cfg(not(feature = "ip_tables"))]
+pub fn ensure_ip_tables_binary(_binary: &Path) -> Result<(), anyhow::Error> {
+    ensure_ip_tables_supported()
+}
+
+/// Fail if `binary`, the iptables binary, cannot be found.
+#[cfg(feature = "ip_tables")]
+pub fn ensure_ip_tables_binary(binary: &Path) -> Result<(), anyhow::Error> {
+    let path = iptables::find_binary(binary)?;
+    debug!("using iptables binary {}", path.display());
+    Ok(())
+}
+
+#[cfg(not(feature = "ip_tables"))]
+pub fn remove_ip_tables(_binary: &Path) -> Result<(), anyhow::Error> {
+    ensure_ip_tables_supported()
+}
+
 #[cfg(feature = "ip_tables")]
-pub fn remove_ip_tables() -> Result<(), anyhow::Error> {
+pub fn remove_ip_tables(binary: &Path) -> Result<(), anyhow::Error> {
     // We want to reset the iptables chains we use for this process.
     // The only way to do this, apparently, is to request the list and filter.
     let chains = IPTable::builder()
+        .binary(binary)
         .build()
-        .list(true, Some(iptables::IP_TABLES_PREFIX))
+        .list(true, Some(IP_TABLES_PREFIX))
         .context("Failed to list existing chains")?;
 
     if chains.is_empty() {
@@ -304,14 +1188,48 @@ pub fn remove_ip_tables() -> Result<(), anyhow::Error> {
     for chain_name in chains {
         debug!("remove_ip_tables: removing chain {}", chain_name);
         IPTable::builder()
+            .binary(binary)
             .build()
             .flush(&chain_name)
             .context("Failed to reset iptables chain")?;
 
         IPTable::builder()
+            .binary(binary)
             .build()
             .delete(&chain_name)
             .context("Failed to drop iptables chain")?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::activity::Activity;
+
+    use super::Verdict;
+
+    #[test]
+    fn test_deferred_then_killed() {
+        let mut activity = Activity::new(100);
+        let process = (1234, 0);
+        let start = Instant::now();
+        let mut sweep = |seconds: u64, cpu_ticks: u64| {
+            let busy = activity.sample(process, cpu_ticks, start + Duration::from_secs(seconds));
+            Verdict::Killed.defer_if_busy(&mut activity, Some((process, busy)))
+        };
+        // Just launched, there is nothing to compare its CPU time to.
+        assert_eq!(sweep(0, 0), Verdict::Killed);
+        // Busy since the previous sweep: stopping it is deferred...
+        assert_eq!(sweep(60, 3_000), Verdict::Deferred);
+        // ... until the next sweep, busy or not.
+        assert_eq!(sweep(120, 6_000), Verdict::Killed);
+
+        // Without `defer_if_active`, processes are stopped right away.
+        assert_eq!(Verdict::Killed.defer_if_busy(&mut activity, None), Verdict::Killed);
+        // Only processes that would be stopped are deferred.
+        let warning = Verdict::Warning { remaining: Duration::from_secs(60) };
+        assert_eq!(warning.defer_if_busy(&mut activity, Some(((4321, 0), true))), warning);
+    }
+}
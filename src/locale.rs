@@ -0,0 +1,281 @@
+//! Translating what users see (command output, notifications) into a few languages.
+//!
+//! Logs remain in English, to ease support.
+
+use std::ops::Not;
+
+use serde::{Deserialize, Serialize};
+
+/// A language in which we may talk to users.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Language {
+    #[default]
+    #[serde(rename = "en")]
+    English,
+
+    #[serde(rename = "fr")]
+    French,
+
+    #[serde(rename = "de")]
+    German,
+}
+
+/// `(english, french, german)`.
+///
+/// English texts serve as keys, see `Language::translate`. Users are addressed informally
+/// ("tu", "du"), as many of them are children.
+const TRANSLATIONS: &[(&str, &str, &str)] = &[
+    // Notifications, see `config::Messages`.
+    (
+        "{binary} will quit in {minutes} minutes{reason}{budget_left}",
+        "{binary} va se fermer dans {minutes} minutes{reason}{budget_left}",
+        "{binary} wird in {minutes} Minuten beendet{reason}{budget_left}",
+    ),
+    (
+        "{binary} is not permitted at this time{reason}, please close it or it will be stopped",
+        "{binary} n'est pas autorisé en ce moment{reason}, ferme-le, sinon il sera arrêté",
+        "{binary} ist zu dieser Zeit nicht erlaubt{reason}, bitte schließe es, sonst wird es beendet",
+    ),
+    (
+        "{binary} is not permitted at this time{reason}, stopping it",
+        "{binary} n'est pas autorisé en ce moment{reason}, arrêt en cours",
+        "{binary} ist zu dieser Zeit nicht erlaubt{reason}, es wird beendet",
+    ),
+    (
+        "{binary} keeps restarting{reason}, stopping {launcher} which launches it",
+        "{binary} redémarre sans cesse{reason}, arrêt de {launcher} qui le lance",
+        "{binary} startet immer wieder{reason}, {launcher}, das es startet, wird beendet",
+    ),
+    (
+        "{binary} keeps restarting{reason}, it will be stopped each time",
+        "{binary} redémarre sans cesse{reason}, il sera arrêté à chaque fois",
+        "{binary} startet immer wieder{reason}, es wird jedes Mal beendet",
+    ),
+    (
+        "{binary} needs a break, it may be relaunched in {minutes} minutes",
+        "{binary} doit faire une pause, il pourra être relancé dans {minutes} minutes",
+        "{binary} braucht eine Pause, es kann in {minutes} Minuten wieder gestartet werden",
+    ),
+    (
+        "{binary} is not permitted at this time{reason}, please close it",
+        "{binary} n'est pas autorisé en ce moment{reason}, s'il te plaît, ferme-le",
+        "{binary} ist zurzeit nicht erlaubt{reason}, bitte schließe es",
+    ),
+    (
+        "{binary} is not permitted while {companion} is running, stopping it",
+        "{binary} n'est pas autorisé pendant que {companion} est lancé, arrêt en cours",
+        "{binary} ist nicht erlaubt, solange {companion} läuft, es wird beendet",
+    ),
+    (
+        "{binary} is only permitted while {companion} is running, stopping it",
+        "{binary} n'est autorisé que pendant que {companion} est lancé, arrêt en cours",
+        "{binary} ist nur erlaubt, solange {companion} läuft, es wird beendet",
+    ),
+    (
+        "{binary} will quit in {minutes} minutes, its weekly budget is almost used up",
+        "{binary} va s'arrêter dans {minutes} minutes, son budget de la semaine est presque épuisé",
+        "{binary} wird in {minutes} Minuten beendet, sein Wochenbudget ist fast aufgebraucht",
+    ),
+    (
+        ", {minutes} minutes left this week",
+        ", il reste {minutes} minutes cette semaine",
+        ", noch {minutes} Minuten diese Woche",
+    ),
+    (
+        "{binary} has used up its weekly budget of {budget} minutes, stopping it",
+        "{binary} a épuisé son budget de {budget} minutes pour la semaine, arrêt en cours",
+        "{binary} hat sein Wochenbudget von {budget} Minuten aufgebraucht, es wird beendet",
+    ),
+    (
+        "{binary} is not permitted at this time{reason}, please save your work, it will be stopped shortly",
+        "{binary} n'est pas autorisé en ce moment{reason}, s'il te plaît, enregistre ton travail, il sera bientôt arrêté",
+        "{binary} ist zurzeit nicht erlaubt{reason}, bitte speichere deine Arbeit, es wird in Kürze beendet",
+    ),
+    // Command output.
+    (
+        "today's rules for {user} ({day})",
+        "règles du jour pour {user} ({day})",
+        "heutige Regeln für {user} ({day})",
+    ),
+    (
+        "today's timeline for {user} ({day})",
+        "journée de {user} ({day})",
+        "Tagesablauf für {user} ({day})",
+    ),
+    (
+        "on this day, no config for user {user}",
+        "aucune règle ce jour pour {user}",
+        "an diesem Tag keine Regeln für {user}",
+    ),
+    ("no rules today", "aucune règle aujourd'hui", "heute keine Regeln"),
+    ("processes of {user}", "processus de {user}", "Prozesse von {user}"),
+    (
+        "{count} processes could not be examined, try as root",
+        "{count} processus n'ont pas pu être examinés, essaie en tant que root",
+        "{count} Prozesse konnten nicht untersucht werden, versuche es als root",
+    ),
+    (
+        "binary {binary} doesn't exist",
+        "le programme {binary} n'existe pas",
+        "Programm {binary} existiert nicht",
+    ),
+    (
+        "{user}, {day}: {target} forbidden from {start} to {end}, which never applies",
+        "{user}, {day} : {target} interdit de {start} à {end}, ce qui ne s'applique jamais",
+        "{user}, {day}: {target} verboten von {start} bis {end}, was nie gilt",
+    ),
+    ("processes", "programmes", "Programme"),
+    ("web", "web", "Web"),
+    ("ip", "ip", "IP"),
+    ("network", "réseau", "Netzwerk"),
+    (
+        "{name}: allowed {allowed}, forbidden {forbidden}",
+        "{name} : autorisé {allowed}, interdit {forbidden}",
+        "{name}: erlaubt {allowed}, verboten {forbidden}",
+    ),
+    (
+        "{name}: forbidden {forbidden}",
+        "{name} : interdit {forbidden}",
+        "{name}: verboten {forbidden}",
+    ),
+    ("never", "jamais", "nie"),
+    ("allowed", "autorisé", "erlaubt"),
+    ("partly allowed", "partiellement autorisé", "teilweise erlaubt"),
+    ("forbidden", "interdit", "verboten"),
+    (
+        "no rule caused a warning or a kill today",
+        "aucune règle n'a causé d'avertissement ou d'arrêt aujourd'hui",
+        "heute hat keine Regel eine Warnung oder ein Beenden ausgelöst",
+    ),
+    (
+        "daemon {version}, configuration generation {generation}, compiled at {time}",
+        "démon {version}, génération de configuration {generation}, compilée à {time}",
+        "Dienst {version}, Konfigurationsgeneration {generation}, kompiliert um {time}",
+    ),
+    (
+        "expired file {path} cannot be removed: {reason}",
+        "le fichier expiré {path} ne peut pas être supprimé : {reason}",
+        "die abgelaufene Datei {path} kann nicht entfernt werden: {reason}",
+    ),
+    (
+        "{path} is up to date",
+        "{path} est à jour",
+        "{path} ist aktuell",
+    ),
+    (
+        "today's enforcement for {user}",
+        "application des règles aujourd'hui pour {user}",
+        "heutige Durchsetzung für {user}",
+    ),
+    (
+        "{binary}: {warnings} warnings, {kills} kills",
+        "{binary} : {warnings} avertissements, {kills} arrêts",
+        "{binary}: {warnings} Warnungen, {kills} Beendigungen",
+    ),
+    (
+        "nothing observed, did you run `keep-it-focused run --observe`?",
+        "rien n'a été observé, as-tu lancé `keep-it-focused run --observe` ?",
+        "nichts beobachtet, hast du `keep-it-focused run --observe` ausgeführt?",
+    ),
+    (
+        "observed for {user}, {first} to {last}",
+        "observé pour {user}, du {first} au {last}",
+        "beobachtet für {user}, {first} bis {last}",
+    ),
+    (
+        "{name}: {seen} minutes, {violations} outside permitted intervals",
+        "{name} : {seen} minutes, dont {violations} hors des plages autorisées",
+        "{name}: {seen} Minuten, davon {violations} außerhalb der erlaubten Zeiten",
+    ),
+    (
+        "this is a test notification from keep-it-focused",
+        "ceci est une notification de test de keep-it-focused",
+        "dies ist eine Testbenachrichtigung von keep-it-focused",
+    ),
+    (
+        "notification sent to {user}",
+        "notification envoyée à {user}",
+        "Benachrichtigung an {user} gesendet",
+    ),
+];
+
+const DAY_NAMES: [[&str; 7]; 3] = [
+    ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"],
+    ["lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi", "dimanche"],
+    ["Montag", "Dienstag", "Mittwoch", "Donnerstag", "Freitag", "Samstag", "Sonntag"],
+];
+
+impl Language {
+    /// Recognize a language from a locale, e.g. `fr_FR.UTF-8`, or a language code, e.g. `de`.
+    pub fn parse(locale: &str) -> Option<Self> {
+        let code = locale.split(['_', '.', '@', '-']).next()?;
+        match code.to_ascii_lowercase().as_str() {
+            "en" | "c" | "posix" => Some(Language::English),
+            "fr" => Some(Language::French),
+            "de" => Some(Language::German),
+            _ => None,
+        }
+    }
+
+    /// The language of the current locale, as per `LC_ALL`, `LC_MESSAGES` or `LANG`.
+    ///
+    /// Defaults to English.
+    pub fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .into_iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| value.is_empty().not())
+            .and_then(|locale| Self::parse(&locale))
+            .unwrap_or_default()
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Language::English => 0,
+            Language::French => 1,
+            Language::German => 2,
+        }
+    }
+
+    /// Translate an English text from `TRANSLATIONS`.
+    ///
+    /// Other texts, e.g. templates customized by the user, are returned as is.
+    pub fn translate(self, english: &str) -> &str {
+        let Some(&(_, french, german)) = TRANSLATIONS.iter().find(|(key, _, _)| *key == english) else {
+            return english;
+        };
+        match self {
+            Language::English => english,
+            Language::French => french,
+            Language::German => german,
+        }
+    }
+
+    /// The names of the days of the week, starting from Monday.
+    pub fn day_names(self) -> [&'static str; 7] {
+        DAY_NAMES[self.index()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::types::DayOfWeek;
+
+    use super::Language;
+
+    #[test]
+    fn test_language() {
+        assert_eq!(Language::parse("fr_FR.UTF-8"), Some(Language::French));
+        assert_eq!(Language::parse("de_CH"), Some(Language::German));
+        assert_eq!(Language::parse("C.UTF-8"), Some(Language::English));
+        assert_eq!(Language::parse("tlh"), None);
+
+        assert_eq!(Language::French.translate("never"), "jamais");
+        assert_eq!(Language::English.translate("never"), "never");
+        // Unknown texts are kept.
+        assert_eq!(Language::German.translate("{binary} kaput"), "{binary} kaput");
+
+        assert_eq!(DayOfWeek::sunday().localized(Language::French), "dimanche");
+        assert_eq!(DayOfWeek::monday().localized(Language::English), "monday");
+    }
+}
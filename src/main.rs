@@ -1,28 +1,43 @@
 use std::{
-    io::ErrorKind,
+    fmt::Display,
+    io::{ErrorKind, IsTerminal},
     ops::{Deref, Not},
-    path::PathBuf,
-    thread,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Context;
-use clap::{ArgAction, Parser, Subcommand};
+use anyhow::{anyhow, Context};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueHint};
+use itertools::Itertools;
+use flexi_logger::{Cleanup, Criterion, FileSpec, LoggerHandle, Naming};
 use log::{debug, info, warn, LevelFilter};
+use owo_colors::OwoColorize;
 use procfs::sys::kernel::random::uuid;
+use sd_notify::NotifyState;
 use systemd_journal_logger::{connected_to_journal, JournalLog};
 
 use keep_it_focused::{
-    config::{Binary, Config, Extension, ProcessFilter, WebFilter, manager::{ConfigManager, Options as ConfigOptions}},
-    types::{DayOfWeek, Domain, Interval, TimeOfDay, Username},
-    KeepItFocused,
+    config::{lock::ConfigLock, Binary, Config, Extension, Messages, ProcessFilter, WebFilter, manager::{ConfigManager, Options as ConfigOptions}},
+    locale::Language,
+    unix::linux::notify::{notify, notify_command, Urgency},
+    observe::Observations,
+    quick::{QuickRule, Target},
+    stats::Report,
+    types::{AcceptedInterval, DayOfWeek, Domain, Interval, RejectedInterval, TimeOfDay, Username},
+    Coverage, DaemonStatus, UserInstructions,
 };
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/keep-it-focused.yaml";
 const DEFAULT_EXTENSIONS_PATH: &str = "/tmp/keep-it-focused.d/";
 const DEFAULT_PORT: &str = "7878";
+const DEFAULT_STATE_PATH: &str = "/var/lib/keep-it-focused/";
+const DEFAULT_KILL_SWITCH_PATH: &str = "/etc/keep-it-focused.disabled";
+/// The maximal length of the description at the start of the name of temporary rule files.
+const MAX_DESCRIPTION_LEN: usize = 64;
 
 #[cfg(target_family="unix")]
 use keep_it_focused::unix::uid_resolver::{Resolver, Uid};
+#[cfg(target_os = "linux")]
+use keep_it_focused::unix::linux::procfs::{exe_path, runs_file, watched_owner};
 
 
 #[derive(Subcommand, Debug)]
@@ -30,7 +45,28 @@ enum Command {
     /// Check the configuration for syntax.
     Check {
         /// If specified, display today's configuration for this user.
-        user: Option<String>
+        #[arg(value_hint = ValueHint::Username)]
+        user: Option<String>,
+
+        /// With `user`, how to display the configuration: `yaml` logs it and prints a
+        /// summary, `json` prints it as is, for tooling.
+        #[arg(long, value_enum, default_value_t = Format::Yaml)]
+        format: Format,
+
+        /// Also fail if a rule references a binary by a path (without wildcards) that doesn't
+        /// exist, which is probably a typo. This doesn't affect enforcement.
+        #[arg(long, default_value = "false")]
+        strict: bool,
+    },
+
+    /// Show today's rules for a user as a timeline, one bar per binary or domain.
+    Timeline {
+        #[arg(long, value_hint = ValueHint::Username)]
+        user: String,
+
+        /// If true, each cell of a bar covers half an hour rather than an hour.
+        #[arg(long, default_value = "false")]
+        half_hours: bool,
     },
 
     /// Run the daemon.
@@ -38,6 +74,8 @@ enum Command {
     /// For iptables, you'll need to be root.
     Run {
         /// How often to check for offending processes.
+        ///
+        /// Processes are also checked right after a permitted interval starts or ends.
         #[arg(short, long, default_value = "60")]
         sleep_s: u64,
 
@@ -46,6 +84,77 @@ enum Command {
 
         #[arg(short, long, default_value = "false")]
         ip_tables: bool,
+
+        /// If true, also serve to the browser extension the intervals during which
+        /// domains are explicitly forbidden.
+        #[arg(long, default_value = "false")]
+        serve_forbidden: bool,
+
+        /// A directory in which to persist state (e.g. offenses) across restarts.
+        #[arg(long, default_value = DEFAULT_STATE_PATH, value_hint = ValueHint::DirPath)]
+        state_dir: PathBuf,
+
+        /// If true, check for offending processes once, then exit.
+        ///
+        /// This doesn't serve web filters to the browser extension.
+        #[arg(long, default_value = "false")]
+        once: bool,
+
+        /// If true, don't enforce anything, only record which watched binaries run when,
+        /// see `keep-it-focused observations`.
+        #[arg(long, default_value = "false")]
+        observe: bool,
+
+        /// If this file exists and belongs to root, don't enforce anything (no kills, no
+        /// iptables) until it's removed, e.g. to recover from a broken configuration.
+        #[arg(long, default_value = DEFAULT_KILL_SWITCH_PATH, value_hint = ValueHint::FilePath)]
+        kill_switch: PathBuf,
+    },
+
+    /// Summarize what `run --observe` recorded over the past week.
+    Observations {
+        /// The directory in which the daemon persists its state.
+        #[arg(long, default_value = DEFAULT_STATE_PATH, value_hint = ValueHint::DirPath)]
+        state_dir: PathBuf,
+    },
+
+    /// Show how many times each rule caused a warning or a kill today.
+    ///
+    /// Requires a running daemon. Users only see their own statistics, root sees everybody's.
+    Stats {
+        /// The port on which the daemon is running.
+        #[arg(short, long, default_value = DEFAULT_PORT)]
+        port: u16,
+    },
+
+    /// Show which version of the configuration the daemon has loaded.
+    ///
+    /// Requires a running daemon. Fails if the main configuration has changed since the daemon
+    /// loaded it, e.g. to wait until an edit has been picked up.
+    Status {
+        /// The port on which the daemon is running.
+        #[arg(short, long, default_value = DEFAULT_PORT)]
+        port: u16,
+    },
+
+    /// List the processes of the users watched today, as the daemon sees them, with the rule
+    /// they match, if any.
+    ///
+    /// Shows the path of the binary as it's matched against rules, which may differ from the
+    /// command line, e.g. because of symlinks or interpreters. You'll need to be root to see
+    /// the processes of other users.
+    Ps {
+        /// If specified, only list the processes of this user.
+        #[arg(long, value_hint = ValueHint::Username)]
+        user: Option<String>,
+    },
+
+    /// Send a sample notification to a user, to check that notifications work.
+    ///
+    /// You'll need to be root.
+    TestNotify {
+        #[arg(value_hint = ValueHint::Username)]
+        user: String,
     },
 
     /// Perform iptables maintenance.
@@ -65,6 +174,59 @@ enum Command {
         #[arg(long, default_value = "true", action=ArgAction::Set)]
         policies: bool,
 
+        /// If true, overwrite any installation mode already specified for the addon
+        /// in /etc/firefox/policies.json and any existing systemd configuration.
+        #[arg(long, default_value = "false")]
+        force: bool,
+
+        /// With `daemon`, the port on which the daemon will run.
+        #[arg(long, default_value = DEFAULT_PORT)]
+        port: u16,
+
+        /// With `daemon`, how often the daemon will check for offending processes.
+        #[arg(long, default_value = "60")]
+        sleep_s: u64,
+
+        /// With `daemon`, whether the daemon will use iptables.
+        #[arg(long, default_value = "false")]
+        ip_tables: bool,
+
+        /// With `daemon`, whether the daemon will serve the intervals during which
+        /// domains are explicitly forbidden.
+        #[arg(long, default_value = "false")]
+        serve_forbidden: bool,
+
+        /// With `daemon`, a directory in which the daemon will persist state.
+        #[arg(long, default_value = DEFAULT_STATE_PATH, value_hint = ValueHint::DirPath)]
+        state_dir: PathBuf,
+
+        /// With `daemon`, the log level of the daemon (`error`, `warn`, `info`, `debug` or `trace`).
+        #[arg(long, default_value = "info")]
+        log_level: String,
+
+        /// With `daemon`, rather than running the daemon continuously, run a single
+        /// pass every `sleep_s` seconds from a systemd timer.
+        ///
+        /// This uses less memory, but the browser extension cannot be served, so
+        /// websites are not filtered.
+        #[arg(long, default_value = "false")]
+        timer: bool,
+
+        /// If true, setup Chrome/Chromium managed policies to install the extension.
+        ///
+        /// Requires the extension to be packaged as a CRX and served with an update
+        /// manifest, see `--chrome-extension-id` and `--chrome-update-url`.
+        #[arg(long, default_value = "false", requires_all = ["chrome_extension_id", "chrome_update_url"])]
+        chrome: bool,
+
+        /// The id of the extension, as determined by the key used to sign the CRX.
+        #[arg(long)]
+        chrome_extension_id: Option<String>,
+
+        /// The url of the update manifest for the extension.
+        #[arg(long, value_hint = ValueHint::Url)]
+        chrome_update_url: Option<String>,
+
         /// If true, setup daemon for start.
         #[arg(long, default_value = "true", action=ArgAction::Set)]
         daemon: bool,
@@ -81,30 +243,114 @@ enum Command {
         #[arg(long, default_value = "true", action=ArgAction::Set)]
         copy_daemon: bool,
 
+        /// If true, copy daemon to /usr/bin even if the installed daemon is more recent.
+        #[arg(long, default_value = "false")]
+        allow_downgrade: bool,
+
         /// If true, create extension directory
         #[arg(long, default_value = "true", action=ArgAction::Set)]
         mkdir: bool,
     },
 
+    /// Remove this tool from the system, undoing `setup`.
+    ///
+    /// You'll need to be root.
+    Uninstall {
+        /// If true, stop and disable the daemon, then remove its systemd configuration.
+        #[arg(long, default_value = "true", action=ArgAction::Set)]
+        service: bool,
+
+        /// If true, remove the addon from /etc/firefox/policies.json, leaving other policies untouched.
+        #[arg(long, default_value = "true", action=ArgAction::Set)]
+        policies: bool,
+
+        /// If true, remove the extension from Chrome/Chromium managed policies.
+        #[arg(long, default_value = "true", action=ArgAction::Set)]
+        chrome: bool,
+
+        /// If true, remove the addon from /etc/firefox/addons
+        #[arg(long, default_value = "true", action=ArgAction::Set)]
+        remove_addon: bool,
+
+        /// If true, remove the daemon from /usr/bin
+        #[arg(long, default_value = "true", action=ArgAction::Set)]
+        remove_daemon: bool,
+
+        /// If true, remove the directory of temporary extensions, along with its contents.
+        #[arg(long, default_value = "false", action=ArgAction::Set)]
+        rmdir: bool,
+    },
+
     /// Add a temporary rule.
     Exceptionally {
         #[command(subcommand)]
         verb: Verb<ExceptionalFilter>,
     },
 
+    /// Add temporary rules, read as JSON from stdin.
+    ///
+    /// The input has the same format as the files in the directory of temporary rules, e.g.
+    /// `{"users": {"alice": {"web": [{"domain": "youtube.com", "permitted": [{"start": "1700", "end": "1800"}]}]}}}`.
+    /// Meant for graphical frontends.
+    Apply,
+
     /// Add a permanent rule.
     Permanently {
         #[command(subcommand)]
         verb: Verb<PermanentFilter>,
     },
+
+    /// Add a permanent rule, written in a compact syntax.
+    ///
+    /// e.g. `keep-it-focused quick allow alice chromium mon-fri 16:00-18:00 homework time`.
+    ///
+    /// The syntax is `<allow|forbid> <user>[,<user>...] <binary|domain> <days> <start>-<end> [label...]`.
+    /// Targets containing a `/`, a wildcard or no `.` are binaries, others are domains, prefix
+    /// them with `binary:` or `domain:` to disambiguate. Days may be e.g. `mon`, `sat,sun` or `mon-fri`.
+    Quick {
+        /// The rule.
+        #[arg(required = true)]
+        expr: Vec<String>,
+
+        /// Show the change that would be applied, without applying it.
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+
+        /// Apply the change without asking for confirmation.
+        #[arg(short, long, default_value = "false")]
+        yes: bool,
+    },
+
+    /// Show build information: version, git hash, target and compiled-in features.
+    Version {
+        /// If true, print as JSON.
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+
+    /// Print a shell completion script to stdout.
+    ///
+    /// e.g. `keep-it-focused completions bash > /etc/bash_completion.d/keep-it-focused`
+    #[command(hide = true)]
+    Completions {
+        /// The shell for which to generate completions.
+        shell: clap_complete::Shell,
+    },
+}
+
+/// How `check` displays the configuration of a user.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Yaml,
+    Json,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 enum Kind {
     Domain {
         /// The domain, e.g. "youtube.com" (subdomains are included automatically).
-        #[arg(required = true)]
-        domains: Vec<String>,
+        #[arg(required = true, value_parser = Domain::parse)]
+        domains: Vec<Domain>,
     },
     Binary {
         /// The binary, e.g. "**/tetris" (globs are permitted).
@@ -153,12 +399,14 @@ struct PermanentFilter {
     #[command(subcommand)]
     kind: Kind,
 
-    #[arg(long)]
-    user: String,
+    /// The user(s) to which this rule applies (repeat or separate with commas).
+    #[arg(long, value_hint = ValueHint::Username, value_delimiter = ',', required = true)]
+    user: Vec<String>,
 
-    /// Which days of the week this rule is good for.
-    #[arg(long, value_parser=keep_it_focused::types::DayOfWeek::parse, required=true)]
-    days: Vec<DayOfWeek>,
+    /// Which days of the week this rule is good for, e.g. `mon`, `sat,sun` or `mon-fri`
+    /// (may be repeated).
+    #[arg(long, value_parser=keep_it_focused::types::DayOfWeek::parse_days, required=true)]
+    days: Vec<Vec<DayOfWeek>>,
 
     /// When the authorization starts.
     #[arg(long, value_parser=TimeOfDay::parse)]
@@ -167,6 +415,24 @@ struct PermanentFilter {
     /// When the authorization stops.
     #[arg(long, value_parser=TimeOfDay::parse)]
     end: TimeOfDay,
+
+    /// A reason shown to the user, e.g. "homework time".
+    #[arg(long)]
+    label: Option<String>,
+
+    /// Show the change that would be applied, without applying it.
+    #[arg(long, default_value = "false")]
+    dry_run: bool,
+
+    /// Apply the change without asking for confirmation.
+    #[arg(short, long, default_value = "false")]
+    yes: bool,
+}
+impl PermanentFilter {
+    /// The days of the week, without duplicates.
+    fn days(&self) -> Vec<DayOfWeek> {
+        self.days.iter().flatten().copied().unique().collect()
+    }
 }
 
 #[derive(clap::Args, Debug, Clone)]
@@ -174,8 +440,9 @@ struct ExceptionalFilter {
     #[command(subcommand)]
     kind: Kind,
 
-    #[arg(long)]
-    user: String,
+    /// The user(s) to which this rule applies (repeat or separate with commas).
+    #[arg(long, value_hint = ValueHint::Username, value_delimiter = ',', required = true)]
+    user: Vec<String>,
 
     /// When it starts [default: immediately].
     #[arg(long, value_parser=TimeOfDay::parse)]
@@ -185,9 +452,447 @@ struct ExceptionalFilter {
     #[arg(long, value_parser=TimeOfDay::parse)]
     end: Option<TimeOfDay>,
 
-    /// How long it lasts, in minutes (conflicts with `end`).
+    /// How long it lasts, in minutes (conflicts with `end`). May extend past midnight.
     #[arg(long, alias="duration", conflicts_with_all=["end"])]
     minutes: Option<u16>,
+
+    /// A reason shown to the user, e.g. "homework time".
+    #[arg(long)]
+    label: Option<String>,
+}
+
+/// Coloring for human-readable output.
+///
+/// Colors are only used when writing to a terminal and when `NO_COLOR` is not
+/// set (see https://no-color.org/). Logs are never colored.
+struct Palette {
+    enabled: bool,
+}
+impl Palette {
+    fn new() -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| value.is_empty().not());
+        Palette {
+            enabled: no_color.not() && std::io::stdout().is_terminal(),
+        }
+    }
+    fn allowed(&self, text: impl Display) -> String {
+        if self.enabled {
+            text.green().to_string()
+        } else {
+            text.to_string()
+        }
+    }
+    fn forbidden(&self, text: impl Display) -> String {
+        if self.enabled {
+            text.red().to_string()
+        } else {
+            text.to_string()
+        }
+    }
+    fn warning(&self, text: impl Display) -> String {
+        if self.enabled {
+            text.yellow().bold().to_string()
+        } else {
+            text.to_string()
+        }
+    }
+    fn heading(&self, text: impl Display) -> String {
+        if self.enabled {
+            text.bold().to_string()
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Translate an English text (see `keep_it_focused::locale`) and replace its placeholders.
+fn tr(language: Language, english: &str, values: &[(&str, &str)]) -> String {
+    Messages::render(language.translate(english), values)
+}
+
+/// Print a human-readable summary of today's rules for a user.
+fn print_summary(palette: &Palette, language: Language, instructions: &UserInstructions) {
+    let join = |intervals: &mut dyn Iterator<Item = Interval>| -> String {
+        let intervals: Vec<String> = intervals.map(|interval| interval.to_string()).collect();
+        if intervals.is_empty() {
+            language.translate("never").to_string()
+        } else {
+            intervals.join(", ")
+        }
+    };
+    let allowed_forbidden = |name: &str, accepted: &[AcceptedInterval]| {
+        let rejected = RejectedInterval::complement(accepted.to_vec());
+        tr(
+            language,
+            "{name}: allowed {allowed}, forbidden {forbidden}",
+            &[
+                ("name", name),
+                ("allowed", &palette.allowed(join(&mut accepted.iter().map(|i| i.0.clone())))),
+                ("forbidden", &palette.forbidden(join(&mut rejected.into_iter().map(|i| i.0)))),
+            ],
+        )
+    };
+    println!(
+        "{}",
+        palette.heading(tr(
+            language,
+            "today's rules for {user} ({day})",
+            &[("user", instructions.user_name()), ("day", DayOfWeek::now().localized(language))]
+        ))
+    );
+    if instructions.processes().is_empty().not() {
+        println!("  {}", palette.heading(language.translate("processes")));
+        for (binary, accepted) in instructions.processes() {
+            println!("    {}", allowed_forbidden(&binary.to_string(), accepted));
+        }
+    }
+    if instructions.web().is_empty().not() {
+        println!("  {}", palette.heading(language.translate("web")));
+        for (domain, accepted) in instructions.web() {
+            println!("    {}", allowed_forbidden(domain, accepted));
+        }
+    }
+    if instructions.ips().is_empty().not() {
+        println!("  {}", palette.heading(language.translate("ip")));
+        for (domain, rejected) in instructions.ips() {
+            let forbidden = palette.forbidden(join(&mut rejected.iter().map(|i| i.0.clone())));
+            println!(
+                "    {}",
+                tr(language, "{name}: forbidden {forbidden}", &[("name", domain), ("forbidden", &forbidden)])
+            );
+        }
+    }
+    if instructions.network().is_empty().not() {
+        println!("  {}", palette.heading(language.translate("network")));
+        for (binary, rejected) in instructions.network() {
+            let forbidden = palette.forbidden(join(&mut rejected.iter().map(|i| i.0.clone())));
+            println!(
+                "    {}",
+                tr(language, "{name}: forbidden {forbidden}", &[("name", &binary.to_string()), ("forbidden", &forbidden)])
+            );
+        }
+    }
+}
+
+/// How much of a cell of a timeline is permitted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    Allowed,
+    Partial,
+    Forbidden,
+}
+
+/// Split the day into `cells` cells and tell, for each of them, how much `accepted` covers.
+fn timeline_cells(accepted: &[AcceptedInterval], cells: u16) -> Vec<Cell> {
+    let cell_minutes = TimeOfDay::END.as_minutes() / cells;
+    (0..cells)
+        .map(|i| {
+            let (start, end) = (i * cell_minutes, (i + 1) * cell_minutes);
+            let covered: u16 = accepted
+                .iter()
+                .map(|interval| {
+                    let start = interval.0.start.as_minutes().max(start);
+                    interval.0.end.as_minutes().min(end).saturating_sub(start)
+                })
+                .sum();
+            match covered {
+                0 => Cell::Forbidden,
+                covered if covered >= cell_minutes => Cell::Allowed,
+                _ => Cell::Partial,
+            }
+        })
+        .collect()
+}
+
+/// A binary or domain, with the intervals during which it's permitted.
+type TimelineRow = (String, Vec<AcceptedInterval>);
+
+/// Print today's rules for a user as one bar per binary or domain.
+fn print_timeline(palette: &Palette, language: Language, instructions: &UserInstructions, cells: u16) {
+    let mut sections: Vec<(&str, Vec<TimelineRow>)> = vec![];
+    sections.push((
+        "processes",
+        instructions
+            .processes()
+            .iter()
+            .map(|(binary, accepted)| (binary.to_string(), accepted.clone()))
+            .collect(),
+    ));
+    sections.push((
+        "web",
+        instructions
+            .web()
+            .iter()
+            .map(|(domain, accepted)| (domain.to_string(), accepted.clone()))
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .collect(),
+    ));
+    sections.push((
+        "ip",
+        instructions
+            .ips()
+            .iter()
+            .map(|(domain, rejected)| {
+                // Complement the rejected intervals.
+                let rejected = rejected.iter().map(|i| AcceptedInterval(i.0.clone())).collect();
+                let accepted = RejectedInterval::complement(rejected)
+                    .into_iter()
+                    .map(|i| AcceptedInterval(i.0))
+                    .collect();
+                (domain.to_string(), accepted)
+            })
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .collect(),
+    ));
+    sections.retain(|(_, rows)| rows.is_empty().not());
+    println!(
+        "{}",
+        palette.heading(tr(
+            language,
+            "today's timeline for {user} ({day})",
+            &[("user", instructions.user_name()), ("day", DayOfWeek::now().localized(language))]
+        ))
+    );
+    if sections.is_empty() {
+        println!("  {}", language.translate("no rules today"));
+        return;
+    }
+    let width = sections
+        .iter()
+        .flat_map(|(_, rows)| rows)
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or_default();
+
+    // Mark every 6 hours.
+    let per_hour = cells as usize / 24;
+    let mut ruler = vec![' '; cells as usize + 2];
+    for hour in (0..=24).step_by(6) {
+        for (i, c) in format!("{hour:02}").chars().enumerate() {
+            if let Some(slot) = ruler.get_mut(hour * per_hour + i) {
+                *slot = c;
+            }
+        }
+    }
+    let ruler: String = ruler.into_iter().collect();
+    for (heading, rows) in sections {
+        println!("  {}", palette.heading(language.translate(heading)));
+        println!("    {:width$}  {}", "", ruler.trim_end());
+        for (name, accepted) in rows {
+            let bar: String = timeline_cells(&accepted, cells)
+                .into_iter()
+                .map(|cell| match cell {
+                    Cell::Allowed => palette.allowed("█"),
+                    Cell::Partial => palette.warning("▒"),
+                    Cell::Forbidden => palette.forbidden("░"),
+                })
+                .collect();
+            let permitted = Coverage::of_accepted(&accepted).permitted_minutes;
+            println!("    {name:width$}  {bar}  {}h{:02}", permitted / 60, permitted % 60);
+        }
+    }
+    println!(
+        "  {} {}  {} {}  {} {}",
+        palette.allowed("█"),
+        language.translate("allowed"),
+        palette.warning("▒"),
+        language.translate("partly allowed"),
+        palette.forbidden("░"),
+        language.translate("forbidden")
+    );
+}
+
+/// How this binary was built.
+#[derive(serde::Serialize)]
+struct BuildInfo {
+    version: &'static str,
+
+    /// The git hash, if the binary was built from a git checkout.
+    git_hash: Option<&'static str>,
+
+    /// The target triple, e.g. "x86_64-unknown-linux-gnu".
+    target: &'static str,
+
+    /// The optional features compiled in, e.g. "ip_tables".
+    features: Vec<&'static str>,
+}
+impl BuildInfo {
+    fn current() -> Self {
+        let mut features = vec![];
+        if cfg!(feature = "ip_tables") {
+            features.push("ip_tables");
+        }
+        if cfg!(feature = "dbus") {
+            features.push("dbus");
+        }
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("KEEP_IT_FOCUSED_GIT_HASH"),
+            target: env!("KEEP_IT_FOCUSED_TARGET"),
+            features,
+        }
+    }
+}
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "keep-it-focused {}", self.version)?;
+        writeln!(f, "git hash: {}", self.git_hash.unwrap_or("unknown"))?;
+        writeln!(f, "target: {}", self.target)?;
+        if self.features.is_empty() {
+            write!(f, "features: none")
+        } else {
+            write!(f, "features: {}", self.features.join(", "))
+        }
+    }
+}
+
+/// Fetch today's statistics from a running daemon.
+fn fetch_stats(port: u16) -> Result<Report, anyhow::Error> {
+    let body = fetch(port, "/stats")?;
+    serde_json::from_str(&body).context("Invalid statistics from daemon")
+}
+
+/// Fetch the status of a running daemon.
+fn fetch_status(port: u16) -> Result<DaemonStatus, anyhow::Error> {
+    let body = fetch(port, "/version")?;
+    serde_json::from_str(&body).context("Invalid status from daemon")
+}
+
+/// Fetch `path` from a running daemon.
+fn fetch(port: u16, path: &str) -> Result<String, anyhow::Error> {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", port))
+        .with_context(|| format!("Could not connect to daemon on port {port}, is it running?"))?;
+    stream
+        .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes())
+        .context("Failed to send request to daemon")?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .context("Failed to read response from daemon")?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("Invalid response from daemon"))?;
+    if head.starts_with("HTTP/1.1 200").not() {
+        return Err(anyhow!("Daemon refused request: {}", head.lines().next().unwrap_or_default()));
+    }
+    Ok(body.to_string())
+}
+
+/// Print a unified diff between two versions of a text.
+fn print_diff(palette: &Palette, label: &str, before: &str, after: &str) {
+    let diff = similar::TextDiff::from_lines(before, after);
+    println!("{}", palette.forbidden(format!("--- {label} (before)")));
+    println!("{}", palette.allowed(format!("+++ {label} (after)")));
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        println!("{}", palette.heading(hunk.header()));
+        for change in hunk.iter_changes() {
+            let line = change.to_string_lossy();
+            let line = line.trim_end_matches('\n');
+            match change.tag() {
+                similar::ChangeTag::Delete => println!("{}", palette.forbidden(format!("-{line}"))),
+                similar::ChangeTag::Insert => println!("{}", palette.allowed(format!("+{line}"))),
+                similar::ChangeTag::Equal => println!(" {line}"),
+            }
+        }
+    }
+}
+
+/// Ask the user a yes/no question on the terminal, defaulting to "no".
+fn confirm(question: &str) -> Result<bool, anyhow::Error> {
+    use std::io::Write;
+    print!("{question} [y/N] ");
+    std::io::stdout().flush().context("Failed to flush stdout")?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read answer")?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// A logger forwarding each record to several loggers.
+struct Loggers(Vec<Box<dyn log::Log>>);
+impl log::Log for Loggers {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.iter().any(|logger| logger.enabled(metadata))
+    }
+    fn log(&self, record: &log::Record) {
+        for logger in &self.0 {
+            if logger.enabled(record.metadata()) {
+                logger.log(record)
+            }
+        }
+    }
+    fn flush(&self) {
+        for logger in &self.0 {
+            logger.flush()
+        }
+    }
+}
+
+/// Install the journal logger (if we're connected to the journal) or a stdout logger,
+/// and optionally a file logger.
+///
+/// The file logger stops once the handle is dropped.
+fn init_logging(args: &Args) -> Result<Option<LoggerHandle>, anyhow::Error> {
+    let mut loggers: Vec<Box<dyn log::Log>> = Vec::new();
+    let verbosity = match args.verbose {
+        0 => None,
+        1 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    };
+    let max_level = if connected_to_journal() {
+        eprintln!("using journal log");
+        if args.log_file_only.not() {
+            let journal = JournalLog::new()
+                .context("Failed to connect to journal")?
+                .with_extra_fields(vec![("VERSION", env!("CARGO_PKG_VERSION"))]);
+            loggers.push(Box::new(journal));
+        }
+        match std::env::var("RUST_LOG").as_deref() {
+            Ok("error") => LevelFilter::Error,
+            Ok("debug") => LevelFilter::Debug,
+            Ok("info") => LevelFilter::Info,
+            Ok("trace") => LevelFilter::Trace,
+            Ok("warn") => LevelFilter::Warn,
+            _ => LevelFilter::Debug,
+        }
+        .max(verbosity.unwrap_or(LevelFilter::Off))
+    } else {
+        let mut logger = simple_logger::SimpleLogger::new().env();
+        if let Some(level) = verbosity {
+            let level = level.max(logger.max_level());
+            logger = logger.with_level(level);
+        }
+        let max_level = logger.max_level();
+        if args.log_file_only.not() {
+            loggers.push(Box::new(logger));
+        }
+        max_level
+    };
+
+    let mut handle = None;
+    if let Some(ref path) = args.log_file {
+        let file_spec = FileSpec::try_from(path)
+            .with_context(|| format!("Invalid log file {}", path.display()))?;
+        let (logger, file_handle) = flexi_logger::Logger::with(max_level)
+            .log_to_file(file_spec)
+            .rotate(
+                Criterion::Size(args.log_file_max_mb * 1_000_000),
+                Naming::Numbers,
+                Cleanup::KeepLogFiles(args.log_file_keep),
+            )
+            .append()
+            .format(flexi_logger::detailed_format)
+            .build()
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        loggers.push(logger);
+        handle = Some(file_handle);
+    }
+
+    log::set_boxed_logger(Box::new(Loggers(loggers))).context("Failed to install logger")?;
+    log::set_max_level(max_level);
+    Ok(handle)
 }
 
 /// A daemon designed to help avoid using some programs or websites
@@ -196,63 +901,344 @@ struct ExceptionalFilter {
 #[command(version, about)]
 struct Args {
     /// The path to the main config file.
-    #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
+    #[arg(short, long, default_value = DEFAULT_CONFIG_PATH, value_hint = ValueHint::FilePath)]
     main_config: PathBuf,
 
     /// A path for storing additional config files valid only for one day.
-    #[arg(short, long, default_value = DEFAULT_EXTENSIONS_PATH)]
+    #[arg(short, long, default_value = DEFAULT_EXTENSIONS_PATH, value_hint = ValueHint::DirPath)]
     extensions: PathBuf,
 
+    /// The iptables binary, e.g. `/usr/sbin/iptables` or `iptables-legacy`. Unless it's a path,
+    /// it's looked up in `PATH`.
+    #[arg(long, env = "KEEP_IT_FOCUSED_IPTABLES", default_value = keep_it_focused::unix::linux::DEFAULT_IP_TABLES_BINARY, value_hint = ValueHint::CommandName)]
+    iptables_binary: PathBuf,
+
+    /// Also write logs to this file, rotated by size.
+    ///
+    /// e.g. with `/var/log/keep-it-focused.log`, the current logs are written to
+    /// `/var/log/keep-it-focused_rCURRENT.log` and older logs to
+    /// `/var/log/keep-it-focused_r00000.log`, `/var/log/keep-it-focused_r00001.log`, etc.
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it reaches this size, in megabytes.
+    #[arg(long, default_value = "10")]
+    log_file_max_mb: u64,
+
+    /// How many rotated log files to keep.
+    #[arg(long, default_value = "5")]
+    log_file_keep: usize,
+
+    /// Only write logs to the log file, not to the journal or stdout.
+    #[arg(long, default_value = "false", requires = "log_file")]
+    log_file_only: bool,
+
+    /// Log more, regardless of `RUST_LOG` (`-v` for debug, `-vv` for trace).
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
     #[command(subcommand)]
     command: Command,
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    if connected_to_journal() {
-        eprintln!("using journal log");
-        JournalLog::new()
-            .unwrap()
-            .with_extra_fields(vec![("VERSION", env!("CARGO_PKG_VERSION"))])
-            .install()
-            .unwrap();
-        let max_level = match std::env::var("RUST_LOG").as_deref() {
-            Ok("error") => LevelFilter::Error,
-            Ok("debug") => LevelFilter::Debug,
-            Ok("info") => LevelFilter::Info,
-            Ok("trace") => LevelFilter::Trace,
-            Ok("warn") => LevelFilter::Warn,
-            _ => LevelFilter::Debug,
-        };
-        log::set_max_level(max_level);
-    } else {
-        simple_logger::SimpleLogger::new().env().init().unwrap();
+    let args = Args::parse();
+    if let Command::Completions { shell } = args.command {
+        // Don't let any log line end up in the middle of the script.
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return Ok(());
+    }
+    if let Command::Version { json } = args.command {
+        // Keep the output machine-readable.
+        let info = BuildInfo::current();
+        if json {
+            println!("{}", serde_json::to_string(&info).expect("error during serialization"));
+        } else {
+            println!("{info}");
+        }
+        return Ok(());
     }
+
+    // Keep the handle alive until the end of `main`.
+    let _log_handle = init_logging(&args)?;
     info!("Starting keep-it-focused {}", env!("CARGO_PKG_VERSION"));
 
-    let args = Args::parse();
     match args.command {
+        Command::Completions { .. } | Command::Version { .. } => {
+            // Handled before the logger is installed.
+        }
+        Command::Status { port } => {
+            let status = fetch_status(port)?;
+            let language = Language::from_env();
+            println!(
+                "{}",
+                tr(
+                    language,
+                    "daemon {version}, configuration generation {generation}, compiled at {time}",
+                    &[
+                        ("version", &status.version),
+                        ("generation", &status.generation.to_string()),
+                        ("time", &status.compiled_at.format("%Y-%m-%d %H:%M:%S").to_string()),
+                    ]
+                )
+            );
+            let palette = Palette::new();
+            for (path, reason) in &status.stuck_files {
+                let path = path.display().to_string();
+                println!(
+                    "{}",
+                    palette.warning(tr(
+                        language,
+                        "expired file {path} cannot be removed: {reason}",
+                        &[("path", &path), ("reason", reason)]
+                    ))
+                );
+            }
+            let modified = std::fs::metadata(&args.main_config)
+                .and_then(|metadata| metadata.modified())
+                .with_context(|| format!("Failed to access {}", args.main_config.display()))?;
+            if status.main_config_modified != Some(chrono::DateTime::from(modified)) {
+                return Err(anyhow!(
+                    "the daemon hasn't loaded the latest version of {} yet",
+                    args.main_config.display()
+                ));
+            }
+            println!(
+                "{}",
+                tr(language, "{path} is up to date", &[("path", &args.main_config.display().to_string())])
+            );
+        }
+        Command::Stats { port } => {
+            let report = fetch_stats(port)?;
+            let palette = Palette::new();
+            let language = Language::from_env();
+            if report.is_empty() {
+                println!("{}", language.translate("no rule caused a warning or a kill today"));
+            }
+            for (user, binaries) in report {
+                println!(
+                    "{}",
+                    palette.heading(tr(language, "today's enforcement for {user}", &[("user", &user)]))
+                );
+                for (binary, hits) in binaries {
+                    println!(
+                        "    {}",
+                        tr(
+                            language,
+                            "{binary}: {warnings} warnings, {kills} kills",
+                            &[
+                                ("binary", &binary),
+                                ("warnings", &palette.warning(hits.warnings)),
+                                ("kills", &palette.forbidden(hits.kills)),
+                            ]
+                        )
+                    );
+                }
+            }
+        }
+        Command::Observations { state_dir } => {
+            let report = Observations::read(&state_dir.join("observations.json"))?;
+            let palette = Palette::new();
+            let language = Language::from_env();
+            let (Some(first), Some(last)) = (report.keys().next(), report.keys().next_back()) else {
+                println!("{}", language.translate("nothing observed, did you run `keep-it-focused run --observe`?"));
+                return Ok(());
+            };
+            let (first, last) = (first.to_string(), last.to_string());
+            let usage = |name: &str, seen: u32, violations: u32| {
+                tr(
+                    language,
+                    "{name}: {seen} minutes, {violations} outside permitted intervals",
+                    &[
+                        ("name", name),
+                        ("seen", &seen.to_string()),
+                        ("violations", &palette.forbidden(violations)),
+                    ],
+                )
+            };
+            for (user, binaries) in keep_it_focused::observe::summarize(&report) {
+                println!(
+                    "{}",
+                    palette.heading(tr(
+                        language,
+                        "observed for {user}, {first} to {last}",
+                        &[("user", &user.to_string()), ("first", &first), ("last", &last)]
+                    ))
+                );
+                for (binary, hours) in binaries {
+                    let seen: u32 = hours.values().map(|minutes| minutes.seen).sum();
+                    let violations: u32 = hours.values().map(|minutes| minutes.violations).sum();
+                    println!("    {}", usage(&binary.to_string(), seen, violations));
+                    for (hour, minutes) in hours {
+                        let hour = format!("{hour:02}:00-{:02}:00", hour + 1);
+                        println!("        {}", usage(&hour, minutes.seen, minutes.violations));
+                    }
+                }
+            }
+        }
+        Command::Timeline { user, half_hours } => {
+            let compiled = ConfigManager::compile_only(&ConfigOptions {
+                main_config: args.main_config,
+                extensions_dir: args.extensions,
+                cache: None,
+            })
+            .context("invalid config")?;
+            let mut resolver = Resolver::new();
+            let uid = resolver.resolve(&Username(user.clone()))?;
+            let palette = Palette::new();
+            let language = Language::from_env();
+            match compiled.today_per_user().get(&uid) {
+                None => println!(
+                    "{}",
+                    palette.warning(tr(language, "on this day, no config for user {user}", &[("user", &user)]))
+                ),
+                Some(instructions) => print_timeline(&palette, language, instructions, if half_hours { 48 } else { 24 }),
+            }
+        }
+        Command::Ps { user } => {
+            let compiled = ConfigManager::compile_only(&ConfigOptions {
+                main_config: args.main_config,
+                extensions_dir: args.extensions,
+                cache: None,
+            })
+            .context("invalid config")?;
+            let only = match user {
+                Some(user) => Some(Resolver::new().resolve(&Username(user))?),
+                None => None,
+            };
+            let palette = Palette::new();
+            let language = Language::from_env();
+            let processes = procfs::process::all_processes()
+                .context("Could not access /proc, is this a Linux machine?")?;
+            // Per user, the processes as examined by the daemon.
+            let mut per_user = std::collections::BTreeMap::new();
+            let mut skipped = 0;
+            for proc in processes {
+                let Ok(proc) = proc else { continue };
+                let Ok(uid) = watched_owner(&proc, |uid| compiled.today_per_user().contains_key(&uid)) else { continue };
+                if only.is_some_and(|only| only != uid) {
+                    continue;
+                }
+                let Some(instructions) = compiled.today_per_user().get(&uid) else { continue };
+                let Ok(exe) = proc.exe() else {
+                    // Typically, a process of another user, while we're not root.
+                    skipped += 1;
+                    continue;
+                };
+                let exe = exe_path(exe);
+                let rule = instructions
+                    .process_rule(&exe, |path| runs_file(proc.pid, path).unwrap_or(false))
+                    .map(|(binary, _)| binary.path.display().to_string());
+                let cmdline = proc.cmdline().map(|args| args.join(" ")).unwrap_or_default();
+                per_user
+                    .entry(instructions.user_name().to_string())
+                    .or_insert_with(Vec::new)
+                    .push((proc.pid, exe, rule, cmdline));
+            }
+            for (user, processes) in per_user {
+                println!("{}", palette.heading(tr(language, "processes of {user}", &[("user", &user)])));
+                for (pid, exe, rule, cmdline) in processes {
+                    match rule {
+                        Some(rule) => println!("    {pid:>7} {} -> {}", exe.display(), palette.forbidden(rule)),
+                        None => println!("    {pid:>7} {}", exe.display()),
+                    }
+                    if cmdline.is_empty().not() {
+                        println!("            {cmdline}");
+                    }
+                }
+            }
+            if skipped > 0 {
+                let skipped = skipped.to_string();
+                println!(
+                    "{}",
+                    palette.warning(tr(
+                        language,
+                        "{count} processes could not be examined, try as root",
+                        &[("count", &skipped)]
+                    ))
+                );
+            }
+        }
+        Command::TestNotify { user } => {
+            let palette = Palette::new();
+            let language = Language::from_env();
+            let message = tr(language, "this is a test notification from keep-it-focused", &[]);
+            println!("{:?}", notify_command(&user, &message, Urgency::Significant));
+            notify(&user, &message, Urgency::Significant).context("Failed to notify user")?;
+            println!("{}", palette.allowed(tr(language, "notification sent to {user}", &[("user", &user)])));
+        }
         Command::IpTables { remove } => {
+            keep_it_focused::ensure_ip_tables_supported()?;
+            keep_it_focused::ensure_ip_tables_binary(&args.iptables_binary)?;
             if remove {
-                keep_it_focused::remove_ip_tables()?;
+                keep_it_focused::remove_ip_tables(&args.iptables_binary)?;
             }
         }
-        Command::Check { user } => {
-            let mut configurator = ConfigManager::new(ConfigOptions {
+        Command::Check { user, format, strict } => {
+            // Check every day of the week, not just today.
+            ConfigManager::validate(&args.main_config)
+                .context("invalid config")?;
+            let input = std::fs::File::open(&args.main_config)
+                .context("Failed to open main configuration")?;
+            let config: Config = serde_yaml::from_reader(std::io::BufReader::new(input))
+                .context("Failed to read/parse main configuration")?;
+            let palette = Palette::new();
+            let language = Language::from_env();
+            for (user, day, target, interval) in config.ineffective_forbidden() {
+                let (user, day) = (user.to_string(), day.to_string());
+                let (start, end) = (interval.start.to_string(), interval.end.to_string());
+                warn!("{user}, {day}: {target} forbidden from {start} to {end}, which never applies");
+                println!(
+                    "{}",
+                    palette.warning(tr(
+                        language,
+                        "{user}, {day}: {target} forbidden from {start} to {end}, which never applies",
+                        &[("user", &user), ("day", &day), ("target", &target), ("start", &start), ("end", &end)]
+                    ))
+                );
+            }
+            if strict {
+                let missing = config.missing_binaries();
+                for binary in &missing {
+                    let binary = binary.path.display().to_string();
+                    warn!("binary {binary} doesn't exist");
+                    println!("{}", palette.warning(tr(language, "binary {binary} doesn't exist", &[("binary", &binary)])));
+                }
+                if missing.is_empty().not() {
+                    return Err(anyhow!("strict check failed, {} binaries don't exist", missing.len()));
+                }
+            }
+            let compiled = ConfigManager::compile_only(&ConfigOptions {
                 main_config: args.main_config,
                 extensions_dir: args.extensions,
-            });
-            configurator.load_config()
-                .context("invalid config")?;
+                cache: None,
+            })
+            .context("invalid config")?;
             info!("config parsed, seems legit");
             if let Some(user) = user {
                 let mut resolver = Resolver::new();
                 let uid = resolver.resolve(&Username(user.clone()))?;
-                match configurator.config().today_per_user().get(&uid) {
-                    None => info!("on this day, no config for user {user}"),
-                    Some(config) =>
+                let palette = Palette::new();
+                let language = Language::from_env();
+                match compiled.today_per_user().get(&uid) {
+                    None if format == Format::Json => println!("null"),
+                    Some(config) if format == Format::Json => {
+                        println!("{}", serde_json::to_string_pretty(&config).context("Failed to serialize")?);
+                    }
+                    None => {
+                        info!("on this day, no config for user {user}");
+                        println!(
+                            "{}",
+                            palette.warning(tr(language, "on this day, no config for user {user}", &[("user", &user)]))
+                        );
+                    }
+                    Some(config) => {
                         info!("today's config for {user}\n {}", serde_yaml::to_string(&config)
-                            .context("Failed to serialize")?)
-
+                            .context("Failed to serialize")?);
+                        print_summary(&palette, language, config);
+                    }
                 }
             }
         }
@@ -260,7 +1246,18 @@ fn main() -> Result<(), anyhow::Error> {
             sleep_s,
             port,
             ip_tables,
+            serve_forbidden,
+            state_dir,
+            once,
+            observe,
+            kill_switch,
         } => {
+            if observe {
+                info!("observe mode, nothing will be enforced");
+            } else if ip_tables {
+                keep_it_focused::ensure_ip_tables_supported()?;
+                keep_it_focused::ensure_ip_tables_binary(&args.iptables_binary)?;
+            }
             info!("preparing file for temporary rules");
             keep_it_focused::setup::make_extension_dir(&args.extensions)
                 .context("Error while creating or setting up temporary rules directory")?;
@@ -268,25 +1265,92 @@ fn main() -> Result<(), anyhow::Error> {
             info!("loop: {}", "starting");
             let mut focuser = keep_it_focused::KeepItFocused::try_new(keep_it_focused::Options {
                 ip_tables,
+                iptables_binary: args.iptables_binary,
                 port,
+                serve_forbidden,
                 main_config: args.main_config,
                 extensions_dir: args.extensions,
+                state_dir: Some(state_dir),
+                observe,
+                kill_switch: Some(kill_switch),
             })
             .context("Failed to apply configuration")?;
+            if once {
+                // `try_new` has already performed a tick.
+                info!("loop: {}", "single pass complete");
+                return Ok(());
+            }
             focuser.background_serve();
+            if let Some(watchdog) = sd_notify::watchdog_enabled() {
+                if watchdog.as_secs() <= sleep_s {
+                    warn!("the systemd watchdog ({}s) will expire between two ticks ({sleep_s}s), please increase WatchdogSec", watchdog.as_secs());
+                }
+            }
+            notify_systemd(&[NotifyState::Ready, NotifyState::Status("running"), NotifyState::Watchdog]);
 
+            // How many ticks have failed in a row.
+            let mut failures = 0;
             loop {
-                info!("loop: {}", "sleeping");
-                thread::sleep(std::time::Duration::from_secs(sleep_s));
-                if let Err(err) = focuser.tick() {
-                    warn!("problem during tick, skipping! {:?}", err);
+                // Wake up right after the next change, so that enforcement lands on time, but
+                // never sleep longer than `sleep_s`, to catch newly launched processes and
+                // changes to the configuration.
+                //
+                // Note: SIGTERM/SIGHUP are not handled, so they stop us immediately, even
+                // while we sleep.
+                let max_sleep = std::time::Duration::from_secs(sleep_s);
+                let sleep = focuser
+                    .next_boundary()
+                    .and_then(|next| (next - chrono::Local::now()).to_std().ok())
+                    .map(|until| until + std::time::Duration::from_secs(1))
+                    .map_or(max_sleep, |until| until.min(max_sleep));
+                info!("loop: sleeping {}s", sleep.as_secs());
+                // With the `dbus` feature, apps are checked as soon as they're launched.
+                let deadline = std::time::Instant::now() + sleep;
+                while let Some(uid) = focuser.wait_until(deadline) {
+                    if let Err(err) = focuser.check_user(uid) {
+                        warn!("problem while checking launched app, skipping! {:?}", err);
+                    }
+                }
+                match focuser.tick() {
+                    Ok(()) => {
+                        failures = 0;
+                        let status = if focuser.is_waiting_for_clock() {
+                            "waiting for clock sync".to_string()
+                        } else if focuser.is_disabled() {
+                            "disabled by kill switch".to_string()
+                        } else {
+                            format!("running, last tick at {}", TimeOfDay::now())
+                        };
+                        // If ticks hang or keep failing, systemd restarts us.
+                        notify_systemd(&[NotifyState::Status(&status), NotifyState::Watchdog]);
+                    }
+                    Err(err) => {
+                        warn!("problem during tick, skipping! {:?}", err);
+                        failures += 1;
+                        // Show up in `systemctl status`.
+                        let status = format!("degraded, {failures} tick(s) failed in a row: {err:#}")
+                            .replace('\n', " ");
+                        notify_systemd(&[NotifyState::Status(&status)]);
+                    }
                 }
             }
         }
         Command::Setup {
             policies,
+            force,
+            port,
+            sleep_s,
+            ip_tables,
+            serve_forbidden,
+            state_dir,
+            log_level,
+            timer,
+            chrome,
+            chrome_extension_id,
+            chrome_update_url,
             copy_addon,
             copy_daemon,
+            allow_downgrade,
             daemon,
             start,
             mkdir,
@@ -294,22 +1358,50 @@ fn main() -> Result<(), anyhow::Error> {
             if Uid::me().is_root().not() {
                 warn!("this command is meant to be executed as root");
             }
-            if policies {
-                info!("setting up policies");
-                keep_it_focused::setup::setup_policies()
-                    .context("Failed to setup policies.json")?;
+            if daemon && ip_tables {
+                // Otherwise, the daemon would fail upon startup.
+                keep_it_focused::ensure_ip_tables_supported()?;
             }
+            // Copy the addon first, as setting up policies checks that it exists.
             if copy_addon {
                 info!("copying addon");
                 keep_it_focused::setup::copy_addon().context("Failed to copy addon xpi")?;
             }
+            if policies {
+                info!("setting up policies");
+                keep_it_focused::setup::setup_policies(force)
+                    .context("Failed to setup policies.json")?;
+            }
+            if chrome {
+                info!("setting up chrome policies");
+                let (Some(extension_id), Some(update_url)) = (chrome_extension_id, chrome_update_url) else {
+                    return Err(anyhow!("`--chrome` requires `--chrome-extension-id` and `--chrome-update-url`"));
+                };
+                keep_it_focused::setup::setup_chromium_policies(&extension_id, &update_url)
+                    .context("Failed to setup chrome policies")?;
+            }
             if copy_daemon {
                 info!("copying daemon");
-                keep_it_focused::setup::copy_daemon().context("Failed to copy daemon")?;
+                keep_it_focused::setup::copy_daemon(allow_downgrade).context("Failed to copy daemon")?;
             }
             if daemon {
                 info!("setting up daemon");
-                keep_it_focused::setup::setup_daemon(start).context("Failed to copy daemon")?;
+                // The daemon runs from another directory, so relative paths won't work.
+                let absolute = |path: &PathBuf| std::path::absolute(path)
+                    .with_context(|| format!("Invalid path {}", path.display()));
+                let options = keep_it_focused::setup::DaemonOptions {
+                    main_config: absolute(&args.main_config)?,
+                    extensions_dir: absolute(&args.extensions)?,
+                    state_dir: absolute(&state_dir)?,
+                    port,
+                    sleep_s,
+                    ip_tables,
+                    serve_forbidden,
+                    log_level,
+                    timer,
+                };
+                keep_it_focused::setup::setup_daemon(&options, start, force)
+                    .context("Failed to setup daemon")?;
             }
             if mkdir {
                 info!("setting up directory for temporary extensions");
@@ -318,171 +1410,413 @@ fn main() -> Result<(), anyhow::Error> {
             }
             info!("setup complete");
         }
+        Command::Uninstall {
+            service,
+            policies,
+            chrome,
+            remove_addon,
+            remove_daemon,
+            rmdir,
+        } => {
+            if Uid::me().is_root().not() {
+                warn!("this command is meant to be executed as root");
+            }
+            // Stop the daemon first, so that it doesn't get restarted while we remove its binary.
+            if service {
+                info!("removing daemon service");
+                keep_it_focused::setup::remove_service().context("Failed to remove daemon service")?;
+            }
+            if policies {
+                info!("removing policies");
+                keep_it_focused::setup::remove_policies()
+                    .context("Failed to remove addon from policies.json")?;
+            }
+            if chrome {
+                info!("removing chrome policies");
+                keep_it_focused::setup::remove_chromium_policies()
+                    .context("Failed to remove chrome policies")?;
+            }
+            if remove_addon {
+                info!("removing addon");
+                keep_it_focused::setup::remove_addon().context("Failed to remove addon xpi")?;
+            }
+            if remove_daemon {
+                info!("removing daemon");
+                keep_it_focused::setup::remove_daemon().context("Failed to remove daemon")?;
+            }
+            if rmdir {
+                info!("removing directory for temporary extensions");
+                keep_it_focused::setup::remove_extension_dir(&args.extensions)
+                    .context("Failed to remove directory for temporary extensions")?;
+            }
+            info!("uninstall complete");
+        }
         Command::Permanently { verb } => {
+            permanently(&args.main_config, verb)?;
+        }
+        Command::Quick { expr, dry_run, yes } => {
+            let rule = QuickRule::parse(&expr)?;
+            let users = rule.users.into_iter().map(|user| user.0).collect();
+            let kind = match rule.target {
+                Target::Binary(binary) => Kind::Binary { binaries: vec![binary.path.to_string_lossy().into_owned()] },
+                Target::Domain(domain) => Kind::Domain { domains: vec![domain] },
+            };
+            let filter = PermanentFilter {
+                kind,
+                user: users,
+                days: vec![rule.days],
+                start: rule.start,
+                end: rule.end,
+                label: rule.label,
+                dry_run,
+                yes,
+            };
+            let verb = if rule.allow { Verb::Allow(filter) } else { Verb::Forbid(filter) };
+            permanently(&args.main_config, verb)?;
+        }
+        Command::Exceptionally { verb } => {
             if Uid::me().is_root().not() {
                 warn!("this command is meant to be executed as root");
             }
+
             let mut resolver = Resolver::new();
-            resolver.resolve(&Username(verb.as_ref().user.clone()))?;
-
-            // 1. Pick a temporary file.
-            let temp_dir = std::env::temp_dir();
-            let (temp_file, file) = loop {
-                let name = format!("{}.yaml", uuid().unwrap());
-                let path = std::path::Path::join(&temp_dir, name);
-                match std::fs::File::create_new(&path) {
-                    Err(err) if err.kind() == ErrorKind::AlreadyExists => {
-                        // We stumbled upon an existing file, try again.
-                        continue;
-                    }
-                    Err(err) => {
-                        return Err(err).context("Could not create file to write temporary rules")
-                    }
-                    Ok(file) => break (path, file),
-                };
-            };
+            for user in &verb.user {
+                // Rules may target a profile of an account, e.g. `alice:kids`.
+                resolver.resolve(&Username(user.clone()).split_profile().0)?;
+            }
 
-            // 2. Read existing config.
-            let input = std::fs::File::open(&args.main_config)
-                .context("Failed to open main configuration")?;
-            let mut config: Config = serde_yaml::from_reader(std::io::BufReader::new(input))
-                .context("Failed to read/parse main configuration")?;
-            let entry = config
-                .users
-                .entry(Username(verb.as_ref().user.clone()))
-                .or_default();
-
-            // 2. Amend it to a temporary file.
-            //
-            // Using a temporary file:
-            // 1. Lets us perform a quick check that we're not breaking things too obviously.
-            // 2. Decreases the chances of two concurrent changes causing us to end up with a
-            //    broken /etc/keep-it-focused.yaml.
-            // 3. Decreases (but does not eliminate) the chances of a power outage while a change
-            //    causing a broken /etc/keep-it-focused.yaml.
-            let intervals = vec![Interval {
-                start: verb.as_ref().start,
-                end: verb.as_ref().end,
-            }];
+            let limits = limits(&args.main_config);
+
+            // Note: we expect that the configuration directory has been created already.
+            // Generate config.
+            let mut extension = Extension::default();
+            let start = verb.start.unwrap_or(TimeOfDay::now());
+            if let Some(end) = verb.end.filter(|end| *end < start) {
+                return Err(anyhow!("rule would end ({end}) before it starts ({start})"));
+            }
+            let allow = matches!(verb, Verb::Allow(_));
+            let duration = limits.exceptional_minutes(allow, start, verb.minutes, verb.end);
+            // The file must not outlive the rule.
+            let intervals = extension.schedule(start, duration, verb.as_ref().label.clone(), chrono::Local::now())?;
             let (permitted, forbidden) = match verb {
                 Verb::Allow(_) => (intervals, vec![]),
                 Verb::Forbid(_) => (vec![], intervals),
             };
-            match verb.as_ref().kind {
-                Kind::Domain { ref domains } => {
-                    for day in &verb.days {
-                        let day_config = entry.0.entry(*day).or_default();
+            debug!("exceptionally {:?}, {:?}", permitted, forbidden);
+            let description = format!(
+                "{}-{}-{}",
+                verb.user.join("+"),
+                match verb {
+                    Verb::Allow(_) => "allow",
+                    Verb::Forbid(_) => "forbid",
+                },
+                match verb.kind {
+                    Kind::Domain { .. } => "domain",
+                    Kind::Binary { .. } => "binary",
+                }
+            );
+            for user in &verb.user {
+                let day_config = extension.users.entry(Username(user.clone())).or_default();
+                match &verb.kind {
+                    Kind::Domain { domains } => {
                         for domain in domains {
                             day_config.web.push(WebFilter {
-                                domain: Domain(domain.clone()),
+                                domain: domain.clone(),
                                 permitted: permitted.clone(),
                                 forbidden: forbidden.clone(),
                             });
                         }
                     }
-                }
-                Kind::Binary { ref binaries } => {
-                    for day in &verb.days {
-                        let day_config = entry.0.entry(*day).or_default();
+                    Kind::Binary { binaries } => {
                         for path in binaries {
                             let binary = Binary::try_new(path.as_ref())?;
-                            day_config.processes.push(ProcessFilter {
-                                binary: binary.clone(),
-                                permitted: permitted.clone(),
-                                forbidden: forbidden.clone(),
-                            });
+                            day_config.processes.push(
+                                ProcessFilter::builder()
+                                    .binary(binary.clone())
+                                    .permitted(permitted.clone())
+                                    .forbidden(forbidden.clone())
+                                    .build(),
+                            );
                         }
                     }
-                }
-            };
-            debug!("preparing to write new file {:?}", config);
-            serde_yaml::to_writer(std::io::BufWriter::new(file), &config)
-                .context("Failed to write temporary file")?;
-
-            // 3. Check that we're not going to break keep-it-focused.
-            let mut simulator = KeepItFocused::try_new(keep_it_focused::Options {
-                ip_tables: false,
-                port: 2425,
-                main_config: temp_file.clone(),
-                extensions_dir: args.extensions,
-            })
-            .context("Failed to launch checker")?;
-            simulator
-                .tick()
-                .context("Could not process change, rolling back")?;
-
-            // 4. Finally, commit change.
-            //
-            // Again, this is still a race condition.
-            info!("committing change");
-            std::fs::rename(temp_file, args.main_config).context("Failed to commit changes")?;
+                };
+            }
+            debug!("extension {:?}", extension);
+            warn_ineffective_forbidden(&extension);
+            write_extension(&args.extensions, &extension, &description, limits.max_extensions_per_day)?;
         }
-        Command::Exceptionally { verb } => {
+        Command::Apply => {
             if Uid::me().is_root().not() {
                 warn!("this command is meant to be executed as root");
             }
+            let extension: Extension = serde_json::from_reader(std::io::stdin().lock())
+                .context("Invalid temporary rules")?;
+            let mut resolver = Resolver::new();
+            for (user, day_config) in &extension.users {
+                resolver.resolve(&user.split_profile().0)?;
+                if let Some(interval) = day_config.intervals().find(|interval| interval.end < interval.start) {
+                    return Err(anyhow!(
+                        "rule for user {user} would end ({}) before it starts ({})",
+                        interval.end,
+                        interval.start
+                    ));
+                }
+            }
+            debug!("extension {:?}", extension);
+            warn_ineffective_forbidden(&extension);
+            let description = format!("{}-apply", extension.users.keys().map(|user| &user.0).sorted().join("+"));
+            write_extension(
+                &args.extensions,
+                &extension,
+                &description,
+                limits(&args.main_config).max_extensions_per_day,
+            )?;
+        }
+    }
+    Ok(())
+}
 
-            // Note: we expect that the configuration directory has been created already.
-            // Generate config.
-            let mut extension = Extension::default();
-            let day_config = extension
-                .users
-                .entry(Username(verb.user.clone()))
-                .or_default();
-            let start = verb.start.unwrap_or(TimeOfDay::now());
-            let end = match verb.minutes {
-                Some(duration) => TimeOfDay::from_minutes(TimeOfDay::now().as_minutes() + duration),
-                None => verb.end.unwrap_or(TimeOfDay::END)
-            };
-            let intervals = vec![Interval {
-                start,
-                end,
-            }];
-            let (permitted, forbidden) = match verb {
-                Verb::Allow(_) => (intervals, vec![]),
-                Verb::Forbid(_) => (vec![], intervals),
-            };
-            debug!("exceptionally {:?}, {:?}", permitted, forbidden);
-            match &verb.kind {
-                Kind::Domain { domains } => {
+/// Report our state to systemd, if we're running as a `Type=notify` service.
+fn notify_systemd(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(state) {
+        debug!("failed to notify systemd: {err}");
+    }
+}
+
+/// Add a permanent rule to the main configuration, after validation and confirmation.
+fn permanently(main_config: &Path, verb: Verb<PermanentFilter>) -> Result<(), anyhow::Error> {
+    if Uid::me().is_root().not() {
+        warn!("this command is meant to be executed as root");
+    }
+    let mut resolver = Resolver::new();
+    for user in &verb.user {
+        resolver.resolve(&Username(user.clone()).split_profile().0)?;
+    }
+
+    // 1. Read existing config.
+    //
+    // We hold the lock until the change is committed or abandoned, so that concurrent edits
+    // are serialized and the daemon doesn't reload the file meanwhile, see `config::lock`.
+    let _lock = ConfigLock::exclusive(main_config).context("Failed to lock main configuration")?;
+    let input = std::fs::File::open(main_config)
+        .context("Failed to open main configuration")?;
+    let mut config: Config = serde_yaml::from_reader(std::io::BufReader::new(input))
+        .context("Failed to read/parse main configuration")?;
+    let mut before = Vec::new();
+    for user in &verb.user {
+        let week = config.users.get(&Username(user.clone()));
+        before.push(serde_yaml::to_string(&week).context("Failed to serialize")?);
+    }
+
+    // 2. Amend it.
+    let intervals = vec![Interval {
+        start: verb.as_ref().start,
+        end: verb.as_ref().end,
+        label: verb.as_ref().label.clone(),
+    }];
+    let days = verb.days();
+    let (permitted, forbidden) = match verb {
+        Verb::Allow(_) => (intervals, vec![]),
+        Verb::Forbid(_) => (vec![], intervals),
+    };
+    for user in &verb.user {
+        let entry = config.users.entry(Username(user.clone())).or_default();
+        match verb.as_ref().kind {
+            Kind::Domain { ref domains } => {
+                for day in &days {
+                    let day_config = entry.0.entry(*day).or_default();
                     for domain in domains {
                         day_config.web.push(WebFilter {
-                            domain: Domain(domain.clone()),
+                            domain: domain.clone(),
                             permitted: permitted.clone(),
                             forbidden: forbidden.clone(),
                         });
                     }
                 }
-                Kind::Binary { binaries } => {
+            }
+            Kind::Binary { ref binaries } => {
+                for day in &days {
+                    let day_config = entry.0.entry(*day).or_default();
                     for path in binaries {
                         let binary = Binary::try_new(path.as_ref())?;
-                        day_config.processes.push(ProcessFilter {
-                            binary: binary.clone(),
-                            permitted: permitted.clone(),
-                            forbidden: forbidden.clone(),
-                        });
+                        day_config.processes.push(
+                            ProcessFilter::builder()
+                                .binary(binary.clone())
+                                .permitted(permitted.clone())
+                                .forbidden(forbidden.clone())
+                                .build(),
+                        );
                     }
                 }
-            };
-            debug!("extension {:?}", extension);
-            // Create temporary buffer.
-            let (path, file) = loop {
-                let name = format!("{}.yaml", uuid().unwrap());
-                let path = std::path::Path::join(&args.extensions, name);
-                match std::fs::File::create_new(&path) {
-                    Err(err) if err.kind() == ErrorKind::AlreadyExists => {
-                        // We stumbled upon an existing file, try again.
-                        continue;
-                    }
-                    Err(err) => {
-                        return Err(err).context("Could not create file to write temporary rule")
-                    }
-                    Ok(file) => break (path, file),
-                };
-            };
-            info!("writing rule to {}", path.display());
-            serde_yaml::to_writer(file, &extension).context("Failed to write extension to file")?;
-        }
+            }
+        };
+    }
+    // 3. Write it to a temporary file.
+    //
+    // Using a temporary file:
+    // 1. Lets us perform a quick check that we're not breaking things too obviously.
+    // 2. Decreases (but does not eliminate) the chances of a power outage while a change
+    //    causing a broken /etc/keep-it-focused.yaml.
+    //
+    // The file lives next to the main configuration, so that renaming it doesn't cross
+    // filesystems, and it's removed if anything fails.
+    let dir = main_config.parent().filter(|dir| dir.as_os_str().is_empty().not()).unwrap_or(Path::new("."));
+    let file_name = main_config.file_name().unwrap_or_default().to_string_lossy();
+    let (temp_file, file) = loop {
+        let path = dir.join(format!(".{file_name}.{}.tmp", uuid().unwrap()));
+        match std::fs::File::create_new(&path) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                // We stumbled upon an existing file, try again.
+                continue;
+            }
+            Err(err) => {
+                return Err(err).context("Could not create file to write temporary rules")
+            }
+            Ok(file) => break (TempFile(Some(path)), file),
+        };
+    };
+    debug!("preparing to write new file {:?}", config);
+    serde_yaml::to_writer(std::io::BufWriter::new(file), &config)
+        .context("Failed to write temporary file")?;
+
+    // 4. Check that we're not going to break keep-it-focused.
+    ConfigManager::validate(temp_file.path())
+        .map_err(|err| anyhow::Error::from(err).context("Could not process change, rolling back"))?;
+
+    // 5. Show the change and, unless we've been told otherwise, ask for confirmation.
+    let palette = Palette::new();
+    for (user, before) in verb.user.iter().zip(before) {
+        let week = config.users.get(&Username(user.clone()));
+        let after = serde_yaml::to_string(&week).context("Failed to serialize")?;
+        print_diff(&palette, user, &before, &after);
+    }
+    if verb.dry_run {
+        info!("dry run, not committing change");
+        return Ok(());
+    }
+    if verb.yes.not() && std::io::stdin().is_terminal() && confirm("Apply this change?")?.not() {
+        info!("change aborted");
+        return Ok(());
     }
+
+    // 6. Finally, commit change.
+    info!("committing change");
+    std::fs::rename(temp_file.path(), main_config).context("Failed to commit changes")?;
+    temp_file.keep();
     Ok(())
 }
+
+/// A temporary file, removed when dropped unless `keep` has been called.
+struct TempFile(Option<PathBuf>);
+impl TempFile {
+    fn path(&self) -> &Path {
+        self.0.as_deref().expect("temporary file already kept")
+    }
+
+    /// Don't remove the file, e.g. because it has been renamed.
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The main configuration, from which we read the limits on temporary rules, e.g.
+/// `max_extensions_per_day`.
+///
+/// If it cannot be read, there are no limits.
+fn limits(main_config: &Path) -> Config {
+    std::fs::File::open(main_config)
+        .context("Failed to open main configuration")
+        .and_then(|input| {
+            serde_yaml::from_reader(std::io::BufReader::new(input))
+                .context("Failed to read/parse main configuration")
+        })
+        .inspect_err(|err| warn!("cannot read the limits on temporary rules, assuming none: {err:#}"))
+        .unwrap_or_default()
+}
+
+/// Warn about the `forbidden` intervals of temporary rules that cannot forbid anything.
+fn warn_ineffective_forbidden(extension: &Extension) {
+    for (user, day_config) in &extension.users {
+        for (target, interval) in day_config.ineffective_forbidden() {
+            warn!(
+                "rule for user {user} forbids {target} from {} to {}, which never applies",
+                interval.start, interval.end
+            );
+        }
+    }
+}
+
+/// Write temporary rules to a new file in `dir`.
+///
+/// The name of the file starts with `description`, e.g. `alice-allow-binary`, so that admins can
+/// tell files apart, and ends with a random UUID, so that it is unique.
+///
+/// If `max_per_day` is specified, refuse to write rules for a user who already has that many
+/// temporary rules today.
+///
+/// The file is written under a hidden name, then renamed, so that the daemon never reads
+/// a partial file.
+fn write_extension(
+    dir: &std::path::Path,
+    extension: &Extension,
+    description: &str,
+    max_per_day: Option<u16>,
+) -> Result<(), anyhow::Error> {
+    if let Some(max) = max_per_day {
+        let accounts = extension
+            .users
+            .keys()
+            .map(|user| user.split_profile().0)
+            .unique()
+            .sorted_by(|a, b| a.0.cmp(&b.0));
+        for user in accounts {
+            let count = ConfigManager::count_extensions_today(dir, &user)
+                .context("Failed to count temporary rules")?;
+            if count >= max as usize {
+                return Err(anyhow!(
+                    "user {user} already has {count} temporary rules today, the maximum is {max}"
+                ));
+            }
+        }
+    }
+    // Keep the name portable and well below the limit of 255 bytes.
+    let description: String = description
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "+-_".contains(c) { c } else { '_' })
+        .take(MAX_DESCRIPTION_LEN)
+        .collect();
+    let (name, path, file) = loop {
+        let name = format!("{description}-{}.yaml", uuid().unwrap());
+        let path = dir.join(format!(".{name}"));
+        match std::fs::File::create_new(&path) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                // We stumbled upon an existing file, try again.
+                continue;
+            }
+            Err(err) => {
+                return Err(err).context("Could not create file to write temporary rule")
+            }
+            Ok(file) => break (name, path, file),
+        };
+    };
+    let destination = dir.join(name);
+    info!("writing rule to {}", destination.display());
+    let result = serde_yaml::to_writer(&file, extension)
+        .context("Failed to write extension to file")
+        .and_then(|()| file.sync_all().context("Failed to write extension to file"))
+        .and_then(|()| {
+            std::fs::rename(&path, &destination)
+                .with_context(|| format!("Failed to move extension to {}", destination.display()))
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&path);
+    }
+    result
+}
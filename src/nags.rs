@@ -0,0 +1,137 @@
+//! Scheduling reminders, for rules with a nag policy.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Local, NaiveDate};
+use log::debug;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{config::Nag, types::TimeOfDay, uid_resolver::Uid};
+
+/// When the next reminder of a (user, binary) is due.
+#[derive(Debug)]
+pub struct Nags {
+    /// The day to which the reminders apply.
+    day: NaiveDate,
+
+    next: HashMap<(Uid, PathBuf), TimeOfDay>,
+
+    /// Picks the delay between two reminders.
+    rng: StdRng,
+}
+impl Default for Nags {
+    fn default() -> Self {
+        Nags::new()
+    }
+}
+impl Nags {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    /// A scheduler with reproducible delays, for testing.
+    #[cfg(test)]
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        Nags {
+            day: Local::now().date_naive(),
+            next: HashMap::new(),
+            rng,
+        }
+    }
+
+    /// Forget all reminders if `today` is not the day to which they apply.
+    pub fn rollover(&mut self, today: NaiveDate) {
+        if today != self.day {
+            self.day = today;
+            self.next.clear();
+        }
+    }
+
+    /// Whether `uid` should be reminded to close `binary` at `now`.
+    ///
+    /// If so, the next reminder is scheduled after a random delay picked from `nag`.
+    pub fn is_due(&mut self, uid: Uid, binary: &Path, nag: Nag, now: TimeOfDay) -> bool {
+        let key = (uid, binary.to_path_buf());
+        if let Some(next) = self.next.get(&key) {
+            if now < *next {
+                return false;
+            }
+        }
+        let delay = self.rng.gen_range(nag.minutes());
+        let next = now.saturating_add_minutes(delay);
+        debug!(
+            "binary {} for user {}, next reminder at {next}",
+            binary.display(),
+            uid.0
+        );
+        self.next.insert(key, next);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use chrono::Local;
+
+    use crate::{config::Nag, types::TimeOfDay, uid_resolver::Uid};
+
+    use super::Nags;
+
+    fn time(hours: u8, minutes: u8) -> TimeOfDay {
+        TimeOfDay { hours, minutes }
+    }
+
+    /// The minutes of `from..to` at which `uid` is reminded about `binary`.
+    fn reminders(nags: &mut Nags, uid: Uid, binary: &Path, nag: Nag, from: u8, to: u8) -> Vec<u8> {
+        (from..to)
+            .filter(|minutes| nags.is_due(uid, binary, nag, time(17, *minutes)))
+            .collect()
+    }
+
+    #[test]
+    fn test_nags() {
+        let tetris = Path::new("**/tetris");
+        let user = Uid(1000);
+        let nag = Nag {
+            min_minutes: 3,
+            max_minutes: 7,
+        };
+
+        let mut nags = Nags::with_seed(42);
+        let first = reminders(&mut nags, user, tetris, nag, 0, 60);
+        // Reminded right away, then after 3 to 7 minutes each time.
+        assert_eq!(first[0], 0);
+        assert!(first.windows(2).all(|pair| (3..=7).contains(&(pair[1] - pair[0]))));
+        // The delays actually vary.
+        assert!(first.windows(3).any(|triple| triple[1] - triple[0] != triple[2] - triple[1]));
+
+        // The same seed gives the same reminders.
+        let mut again = Nags::with_seed(42);
+        assert_eq!(reminders(&mut again, user, tetris, nag, 0, 60), first);
+
+        // Other users are tracked separately.
+        assert!(nags.is_due(Uid(1001), tetris, nag, time(17, 1)));
+
+        // Degenerate ranges still space reminders by at least a minute.
+        let fixed = Nag {
+            min_minutes: 0,
+            max_minutes: 0,
+        };
+        assert_eq!(reminders(&mut Nags::with_seed(0), user, tetris, fixed, 0, 4), vec![0, 1, 2, 3]);
+
+        // A new day forgets reminders.
+        let last = *first.last().unwrap();
+        assert!(!nags.is_due(user, tetris, nag, time(17, last)));
+        nags.rollover(Local::now().date_naive().succ_opt().unwrap());
+        assert!(nags.is_due(user, tetris, nag, time(17, last)));
+    }
+}
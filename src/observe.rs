@@ -0,0 +1,195 @@
+//! Recording which watched binaries run when, without enforcing anything.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{Local, NaiveDate};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::types::TimeOfDay;
+
+/// How many days of observations we keep.
+pub const OBSERVATION_DAYS: u64 = 7;
+
+/// Usage of a binary during one hour.
+///
+/// Each field is a bitmask of the minutes of the hour, so that a binary caught several times
+/// during the same minute (e.g. several processes) is only counted once.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// The minutes during which the binary was running.
+    pub seen: u64,
+
+    /// The minutes during which the binary was running outside of its permitted intervals.
+    pub violations: u64,
+}
+impl Usage {
+    pub fn seen_minutes(&self) -> u32 {
+        self.seen.count_ones()
+    }
+    pub fn violation_minutes(&self) -> u32 {
+        self.violations.count_ones()
+    }
+}
+
+/// `day => user name => binary => hour => usage`.
+pub type Report = BTreeMap<NaiveDate, BTreeMap<String, BTreeMap<String, BTreeMap<u8, Usage>>>>;
+
+/// Total usage of a binary during an hour of the day, over several days.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Minutes {
+    pub seen: u32,
+    pub violations: u32,
+}
+
+/// `user name => binary => hour => usage`, summed over all days.
+pub type Summary = BTreeMap<String, BTreeMap<String, BTreeMap<u8, Minutes>>>;
+
+/// Sum observations over all days.
+pub fn summarize(report: &Report) -> Summary {
+    let mut summary = Summary::new();
+    for (user, binaries) in report.values().flatten() {
+        for (binary, hours) in binaries {
+            for (hour, usage) in hours {
+                let minutes = summary
+                    .entry(user.clone())
+                    .or_default()
+                    .entry(binary.clone())
+                    .or_default()
+                    .entry(*hour)
+                    .or_default();
+                minutes.seen += usage.seen_minutes();
+                minutes.violations += usage.violation_minutes();
+            }
+        }
+    }
+    summary
+}
+
+/// The observations of the latest `OBSERVATION_DAYS` days.
+///
+/// Observations are persisted to disk, so that they cover restarts.
+#[derive(Debug)]
+pub struct Observations {
+    report: Report,
+
+    /// Where to persist observations. If `None`, observations are not persisted.
+    path: Option<PathBuf>,
+}
+impl Observations {
+    /// Load the observations from `path`, if any.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let report = match path.as_deref().map(Self::read) {
+            None => Report::new(),
+            Some(Ok(report)) => report,
+            Some(Err(err)) => {
+                warn!("failed to read observations, starting from scratch: {err:#}");
+                Report::new()
+            }
+        };
+        Observations { report, path }
+    }
+
+    /// Read observations persisted by a daemon.
+    pub fn read(path: &Path) -> Result<Report, anyhow::Error> {
+        let file = match std::fs::File::open(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Report::new()),
+            result => result.with_context(|| format!("Failed to open {}", path.display()))?,
+        };
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Persist observations, forgetting those older than `OBSERVATION_DAYS`.
+    pub fn save(&mut self) -> Result<(), anyhow::Error> {
+        let today = Local::now().date_naive();
+        self.report
+            .retain(|day, _| today.signed_duration_since(*day).num_days() < OBSERVATION_DAYS as i64);
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let data = serde_json::to_string(&self.report).expect("error during serialization");
+        std::fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Record that `binary` was running for `user` at `now`, and whether this was
+    /// outside of its permitted intervals.
+    pub fn record(&mut self, day: NaiveDate, user: &str, binary: &Path, now: TimeOfDay, violation: bool) {
+        if now.hours >= 24 {
+            return;
+        }
+        let usage = self
+            .report
+            .entry(day)
+            .or_default()
+            .entry(user.to_string())
+            .or_default()
+            .entry(binary.to_string_lossy().to_string())
+            .or_default()
+            .entry(now.hours)
+            .or_default();
+        let minute = 1 << now.minutes;
+        usage.seen |= minute;
+        if violation {
+            usage.violations |= minute;
+        }
+    }
+
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use chrono::Local;
+
+    use crate::types::TimeOfDay;
+
+    use super::{summarize, Minutes, Observations, Usage};
+
+    #[test]
+    fn test_observations() {
+        let path = std::env::temp_dir().join(format!(
+            "keep-it-focused-test-observations-{}.json",
+            std::process::id()
+        ));
+        let today = Local::now().date_naive();
+        let tetris = Path::new("/usr/games/**");
+        let time = |hours, minutes| TimeOfDay { hours, minutes };
+
+        let mut observations = Observations::load(Some(path.clone()));
+        observations.record(today, "alice", tetris, time(17, 0), false);
+        // Two processes during the same minute only count once.
+        observations.record(today, "alice", tetris, time(17, 1), false);
+        observations.record(today, "alice", tetris, time(17, 1), false);
+        observations.record(today, "alice", tetris, time(18, 0), true);
+        // Too old, forgotten when saving.
+        observations.record(today - chrono::Days::new(7), "alice", tetris, time(18, 0), true);
+        observations.save().unwrap();
+
+        // Restarting doesn't lose observations.
+        let observations = Observations::load(Some(path.clone()));
+        let report = observations.report();
+        assert_eq!(report.len(), 1);
+        let hours = &report[&today]["alice"]["/usr/games/**"];
+        assert_eq!(hours[&17].seen_minutes(), 2);
+        assert_eq!(hours[&17].violation_minutes(), 0);
+        assert_eq!(hours[&18], Usage { seen: 1, violations: 1 });
+
+        let summary = summarize(report);
+        assert_eq!(summary["alice"]["/usr/games/**"][&17], Minutes { seen: 2, violations: 0 });
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Keeping track of offenses, for rules with an escalation policy.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{Local, NaiveDate};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Escalation, types::TimeOfDay, uid_resolver::Uid};
+
+/// What to do with a process caught outside of its permitted intervals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sanction {
+    /// No recent offense, only warn.
+    Warn,
+
+    /// Repeat offense, stop the process.
+    Kill,
+}
+
+/// The latest offense for a (user, binary).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Offense {
+    uid: u32,
+
+    /// The binary, as specified in the configuration (may be a glob).
+    binary: PathBuf,
+
+    /// How many offenses have been recorded today.
+    count: u32,
+
+    /// When the user was last warned.
+    warned: TimeOfDay,
+
+    /// When the latest offense was recorded.
+    latest: TimeOfDay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Persisted {
+    day: Option<NaiveDate>,
+    offenses: Vec<Offense>,
+}
+
+/// The offenses recorded today.
+///
+/// Offenses are persisted to disk, so that restarting the daemon doesn't reset escalation.
+#[derive(Debug)]
+pub struct Offenses {
+    /// The day to which the offenses apply.
+    day: NaiveDate,
+
+    offenses: HashMap<(Uid, PathBuf), Offense>,
+
+    /// Where to persist offenses. If `None`, offenses are not persisted.
+    path: Option<PathBuf>,
+}
+impl Offenses {
+    /// Load the offenses recorded today from `path`, if any.
+    ///
+    /// Offenses recorded on previous days are ignored.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let today = Local::now().date_naive();
+        let persisted = match path.as_deref().map(Self::read) {
+            None => Persisted::default(),
+            Some(Ok(persisted)) => persisted,
+            Some(Err(err)) => {
+                warn!("failed to read offenses, starting from scratch: {err:#}");
+                Persisted::default()
+            }
+        };
+        let offenses = if persisted.day == Some(today) {
+            persisted
+                .offenses
+                .into_iter()
+                .map(|offense| ((Uid(offense.uid), offense.binary.clone()), offense))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Offenses {
+            day: today,
+            offenses,
+            path,
+        }
+    }
+
+    fn read(path: &Path) -> Result<Persisted, anyhow::Error> {
+        let file = match std::fs::File::open(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Persisted::default())
+            }
+            result => result.with_context(|| format!("Failed to open {}", path.display()))?,
+        };
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let persisted = Persisted {
+            day: Some(self.day),
+            offenses: self.offenses.values().cloned().collect(),
+        };
+        let data = serde_json::to_string(&persisted).expect("error during serialization");
+        std::fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Forget all offenses if `today` is not the day to which they apply.
+    pub fn rollover(&mut self, today: NaiveDate) {
+        if today != self.day {
+            self.day = today;
+            self.offenses.clear();
+        }
+    }
+
+    /// Record that `binary` was caught outside of its permitted intervals for `uid`, and
+    /// decide what to do about it.
+    pub fn record(
+        &mut self,
+        uid: Uid,
+        binary: &Path,
+        escalation: Escalation,
+        now: TimeOfDay,
+    ) -> Sanction {
+        let offense = self
+            .offenses
+            .entry((uid, binary.to_path_buf()))
+            .or_insert_with(|| Offense {
+                uid: uid.0,
+                binary: binary.to_path_buf(),
+                count: 0,
+                warned: now,
+                latest: now,
+            });
+        let sanction = if offense.count == 0
+            || now.as_minutes().saturating_sub(offense.latest.as_minutes()) > escalation.cooldown
+        {
+            // First offense, or the previous offense is old enough to be forgiven.
+            offense.warned = now;
+            Sanction::Warn
+        } else if offense.warned == now {
+            // Another process of the same binary, caught while we were warning the user.
+            // Don't count it as a repeat offense.
+            return Sanction::Warn;
+        } else {
+            Sanction::Kill
+        };
+        offense.count += 1;
+        offense.latest = now;
+        debug!(
+            "offense #{} for binary {} by user {}: {sanction:?}",
+            offense.count,
+            binary.display(),
+            uid.0
+        );
+        if let Err(err) = self.save() {
+            warn!("failed to persist offenses: {err:#}");
+        }
+        sanction
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use chrono::Local;
+
+    use crate::{config::Escalation, types::TimeOfDay, uid_resolver::Uid};
+
+    use super::{Offenses, Sanction};
+
+    fn time(hours: u8, minutes: u8) -> TimeOfDay {
+        TimeOfDay { hours, minutes }
+    }
+
+    #[test]
+    fn test_escalation() {
+        let path = std::env::temp_dir().join(format!(
+            "keep-it-focused-test-offenses-{}.json",
+            std::process::id()
+        ));
+        let escalation = Escalation { cooldown: 10 };
+        let tetris = Path::new("**/tetris");
+        let user = Uid(1000);
+
+        let mut offenses = Offenses::load(Some(path.clone()));
+        // First offense, warn.
+        assert_eq!(offenses.record(user, tetris, escalation, time(10, 0)), Sanction::Warn);
+        // Another process caught during the same sweep, still warn.
+        assert_eq!(offenses.record(user, tetris, escalation, time(10, 0)), Sanction::Warn);
+        // Still there within the cooldown, kill.
+        assert_eq!(offenses.record(user, tetris, escalation, time(10, 1)), Sanction::Kill);
+        // Back again after the cooldown, warn.
+        assert_eq!(offenses.record(user, tetris, escalation, time(10, 30)), Sanction::Warn);
+        // Other users are tracked separately.
+        assert_eq!(offenses.record(Uid(1001), tetris, escalation, time(10, 31)), Sanction::Warn);
+
+        // Restarting doesn't reset escalation.
+        let mut offenses = Offenses::load(Some(path.clone()));
+        assert_eq!(offenses.record(user, tetris, escalation, time(10, 32)), Sanction::Kill);
+
+        // A new day resets escalation.
+        offenses.rollover(Local::now().date_naive().succ_opt().unwrap());
+        assert_eq!(offenses.record(user, tetris, escalation, time(10, 33)), Sanction::Warn);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
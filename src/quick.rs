@@ -0,0 +1,162 @@
+//! A compact syntax for permanent rules, e.g. `allow alice chromium mon-fri 16:00-18:00`.
+//!
+//! The grammar is `<allow|forbid> <user>[,<user>...] <binary|domain> <days> <start>-<end> [label...]`,
+//! where:
+//! - a target containing `/` or a wildcard, or without any `.`, is a binary (see `Binary`),
+//!   otherwise it's a domain; prefix it with `binary:` or `domain:` to disambiguate,
+//!   e.g. `binary:tetris.sh`;
+//! - days are parsed by `DayOfWeek::parse_days`, e.g. `mon`, `sat,sun` or `mon-fri`;
+//! - times are parsed by `TimeOfDay::parse`, e.g. `1600-1800` or `16:00-18:00`;
+//! - any remaining words are the label of the rule, e.g. `homework time`.
+
+use std::ops::Not;
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    config::Binary,
+    types::{DayOfWeek, Domain, TimeOfDay, Username},
+};
+
+/// The grammar, for error messages.
+pub const GRAMMAR: &str = "<allow|forbid> <user>[,<user>...] <binary|domain> <days> <start>-<end> [label...]";
+
+/// What a rule applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Binary(Binary),
+    Domain(Domain),
+}
+impl Target {
+    fn parse(source: &str) -> Result<Self, anyhow::Error> {
+        if let Some(binary) = source.strip_prefix("binary:") {
+            return Ok(Target::Binary(Binary::try_new(binary)?));
+        }
+        if let Some(domain) = source.strip_prefix("domain:") {
+            return Ok(Target::Domain(Domain::parse(domain)?));
+        }
+        if source.contains(['/', '*', '?', '[', '{']) || source.contains('.').not() {
+            return Ok(Target::Binary(Binary::try_new(source)?));
+        }
+        let domain = Domain::parse(source)
+            .with_context(|| format!("'{source}' is neither a binary nor a domain, use `binary:{source}` for a binary"))?;
+        Ok(Target::Domain(domain))
+    }
+}
+
+/// A rule written with the compact syntax.
+#[derive(Debug, Clone)]
+pub struct QuickRule {
+    /// `true` for `allow`, `false` for `forbid`.
+    pub allow: bool,
+    pub users: Vec<Username>,
+    pub target: Target,
+    pub days: Vec<DayOfWeek>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    pub label: Option<String>,
+}
+impl QuickRule {
+    /// Parse a rule from words, e.g. the arguments of a command. Words may contain several
+    /// words separated by spaces, e.g. if the entire rule is quoted.
+    pub fn parse<S: AsRef<str>>(words: &[S]) -> Result<Self, anyhow::Error> {
+        let words: Vec<&str> = words.iter().flat_map(|word| word.as_ref().split_whitespace()).collect();
+        let [verb, users, target, days, times, label @ ..] = words.as_slice() else {
+            if words.len() == 4 && DayOfWeek::parse_days(words[2]).is_ok() {
+                // e.g. `allow alice mon-fri 1600-1800`.
+                return Err(anyhow!("missing binary or domain before days '{}', expected {GRAMMAR}", words[2]));
+            }
+            return Err(anyhow!("incomplete rule '{}', expected {GRAMMAR}", words.join(" ")));
+        };
+        let allow = match verb.to_ascii_lowercase().as_str() {
+            "allow" | "permit" => true,
+            "forbid" | "deny" => false,
+            _ => return Err(anyhow!("invalid verb '{verb}', expected `allow` or `forbid`")),
+        };
+        let users: Vec<Username> = users
+            .split(',')
+            .filter(|user| user.is_empty().not())
+            .map(|user| Username(user.to_string()))
+            .collect();
+        if users.is_empty() {
+            return Err(anyhow!("missing user, expected {GRAMMAR}"));
+        }
+        let target = Target::parse(target)?;
+        let days = DayOfWeek::parse_days(days).map_err(|err| {
+            if DayOfWeek::parse_days(words[2]).is_ok() {
+                // e.g. `allow alice mon-fri 1600-1800`.
+                anyhow!("missing binary or domain before days '{}', expected {GRAMMAR}", words[2])
+            } else {
+                err.context(format!("invalid days '{days}', expected e.g. `mon`, `sat,sun` or `mon-fri`"))
+            }
+        })?;
+        let Some((start, end)) = times.split_once('-') else {
+            return Err(anyhow!("invalid time range '{times}', expected e.g. `16:00-18:00`"));
+        };
+        let start = TimeOfDay::parse(start).with_context(|| format!("invalid start in '{times}'"))?;
+        let end = TimeOfDay::parse(end).with_context(|| format!("invalid end in '{times}'"))?;
+        if end <= start {
+            return Err(anyhow!(
+                "time range '{times}' ends before it starts, rules may not extend past midnight, use two rules"
+            ));
+        }
+        let label = if label.is_empty() { None } else { Some(label.join(" ")) };
+        Ok(QuickRule {
+            allow,
+            users,
+            target,
+            days,
+            start,
+            end,
+            label,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Not;
+
+    use crate::types::{DayOfWeek, Domain, TimeOfDay, Username};
+
+    use super::{QuickRule, Target};
+
+    #[test]
+    fn test_quick_rule() {
+        let rule = QuickRule::parse(&["allow", "kid", "chromium", "mon-fri", "16:00-18:00"]).unwrap();
+        assert!(rule.allow);
+        assert_eq!(rule.users, vec![Username("kid".to_string())]);
+        let Target::Binary(ref binary) = rule.target else {
+            panic!("expected a binary, got {:?}", rule.target);
+        };
+        assert!(binary.matcher.is_match("/usr/bin/chromium"));
+        assert_eq!(rule.days, DayOfWeek::all().take(5).collect::<Vec<_>>());
+        assert_eq!(rule.start, TimeOfDay { hours: 16, minutes: 0 });
+        assert_eq!(rule.end, TimeOfDay { hours: 18, minutes: 0 });
+        assert_eq!(rule.label, None);
+
+        // As a single string, with a label.
+        let rule = QuickRule::parse(&["forbid alice,bob youtube.com sat,sun 0900-1200 homework time"]).unwrap();
+        assert!(rule.allow.not());
+        assert_eq!(rule.users.len(), 2);
+        assert_eq!(rule.target, Target::Domain(Domain("youtube.com".to_string())));
+        assert_eq!(rule.label.as_deref(), Some("homework time"));
+
+        let rule = QuickRule::parse(&["allow kid binary:tetris.sh sun 1000-1100"]).unwrap();
+        assert!(matches!(rule.target, Target::Binary(_)));
+        let rule = QuickRule::parse(&["allow kid /usr/games/** sun 1000-1100"]).unwrap();
+        assert!(matches!(rule.target, Target::Binary(_)));
+    }
+
+    #[test]
+    fn test_quick_rule_errors() {
+        let error = |source: &str| format!("{:#}", QuickRule::parse(&[source]).unwrap_err());
+        assert!(error("allow kid chromium mon-fri").contains("incomplete rule"));
+        assert!(error("permit-ish kid chromium mon-fri 1600-1800").contains("invalid verb"));
+        assert!(error("allow kid mon-fri 1600-1800").contains("missing binary or domain"));
+        assert!(error("allow kid mon-fri 1600-1800 homework").contains("missing binary or domain before days 'mon-fri'"));
+        assert!(error("allow kid chromium someday 1600-1800").contains("invalid days 'someday'"));
+        assert!(error("allow kid chromium mon 1600").contains("invalid time range"));
+        assert!(error("allow kid chromium mon 1800-1600").contains("ends before it starts"));
+    }
+}
@@ -0,0 +1,169 @@
+//! Detecting processes that come back as soon as they're stopped, e.g. because a launcher
+//! restarts them.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::uid_resolver::Uid;
+
+/// If a process is stopped again within this delay, consider that it's being restarted.
+pub const RESPAWN_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// What to do with a process that needs to be stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillAction {
+    /// Stop the process and notify the user.
+    Kill,
+
+    /// The process keeps coming back: stop its launcher along with it and notify the user.
+    KillLauncher,
+
+    /// Stop the process without notifying the user, who has already been notified.
+    KillQuietly,
+}
+
+#[derive(Debug)]
+struct RecentKill {
+    /// When the process was last stopped.
+    latest: Instant,
+
+    /// The pid of the parent of the process last stopped, if known.
+    parent: Option<i32>,
+
+    /// Whether we have already attempted to stop the launcher.
+    escalated: bool,
+}
+
+/// The processes stopped recently, per (user, binary).
+#[derive(Debug, Default)]
+pub struct RecentKills {
+    kills: HashMap<(Uid, PathBuf), RecentKill>,
+
+    /// The start of the current sweep.
+    current_sweep: Option<Instant>,
+
+    /// The start of the previous sweep.
+    previous_sweep: Option<Instant>,
+}
+impl RecentKills {
+    /// Record that a sweep starts at `now`.
+    pub fn begin_sweep(&mut self, now: Instant) {
+        if self.current_sweep != Some(now) {
+            self.previous_sweep = self.current_sweep;
+            self.current_sweep = Some(now);
+        }
+    }
+
+    /// Record that `exe`, launched by `parent`, needs to be stopped for `uid`, and decide how.
+    ///
+    /// `now` should be the start of the current sweep, so that several processes of the same
+    /// binary caught during the same sweep are not mistaken for respawns.
+    ///
+    /// We only blame the launcher if the same parent has restarted the binary since the previous
+    /// sweep: a binary relaunched by hand, e.g. from the desktop or a terminal, has another parent
+    /// or takes longer to come back.
+    pub fn record(&mut self, uid: Uid, exe: &Path, parent: Option<i32>, now: Instant) -> KillAction {
+        self.kills
+            .retain(|_, kill| now.saturating_duration_since(kill.latest) <= RESPAWN_WINDOW);
+        let previous_sweep = self.previous_sweep;
+        let Some(kill) = self.kills.get_mut(&(uid, exe.to_path_buf())) else {
+            self.kills.insert(
+                (uid, exe.to_path_buf()),
+                RecentKill {
+                    latest: now,
+                    parent,
+                    escalated: false,
+                },
+            );
+            return KillAction::Kill;
+        };
+        let action = if kill.latest == now || kill.escalated {
+            // Either another process caught during the same sweep, or we have already
+            // done our best.
+            KillAction::KillQuietly
+        } else if parent.is_some() && kill.parent == parent && Some(kill.latest) == previous_sweep {
+            debug!("binary {} keeps coming back for user {}", exe.display(), uid.0);
+            kill.escalated = true;
+            KillAction::KillLauncher
+        } else {
+            KillAction::Kill
+        };
+        kill.latest = now;
+        kill.parent = parent;
+        action
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use crate::uid_resolver::Uid;
+
+    use super::{KillAction, RecentKills, RESPAWN_WINDOW};
+
+    #[test]
+    fn test_respawn() {
+        let mut kills = RecentKills::default();
+        let tetris = Path::new("/usr/games/tetris");
+        let user = Uid(1000);
+        let launcher = Some(42);
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let sweep = |kills: &mut RecentKills, minutes: u32| {
+            let now = start + minutes * minute;
+            kills.begin_sweep(now);
+            now
+        };
+
+        let now = sweep(&mut kills, 0);
+        assert_eq!(kills.record(user, tetris, launcher, now), KillAction::Kill);
+        // Another process during the same sweep.
+        assert_eq!(kills.record(user, tetris, launcher, now), KillAction::KillQuietly);
+        // Other users are tracked separately.
+        assert_eq!(kills.record(Uid(1001), tetris, launcher, now), KillAction::Kill);
+        // Back at the next sweep from the same parent, escalate once.
+        let now = sweep(&mut kills, 1);
+        assert_eq!(kills.record(user, tetris, launcher, now), KillAction::KillLauncher);
+        let now = sweep(&mut kills, 2);
+        assert_eq!(kills.record(user, tetris, launcher, now), KillAction::KillQuietly);
+        let now = sweep(&mut kills, 3);
+        assert_eq!(kills.record(user, tetris, launcher, now), KillAction::KillQuietly);
+
+        // After a while, start again from scratch.
+        let now = sweep(&mut kills, 3 + RESPAWN_WINDOW.as_secs() as u32 / 60 + 1);
+        assert_eq!(kills.record(user, tetris, launcher, now), KillAction::Kill);
+    }
+
+    #[test]
+    fn test_respawn_by_hand() {
+        let mut kills = RecentKills::default();
+        let tetris = Path::new("/usr/games/tetris");
+        let user = Uid(1000);
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+
+        kills.begin_sweep(start);
+        assert_eq!(kills.record(user, tetris, Some(42), start), KillAction::Kill);
+        // Relaunched from another terminal.
+        kills.begin_sweep(start + minute);
+        assert_eq!(kills.record(user, tetris, Some(43), start + minute), KillAction::Kill);
+        // Relaunched from the same terminal, but not right away.
+        kills.begin_sweep(start + 2 * minute);
+        kills.begin_sweep(start + 3 * minute);
+        assert_eq!(kills.record(user, tetris, Some(43), start + 3 * minute), KillAction::Kill);
+        // Without a known parent, we never blame the launcher.
+        kills.begin_sweep(start + 4 * minute);
+        assert_eq!(kills.record(user, tetris, None, start + 4 * minute), KillAction::Kill);
+        kills.begin_sweep(start + 5 * minute);
+        assert_eq!(kills.record(user, tetris, None, start + 5 * minute), KillAction::Kill);
+    }
+}
@@ -1,9 +1,14 @@
 use std::{
     collections::HashMap,
-    io::Write,
+    io::{BufRead, BufReader, ErrorKind, Write},
     net::{TcpListener, TcpStream},
     ops::Not,
-    sync::RwLock,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+    thread,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
@@ -22,10 +27,36 @@ use crate::unix::linux::procfs::find_peer_owner;
 /// for a simpler data model.
 pub type Data = HashMap<Uid, String>;
 
+/// The pre-serialized web rules of each profile of a shared account.
+pub type ProfileData = HashMap<(Uid, String), String>;
+
+/// How long we keep an idle connection open, waiting for the next request.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many connections we serve at once. Further connections are rejected until one of
+/// them closes.
+const MAX_CONNECTIONS: usize = 32;
+
+/// The header through which the browser extension may specify a profile, for accounts
+/// shared by several people.
+const PROFILE_HEADER: &str = "x-keep-it-focused-profile";
+
 pub struct Server {
     /// The pre-serialized data to serve.
     data: RwLock<Data>,
 
+    /// The pre-serialized statistics to serve on `/stats`.
+    stats: RwLock<Data>,
+
+    /// The pre-serialized instructions for today to serve on `/today`.
+    instructions: RwLock<Data>,
+
+    /// The pre-serialized web rules to serve on `/` to requests with a `PROFILE_HEADER`.
+    profiles: RwLock<ProfileData>,
+
+    /// The pre-serialized status of the daemon to serve on `/version`, the same for all users.
+    status: RwLock<String>,
+
     /// The port on which we serve.
     port: u16,
 }
@@ -33,6 +64,10 @@ impl Server {
     pub fn new(data: Data, port: u16) -> Self {
         Server {
             data: RwLock::new(data),
+            stats: RwLock::new(HashMap::new()),
+            instructions: RwLock::new(HashMap::new()),
+            profiles: RwLock::new(HashMap::new()),
+            status: RwLock::new("{}".to_string()),
             port,
         }
     }
@@ -44,19 +79,44 @@ impl Server {
     pub fn serve_blocking(&self) -> Result<(), anyhow::Error> {
         let listener = TcpListener::bind(format!("127.0.0.1:{}", self.port))
             .with_context(|| format!("Failed to acquire port {}", self.port))?;
-        for stream in listener.incoming() {
-            let stream = match stream {
-                Ok(stream) => stream,
-                Err(err) => {
-                    warn!("stream acquisition error {}", err);
+        // Connections may be kept alive, so each of them gets its own thread, lest an idle
+        // connection blocks the others.
+        let connections = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("stream acquisition error {}", err);
+                        continue;
+                    }
+                };
+                // Only this loop increments `connections`, so it cannot exceed the maximum.
+                if connections.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    warn!("too many connections, rejecting");
+                    let response = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                    if let Err(err) = stream.write_all(response.as_bytes()) {
+                        warn!("error responding with SERVICE UNAVAILABLE {}", err);
+                    }
                     continue;
                 }
-            };
-            if let Err(err) = self.handle_stream(stream) {
-                warn!("stream handling error {}", err);
-                continue;
+                connections.fetch_add(1, Ordering::SeqCst);
+                let connections = &connections;
+                let spawned = thread::Builder::new()
+                    .name("connection".to_string())
+                    .spawn_scoped(scope, move || {
+                        if let Err(err) = self.handle_stream(stream) {
+                            warn!("stream handling error {}", err);
+                        }
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                if let Err(err) = spawned {
+                    // The stream has been dropped along with the closure, closing the connection.
+                    warn!("failed to spawn thread for connection {}", err);
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                }
             }
-        }
+        });
         Ok(())
     }
 
@@ -70,7 +130,48 @@ impl Server {
         Ok(())
     }
 
-    /// Respond to a HTTP request.
+    /// Replace the pre-serialized statistics.
+    pub fn update_stats(&self, stats: Data) -> Result<(), anyhow::Error> {
+        let mut lock = self
+            .stats
+            .write()
+            .map_err(|_| anyhow!("failed to acquire lock"))?;
+        *lock = stats;
+        Ok(())
+    }
+
+    /// Replace the pre-serialized instructions for today.
+    pub fn update_instructions(&self, instructions: Data) -> Result<(), anyhow::Error> {
+        let mut lock = self
+            .instructions
+            .write()
+            .map_err(|_| anyhow!("failed to acquire lock"))?;
+        *lock = instructions;
+        Ok(())
+    }
+
+    /// Replace the pre-serialized web rules of profiles.
+    pub fn update_profiles(&self, profiles: ProfileData) -> Result<(), anyhow::Error> {
+        let mut lock = self
+            .profiles
+            .write()
+            .map_err(|_| anyhow!("failed to acquire lock"))?;
+        *lock = profiles;
+        Ok(())
+    }
+
+    /// Replace the pre-serialized status of the daemon.
+    pub fn update_status(&self, status: String) -> Result<(), anyhow::Error> {
+        let mut lock = self
+            .status
+            .write()
+            .map_err(|_| anyhow!("failed to acquire lock"))?;
+        *lock = status;
+        Ok(())
+    }
+
+    /// Respond to the HTTP requests of a connection, until the client closes it or it
+    /// remains idle for `KEEP_ALIVE_TIMEOUT`.
     fn handle_stream(&self, mut stream: TcpStream) -> Result<(), anyhow::Error> {
         let peer = stream
             .peer_addr()
@@ -86,26 +187,153 @@ impl Server {
         }
         // Find out which process sent this request.
         info!("received request from port: {}", peer.port());
+        stream
+            .set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))
+            .context("Failed to set timeout")?;
+        let mut reader = BufReader::new(stream.try_clone().context("Failed to clone stream")?);
 
-        // Find the inode for this port.
-        let owner = find_peer_owner(peer)?;
+        // The owner of a connection doesn't change, so we only need to find it once, rather
+        // than scanning `/proc` for each request. Preflights and `/version` don't need it.
+        let mut known_owner = None;
+        loop {
+            let mut request_line = String::new();
+            match reader.read_line(&mut request_line) {
+                Ok(0) => {
+                    debug!("connection closed by client");
+                    return Ok(());
+                }
+                Ok(_) => {}
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    debug!("closing idle connection");
+                    return Ok(());
+                }
+                Err(err) => return Err(err).context("Failed to read request"),
+            }
 
-        let contents = self
-            .data
-            .read()
-            .map_err(|_| anyhow!("couldn't acquire rwlock"))?
-            .get(&owner)
-            .cloned()
-            .unwrap_or_else(|| "{}".to_string());
-        let length = contents.len();
-        let response =
-        format!("HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\nAccess-Control-Allow-Origin: *\r\nContent-Length: {length}\r\n\r\n{contents}");
-        debug!("response {}", response);
-        stream
-            .write_all(response.as_bytes())
-            .context("Failed to respond with OK")?;
+            // Skip headers, except to find out whether the client wishes to keep the
+            // connection alive, the default since HTTP/1.1, its profile, if any, and whether
+            // the request has a body.
+            let mut keep_alive = request_line.trim_end().ends_with("HTTP/1.0").not();
+            let mut profile = None;
+            let mut has_body = false;
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header).context("Failed to read headers")? == 0 {
+                    break;
+                }
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("connection") {
+                        keep_alive = value.trim().eq_ignore_ascii_case("keep-alive");
+                    } else if name.trim().eq_ignore_ascii_case(PROFILE_HEADER) {
+                        profile = Some(value.trim().to_string());
+                    } else if name.trim().eq_ignore_ascii_case("content-length") {
+                        has_body |= value.trim() != "0";
+                    } else if name.trim().eq_ignore_ascii_case("transfer-encoding") {
+                        has_body = true;
+                    }
+                }
+            }
+            // We never read request bodies, so the next request can only be found if there
+            // is none. Other methods than `GET` and `OPTIONS` may always have one.
+            let method = request_line.split_whitespace().next().unwrap_or_default();
+            let keep_alive = keep_alive && has_body.not() && matches!(method, "GET" | "OPTIONS");
+            let connection = if keep_alive { "keep-alive" } else { "close" };
+
+            // Answer CORS preflights and reject anything but `GET` immediately, rather than
+            // letting the browser wait.
+            let response = if let Some(head) = response_to_method(method) {
+                format!("{head}Content-Length: 0\r\nConnection: {connection}\r\n\r\n")
+            } else {
+                // Web rules (for the browser extension) are served from `/`, statistics from `/stats`,
+                // everything applying today (for desktop clients) from `/today` and the status of
+                // the daemon, which is the same for all users, from `/version`.
+                let path = request_line.split_whitespace().nth(1);
+                let source = match path {
+                    Some("/version") => None,
+                    Some("/stats") => Some(&self.stats),
+                    Some("/today") => Some(&self.instructions),
+                    _ => Some(&self.data),
+                };
+                let contents = match source {
+                    None => self
+                        .status
+                        .read()
+                        .map_err(|_| anyhow!("couldn't acquire rwlock"))?
+                        .clone(),
+                    Some(source) => {
+                        let owner = match known_owner {
+                            Some(owner) => owner,
+                            // Find the inode for this port.
+                            None => *known_owner.insert(find_peer_owner(peer)?),
+                        };
+                        // Without a profile, or with a profile that has no rules of its own,
+                        // the rules of the account apply.
+                        let for_profile = match (path, profile) {
+                            (Some("/stats" | "/today"), _) | (_, None) => None,
+                            (_, Some(profile)) => self
+                                .profiles
+                                .read()
+                                .map_err(|_| anyhow!("couldn't acquire rwlock"))?
+                                .get(&(owner, profile))
+                                .cloned(),
+                        };
+                        match for_profile {
+                            Some(contents) => contents,
+                            None => source
+                                .read()
+                                .map_err(|_| anyhow!("couldn't acquire rwlock"))?
+                                .get(&owner)
+                                .cloned()
+                                .unwrap_or_else(|| "{}".to_string()),
+                        }
+                    }
+                };
+                let length = contents.len();
+                format!("HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\nAccess-Control-Allow-Origin: *\r\nContent-Length: {length}\r\nConnection: {connection}\r\n\r\n{contents}")
+            };
+            debug!("response {}", response);
+            stream
+                .write_all(response.as_bytes())
+                .with_context(|| format!("Failed to respond to {method}"))?;
+            stream.flush().context("Failed to flush")?;
+            debug!("responded");
+
+            if keep_alive.not() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The status line and headers of the response to a request with a method other than
+/// `GET`, if any.
+///
+/// The caller is in charge of `Content-Length`, `Connection` and the end of headers.
+fn response_to_method(method: &str) -> Option<&'static str> {
+    match method {
+        "GET" => None,
+        "OPTIONS" => Some(
+            "HTTP/1.1 204 NO CONTENT\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\nAccess-Control-Allow-Headers: *\r\nAccess-Control-Max-Age: 86400\r\n",
+        ),
+        _ => Some("HTTP/1.1 405 METHOD NOT ALLOWED\r\nAllow: GET, OPTIONS\r\nAccess-Control-Allow-Origin: *\r\n"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::response_to_method;
 
-        debug!("responded");
-        stream.flush().context("Failed to flush")
+    #[test]
+    fn test_response_to_method() {
+        assert_eq!(response_to_method("GET"), None);
+        let preflight = response_to_method("OPTIONS").unwrap();
+        assert!(preflight.starts_with("HTTP/1.1 204 "));
+        assert!(preflight.contains("Access-Control-Allow-Methods: GET, OPTIONS\r\n"));
+        assert!(response_to_method("POST").unwrap().starts_with("HTTP/1.1 405 "));
+        assert!(response_to_method("").unwrap().starts_with("HTTP/1.1 405 "));
     }
 }
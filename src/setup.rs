@@ -3,18 +3,213 @@
 use std::{
     collections::HashMap,
     io::{ErrorKind, Write},
+    ops::Not,
     os::unix::fs::PermissionsExt,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
 use std::os::unix::fs::MetadataExt;
 
 use crate::config;
 
 const ADDON_FILE_NAME: &str = "keep-it-focused.xpi";
+const ADDONS_PATH: &str = "/etc/firefox/addons";
+const POLICIES_PATH: &str = "/etc/firefox/policies.json";
+const EXTENSION_ID: &str = "keep-it-focused@yoric.xyz";
+const DAEMON_DEST_DIRECTORY: &str = "/usr/bin";
+const SYSTEMD_CONFIG_PATH: &str = "/etc/systemd/system/keep-it-focused.service";
+const SYSTEMD_TIMER_PATH: &str = "/etc/systemd/system/keep-it-focused.timer";
+const SYSTEMD_ENVIRONMENT_PATH: &str = "/etc/default/keep-it-focused";
+const DAEMON_CONFIG_PATH: &str = "/etc/keep-it-focused.yaml";
+
+// A data structure representing /etc/firefox/policies.json.
+//
+// Note that we maintain fields `_others` to maintain all the data
+// we don't want to change.
+#[derive(Deserialize, Serialize, Default, Debug)]
+struct Configuration {
+    policies: Policies,
+    #[serde(flatten)]
+    _others: serde_json::Value,
+}
+#[derive(Deserialize, Serialize, Default, Debug)]
+struct Policies {
+    #[serde(rename = "ExtensionSettings")]
+    extension_settings: HashMap<String, ExtensionSettings>,
+    #[serde(flatten)]
+    _others: serde_json::Value,
+}
+#[derive(Deserialize, Serialize, Default, Debug)]
+struct ExtensionSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    installation_mode: Option<InstallationMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    install_url: Option<String>,
+    #[serde(flatten)]
+    _others: serde_json::Value,
+}
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum InstallationMode {
+    #[serde(rename = "allowed")]
+    Allowed,
+    #[serde(rename = "blocked")]
+    Blocked,
+    #[serde(rename = "force_installed")]
+    ForceInstalled,
+    #[serde(rename = "normal_installed")]
+    NormalInstalled,
+}
+impl Configuration {
+    /// Load /etc/firefox/policies.json, or an empty configuration if it doesn't exist.
+    fn load() -> Result<Self, anyhow::Error> {
+        debug!("reading {}", POLICIES_PATH);
+        match std::fs::File::open(POLICIES_PATH) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))
+                .context("Failed to parse policies.json"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("file is empty, creating");
+                Ok(Configuration::default())
+            }
+            Err(err) => Err(err).with_context(|| format!("failed to open {POLICIES_PATH}")),
+        }
+    }
+
+    /// Make sure that the addon is force-installed from `install_url`.
+    ///
+    /// If the administrator has already specified another installation mode or url for
+    /// the addon, leave it alone, unless `force` is true.
+    ///
+    /// Returns `true` if the configuration was changed.
+    fn install(&mut self, install_url: &str, force: bool) -> bool {
+        let extension_settings = self
+            .policies
+            .extension_settings
+            .entry(EXTENSION_ID.to_string())
+            .or_default();
+        let mut changed = false;
+        match extension_settings.installation_mode {
+            Some(InstallationMode::ForceInstalled) => {}
+            Some(mode) if force.not() => {
+                warn!("{POLICIES_PATH} already specifies installation mode {mode:?} for {EXTENSION_ID}, leaving it alone, use `--force` to overwrite");
+            }
+            _ => {
+                extension_settings.installation_mode = Some(InstallationMode::ForceInstalled);
+                changed = true;
+            }
+        }
+        match extension_settings.install_url {
+            Some(ref url) if url == install_url => {}
+            Some(ref url) if force.not() => {
+                warn!("{POLICIES_PATH} already specifies install url {url} for {EXTENSION_ID}, leaving it alone, use `--force` to overwrite");
+            }
+            _ => {
+                extension_settings.install_url = Some(install_url.to_string());
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Write back /etc/firefox/policies.json.
+    fn store(&self) -> Result<(), anyhow::Error> {
+        debug!("writing {}", POLICIES_PATH);
+        let file = std::fs::File::create(POLICIES_PATH)
+            .with_context(|| format!("failed to open {POLICIES_PATH} for writing"))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .with_context(|| format!("failed to write to {POLICIES_PATH}"))
+    }
+}
+
+/// The directories in which Chrome and Chromium look for managed policies.
+const CHROMIUM_POLICIES_DIRECTORIES: [&str; 3] = [
+    "/etc/opt/chrome/policies/managed",
+    "/etc/chromium/policies/managed",
+    "/etc/chromium-browser/policies/managed",
+];
+const CHROMIUM_POLICIES_FILE_NAME: &str = "keep-it-focused.json";
+
+// A data structure representing a Chromium managed policy file.
+//
+// Note that we maintain fields `_others` to maintain all the data
+// we don't want to change.
+#[derive(Deserialize, Serialize, Default, Debug)]
+struct ChromiumPolicies {
+    /// Entries `extension_id;update_url`.
+    #[serde(rename = "ExtensionInstallForcelist", default)]
+    extension_install_forcelist: Vec<String>,
+    #[serde(flatten)]
+    _others: serde_json::Map<String, serde_json::Value>,
+}
+impl ChromiumPolicies {
+    fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        debug!("reading {}", path.display());
+        match std::fs::File::open(path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(ChromiumPolicies::default()),
+            Err(err) => Err(err).with_context(|| format!("failed to open {}", path.display())),
+        }
+    }
+
+    fn store(&self, path: &Path) -> Result<(), anyhow::Error> {
+        debug!("writing {}", path.display());
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to open {} for writing", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .with_context(|| format!("failed to write to {}", path.display()))
+    }
+
+    /// Make sure that `extension_id` is force-installed from `update_url`.
+    ///
+    /// Returns `true` if the configuration was changed.
+    fn install(&mut self, extension_id: &str, update_url: &str) -> bool {
+        let entry = format!("{extension_id};{update_url}");
+        if self.extension_install_forcelist.contains(&entry) {
+            return false;
+        }
+        // Replace any previous entry for this extension.
+        self.extension_install_forcelist
+            .retain(|entry| entry.split(';').next() != Some(extension_id));
+        self.extension_install_forcelist.push(entry);
+        true
+    }
+}
+
+/// Remove a file, if it exists.
+fn remove_file_if_exists(path: &Path) -> Result<(), anyhow::Error> {
+    match std::fs::remove_file(path) {
+        Ok(()) => {
+            debug!("removed {}", path.display());
+            Ok(())
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("{} doesn't exist, nothing to remove", path.display());
+            Ok(())
+        }
+        Err(err) => Err(err).with_context(|| format!("failed to remove {}", path.display())),
+    }
+}
+
+/// Run `systemctl <args>` and wait for it to complete.
+fn systemctl(args: &[&str]) -> Result<(), anyhow::Error> {
+    let output = std::process::Command::new("systemctl")
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to launch `systemctl {}`", args.join(" ")))?;
+    if output.status.success().not() {
+        return Err(anyhow!(
+            "`systemctl {}` failed ({}): {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
 
 fn exe_name() -> String {
     std::env::args()
@@ -26,7 +221,6 @@ fn exe_name() -> String {
 pub fn copy_addon() -> Result<(), anyhow::Error> {
     const ADDON_SOURCE_SUBDIRECTORY: &str = "target/webext";
     const DIST_SOURCE_SUBDIRECTORY: &str = "dist";
-    const ADDONS_PATH: &str = "/etc/firefox/addons";
 
     // Create directory.
     std::fs::create_dir_all(ADDONS_PATH)
@@ -61,123 +255,416 @@ pub fn copy_addon() -> Result<(), anyhow::Error> {
 
 /// Setup /etc/firefox/policies.json to ensure that this addon
 /// is automatically installed to all users on this machine.
-pub fn setup_policies() -> Result<(), anyhow::Error> {
-    const CONFIG_PATH: &str = "/etc/firefox/policies.json";
-    const EXTENSION_ID: &str = "keep-it-focused@yoric.xyz";
+///
+/// If `force` is false, an installation mode or url already specified by the
+/// administrator for this addon is left alone.
+pub fn setup_policies(force: bool) -> Result<(), anyhow::Error> {
     const INSTALL_URL: &str = "file:///etc/firefox/addons/keep-it-focused.xpi";
 
-    // A data structure representing /etc/firefox/policies.json.
-    //
-    // Note that we maintain fields `_others` to maintain all the data
-    // we don't want to change.
-    #[derive(Deserialize, Serialize, Default)]
-    struct Configuration {
-        policies: Policies,
-        #[serde(flatten)]
-        _others: serde_json::Value,
-    }
-    #[derive(Deserialize, Serialize, Default)]
-    struct Policies {
-        #[serde(rename = "ExtensionSettings")]
-        extension_settings: HashMap<String, ExtensionSettings>,
-        #[serde(flatten)]
-        _others: serde_json::Value,
-    }
-    #[derive(Deserialize, Serialize, Default)]
-    struct ExtensionSettings {
-        installation_mode: Option<InstallationMode>,
-        install_url: Option<String>,
-        #[serde(flatten)]
-        _others: serde_json::Value,
-    }
-    #[derive(Deserialize, Serialize)]
-    enum InstallationMode {
-        #[serde(rename = "allowed")]
-        Allowed,
-        #[serde(rename = "blocked")]
-        Blocked,
-        #[serde(rename = "force_installed")]
-        ForceInstalled,
-        #[serde(rename = "normal_installed")]
-        NormalInstalled,
-    }
-
-    std::fs::create_dir_all("/etc/firefox/addons")
-        .context("Failed to create /etc/firefox/addons")?;
+    std::fs::create_dir_all(ADDONS_PATH)
+        .with_context(|| format!("Failed to create {ADDONS_PATH}"))?;
+
+    let addon = Path::new(ADDONS_PATH).join(ADDON_FILE_NAME);
+    if std::fs::metadata(&addon).is_err() {
+        warn!("{} doesn't exist, Firefox won't be able to install the addon, use `--copy-addon` to copy it", addon.display());
+    }
 
     // Load /etc/firefox/policies.json.
-    debug!("reading {}", CONFIG_PATH);
-    let mut config: Configuration = match std::fs::File::open(CONFIG_PATH) {
-        Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))
-            .context("Failed to parse policies.json")?,
-        Err(err) if err.kind() == ErrorKind::NotFound => {
-            debug!("file is empty, creating");
-            Configuration::default()
-        }
-        Err(err) => return Err(err).with_context(|| format!("failed to open {CONFIG_PATH}")),
-    };
+    let mut config = Configuration::load()?;
 
     // Patch content.
-    let extension_settings = config
+    if config.install(INSTALL_URL, force).not() {
+        info!("{POLICIES_PATH} is already up to date");
+        return Ok(());
+    }
+
+    // Write back content.
+    config.store()
+}
+
+/// Remove this addon from /etc/firefox/policies.json, leaving other policies untouched.
+pub fn remove_policies() -> Result<(), anyhow::Error> {
+    if std::fs::metadata(POLICIES_PATH).is_err() {
+        info!("{POLICIES_PATH} doesn't exist, nothing to remove");
+        return Ok(());
+    }
+    let mut config = Configuration::load()?;
+    if config
         .policies
         .extension_settings
-        .entry(EXTENSION_ID.to_string())
-        .or_default();
-    extension_settings.install_url = Some(INSTALL_URL.to_string());
-    extension_settings.installation_mode = Some(InstallationMode::ForceInstalled);
+        .remove(EXTENSION_ID)
+        .is_none()
+    {
+        info!("{POLICIES_PATH} doesn't mention {EXTENSION_ID}, nothing to remove");
+        return Ok(());
+    }
+    config.store()
+}
 
-    // Write back content.
-    debug!("writing {}", CONFIG_PATH);
-    let file = std::fs::File::create(CONFIG_PATH)
-        .with_context(|| format!("failed to open {CONFIG_PATH} for writing"))?;
-    serde_json::to_writer_pretty(std::io::BufWriter::new(file), &config)
-        .with_context(|| format!("failed to write to {CONFIG_PATH}"))?;
+/// Setup Chrome/Chromium managed policies to ensure that this extension is
+/// automatically installed to all users on this machine.
+///
+/// Unlike Firefox, Chrome/Chromium cannot install an extension from a local file:
+/// the extension must be packaged as a CRX, signed with a key that determines
+/// `extension_id`, and served along with an update manifest at `update_url`.
+pub fn setup_chromium_policies(extension_id: &str, update_url: &str) -> Result<(), anyhow::Error> {
+    for directory in CHROMIUM_POLICIES_DIRECTORIES {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create {directory}"))?;
+        let path = Path::new(directory).join(CHROMIUM_POLICIES_FILE_NAME);
+        let mut policies = ChromiumPolicies::load(&path)?;
+        if policies.install(extension_id, update_url).not() {
+            info!("{} is already up to date", path.display());
+            continue;
+        }
+        policies.store(&path)?;
+    }
     Ok(())
 }
 
+/// Remove this extension from Chrome/Chromium managed policies, leaving other policies untouched.
+pub fn remove_chromium_policies() -> Result<(), anyhow::Error> {
+    for directory in CHROMIUM_POLICIES_DIRECTORIES {
+        let path = Path::new(directory).join(CHROMIUM_POLICIES_FILE_NAME);
+        if std::fs::metadata(&path).is_err() {
+            info!("{} doesn't exist, nothing to remove", path.display());
+            continue;
+        }
+        let policies = ChromiumPolicies::load(&path)?;
+        if policies._others.is_empty() && policies.extension_install_forcelist.len() <= 1 {
+            // This file only contains what we wrote.
+            remove_file_if_exists(&path)?;
+        } else {
+            warn!("{} contains other policies, please edit it manually", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Remove the addon from /etc/firefox/addons.
+pub fn remove_addon() -> Result<(), anyhow::Error> {
+    remove_file_if_exists(&Path::new(ADDONS_PATH).join(ADDON_FILE_NAME))
+}
+
+/// A version `major.minor.patch`.
+type Version = (u64, u64, u64);
+
+/// Parse the output of `keep-it-focused --version`, e.g. `keep-it-focused 0.5.2`.
+fn parse_version(output: &str) -> Option<Version> {
+    let version = output.split_whitespace().last()?;
+    let mut numbers = version.split('.').map(|number| number.parse::<u64>().ok());
+    let version = (numbers.next()??, numbers.next()??, numbers.next()??);
+    if numbers.next().is_some() {
+        return None;
+    }
+    Some(version)
+}
+
+/// The version of the daemon installed at `path`, if it can be determined.
+///
+/// If the installed binary cannot be executed (e.g. it was built for another
+/// architecture) or doesn't report a version, return `None`.
+fn installed_version(path: &Path) -> Option<Version> {
+    let output = match std::process::Command::new(path).arg("--version").output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warn!("could not determine version of {}: {}", path.display(), output.status);
+            return None;
+        }
+        Err(err) => {
+            warn!("could not determine version of {}: {err}", path.display());
+            return None;
+        }
+    };
+    parse_version(&String::from_utf8_lossy(&output.stdout))
+}
+
 /// Copy this binary to /usr/bin, make it world-executable.
-pub fn copy_daemon() -> Result<(), anyhow::Error> {
-    info!("if the daemon is started, let's stop it before copying");
-    let mut stop_command = std::process::Command::new("systemctl");
-    stop_command.args(["stop", "keep-it-focused"]);
-    let mut child = stop_command
-        .spawn()
-        .with_context(|| "failed to stop daemon")?;
-    if let Err(err) = child.wait() {
-        debug!("could not stop daemon: {}", err);
-    }
-
-    const DEST_DIRECTORY: &str = "/usr/bin";
+///
+/// Unless `allow_downgrade` is true, refuse to overwrite a more recent version.
+pub fn copy_daemon(allow_downgrade: bool) -> Result<(), anyhow::Error> {
     let source = exe_name();
     let name = std::path::Path::new(&source).file_name()
         .expect("missing file name? this should be impossible unless you're writing your own operating system");
-    let dest = Path::new(DEST_DIRECTORY).join(name);
+    let dest = Path::new(DAEMON_DEST_DIRECTORY).join(name);
+
+    // Make sure that we're not copying the binary onto itself.
+    if let (Ok(source), Ok(dest)) = (std::fs::canonicalize(&source), std::fs::canonicalize(&dest)) {
+        if source == dest {
+            info!("{} is already installed, nothing to copy", dest.display());
+            return Ok(());
+        }
+    }
+
+    // Make sure that we're not downgrading.
+    let version = parse_version(env!("CARGO_PKG_VERSION")).expect("invalid CARGO_PKG_VERSION");
+    if std::fs::metadata(&dest).is_ok() {
+        match installed_version(&dest) {
+            Some(installed) if installed > version && allow_downgrade.not() => {
+                return Err(anyhow!(
+                    "{} is version {}.{}.{}, which is more recent than this version {}, use `--allow-downgrade` to overwrite it anyway",
+                    dest.display(),
+                    installed.0,
+                    installed.1,
+                    installed.2,
+                    env!("CARGO_PKG_VERSION")
+                ));
+            }
+            Some(installed) => debug!("replacing installed version {installed:?} with {version:?}"),
+            None => warn!("could not determine the version of {}, overwriting it", dest.display()),
+        }
+    }
+
+    // If the daemon is running, we'll need to restart it once the copy is complete,
+    // otherwise we'd leave the machine without enforcement.
+    let was_active = systemctl(&["is-active", "--quiet", "keep-it-focused"]).is_ok();
+    if was_active {
+        info!("the daemon is started, let's stop it before copying");
+        if let Err(err) = systemctl(&["stop", "keep-it-focused"]) {
+            warn!("could not stop daemon: {err:#}");
+        }
+    }
+
     debug!("copying {source} to {}", dest.display());
-    std::fs::copy(&source, dest)
-        .with_context(|| format!("failed to copy {source} to {DEST_DIRECTORY} - perhaps you need to stop the daemon with `sudo systemctl stop keep-it-focused`"))?;
+    let copied = std::fs::copy(&source, dest)
+        .with_context(|| format!("failed to copy {source} to {DAEMON_DEST_DIRECTORY} - perhaps you need to stop the daemon with `sudo systemctl stop keep-it-focused`"));
+
+    // Restart even if the copy failed, to restore the previous daemon.
+    if was_active {
+        info!("restarting daemon");
+        systemctl(&["start", "keep-it-focused"]).context("Failed to restart daemon after copy")?;
+    }
+    copied?;
     Ok(())
 }
 
-/// Setup this daemon for start upon next system launch.
-pub fn setup_daemon(auto_start: bool) -> Result<(), anyhow::Error> {
-    // Create an empty config if there's no config at the oment.
-    const DAEMON_CONFIG_PATH: &str = "/etc/keep-it-focused.yaml";
-    info!("creating empty config at {DAEMON_CONFIG_PATH}");
-    if std::fs::metadata(DAEMON_CONFIG_PATH).is_ok() {
+/// Remove the daemon from /usr/bin.
+pub fn remove_daemon() -> Result<(), anyhow::Error> {
+    remove_file_if_exists(&Path::new(DAEMON_DEST_DIRECTORY).join("keep-it-focused"))
+}
+
+/// Stop and disable the daemon, then remove its systemd configuration.
+pub fn remove_service() -> Result<(), anyhow::Error> {
+    remove_timer()?;
+    if std::fs::metadata(SYSTEMD_CONFIG_PATH).is_err() {
+        info!("{SYSTEMD_CONFIG_PATH} doesn't exist, nothing to remove");
+        return Ok(());
+    }
+    info!("stopping and disabling daemon");
+    if let Err(err) = systemctl(&["disable", "--now", "keep-it-focused.service"]) {
+        warn!("could not stop and disable daemon: {err:#}");
+    }
+    remove_file_if_exists(Path::new(SYSTEMD_CONFIG_PATH))?;
+    remove_file_if_exists(Path::new(SYSTEMD_ENVIRONMENT_PATH))?;
+    systemctl(&["daemon-reload"])
+}
+
+/// Stop and disable the timer, if any, then remove its systemd configuration.
+fn remove_timer() -> Result<(), anyhow::Error> {
+    if std::fs::metadata(SYSTEMD_TIMER_PATH).is_err() {
+        debug!("{SYSTEMD_TIMER_PATH} doesn't exist, nothing to remove");
+        return Ok(());
+    }
+    info!("stopping and disabling timer");
+    if let Err(err) = systemctl(&["disable", "--now", "keep-it-focused.timer"]) {
+        warn!("could not stop and disable timer: {err:#}");
+    }
+    remove_file_if_exists(Path::new(SYSTEMD_TIMER_PATH))?;
+    systemctl(&["daemon-reload"])
+}
+
+/// The options with which the daemon is launched by systemd.
+#[derive(TypedBuilder, Debug)]
+pub struct DaemonOptions {
+    #[builder(default = PathBuf::from(DAEMON_CONFIG_PATH))]
+    pub main_config: PathBuf,
+    pub extensions_dir: PathBuf,
+    pub state_dir: PathBuf,
+    pub port: u16,
+    pub sleep_s: u64,
+    #[builder(default = false)]
+    pub ip_tables: bool,
+    #[builder(default = false)]
+    pub serve_forbidden: bool,
+
+    /// The value of `RUST_LOG`.
+    #[builder(default = "info".to_string())]
+    pub log_level: String,
+
+    /// If true, rather than running the daemon continuously, run a single pass
+    /// every `sleep_s` seconds from a systemd timer.
+    #[builder(default = false)]
+    pub timer: bool,
+}
+
+/// Quote a path for use in a systemd `ExecStart`.
+///
+/// Besides quotes, systemd expands specifiers (`%`) and environment variables (`$`).
+fn systemd_quote(path: &Path) -> String {
+    let path = path
+        .to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{path}\"")
+}
+
+/// The options passed to `run` through `$KEEP_IT_FOCUSED_RUN_OPTIONS`.
+fn run_options(options: &DaemonOptions) -> String {
+    let mut run_options = format!("--port {} --sleep-s {}", options.port, options.sleep_s);
+    if options.ip_tables {
+        run_options.push_str(" --ip-tables");
+    }
+    if options.serve_forbidden {
+        run_options.push_str(" --serve-forbidden");
+    }
+    run_options
+}
+
+/// Generate /etc/systemd/system/keep-it-focused.service.
+///
+/// `RUST_LOG` and `KEEP_IT_FOCUSED_RUN_OPTIONS` are set inline, but may be overridden
+/// from /etc/default/keep-it-focused.
+fn systemd_unit(options: &DaemonOptions) -> String {
+    let mut exec_start = format!(
+        "{} --main-config {} --extensions {} run --state-dir {} $KEEP_IT_FOCUSED_RUN_OPTIONS",
+        Path::new(DAEMON_DEST_DIRECTORY).join("keep-it-focused").display(),
+        systemd_quote(&options.main_config),
+        systemd_quote(&options.extensions_dir),
+        systemd_quote(&options.state_dir),
+    );
+    let environment = format!(
+        "Environment=RUST_LOG={log_level} \"KEEP_IT_FOCUSED_RUN_OPTIONS={run_options}\"\nEnvironmentFile=-{SYSTEMD_ENVIRONMENT_PATH}",
+        log_level = options.log_level,
+        run_options = run_options(options),
+    );
+    if options.timer {
+        exec_start.push_str(" --once");
+        // The service is started by the timer, not at startup.
+        return format!(
+            r#"[Unit]
+Description=Prevent some distracting applications from launching outside allowed times (single pass).
+# Rules depend on the time, wait for the clock to be synced, if possible.
+After=time-sync.target
+
+[Service]
+Type=oneshot
+User=root
+WorkingDirectory=/root
+ExecStart={exec_start}
+{environment}
+"#
+        );
+    }
+    format!(
+        r#"[Unit]
+Description=Prevent some distracting applications from launching outside allowed times.
+# Rules depend on the time, wait for the clock to be synced, if possible.
+# The daemon also waits by itself if the clock is clearly wrong.
+After=time-sync.target
+
+[Install]
+# Make sure that the daemon is launched on startup.
+WantedBy=graphical.target multi-user.target
+
+[Service]
+# The daemon reports its status, see `systemctl status keep-it-focused`.
+Type=notify
+User=root
+WorkingDirectory=/root
+ExecStart={exec_start}
+{environment}
+Restart=always
+RestartSec=3
+WatchdogSec={watchdog_s}s
+"#,
+        watchdog_s = watchdog_s(options),
+    )
+}
+
+/// How long systemd should wait for a tick before restarting the daemon.
+///
+/// Ticks are `sleep_s` apart, leave some room for slow ticks.
+fn watchdog_s(options: &DaemonOptions) -> u64 {
+    (options.sleep_s * 3).max(30)
+}
+
+/// Generate /etc/default/keep-it-focused.
+fn systemd_environment(options: &DaemonOptions) -> String {
+    format!(
+        r#"# Environment of keep-it-focused, applied upon `systemctl restart keep-it-focused`.
+
+# The log level (`error`, `warn`, `info`, `debug` or `trace`).
+RUST_LOG={log_level}
+
+# Options passed to `keep-it-focused run`, see `keep-it-focused help run`.
+# If you increase `--sleep-s`, make sure that it remains below `WatchdogSec` in the unit.
+KEEP_IT_FOCUSED_RUN_OPTIONS="{run_options}"
+"#,
+        log_level = options.log_level,
+        run_options = run_options(options),
+    )
+}
+
+/// Generate /etc/systemd/system/keep-it-focused.timer.
+fn systemd_timer(options: &DaemonOptions) -> String {
+    format!(
+        r#"[Unit]
+Description=Periodically prevent some distracting applications from launching outside allowed times.
+
+[Install]
+# Make sure that the timer is launched on startup.
+WantedBy=timers.target
+
+[Timer]
+OnBootSec=0
+OnUnitActiveSec={sleep_s}s
+AccuracySec=1s
+"#,
+        sleep_s = options.sleep_s
+    )
+}
+
+/// Write a systemd file, unless it already exists with different content and `force` is false.
+///
+/// Returns `true` if the file was written.
+fn write_systemd_file(path: &str, content: &str, force: bool) -> Result<bool, anyhow::Error> {
+    match std::fs::read_to_string(path) {
+        Ok(existing) if existing == content => {
+            info!("file {path} is already up to date");
+            Ok(false)
+        }
+        Ok(_) if force.not() => {
+            warn!("file {path} already exists, we're not overwriting it, use `--force` to overwrite");
+            Ok(false)
+        }
+        _ => {
+            std::fs::write(path, content).with_context(|| format!("failed to write {path}"))?;
+            Ok(true)
+        }
+    }
+}
+
+/// Create an empty config at `path`, unless there's already a config, in which case
+/// we check that it's syntactically correct.
+fn ensure_config(path: &Path) -> Result<(), anyhow::Error> {
+    info!("creating empty config at {}", path.display());
+    if std::fs::metadata(path).is_ok() {
         warn!(
             "file {} already exists, we're not overwriting it",
-            DAEMON_CONFIG_PATH
+            path.display()
         );
-        let reader = std::fs::File::open(DAEMON_CONFIG_PATH).with_context(|| {
+        let reader = std::fs::File::open(path).with_context(|| {
             format!(
                 "could not open existing configuration {}",
-                DAEMON_CONFIG_PATH
+                path.display()
             )
         })?;
         let config: config::Config = serde_yaml::from_reader(reader).with_context(|| {
             format!(
                 "could not parse existing configuration {}",
-                DAEMON_CONFIG_PATH
+                path.display()
             )
         })?;
         info!(
@@ -185,62 +672,86 @@ pub fn setup_daemon(auto_start: bool) -> Result<(), anyhow::Error> {
             serde_yaml::to_string(&config).expect("failed to display config")
         );
     } else {
-        let mut file = std::fs::File::create_new(SYSTEMD_CONFIG_PATH)
-            .with_context(|| format!("failed to create {SYSTEMD_CONFIG_PATH}"))?;
+        let mut file = std::fs::File::create_new(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
         let config = config::Config::default();
         let data = serde_yaml::to_string(&config).expect("cannot serialize an empty config?");
         file.write_all(data.as_bytes())
-            .with_context(|| format!("failed to write {SYSTEMD_CONFIG_PATH}"))?;
+            .with_context(|| format!("failed to write {}", path.display()))?;
     }
+    Ok(())
+}
 
-    // Write /etc/systemd/system/keep-it-focused.service
-    info!("writing down system configuration to start daemon automatically");
-    const SYSTEMD_DATA: &str = r#"
-    [Unit]
-    Description=Prevent some distracting applications from launching outside allowed times.
-    
-    [Install]
-    # Make sure that the daemon is launched on startup.
-    WantedBy=graphical.target multi-user.target
-    
-    [Service]
-    User=root
-    WorkingDirectory=/root
-    ExecStart=/usr/bin/keep-it-focused run
-    Environment=RUST_LOG=info
-    Restart=always
-    RestartSec=3
-    "#;
-    const SYSTEMD_CONFIG_PATH: &str = "/etc/systemd/system/keep-it-focused.service";
-    if std::fs::metadata(SYSTEMD_CONFIG_PATH).is_ok() {
-        warn!(
-            "file {} already exists, we're not overwriting it",
-            SYSTEMD_CONFIG_PATH
-        );
+/// Setup this daemon for start upon next system launch.
+///
+/// If `force` is true, overwrite any existing systemd configuration.
+pub fn setup_daemon(options: &DaemonOptions, auto_start: bool, force: bool) -> Result<(), anyhow::Error> {
+    // Create an empty config if there's no config at the moment.
+    ensure_config(&options.main_config)?;
+
+    // Switching between daemon and timer? Stop whichever was running.
+    if options.timer {
+        if let Err(err) = systemctl(&["disable", "--now", "keep-it-focused.service"]) {
+            debug!("could not stop and disable daemon: {err:#}");
+        }
     } else {
-        let mut file = std::fs::File::create_new(SYSTEMD_CONFIG_PATH)
-            .with_context(|| format!("failed to create {SYSTEMD_CONFIG_PATH}"))?;
-        file.write_all(SYSTEMD_DATA.as_bytes())
-            .with_context(|| format!("failed to write {SYSTEMD_CONFIG_PATH}"))?;
+        remove_timer()?;
     }
 
+    // Write /etc/systemd/system/keep-it-focused.service (and .timer)
+    info!("writing down system configuration to start daemon automatically");
+    let mut changed = write_systemd_file(SYSTEMD_CONFIG_PATH, &systemd_unit(options), force)?;
+    if options.timer {
+        changed |= write_systemd_file(SYSTEMD_TIMER_PATH, &systemd_timer(options), force)?;
+    }
+    if changed {
+        // Let systemd know about the new units.
+        systemctl(&["daemon-reload"]).context("Failed to reload systemd configuration")?;
+    }
+
+    // Write /etc/default/keep-it-focused, for admins to tweak. This doesn't require a
+    // `daemon-reload`, but a running daemon needs a restart to take changes into account.
+    changed |= write_systemd_file(
+        SYSTEMD_ENVIRONMENT_PATH,
+        &systemd_environment(options),
+        force,
+    )?;
+
     // Prepare for restart.
-    info!("preparing daemon for next startup");
-    let mut cmd = std::process::Command::new("systemctl");
-    cmd.args(["enable", "keep-it-focused"]);
-    cmd.spawn().context("Error in `systemctl enable`")?;
+    let unit = if options.timer {
+        "keep-it-focused.timer"
+    } else {
+        "keep-it-focused.service"
+    };
+    if systemctl(&["is-enabled", "--quiet", unit]).is_ok() {
+        info!("{unit} is already enabled for next startup");
+    } else {
+        info!("preparing {unit} for next startup");
+        systemctl(&["enable", unit]).with_context(|| format!("Failed to enable {unit}"))?;
+    }
 
     // Prepare for start.
     if auto_start {
-        info!("attempting to start daemon");
-        let mut cmd = std::process::Command::new("systemctl");
-        cmd.args(["start", "keep-it-focused"]);
-        cmd.spawn().context("Error in `systemctl start`")?;
+        // If the configuration has changed, make sure that it's applied.
+        let verb = if changed { "restart" } else { "start" };
+        info!("attempting to {verb} {unit}");
+        systemctl(&[verb, unit]).with_context(|| format!("Failed to {verb} {unit}"))?;
     }
 
     Ok(())
 }
 
+/// Remove the directory of temporary rules, along with its contents.
+pub fn remove_extension_dir(path: &Path) -> Result<(), anyhow::Error> {
+    match std::fs::remove_dir_all(path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("{} doesn't exist, nothing to remove", path.display());
+            Ok(())
+        }
+        result => result.with_context(|| format!("failed to remove {}", path.display())),
+    }
+}
+
 pub fn make_extension_dir(path: &Path) -> Result<(), anyhow::Error> {
     // Note: this direcotry MUST belong to root and be writeable only by root.
     let trusted = match std::fs::create_dir_all(path) {
@@ -281,3 +792,145 @@ pub fn make_extension_dir(path: &Path) -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::ops::Not;
+
+    use crate::config::Config;
+
+    use super::{
+        ensure_config, parse_version, systemd_environment, systemd_timer, systemd_unit, ChromiumPolicies, Configuration,
+        DaemonOptions, InstallationMode, DAEMON_CONFIG_PATH, EXTENSION_ID, SYSTEMD_CONFIG_PATH,
+    };
+
+    const INSTALL_URL: &str = "file:///test.xpi";
+
+    #[test]
+    fn test_policies_install() {
+        // From scratch.
+        let mut config = Configuration::default();
+        assert!(config.install(INSTALL_URL, false));
+        let settings = &config.policies.extension_settings[EXTENSION_ID];
+        assert_eq!(settings.installation_mode, Some(InstallationMode::ForceInstalled));
+        assert_eq!(settings.install_url.as_deref(), Some(INSTALL_URL));
+
+        // Idempotent.
+        assert!(config.install(INSTALL_URL, false).not());
+
+        // Leave an explicit choice alone, unless forced, and preserve everything else.
+        let source = serde_json::json!({
+            "policies": {
+                "DisableTelemetry": true,
+                "ExtensionSettings": {
+                    "other@example.org": { "installation_mode": "blocked" },
+                    EXTENSION_ID: { "installation_mode": "normal_installed", "install_url": INSTALL_URL, "updates_disabled": true }
+                }
+            },
+            "other": 1
+        });
+        let mut config: Configuration = serde_json::from_value(source.clone()).unwrap();
+        assert!(config.install(INSTALL_URL, false).not());
+        assert_eq!(serde_json::to_value(&config).unwrap(), source);
+
+        assert!(config.install(INSTALL_URL, true));
+        let mut expected = source;
+        expected["policies"]["ExtensionSettings"][EXTENSION_ID]["installation_mode"] = "force_installed".into();
+        assert_eq!(serde_json::to_value(&config).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_chromium_policies_install() {
+        const ID: &str = "abcdefghijklmnopabcdefghijklmnop";
+        let source = serde_json::json!({
+            "ExtensionInstallForcelist": ["other;https://example.org/update.xml", format!("{ID};https://old.example.org/update.xml")],
+            "BrowserSignin": 0
+        });
+        let mut policies: ChromiumPolicies = serde_json::from_value(source).unwrap();
+        assert!(policies.install(ID, "https://example.com/update.xml"));
+        assert!(policies.install(ID, "https://example.com/update.xml").not());
+        assert_eq!(
+            serde_json::to_value(&policies).unwrap(),
+            serde_json::json!({
+                "ExtensionInstallForcelist": ["other;https://example.org/update.xml", format!("{ID};https://example.com/update.xml")],
+                "BrowserSignin": 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("keep-it-focused 0.5.2\n"), Some((0, 5, 2)));
+        assert_eq!(parse_version("0.10.0"), Some((0, 10, 0)));
+        assert!(parse_version("keep-it-focused 0.10.0").unwrap() > parse_version("0.9.12").unwrap());
+        assert_eq!(parse_version("keep-it-focused 0.5"), None);
+        assert_eq!(parse_version("keep-it-focused 0.5.2-beta"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn test_ensure_config() {
+        assert_ne!(DAEMON_CONFIG_PATH, SYSTEMD_CONFIG_PATH);
+
+        let path = std::env::temp_dir().join(format!(
+            "keep-it-focused-test-config-{}.yaml",
+            std::process::id()
+        ));
+        // Create an empty config.
+        ensure_config(&path).unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        let config: Config = serde_yaml::from_str(&data).unwrap();
+        assert!(config.users.is_empty());
+
+        // Don't overwrite an existing config.
+        std::fs::write(&path, "users:\n  mickey: {}\n").unwrap();
+        ensure_config(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "users:\n  mickey: {}\n");
+
+        // Reject an invalid config.
+        std::fs::write(&path, "users: 12\n").unwrap();
+        assert!(ensure_config(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let options = DaemonOptions::builder()
+            .extensions_dir("/tmp/keep-it-focused.d/".into())
+            .state_dir("/var/lib/my \"state\" 100%".into())
+            .port(1234)
+            .sleep_s(30)
+            .ip_tables(true)
+            .log_level("debug".to_string())
+            .build();
+        let unit = systemd_unit(&options);
+        assert!(unit.contains(r#"ExecStart=/usr/bin/keep-it-focused --main-config "/etc/keep-it-focused.yaml" --extensions "/tmp/keep-it-focused.d/" run --state-dir "/var/lib/my \"state\" 100%%" $KEEP_IT_FOCUSED_RUN_OPTIONS
+"#), "{unit}");
+        assert!(unit.contains(r#"Environment=RUST_LOG=debug "KEEP_IT_FOCUSED_RUN_OPTIONS=--port 1234 --sleep-s 30 --ip-tables"
+EnvironmentFile=-/etc/default/keep-it-focused
+"#), "{unit}");
+        assert!(unit.contains("Restart=always\n"), "{unit}");
+        assert!(unit.contains("Type=notify\n"), "{unit}");
+        assert!(unit.contains("WatchdogSec=90s\n"), "{unit}");
+        assert!(unit.contains("\nAfter=time-sync.target\n"), "{unit}");
+        let environment = systemd_environment(&options);
+        assert!(environment.contains("\nRUST_LOG=debug\n"), "{environment}");
+        assert!(environment.contains("\nKEEP_IT_FOCUSED_RUN_OPTIONS=\"--port 1234 --sleep-s 30 --ip-tables\"\n"), "{environment}");
+
+        let options = DaemonOptions::builder()
+            .extensions_dir("/tmp/keep-it-focused.d/".into())
+            .state_dir("/var/lib/keep-it-focused/".into())
+            .port(1234)
+            .sleep_s(30)
+            .timer(true)
+            .build();
+        let unit = systemd_unit(&options);
+        assert!(unit.contains(" $KEEP_IT_FOCUSED_RUN_OPTIONS --once\n"), "{unit}");
+        assert!(unit.contains("Type=oneshot\n"), "{unit}");
+        assert!(unit.contains("\nAfter=time-sync.target\n"), "{unit}");
+        assert!(unit.contains("Restart=").not(), "{unit}");
+        let timer = systemd_timer(&options);
+        assert!(timer.contains("OnUnitActiveSec=30s\n"), "{timer}");
+    }
+}
@@ -0,0 +1,147 @@
+//! Counting how often each rule actually causes enforcement.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Binary, uid_resolver::Uid, Verdict};
+
+/// How many times a rule caused a warning or a kill.
+///
+/// Note that a process running close to the end of its permitted interval causes one
+/// warning per tick.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Hits {
+    pub warnings: u32,
+    pub kills: u32,
+}
+
+/// The statistics, as served to clients: `user name => binary => hits`.
+pub type Report = BTreeMap<String, BTreeMap<String, Hits>>;
+
+/// Per-rule enforcement counters for the current day.
+///
+/// Only process rules are counted: domain rules are enforced by the browser
+/// extension or by iptables, out of sight of the daemon.
+#[derive(Debug)]
+pub struct Stats {
+    /// The day to which the counters apply.
+    day: NaiveDate,
+
+    processes: HashMap<(Uid, Binary), Hits>,
+}
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            day: Local::now().date_naive(),
+            processes: HashMap::new(),
+        }
+    }
+
+    /// Reset all counters if `today` is not the day to which they apply.
+    pub fn rollover(&mut self, today: NaiveDate) {
+        if today != self.day {
+            self.day = today;
+            self.processes.clear();
+        }
+    }
+
+    /// Record the verdict of a rule on a process.
+    pub fn record(&mut self, uid: Uid, binary: &Binary, verdict: Verdict) {
+        let hits = match verdict {
+            Verdict::Allowed { .. } => return,
+            Verdict::Warning { .. } | Verdict::FirstOffense | Verdict::Nag | Verdict::Deferred => Hits {
+                warnings: 1,
+                kills: 0,
+            },
+            Verdict::Killed => Hits {
+                warnings: 0,
+                kills: 1,
+            },
+        };
+        let entry = self.processes.entry((uid, binary.clone())).or_default();
+        entry.warnings += hits.warnings;
+        entry.kills += hits.kills;
+    }
+
+    pub fn hits(&self, uid: Uid, binary: &Binary) -> Hits {
+        self.processes
+            .get(&(uid, binary.clone()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Serialize the counters to JSON, fit for serving.
+    ///
+    /// Each user only sees their own counters, except root, who sees everybody's.
+    pub fn serialize(&self) -> HashMap<Uid, String> {
+        let mut per_user: HashMap<Uid, Report> = HashMap::new();
+        let mut all = Report::new();
+        for ((uid, binary), hits) in &self.processes {
+            let name = uid.name().unwrap_or_else(|_| uid.0.to_string());
+            let binary = binary.path.to_string_lossy().to_string();
+            per_user
+                .entry(*uid)
+                .or_default()
+                .entry(name.clone())
+                .or_default()
+                .insert(binary.clone(), *hits);
+            all.entry(name).or_default().insert(binary, *hits);
+        }
+        per_user.insert(Uid(0), all);
+        per_user
+            .into_iter()
+            .map(|(uid, report)| {
+                let data = serde_json::to_string(&report).expect("error during serialization");
+                (uid, data)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::NaiveDate;
+
+    use crate::{config::Binary, stats::Report, uid_resolver::Uid, Verdict};
+
+    use super::{Hits, Stats};
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::new();
+        let day = NaiveDate::from_ymd_opt(2024, 11, 4).unwrap();
+        stats.rollover(day);
+        let tetris = Binary::try_new("/usr/games/tetris").unwrap();
+        let user = Uid(1000);
+        stats.record(user, &tetris, Verdict::Allowed { remaining: Duration::from_secs(3600) });
+        stats.record(user, &tetris, Verdict::Warning { remaining: Duration::from_secs(60) });
+        stats.record(user, &tetris, Verdict::Killed);
+        stats.record(user, &tetris, Verdict::Killed);
+        assert_eq!(stats.hits(user, &tetris), Hits { warnings: 1, kills: 2 });
+        assert_eq!(stats.hits(Uid(1001), &tetris), Hits::default());
+
+        // Users only see their own counters, root sees everything.
+        let served = stats.serialize();
+        let mine: Report = serde_json::from_str(&served[&user]).unwrap();
+        assert_eq!(mine.len(), 1);
+        let all: Report = serde_json::from_str(&served[&Uid(0)]).unwrap();
+        assert_eq!(all, mine);
+
+        // Same day, nothing changes.
+        stats.rollover(day);
+        assert_eq!(stats.hits(user, &tetris), Hits { warnings: 1, kills: 2 });
+
+        // Next day, reset.
+        stats.rollover(day.succ_opt().unwrap());
+        assert_eq!(stats.hits(user, &tetris), Hits::default());
+    }
+}
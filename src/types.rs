@@ -5,8 +5,9 @@ use std::{
 };
 
 use anyhow::anyhow;
-use chrono::{DateTime, Datelike, Local, Timelike};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, Timelike};
 use derive_more::derive::{AsRef, Deref, Display};
+use itertools::Itertools;
 use lazy_regex::lazy_regex;
 #[allow(unused)]
 use log::{debug, trace};
@@ -16,6 +17,8 @@ use serde::{
 };
 use typed_builder::TypedBuilder;
 
+use crate::locale::Language;
+
 /// A time of day.
 #[derive(PartialEq, Eq, Debug, Clone, Copy, TypedBuilder)]
 pub struct TimeOfDay {
@@ -31,22 +34,43 @@ impl TimeOfDay {
     pub fn as_minutes(&self) -> u16 {
         self.minutes as u16 + self.hours as u16 * 60
     }
+    /// Convert a number of minutes since the start of the day.
+    ///
+    /// Anything past the end of the day is clamped to `DAY_ENDS`.
     pub fn from_minutes(minutes: u16) -> Self {
-        let mm = (minutes % 60) as u8;
-        let hh = u16::min(minutes / 60, 24) as u8;
+        if minutes >= DAY_ENDS.as_minutes() {
+            return DAY_ENDS;
+        }
         Self {
-            hours: hh,
-            minutes: mm,
+            hours: (minutes / 60) as u8,
+            minutes: (minutes % 60) as u8,
         }
     }
+    /// Add a number of minutes, clamping to `DAY_ENDS` if we cross midnight.
+    pub fn saturating_add_minutes(&self, minutes: u16) -> Self {
+        Self::from_minutes(self.as_minutes().saturating_add(minutes))
+    }
     pub fn now() -> TimeOfDay {
         let now = Local::now();
         now.into()
     }
+    /// This time of day on a given day, `2400` being midnight on the next day.
+    ///
+    /// Returns `None` if this time doesn't exist on that day, e.g. because of a DST change.
+    pub fn on(&self, day: NaiveDate) -> Option<DateTime<Local>> {
+        let naive = day.and_time(NaiveTime::MIN) + chrono::Duration::minutes(self.as_minutes() as i64);
+        naive.and_local_timezone(Local).earliest()
+    }
     pub const START: TimeOfDay = DAY_BEGINS;
     pub const END: TimeOfDay = DAY_ENDS;
 }
 
+impl Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.hours, self.minutes)
+    }
+}
+
 impl From<TimeOfDay> for std::time::Duration {
     fn from(t: TimeOfDay) -> std::time::Duration {
         std::time::Duration::new(t.hours as u64 * 3_600 + t.minutes as u64 * 60, 0)
@@ -83,8 +107,28 @@ pub const DAY_ENDS: TimeOfDay = TimeOfDay {
 };
 
 impl TimeOfDay {
+    /// Build a time of day from hours and minutes, e.g. `(11, 35)`.
+    ///
+    /// The only time accepted past `2359` is `2400`, i.e. the end of the day.
+    pub fn try_new(hours: u64, minutes: u64) -> Result<Self, anyhow::Error> {
+        match (hours, minutes) {
+            (24, 00) => Ok(DAY_ENDS),
+            (0..=23, 00..=59) => Ok(TimeOfDay {
+                hours: hours as u8,
+                minutes: minutes as u8,
+            }),
+            (24, _) => Err(anyhow!(
+                "invalid time {hours:02}{minutes:02}, the only valid time after 2359 is 2400"
+            )),
+            (0..=23, _) => Err(anyhow!(
+                "invalid minutes {minutes}, expected a number in [0, 59]"
+            )),
+            _ => Err(anyhow!("invalid hours {hours}, expected a number in [0, 23]")),
+        }
+    }
+
     pub fn parse(source: &str) -> Result<Self, anyhow::Error> {
-        let re = lazy_regex!("([0-2][0-9]):?([0-5][0-9])");
+        let re = lazy_regex!("^([0-2][0-9]):?([0-5][0-9])$");
         let Some(captures) = re.captures(source) else {
             return Err(anyhow!(
                 "invalid time of day, expecting e.g. \"1135\" (11:35 am) or \"1759\" (5:59pm)"
@@ -97,17 +141,7 @@ impl TimeOfDay {
         let Ok(mm) = mm.parse::<u64>() else {
             return Err(anyhow!("minutes should be a valid number"));
         };
-        match (hh, mm) {
-            (24, 00) => Ok(DAY_ENDS),
-            (0..=23, 00..=59) => Ok(TimeOfDay {
-                hours: hh as u8,
-                minutes: mm as u8,
-            }),
-            (0..=23, _) => Err(anyhow!(
-                "invalid minutes {mm}, expected a number in [0, 59]"
-            )),
-            _ => Err(anyhow!("invalid hours {hh}, expected a number in [0, 23]")),
-        }
+        Self::try_new(hh, mm)
     }
 }
 
@@ -120,35 +154,9 @@ impl<'de> Deserialize<'de> for TimeOfDay {
         // untagged enum parsers are really bad for error messages, so we use an intermediate
         // yaml parser
         let value = serde_yaml::Value::deserialize(deserializer)?;
-        let (maybe_str, maybe_u64) = (value.as_str(), value.as_u64());
-        let (h, m) = match (maybe_str, maybe_u64) {
-            (_, Some(num)) => (num / 100, num % 100),
-            (Some(source), _) => {
-                if source.len() != 4 {
-                    return Err(D::Error::invalid_length(source.len(), &"4"));
-                }
-                let re = lazy_regex!("([0-2][0-9]):?([0-5][0-9])");
-                let Some(captures) = re.captures(source) else {
-                    return Err(D::Error::invalid_value(
-                        Unexpected::Str(source),
-                        &"a time of day, e.g. \"1135\" (11:35 am) or \"1759\" (5:59pm)",
-                    ));
-                };
-                let (_, [hh, mm]) = captures.extract();
-                let Ok(hh) = hh.parse::<u64>() else {
-                    return Err(D::Error::invalid_value(
-                        Unexpected::Str(hh),
-                        &"a number between 00 and 23",
-                    ));
-                };
-                let Ok(mm) = mm.parse::<u64>() else {
-                    return Err(D::Error::invalid_value(
-                        Unexpected::Str(mm),
-                        &"a number between 00 and 59",
-                    ));
-                };
-                (hh, mm)
-            }
+        let result = match (value.as_str(), value.as_u64()) {
+            (_, Some(num)) => TimeOfDay::try_new(num / 100, num % 100),
+            (Some(source), _) => TimeOfDay::parse(source),
             (None, None) => {
                 return Err(D::Error::invalid_value(
                     Unexpected::Other(&format!("{value:?}")),
@@ -156,27 +164,8 @@ impl<'de> Deserialize<'de> for TimeOfDay {
                 ))
             }
         };
-        trace!("TimeOfDayParser {h} {m}");
-        match (h, m) {
-            (24, 00) => {}
-            _ if h > 23 => {
-                return Err(D::Error::invalid_value(
-                    Unexpected::Str(&format!("{h}")),
-                    &"a number between 00 and 23",
-                ));
-            }
-            _ if m > 59 => {
-                return Err(D::Error::invalid_value(
-                    Unexpected::Str(&format!("{m}")),
-                    &"a number between 00 and 59",
-                ));
-            }
-            _ => {}
-        }
-        Ok(TimeOfDay {
-            hours: h as u8,
-            minutes: m as u8,
-        })
+        trace!("TimeOfDayParser {value:?} to {result:?}");
+        result.map_err(|err| D::Error::custom(format!("{err:#}")))
     }
 }
 
@@ -199,21 +188,13 @@ impl<'de> Deserialize<'de> for DayOfWeek {
             type Value = DayOfWeek;
 
             fn visit_str<E: serde::de::Error>(self, source: &str) -> Result<Self::Value, E> {
-                let prefix = &source.to_ascii_lowercase()[0..3];
-                let result = match prefix {
-                    "0" | "mon" => Ok(DayOfWeek(0)),
-                    "1" | "tue" => Ok(DayOfWeek(1)),
-                    "2" | "wed" | "wen" => Ok(DayOfWeek(2)),
-                    "3" | "thu" => Ok(DayOfWeek(3)),
-                    "4" | "fri" => Ok(DayOfWeek(4)),
-                    "5" | "sat" => Ok(DayOfWeek(5)),
-                    "6" | "sun" => Ok(DayOfWeek(6)),
-                    _ => Err(E::invalid_value(
+                let result = DayOfWeek::from_prefix(source).ok_or_else(|| {
+                    E::invalid_value(
                         Unexpected::Other(source),
                         &"day of week (either a number in [0, 6] or a named day",
-                    )),
-                };
-                trace!("DayOfWeek - deserialized {prefix} to {result:?}");
+                    )
+                });
+                trace!("DayOfWeek - deserialized {source} to {result:?}");
                 result
             }
             fn visit_u8<E: serde::de::Error>(self, source: u8) -> Result<Self::Value, E> {
@@ -263,22 +244,77 @@ impl DayOfWeek {
     pub fn sunday() -> Self {
         DayOfWeek(6)
     }
+    /// All days of the week, starting from Monday.
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..7).map(DayOfWeek)
+    }
+    /// Recognize a day from its number (Monday = 0) or from the first three letters of its name,
+    /// in English, French or German.
+    fn from_prefix(source: &str) -> Option<Self> {
+        let source = source.to_lowercase();
+        // Note: counting characters rather than bytes, as `source` may not be ASCII.
+        let prefix: String = match source.chars().count() {
+            1 => source,
+            2 => return None,
+            _ => source.chars().take(3).collect(),
+        };
+        let day = match prefix.as_str() {
+            "mon" | "lun" | "0" => Self::monday(),
+            "tue" | "mar" | "die" | "1" => Self::tuesday(),
+            "wed" | "wen" | "mer" | "mit" | "2" => Self::wednesday(),
+            "thu" | "jeu" | "don" | "3" => Self::thursday(),
+            "fri" | "ven" | "fre" | "4" => Self::friday(),
+            "sat" | "sam" | "5" => Self::saturday(),
+            "sun" | "dim" | "son" | "6" => Self::sunday(),
+            _ => return None,
+        };
+        Some(day)
+    }
+    /// Recognize a day from its full name, in English, French or German, up to case.
+    ///
+    /// Unlike `parse`, this doesn't accept prefixes, so that a typo isn't mistaken for a day.
+    pub fn from_name(source: &str) -> Option<Self> {
+        let source = source.to_lowercase();
+        [Language::English, Language::French, Language::German]
+            .into_iter()
+            .find_map(|language| {
+                language
+                    .day_names()
+                    .iter()
+                    .position(|name| name.to_lowercase() == source)
+            })
+            .map(|index| DayOfWeek(index as u8))
+    }
+    /// The name of this day, for users.
+    ///
+    /// Unlike `Display`, which is used in configuration files and logs, this is localized.
+    pub fn localized(&self, language: Language) -> &'static str {
+        language.day_names()[self.0 as usize]
+    }
     pub fn parse(source: &str) -> Result<Self, anyhow::Error> {
-        let day = match source[0..3].to_ascii_lowercase().as_str() {
-            "mon" | "0" => Self::monday(),
-            "tue" | "1" => Self::tuesday(),
-            "wed" | "2" => Self::wednesday(),
-            "thu" | "3" => Self::thursday(),
-            "fri" | "4" => Self::friday(),
-            "sat" | "5" => Self::saturday(),
-            "sun" | "6" => Self::sunday(),
-            _ => {
-                return Err(anyhow!(
-                    "invalid day '{source}', expected one of mon, tue, wed, thu, fri, sat, sun"
-                ))
+        Self::from_prefix(source).ok_or_else(|| {
+            anyhow!("invalid day '{source}', expected one of mon, tue, wed, thu, fri, sat, sun")
+        })
+    }
+    /// Parse a set of days, e.g. `mon`, `sat,sun` or `mon-fri`.
+    ///
+    /// Ranges may wrap around the end of the week, e.g. `fri-mon` is Friday to Monday.
+    pub fn parse_days(source: &str) -> Result<Vec<Self>, anyhow::Error> {
+        let mut days = vec![];
+        for item in source.split(',') {
+            match item.trim().split_once('-') {
+                None => days.push(Self::parse(item.trim())?),
+                Some((first, last)) => {
+                    let (mut day, last) = (Self::parse(first.trim())?, Self::parse(last.trim())?);
+                    days.push(day);
+                    while day != last {
+                        day = DayOfWeek((day.0 + 1) % 7);
+                        days.push(day);
+                    }
+                }
             }
-        };
-        Ok(day)
+        }
+        Ok(days.into_iter().unique().collect())
     }
 }
 impl Display for DayOfWeek {
@@ -305,56 +341,128 @@ impl Serialize for DayOfWeek {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
-pub struct Interval {
-    #[serde(default = "Interval::default_start")]
-    pub start: TimeOfDay,
+/// A point on which intervals are defined, e.g. a time of day.
+///
+/// The interval algebra below (`Interval`, `AcceptedInterval`, `RejectedInterval`,
+/// `IntervalsDiff`) works on any point type. Points are totally ordered and bounded:
+/// `MIN` and `MAX` are the bounds of the domain, e.g. the start and the end of the day,
+/// used as default bounds and to complement intervals.
+pub trait Point: Ord + Copy {
+    const MIN: Self;
+    const MAX: Self;
+}
+impl Point for TimeOfDay {
+    const MIN: Self = DAY_BEGINS;
+    const MAX: Self = DAY_ENDS;
+}
 
-    #[serde(default = "Interval::default_end")]
-    pub end: TimeOfDay,
+fn point_min<T: Point>() -> T {
+    T::MIN
+}
+fn point_max<T: Point>() -> T {
+    T::MAX
 }
-impl Interval {
+
+/// An interval of points, by default an interval of time within a day.
+///
+/// Intervals are half-open: `start` is included, `end` is not. So `0900-1000` and
+/// `1000-1100` do not overlap, but they touch, and their union is `0900-1100`. This
+/// matches how the browser extension interprets intervals.
+///
+/// Use `Interval::new(start, end)` for an interval without a label, or `Interval::builder()`.
+/// Only `Interval::new` checks that the interval doesn't end before it starts: neither
+/// `Interval::builder()` nor deserialization do, as configuration files may contain such
+/// intervals, which we report rather than reject (see `DayConfig::ineffective_forbidden`).
+/// Such intervals contain nothing and their length is zero.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, TypedBuilder)]
+#[serde(bound(deserialize = "T: Deserialize<'de> + Point"))]
+pub struct Interval<T = TimeOfDay> {
+    #[serde(default = "point_min")]
+    pub start: T,
+
+    #[serde(default = "point_max")]
+    pub end: T,
+
+    /// A reason shown to the user, e.g. "homework time".
+    ///
+    /// Labels are informative: for `forbidden` intervals, they are shown when a binary is
+    /// stopped (or about to be) and served to the browser extension.
+    #[builder(default, setter(strip_option, into))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+impl<T: Point + Display> Interval<T> {
+    /// An interval without a label.
+    ///
+    /// Fails if the interval would end before it starts. An empty interval (`start == end`) is fine.
+    pub fn new(start: T, end: T) -> Result<Self, anyhow::Error> {
+        if end < start {
+            return Err(anyhow!("interval would end ({end}) before it starts ({start})"));
+        }
+        Ok(Interval {
+            start,
+            end,
+            label: None,
+        })
+    }
+}
+impl Interval<TimeOfDay> {
+    /// If `time` is within this interval, the duration until the end of the interval.
     pub fn remaining(&self, time: TimeOfDay) -> Option<std::time::Duration> {
-        if self.start > time || self.end < time {
+        if self.contains(time).not() {
             return None;
         }
         let end: Duration = self.end.into();
         let time: Duration = time.into();
         Some(end - time)
     }
-    /// Return the length of an interval, in minutes.
+    /// Return the length of an interval, in minutes, or zero if it ends before it starts.
     pub fn len(&self) -> u16 {
-        self.end.as_minutes() - self.start.as_minutes()
+        self.end.as_minutes().saturating_sub(self.start.as_minutes())
+    }
+    /// Return the length of an interval, or zero if it ends before it starts.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.len() as u64 * 60)
+    }
+}
+impl<T: Point> Interval<T> {
+    /// Return `true` if `point` is within this interval.
+    ///
+    /// As intervals are half-open, `start` is within the interval and `end` isn't, so
+    /// `0900-1000` contains `0900` and `0959` but not `1000`.
+    pub fn contains(&self, point: T) -> bool {
+        self.start <= point && point < self.end
     }
+    /// Return `true` if both intervals have at least one point in common.
+    ///
+    /// Intervals that merely touch, e.g. `0900-1000` and `1000-1100`, do not intersect.
     pub fn intersects(&self, other: &Self) -> bool {
-        if self.start <= other.start && self.end >= other.start {
-            return true;
-        }
-        if self.start <= other.end && self.end >= other.end {
-            return true;
-        }
-        false
+        self.start < other.end && other.start < self.end
+    }
+    /// Return `true` if both intervals intersect or touch, i.e. if their union is an interval.
+    pub fn is_contiguous_with(&self, other: &Self) -> bool {
+        self.start <= other.end && other.start <= self.end
     }
+    /// Return the union of both intervals, if it is an interval.
+    ///
+    /// Touching intervals are merged, e.g. `0900-1000` and `1000-1100` become `0900-1100`.
+    /// The union keeps the label of `self`, if any, otherwise that of `other`.
     pub fn merge(&self, other: &Self) -> Option<Self> {
-        if self.intersects(other).not() {
+        if self.is_contiguous_with(other).not() {
             return None;
         }
         Some(Interval {
-            start: TimeOfDay::min(self.start, other.start),
-            end: TimeOfDay::max(self.end, other.end),
+            start: T::min(self.start, other.start),
+            end: T::max(self.end, other.end),
+            label: self.label.clone().or_else(|| other.label.clone()),
         })
     }
     pub fn is_empty(&self) -> bool {
         assert!(self.start <= self.end);
-        self.start < self.end
-    }
-    fn default_start() -> TimeOfDay {
-        DAY_BEGINS
-    }
-    fn default_end() -> TimeOfDay {
-        DAY_ENDS
+        self.start == self.end
     }
-    pub fn subtract(self, other: Interval) -> IntervalSubtraction {
+    /// Compute `self - other`. The remaining pieces keep the label of `self`.
+    pub fn subtract(self, other: Interval<T>) -> IntervalSubtraction<T> {
         match () {
             // `self` included in `other`.
             _ if self.start >= other.start && self.end <= other.end => IntervalSubtraction::Empty,
@@ -366,12 +474,14 @@ impl Interval {
                 IntervalSubtraction::HitLeft(Interval {
                     start: other.end,
                     end: self.end,
+                    label: self.label,
                 })
             }
-            _ if self.start <= other.start && self.end < other.end => {
-                IntervalSubtraction::HitLeft(Interval {
+            _ if self.start <= other.start && self.end <= other.end => {
+                IntervalSubtraction::HitRight(Interval {
                     start: self.start,
                     end: other.start,
+                    label: self.label,
                 })
             }
             // `other` included in `self`
@@ -379,43 +489,90 @@ impl Interval {
                 Interval {
                     start: self.start,
                     end: other.start,
+                    label: self.label.clone(),
                 },
                 Interval {
                     start: other.end,
                     end: self.end,
+                    label: self.label,
                 },
             ),
         }
     }
 }
 
+impl<T: Display> Display for Interval<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)?;
+        if let Some(ref label) = self.label {
+            write!(f, " ({label})")?;
+        }
+        Ok(())
+    }
+}
+
 /// The result of computing A - B on intervals
-pub enum IntervalSubtraction {
+pub enum IntervalSubtraction<T = TimeOfDay> {
     /// No overlap, B.start < B.end <= A.start.
-    MissLeft(Interval),
+    MissLeft(Interval<T>),
 
     /// Overlap, B.start <= A.start < B.end < A.end.
-    HitLeft(Interval),
+    HitLeft(Interval<T>),
 
-    HitCenter(Interval, Interval),
+    /// Overlap, A.start < B.start < B.end < A.end.
+    HitCenter(Interval<T>, Interval<T>),
 
-    /// Overlap, A.start <= B.start < A.end < B.end.
-    HitRight(Interval),
+    /// Overlap, A.start < B.start < A.end <= B.end.
+    HitRight(Interval<T>),
 
-    /// No overlap, B strictly after A
-    MissRight(Interval),
+    /// No overlap, A.end <= B.start.
+    MissRight(Interval<T>),
 
     /// A included in B.
     Empty,
 }
 
+/// An interval during which something is accepted, e.g. a binary is permitted.
 #[derive(Debug, Clone, Serialize, PartialEq)]
-pub struct AcceptedInterval(pub Interval);
-impl AcceptedInterval {
+pub struct AcceptedInterval<T = TimeOfDay>(pub Interval<T>);
+impl AcceptedInterval<TimeOfDay> {
+    /// The number of minutes covered by `intervals`, counting overlaps once.
+    pub fn total_minutes(intervals: &[AcceptedInterval]) -> u16 {
+        AcceptedInterval::simplify(intervals.to_vec())
+            .iter()
+            .map(|interval| interval.0.len())
+            .sum()
+    }
+
+    /// If `time` is within one of `intervals`, the duration until the end of the permission.
+    ///
+    /// Back-to-back intervals, e.g. `1600-1700` and `1700-1800`, count as a single permission,
+    /// so at `16:30` this is 90 minutes rather than 30.
+    pub fn remaining(intervals: &[AcceptedInterval], time: TimeOfDay) -> Option<Duration> {
+        let mut end = intervals
+            .iter()
+            .find(|interval| interval.0.remaining(time).is_some())?
+            .0
+            .end;
+        // Intervals are generally already simplified, but labels may keep them apart.
+        while let Some(next) = intervals
+            .iter()
+            .find(|interval| interval.0.contains(end))
+        {
+            end = next.0.end;
+        }
+        let end: Duration = end.into();
+        let time: Duration = time.into();
+        Some(end - time)
+    }
+}
+impl<T: Point> AcceptedInterval<T> {
     /// Simplify a bunch of accepted intervals.
-    pub fn simplify(mut intervals: Vec<AcceptedInterval>) -> Vec<AcceptedInterval> {
+    ///
+    /// The result is sorted, and contiguous intervals are merged.
+    pub fn simplify(mut intervals: Vec<AcceptedInterval<T>>) -> Vec<AcceptedInterval<T>> {
         intervals.sort_by_key(|interval| interval.0.start);
-        let mut normalized: Vec<AcceptedInterval> = vec![];
+        let mut normalized: Vec<AcceptedInterval<T>> = vec![];
         for interval in intervals {
             if let Some(latest) = normalized.last_mut() {
                 if let Some(merged) = latest.0.merge(&interval.0) {
@@ -431,21 +588,21 @@ impl AcceptedInterval {
 
     /// ```
     /// use keep_it_focused::types::*;
-    /// let accepted = vec![AcceptedInterval(Interval { start: TimeOfDay::START, end: TimeOfDay::END})];
-    /// let rejected = vec![RejectedInterval(Interval { start: TimeOfDay { hours: 12, minutes: 0}, end: TimeOfDay { hours: 12, minutes: 5} })];
+    /// let accepted = vec![AcceptedInterval(Interval { start: TimeOfDay::START, end: TimeOfDay::END, label: None })];
+    /// let rejected = vec![RejectedInterval(Interval { start: TimeOfDay { hours: 12, minutes: 0}, end: TimeOfDay { hours: 12, minutes: 5}, label: None })];
     ///
     /// let difference = AcceptedInterval::subtract(accepted, rejected);
     /// assert_eq!(difference, vec![
-    ///     AcceptedInterval(Interval { start: TimeOfDay::START, end: TimeOfDay { hours: 12, minutes: 0} }),
-    ///     AcceptedInterval(Interval { start: TimeOfDay { hours: 12, minutes: 5}, end: TimeOfDay::END }),
+    ///     AcceptedInterval(Interval { start: TimeOfDay::START, end: TimeOfDay { hours: 12, minutes: 0}, label: None }),
+    ///     AcceptedInterval(Interval { start: TimeOfDay { hours: 12, minutes: 5}, end: TimeOfDay::END, label: None }),
     /// ])
     /// ```
     pub fn subtract(
-        accepted: Vec<AcceptedInterval>,
-        rejected: Vec<RejectedInterval>,
-    ) -> Vec<AcceptedInterval> {
+        accepted: Vec<AcceptedInterval<T>>,
+        rejected: Vec<RejectedInterval<T>>,
+    ) -> Vec<AcceptedInterval<T>> {
         if rejected.is_empty() {
-            return accepted;
+            return Self::simplify(accepted);
         }
         let mut accepted = Self::simplify(accepted).into_iter().peekable();
         let mut rejected = RejectedInterval::simplify(rejected).into_iter().peekable();
@@ -486,7 +643,7 @@ impl AcceptedInterval {
                 IntervalSubtraction::HitRight(difference) => {
                     // We may still have intersections between `difference` and any further rejected interval.
                     // However, `difference` is strictly smaller than `acc`.
-                    assert!(difference.len() < acc.0.len());
+                    assert!(difference.end < acc.0.end);
                     *acc = AcceptedInterval(difference);
                 }
                 IntervalSubtraction::MissRight(unchanged) => {
@@ -503,13 +660,25 @@ impl AcceptedInterval {
 }
 
 /// A difference between two unions of intervals.
-#[derive(Default)]
-pub struct IntervalsDiff {
-    pub accepted: Vec<AcceptedInterval>,
-    pub rejected: Vec<RejectedInterval>,
+#[derive(Clone)]
+pub struct IntervalsDiff<T = TimeOfDay> {
+    pub accepted: Vec<AcceptedInterval<T>>,
+    pub rejected: Vec<RejectedInterval<T>>,
 }
-impl IntervalsDiff {
-    pub fn compute_accepted_intervals(from: Vec<IntervalsDiff>) -> Vec<AcceptedInterval> {
+impl<T> Default for IntervalsDiff<T> {
+    fn default() -> Self {
+        IntervalsDiff {
+            accepted: Vec::new(),
+            rejected: Vec::new(),
+        }
+    }
+}
+impl<T: Point> IntervalsDiff<T> {
+    /// Apply a sequence of diffs, starting from nothing accepted.
+    ///
+    /// This is a union: a point is accepted if some diff accepts it and no later diff
+    /// rejects it.
+    pub fn compute_accepted_intervals(from: Vec<IntervalsDiff<T>>) -> Vec<AcceptedInterval<T>> {
         // Successively add `accepted`, reject `rejected`.
         let mut accepted = vec![];
         for diff in from {
@@ -518,9 +687,82 @@ impl IntervalsDiff {
         }
         accepted
     }
-    pub fn compute_rejected_intervals(from: Vec<IntervalsDiff>) -> Vec<RejectedInterval> {
+    /// Intersect a sequence of diffs: a point is accepted only if every diff accepts it.
+    ///
+    /// A diff accepts its `accepted` intervals minus its `rejected` intervals. A diff without
+    /// any `accepted` interval accepts everything but its `rejected` intervals. Labels are
+    /// those of the first diff.
+    pub fn compute_intersected_accepted_intervals(from: Vec<IntervalsDiff<T>>) -> Vec<AcceptedInterval<T>> {
+        let mut result: Option<Vec<AcceptedInterval<T>>> = None;
+        for diff in from {
+            let accepted = if diff.accepted.is_empty() {
+                vec![AcceptedInterval(Interval {
+                    start: T::MIN,
+                    end: T::MAX,
+                    label: None,
+                })]
+            } else {
+                diff.accepted
+            };
+            let accepted = AcceptedInterval::subtract(accepted, diff.rejected);
+            result = Some(match result {
+                None => accepted,
+                // A & B = A - !B
+                Some(result) => AcceptedInterval::subtract(result, RejectedInterval::complement(accepted)),
+            });
+        }
+        result.unwrap_or_default()
+    }
+    /// Apply a sequence of diffs, starting from nothing accepted, and return the complement.
+    pub fn compute_rejected_intervals(from: Vec<IntervalsDiff<T>>) -> Vec<RejectedInterval<T>> {
         RejectedInterval::complement(Self::compute_accepted_intervals(from))
     }
+    /// Compute the intervals explicitly rejected by `from`, minus whatever has been
+    /// accepted again by a later diff, i.e. minus `accepted`, the result of
+    /// `compute_accepted_intervals(from)`.
+    ///
+    /// Rejected intervals keep their label. Intervals with distinct labels are never merged,
+    /// so they may overlap.
+    pub fn compute_explicitly_rejected_intervals(
+        from: &[IntervalsDiff<T>],
+        accepted: &[AcceptedInterval<T>],
+    ) -> Vec<RejectedInterval<T>> {
+        let accepted = accepted
+            .iter()
+            .map(|interval| RejectedInterval(interval.0.clone()))
+            .collect_vec();
+        from.iter()
+            .flat_map(|diff| diff.rejected.iter())
+            .map(|interval| AcceptedInterval(interval.0.clone()))
+            .into_group_map_by(|interval| interval.0.label.clone())
+            .into_values()
+            .flat_map(|explicit| AcceptedInterval::subtract(explicit, accepted.clone()))
+            .map(|interval| RejectedInterval(interval.0))
+            .sorted_by(|a, b| a.0.start.cmp(&b.0.start).then_with(|| a.0.label.cmp(&b.0.label)))
+            .collect()
+    }
+    /// Merge a sequence of diffs into a single diff, applying them in order as
+    /// `compute_accepted_intervals` does.
+    ///
+    /// This lets `compute_intersected_accepted_intervals` combine the rules of a single file
+    /// before intersecting them with those of other files. If the diffs accept some intervals
+    /// but none of them remains, the merged diff rejects everything.
+    pub fn merge(from: Vec<IntervalsDiff<T>>) -> IntervalsDiff<T> {
+        if from.len() == 1 {
+            return from.into_iter().next().unwrap();
+        }
+        let accepts = from.iter().any(|diff| diff.accepted.is_empty().not());
+        let accepted = Self::compute_accepted_intervals(from.clone());
+        let mut rejected = Self::compute_explicitly_rejected_intervals(&from, &accepted);
+        if accepts && accepted.is_empty() {
+            rejected.push(RejectedInterval(Interval {
+                start: T::MIN,
+                end: T::MAX,
+                label: None,
+            }));
+        }
+        IntervalsDiff { accepted, rejected }
+    }
 }
 
 /// From a list of intervals within a day, return the list of complementary intervals,
@@ -531,26 +773,40 @@ impl IntervalsDiff {
 ///   AcceptedInterval(Interval { // This interval represents 12:15-13:37
 ///     start: TimeOfDay { hours: 12, minutes: 15 },
 ///     end: TimeOfDay  { hours: 13, minutes: 37 },
+///     label: None,
 ///   })
 /// ]);
 /// assert_eq!(complement, vec![
 ///    RejectedInterval(Interval { // 00:00-12:15
 ///       start: TimeOfDay { hours: 0, minutes: 0 },
 ///       end: TimeOfDay { hours: 12, minutes: 15 },
+///       label: None,
 ///    }),
 ///    RejectedInterval(Interval { // 13:37-24:00
 ///       start: TimeOfDay { hours: 13, minutes: 37 },
 ///       end: TimeOfDay { hours: 24, minutes: 00 },
+///       label: None,
 ///    })
 /// ]);
 /// ```
 #[derive(Debug, Clone, Serialize, PartialEq)]
-pub struct RejectedInterval(pub Interval);
-impl RejectedInterval {
-    /// Simplify a bunch of accepted intervals.
-    pub fn simplify(mut intervals: Vec<RejectedInterval>) -> Vec<RejectedInterval> {
+pub struct RejectedInterval<T = TimeOfDay>(pub Interval<T>);
+impl RejectedInterval<TimeOfDay> {
+    /// The number of minutes covered by `intervals`, counting overlaps once.
+    pub fn total_minutes(intervals: &[RejectedInterval]) -> u16 {
+        RejectedInterval::simplify(intervals.to_vec())
+            .iter()
+            .map(|interval| interval.0.len())
+            .sum()
+    }
+}
+impl<T: Point> RejectedInterval<T> {
+    /// Simplify a bunch of rejected intervals.
+    ///
+    /// The result is sorted, and contiguous intervals are merged.
+    pub fn simplify(mut intervals: Vec<RejectedInterval<T>>) -> Vec<RejectedInterval<T>> {
         intervals.sort_by_key(|interval| interval.0.start);
-        let mut normalized: Vec<RejectedInterval> = vec![];
+        let mut normalized: Vec<RejectedInterval<T>> = vec![];
         for interval in intervals {
             if let Some(latest) = normalized.last_mut() {
                 if let Some(merged) = latest.0.merge(&interval.0) {
@@ -564,8 +820,8 @@ impl RejectedInterval {
         normalized
     }
 
-    /// Complement a bunch of accepted intervals into rejected intervals.
-    pub fn complement(intervals: Vec<AcceptedInterval>) -> Vec<RejectedInterval> {
+    /// Complement a bunch of accepted intervals into rejected intervals, within `T::MIN..T::MAX`.
+    pub fn complement(intervals: Vec<AcceptedInterval<T>>) -> Vec<RejectedInterval<T>> {
         let accepted = AcceptedInterval::simplify(intervals);
 
         // Obtain the intervals during which use is forbidden.
@@ -573,17 +829,12 @@ impl RejectedInterval {
         if accepted.is_empty() {
             // Trivial case: nothing is permitted, so reject the entire day.
             complement.push(RejectedInterval(Interval {
-                start: TimeOfDay {
-                    hours: 0,
-                    minutes: 0,
-                },
-                end: TimeOfDay {
-                    hours: 24,
-                    minutes: 0,
-                },
+                start: T::MIN,
+                end: T::MAX,
+                label: None,
             }));
         } else {
-            let mut latest_in = DAY_BEGINS;
+            let mut latest_in = T::MIN;
             for interval in accepted {
                 if interval.0.start > latest_in {
                     // Nothing is permitted between `latest_in` and `interval.0.start`,
@@ -591,14 +842,16 @@ impl RejectedInterval {
                     complement.push(RejectedInterval(Interval {
                         start: latest_in,
                         end: interval.0.start,
+                        label: None,
                     }));
                 }
                 latest_in = interval.0.end;
             }
-            if latest_in < DAY_ENDS {
+            if latest_in < T::MAX {
                 complement.push(RejectedInterval(Interval {
                     start: latest_in,
-                    end: DAY_ENDS,
+                    end: T::MAX,
+                    label: None,
                 }));
             }
         }
@@ -611,6 +864,452 @@ mod test {
     use itertools::Itertools;
 
     use crate::types::*;
+
+    #[test]
+    fn test_domain_parse() {
+        assert_eq!(
+            Domain::parse("YouTube.com").unwrap(),
+            Domain("youtube.com".to_string())
+        );
+        assert_eq!(
+            Domain::parse("1.2.3.0/24").unwrap(),
+            Domain("1.2.3.0/24".to_string())
+        );
+        // URLs, paths, spaces and trailing dots are rejected.
+        assert!(Domain::parse("https://youtube.com/").is_err());
+        assert!(Domain::parse("youtube.com/watch").is_err());
+        assert!(Domain::parse("you tube.com").is_err());
+        assert!(Domain::parse("").is_err());
+        assert!(Domain::parse(".").is_err());
+
+        // The same rules apply when reading the configuration.
+        assert!(serde_yaml::from_str::<Domain>("https://youtube.com/").is_err());
+        assert_eq!(
+            serde_yaml::from_str::<Domain>("youtube.com").unwrap(),
+            Domain("youtube.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_domain_normalize() {
+        let youtube = Domain("youtube.com".to_string());
+        assert_eq!(Domain::parse("youtube.com.").unwrap(), youtube);
+        assert_eq!(Domain::parse("YOUTUBE.com.").unwrap(), youtube);
+        assert_eq!(Domain::parse("www.youtube.com").unwrap().fold_www(), youtube);
+        assert_eq!(Domain::parse("www.youtube.com.").unwrap().fold_www(), youtube);
+        assert_eq!(youtube.fold_www(), youtube);
+        // Other subdomains are preserved.
+        assert_eq!(
+            Domain::parse("music.youtube.com").unwrap().fold_www(),
+            Domain("music.youtube.com".to_string())
+        );
+        // Don't fold a domain into a top-level domain.
+        assert_eq!(
+            Domain::parse("www.com").unwrap().fold_www(),
+            Domain("www.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_day_of_week_parse() {
+        assert_eq!(DayOfWeek::parse("Monday").unwrap(), DayOfWeek::monday());
+        assert_eq!(DayOfWeek::parse("sun").unwrap(), DayOfWeek::sunday());
+        assert_eq!(DayOfWeek::parse("2").unwrap(), DayOfWeek::wednesday());
+        // Regression tests: these used to panic.
+        assert!(DayOfWeek::parse("mo").is_err());
+        assert_eq!(DayOfWeek::parse("Dimanche").unwrap(), DayOfWeek::sunday());
+        assert_eq!(DayOfWeek::parse("mercredi").unwrap(), DayOfWeek::wednesday());
+        assert_eq!(DayOfWeek::parse("Donnerstag").unwrap(), DayOfWeek::thursday());
+        assert_eq!(DayOfWeek::parse("samstag").unwrap(), DayOfWeek::saturday());
+        assert_eq!(serde_yaml::from_str::<DayOfWeek>("lundi").unwrap(), DayOfWeek::monday());
+        assert!(DayOfWeek::parse("").is_err());
+        assert!(DayOfWeek::parse("été").is_err());
+        assert!(serde_yaml::from_str::<DayOfWeek>("\"\"").is_err());
+        assert!(serde_yaml::from_str::<DayOfWeek>("\"é\"").is_err());
+        assert_eq!(
+            serde_yaml::from_str::<DayOfWeek>("\"0\"").unwrap(),
+            DayOfWeek::monday()
+        );
+
+        assert_eq!(DayOfWeek::from_name("Monday"), Some(DayOfWeek::monday()));
+        assert_eq!(DayOfWeek::from_name("dimanche"), Some(DayOfWeek::sunday()));
+        assert_eq!(DayOfWeek::from_name("donnerstag"), Some(DayOfWeek::thursday()));
+        assert_eq!(DayOfWeek::from_name("mon"), None);
+        assert_eq!(DayOfWeek::from_name("sam"), None);
+        assert_eq!(DayOfWeek::from_name("0"), None);
+    }
+
+    #[test]
+    fn test_day_of_week_parse_days() {
+        let days = |source| DayOfWeek::parse_days(source).unwrap();
+        assert_eq!(days("sun"), vec![DayOfWeek::sunday()]);
+        assert_eq!(days("sat,sun"), vec![DayOfWeek::saturday(), DayOfWeek::sunday()]);
+        assert_eq!(days("mon-fri"), DayOfWeek::all().take(5).collect_vec());
+        // Wrap around the end of the week.
+        assert_eq!(
+            days("fri-mon"),
+            vec![DayOfWeek::friday(), DayOfWeek::saturday(), DayOfWeek::sunday(), DayOfWeek::monday()]
+        );
+        assert_eq!(days("mon-sun"), DayOfWeek::all().collect_vec());
+        assert_eq!(days("wed-wed, mon"), vec![DayOfWeek::wednesday(), DayOfWeek::monday()]);
+        // Duplicates are ignored.
+        assert_eq!(days("sat-sun,sun"), vec![DayOfWeek::saturday(), DayOfWeek::sunday()]);
+        assert!(DayOfWeek::parse_days("mon-").is_err());
+        assert!(DayOfWeek::parse_days("16:00-18:00").is_err());
+        assert!(DayOfWeek::parse_days("").is_err());
+    }
+
+    #[test]
+    fn test_username_split_profile() {
+        let alice = Username("alice".to_string());
+        assert_eq!(alice.split_profile(), (alice.clone(), None));
+        assert_eq!(Username("alice:kids".to_string()).split_profile(), (alice, Some("kids")));
+    }
+
+    #[test]
+    fn test_time_of_day_parse() {
+        // `parse` and `Deserialize` share the same validation, whether the source is a string
+        // or a number.
+        for (source, expected) in [
+            ("2400", Some(DAY_ENDS)),
+            ("2401", None),
+            ("2500", None),
+            ("1135", Some(TimeOfDay { hours: 11, minutes: 35 })),
+            ("11:35", Some(TimeOfDay { hours: 11, minutes: 35 })),
+            ("11355", None),
+        ] {
+            let parsed = TimeOfDay::parse(source);
+            let from_str = serde_yaml::from_str::<TimeOfDay>(&format!("\"{source}\""));
+            match expected {
+                Some(expected) => {
+                    assert_eq!(parsed.unwrap(), expected, "{source}");
+                    assert_eq!(from_str.unwrap(), expected, "{source}");
+                }
+                None => {
+                    let err = parsed.unwrap_err().to_string();
+                    assert!(from_str.unwrap_err().to_string().contains(&err), "{source}");
+                }
+            }
+        }
+        assert_eq!(serde_yaml::from_str::<TimeOfDay>("2400").unwrap(), DAY_ENDS);
+        let err = serde_yaml::from_str::<TimeOfDay>("2401").unwrap_err().to_string();
+        assert!(err.contains(&TimeOfDay::parse("2401").unwrap_err().to_string()), "{err}");
+        assert!(serde_yaml::from_str::<TimeOfDay>("1260").is_err());
+    }
+
+    #[test]
+    fn test_add_minutes_past_midnight() {
+        let start = TimeOfDay {
+            hours: 23,
+            minutes: 30,
+        };
+        assert_eq!(start.saturating_add_minutes(60), DAY_ENDS);
+        assert_eq!(start.saturating_add_minutes(u16::MAX), DAY_ENDS);
+        assert_eq!(
+            start.saturating_add_minutes(15),
+            TimeOfDay {
+                hours: 23,
+                minutes: 45
+            }
+        );
+        assert_eq!(TimeOfDay::from_minutes(24 * 60 + 30), DAY_ENDS);
+    }
+
+    #[test]
+    fn test_time_of_day_on() {
+        let day = NaiveDate::from_ymd_opt(2024, 11, 4).unwrap();
+        let at = |time: TimeOfDay| time.on(day).unwrap().naive_local();
+        assert_eq!(at(TimeOfDay { hours: 23, minutes: 30 }), day.and_hms_opt(23, 30, 0).unwrap());
+        assert_eq!(at(DAY_ENDS), day.succ_opt().unwrap().and_hms_opt(0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_interval_boundaries() {
+        let hhmm = |hours, minutes| TimeOfDay { hours, minutes };
+        let interval = |start, end| Interval::new(start, end).unwrap();
+        let nine_ten = interval(hhmm(9, 0), hhmm(10, 0));
+        let ten_eleven = interval(hhmm(10, 0), hhmm(11, 0));
+
+        // The start is included, the end isn't.
+        assert_eq!(nine_ten.remaining(hhmm(9, 0)), Some(Duration::from_secs(3600)));
+        assert_eq!(nine_ten.remaining(hhmm(9, 59)), Some(Duration::from_secs(60)));
+        assert_eq!(nine_ten.remaining(hhmm(10, 0)), None);
+        assert_eq!(ten_eleven.remaining(hhmm(10, 0)), Some(Duration::from_secs(3600)));
+        assert!(nine_ten.contains(hhmm(9, 0)));
+        assert!(nine_ten.contains(hhmm(9, 59)));
+        assert!(!nine_ten.contains(hhmm(10, 0)));
+        assert!(!nine_ten.contains(hhmm(8, 59)));
+        assert_eq!(nine_ten.duration(), Duration::from_secs(3600));
+
+        // Empty intervals contain nothing, inverted intervals are rejected.
+        let empty = interval(hhmm(10, 0), hhmm(10, 0));
+        assert!(!empty.contains(hhmm(10, 0)));
+        assert_eq!(empty.duration(), Duration::ZERO);
+        let err = Interval::new(hhmm(11, 0), hhmm(10, 0)).unwrap_err();
+        assert_eq!(err.to_string(), "interval would end (10:00) before it starts (11:00)");
+        // ... unless they're built otherwise, in which case they're empty, too.
+        let inverted = Interval::builder().start(hhmm(11, 0)).end(hhmm(10, 0)).build();
+        assert!(!inverted.contains(hhmm(10, 30)));
+        assert_eq!(inverted.len(), 0);
+        assert_eq!(inverted.duration(), Duration::ZERO);
+
+        // Touching intervals don't intersect, but their union is an interval.
+        assert!(nine_ten.intersects(&ten_eleven).not());
+        assert!(ten_eleven.intersects(&nine_ten).not());
+        assert_eq!(nine_ten.merge(&ten_eleven), Some(interval(hhmm(9, 0), hhmm(11, 0))));
+        assert_eq!(ten_eleven.merge(&nine_ten), Some(interval(hhmm(9, 0), hhmm(11, 0))));
+        let eleven_noon = interval(hhmm(11, 0), hhmm(12, 0));
+        assert!(nine_ten.merge(&eleven_noon).is_none());
+
+        // Intersection doesn't depend on the order.
+        let all_morning = interval(hhmm(8, 0), hhmm(12, 0));
+        assert!(nine_ten.intersects(&all_morning));
+        assert!(all_morning.intersects(&nine_ten));
+
+        // Touching intervals are simplified into one.
+        let simplified = AcceptedInterval::simplify(vec![
+            AcceptedInterval(ten_eleven.clone()),
+            AcceptedInterval(nine_ten.clone()),
+            AcceptedInterval(interval(hhmm(14, 0), hhmm(15, 0))),
+        ]);
+        assert_eq!(
+            simplified,
+            vec![
+                AcceptedInterval(interval(hhmm(9, 0), hhmm(11, 0))),
+                AcceptedInterval(interval(hhmm(14, 0), hhmm(15, 0))),
+            ]
+        );
+
+        // Subtracting a touching interval changes nothing.
+        let difference = AcceptedInterval::subtract(
+            vec![AcceptedInterval(nine_ten.clone())],
+            vec![RejectedInterval(ten_eleven.clone())],
+        );
+        assert_eq!(difference, vec![AcceptedInterval(nine_ten.clone())]);
+
+        // Subtracting an interval ending with ours leaves no empty interval behind.
+        let difference = AcceptedInterval::subtract(
+            vec![AcceptedInterval(interval(hhmm(9, 0), hhmm(11, 0)))],
+            vec![RejectedInterval(ten_eleven.clone())],
+        );
+        assert_eq!(difference, vec![AcceptedInterval(nine_ten.clone())]);
+
+        // A rejected interval overlapping the end of an accepted interval may also overlap the
+        // next accepted interval.
+        let difference = AcceptedInterval::subtract(
+            vec![
+                AcceptedInterval(nine_ten.clone()),
+                AcceptedInterval(eleven_noon.clone()),
+            ],
+            vec![RejectedInterval(interval(hhmm(9, 30), hhmm(11, 30)))],
+        );
+        assert_eq!(
+            difference,
+            vec![
+                AcceptedInterval(interval(hhmm(9, 0), hhmm(9, 30))),
+                AcceptedInterval(interval(hhmm(11, 30), hhmm(12, 0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explicitly_rejected_labels() {
+        let hhmm = |hours, minutes| TimeOfDay { hours, minutes };
+        let interval = |start, end, label: Option<&str>| Interval {
+            start,
+            end,
+            label: label.map(str::to_string),
+        };
+        let diffs = vec![
+            IntervalsDiff {
+                accepted: vec![],
+                rejected: vec![
+                    RejectedInterval(interval(hhmm(16, 0), hhmm(17, 0), Some("homework"))),
+                    RejectedInterval(interval(hhmm(17, 0), hhmm(18, 0), Some("dinner"))),
+                ],
+            },
+            // Exceptionally permitted.
+            IntervalsDiff {
+                accepted: vec![AcceptedInterval(interval(hhmm(16, 30), hhmm(16, 45), None))],
+                rejected: vec![],
+            },
+        ];
+        let accepted = IntervalsDiff::compute_accepted_intervals(diffs.clone());
+        let rejected = IntervalsDiff::compute_explicitly_rejected_intervals(&diffs, &accepted);
+        // Touching intervals with distinct labels are not merged, pieces keep their label.
+        assert_eq!(
+            rejected,
+            vec![
+                RejectedInterval(interval(hhmm(16, 0), hhmm(16, 30), Some("homework"))),
+                RejectedInterval(interval(hhmm(16, 45), hhmm(17, 0), Some("homework"))),
+                RejectedInterval(interval(hhmm(17, 0), hhmm(18, 0), Some("dinner"))),
+            ]
+        );
+        assert_eq!(rejected[0].0.to_string(), "16:00-16:30 (homework)");
+    }
+
+    #[test]
+    fn test_intersected_intervals() {
+        let hhmm = |hours, minutes| TimeOfDay { hours, minutes };
+        let interval = |start, end| Interval {
+            start,
+            end,
+            label: None,
+        };
+        let diffs = vec![
+            // Main configuration.
+            IntervalsDiff {
+                accepted: vec![AcceptedInterval(interval(hhmm(16, 0), hhmm(19, 0)))],
+                rejected: vec![RejectedInterval(interval(hhmm(17, 0), hhmm(17, 30)))],
+            },
+            // Another source, only forbidding.
+            IntervalsDiff {
+                accepted: vec![],
+                rejected: vec![RejectedInterval(interval(hhmm(18, 30), hhmm(20, 0)))],
+            },
+            // Another source, permitting outside of the main configuration.
+            IntervalsDiff {
+                accepted: vec![AcceptedInterval(interval(hhmm(9, 0), hhmm(18, 0)))],
+                rejected: vec![],
+            },
+        ];
+        // Union: later sources may permit more.
+        assert_eq!(
+            IntervalsDiff::compute_accepted_intervals(diffs.clone()),
+            vec![AcceptedInterval(interval(hhmm(9, 0), hhmm(18, 30)))]
+        );
+        // Intersection: every source must permit.
+        assert_eq!(
+            IntervalsDiff::compute_intersected_accepted_intervals(diffs),
+            vec![
+                AcceptedInterval(interval(hhmm(16, 0), hhmm(17, 0))),
+                AcceptedInterval(interval(hhmm(17, 30), hhmm(18, 0))),
+            ]
+        );
+        assert_eq!(IntervalsDiff::<TimeOfDay>::compute_intersected_accepted_intervals(vec![]), vec![]);
+
+        // Merged diffs are unions.
+        let merged = IntervalsDiff::merge(vec![
+            IntervalsDiff {
+                accepted: vec![AcceptedInterval(interval(hhmm(9, 0), hhmm(10, 0)))],
+                rejected: vec![],
+            },
+            IntervalsDiff {
+                accepted: vec![AcceptedInterval(interval(hhmm(16, 0), hhmm(19, 0)))],
+                rejected: vec![],
+            },
+        ]);
+        assert_eq!(
+            IntervalsDiff::compute_intersected_accepted_intervals(vec![merged]),
+            vec![
+                AcceptedInterval(interval(hhmm(9, 0), hhmm(10, 0))),
+                AcceptedInterval(interval(hhmm(16, 0), hhmm(19, 0))),
+            ]
+        );
+        // If nothing remains accepted, nothing is.
+        let merged = IntervalsDiff::merge(vec![
+            IntervalsDiff {
+                accepted: vec![AcceptedInterval(interval(hhmm(9, 0), hhmm(10, 0)))],
+                rejected: vec![],
+            },
+            IntervalsDiff {
+                accepted: vec![],
+                rejected: vec![RejectedInterval(interval(hhmm(9, 0), hhmm(10, 0)))],
+            },
+        ]);
+        assert_eq!(IntervalsDiff::compute_intersected_accepted_intervals(vec![merged]), vec![]);
+    }
+
+    #[test]
+    fn test_remaining_accepted() {
+        let hhmm = |hours, minutes| TimeOfDay { hours, minutes };
+        let interval = |start, end, label: Option<&str>| {
+            AcceptedInterval(Interval {
+                start,
+                end,
+                label: label.map(str::to_string),
+            })
+        };
+        let minutes = |m: u64| Some(Duration::from_secs(m * 60));
+        let intervals = vec![
+            interval(hhmm(18, 0), hhmm(19, 0), Some("evening")),
+            interval(hhmm(9, 0), hhmm(10, 0), None),
+            interval(hhmm(16, 0), hhmm(17, 0), None),
+            interval(hhmm(17, 0), hhmm(18, 0), Some("after homework")),
+        ];
+        // Adjacent intervals sum up, whatever their order and labels.
+        assert_eq!(AcceptedInterval::remaining(&intervals, hhmm(16, 30)), minutes(150));
+        assert_eq!(AcceptedInterval::remaining(&intervals, hhmm(18, 0)), minutes(60));
+        // Gaps don't.
+        assert_eq!(AcceptedInterval::remaining(&intervals, hhmm(9, 59)), minutes(1));
+        assert_eq!(AcceptedInterval::remaining(&intervals, hhmm(10, 0)), None);
+        assert_eq!(AcceptedInterval::remaining(&intervals, hhmm(19, 0)), None);
+        assert_eq!(AcceptedInterval::remaining(&[], hhmm(12, 0)), None);
+    }
+
+    #[test]
+    fn test_total_minutes() {
+        let hhmm = |hours, minutes| TimeOfDay { hours, minutes };
+        let interval = |start, end| Interval::new(start, end).unwrap();
+        let accepted = vec![
+            AcceptedInterval(interval(hhmm(16, 0), hhmm(17, 30))),
+            AcceptedInterval(interval(hhmm(9, 0), hhmm(10, 0))),
+            // Overlaps count once.
+            AcceptedInterval(interval(hhmm(17, 0), hhmm(18, 0))),
+        ];
+        assert_eq!(AcceptedInterval::total_minutes(&accepted), 180);
+        assert_eq!(AcceptedInterval::total_minutes(&[]), 0);
+        let rejected = vec![RejectedInterval(interval(TimeOfDay::START, TimeOfDay::END))];
+        assert_eq!(RejectedInterval::total_minutes(&rejected), 24 * 60);
+    }
+
+    #[test]
+    fn test_generic_points() {
+        // The algebra doesn't depend on times of day.
+        #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+        struct Page(u32);
+        impl Point for Page {
+            const MIN: Self = Page(0);
+            const MAX: Self = Page(100);
+        }
+        let interval = |start, end| Interval {
+            start: Page(start),
+            end: Page(end),
+            label: None,
+        };
+        let diffs = vec![
+            IntervalsDiff {
+                accepted: vec![
+                    AcceptedInterval(interval(10, 20)),
+                    AcceptedInterval(interval(20, 40)),
+                ],
+                rejected: vec![],
+            },
+            IntervalsDiff {
+                accepted: vec![],
+                rejected: vec![RejectedInterval(interval(25, 30))],
+            },
+        ];
+        let accepted = IntervalsDiff::compute_accepted_intervals(diffs.clone());
+        assert_eq!(
+            accepted,
+            vec![
+                AcceptedInterval(interval(10, 25)),
+                AcceptedInterval(interval(30, 40)),
+            ]
+        );
+        assert_eq!(
+            IntervalsDiff::compute_rejected_intervals(diffs),
+            vec![
+                RejectedInterval(interval(0, 10)),
+                RejectedInterval(interval(25, 30)),
+                RejectedInterval(interval(40, 100)),
+            ]
+        );
+        assert!(interval(0, 10).is_contiguous_with(&interval(10, 20)));
+        assert!(interval(0, 10).intersects(&interval(10, 20)).not());
+    }
+
     #[test]
     fn test_interval_sub() {
         let diffs = vec![
@@ -626,6 +1325,7 @@ mod test {
                                 hours: hh,
                                 minutes: 10,
                             },
+                            label: None,
                         })
                     })
                     .collect_vec(),
@@ -640,6 +1340,7 @@ mod test {
                             hours: 1,
                             minutes: 9,
                         },
+                        label: None,
                     }),
                     // This doesn't intersect with anything
                     RejectedInterval(Interval {
@@ -651,6 +1352,7 @@ mod test {
                             hours: 1,
                             minutes: 20,
                         },
+                        label: None,
                     }),
                     RejectedInterval(Interval {
                         start: TimeOfDay {
@@ -661,6 +1363,7 @@ mod test {
                             hours: 3,
                             minutes: 1,
                         },
+                        label: None,
                     }),
                 ],
             },
@@ -671,6 +1374,7 @@ mod test {
                         minutes: 0,
                     },
                     end: TimeOfDay::END,
+                    label: None,
                 })],
                 rejected: vec![
                     RejectedInterval(Interval {
@@ -682,6 +1386,7 @@ mod test {
                             hours: 9,
                             minutes: 9,
                         },
+                        label: None,
                     }),
                     RejectedInterval(Interval {
                         start: TimeOfDay {
@@ -692,6 +1397,7 @@ mod test {
                             hours: 7,
                             minutes: 11,
                         },
+                        label: None,
                     }),
                     RejectedInterval(Interval {
                         start: TimeOfDay {
@@ -702,6 +1408,7 @@ mod test {
                             hours: 6,
                             minutes: 11,
                         },
+                        label: None,
                     }),
                     RejectedInterval(Interval {
                         start: TimeOfDay {
@@ -712,6 +1419,7 @@ mod test {
                             hours: 4,
                             minutes: 7,
                         },
+                        label: None,
                     }),
                 ],
             },
@@ -728,7 +1436,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 1,
                         minutes: 10
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -738,7 +1447,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 2,
                         minutes: 10
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -748,7 +1458,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 3,
                         minutes: 10
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -758,7 +1469,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 4,
                         minutes: 5
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -768,7 +1480,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 4,
                         minutes: 10
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -778,7 +1491,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 7,
                         minutes: 1
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -788,7 +1502,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 8,
                         minutes: 10
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -798,7 +1513,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 9,
                         minutes: 10
-                    }
+                    },
+                    label: None,
                 }),
                 AcceptedInterval(Interval {
                     start: TimeOfDay {
@@ -808,7 +1524,8 @@ mod test {
                     end: TimeOfDay {
                         hours: 24,
                         minutes: 0
-                    }
+                    },
+                    label: None,
                 }),
             ]
         )
@@ -817,9 +1534,98 @@ mod test {
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize, AsRef, Deref, Display)]
 pub struct Username(pub String);
+impl Username {
+    /// Split a name such as `alice:kids` into the account (`alice`) and the profile (`kids`),
+    /// for accounts shared by several people.
+    ///
+    /// As `:` separates fields in `/etc/passwd`, it cannot appear in the name of an account.
+    pub fn split_profile(&self) -> (Username, Option<&str>) {
+        match self.0.split_once(':') {
+            Some((account, profile)) => (Username(account.to_string()), Some(profile)),
+            None => (self.clone(), None),
+        }
+    }
+}
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize, AsRef, Deref, Display)]
+/// A domain name (e.g. "youtube.com") or an IP address (e.g. "1.2.3.4", "1.2.3.0/24").
+///
+/// When deserialized or parsed, domains are validated and normalized to lowercase,
+/// without trailing dot (i.e. "YouTube.com." is the same domain as "youtube.com").
+///
+/// For web rules, a domain always covers all its subdomains: the browser extension
+/// matches "youtube.com" against both "youtube.com" and "*.youtube.com". Consequently,
+/// a web rule on "www.youtube.com" is folded into a rule on "youtube.com", see `fold_www`.
+/// However, a rule on a subdomain overrides the rules on its parents, see `specificity`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, AsRef, Deref, Display)]
 pub struct Domain(pub String);
+impl Domain {
+    pub fn parse(source: &str) -> Result<Self, anyhow::Error> {
+        const EXPECTED: &str = "expected a bare host name such as \"youtube.com\"";
+        if source.contains("://") {
+            return Err(anyhow!("invalid domain \"{source}\", {EXPECTED}, without scheme"));
+        }
+        // IP addresses, possibly with a mask, are useful for iptables.
+        let (address, mask) = match source.split_once('/') {
+            Some((address, mask)) if mask.parse::<u8>().is_ok() => (address, Some(mask)),
+            Some(_) => {
+                return Err(anyhow!("invalid domain \"{source}\", {EXPECTED}, without path"));
+            }
+            None => (source, None),
+        };
+        if address.parse::<std::net::IpAddr>().is_ok() {
+            return Ok(Domain(source.to_string()));
+        }
+        if mask.is_some() {
+            return Err(anyhow!("invalid domain \"{source}\", {EXPECTED}, without path"));
+        }
+        if source.is_empty() || source == "." || source.len() > 254 {
+            return Err(anyhow!("invalid domain \"{source}\", {EXPECTED}"));
+        }
+        // "youtube.com." is the fully qualified version of "youtube.com".
+        let normalized = source.strip_suffix('.').unwrap_or(source).to_ascii_lowercase();
+        for label in normalized.split('.') {
+            let is_valid = (1..=63).contains(&label.len())
+                && label.starts_with('-').not()
+                && label.ends_with('-').not()
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            if is_valid.not() {
+                return Err(anyhow!("invalid domain \"{source}\", {EXPECTED}, invalid component \"{label}\""));
+            }
+        }
+        Ok(Domain(normalized))
+    }
+
+    /// How specific this domain is, i.e. its number of components, e.g. 3 for "docs.google.com".
+    ///
+    /// For a given host, the web rule on the most specific domain covering the host wins,
+    /// e.g. a rule on "docs.google.com" overrides a rule on "google.com" for "docs.google.com"
+    /// and its subdomains, but not for "mail.google.com".
+    pub fn specificity(&self) -> usize {
+        self.0.split('.').count()
+    }
+
+    /// Strip any leading "www.", e.g. "www.youtube.com" becomes "youtube.com".
+    ///
+    /// As web rules include subdomains, this broadens the rule to the entire site, which
+    /// is what users typically mean. Don't use this for iptables, as "www.youtube.com" and
+    /// "youtube.com" may resolve to different addresses.
+    pub fn fold_www(&self) -> Domain {
+        match self.0.strip_prefix("www.") {
+            Some(rest) if rest.contains('.') => Domain(rest.to_string()),
+            _ => self.clone(),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for Domain {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let source = String::deserialize(deserializer)?;
+        Domain::parse(&source).map_err(|err| D::Error::custom(format!("{err}")))
+    }
+}
 
 pub fn is_today(date: SystemTime) -> bool {
     let latest_update_chrono = DateTime::<Local>::from(date);
@@ -0,0 +1,192 @@
+//! Watching D-Bus for app launches, to check them right away rather than at the next sweep.
+//!
+//! Best effort: this relies on `dbus-monitor` and on launchers that use GIO (e.g. GNOME Shell),
+//! which signal `org.gtk.gio.DesktopAppInfo.Launched` on the session bus of the user. Apps
+//! launched otherwise are caught by the next sweep, as usual.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader},
+    ops::Not,
+    path::Path,
+    process::Stdio,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+
+use crate::unix::uid_resolver::Uid;
+
+/// The signals sent by GIO when an app is launched.
+const LAUNCHED_MATCH: &str = "type='signal',interface='org.gtk.gio.DesktopAppInfo',member='Launched'";
+
+/// The shortest delay between two checks triggered by the launches of the same user.
+///
+/// Users control their own session bus, so without this, they could make us sweep `/proc`
+/// continuously by sending `Launched` signals back to back.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One `dbus-monitor` per user, each on its own thread.
+pub struct LaunchWatcher {
+    watchers: HashMap<Uid, JoinHandle<()>>,
+    sender: Sender<Uid>,
+    receiver: Receiver<Uid>,
+
+    /// When the launches of each user last triggered a check.
+    last_checks: HashMap<Uid, Instant>,
+
+    /// The users who have launched an app since their last check, but too soon to check again.
+    pending: HashSet<Uid>,
+}
+impl Default for LaunchWatcher {
+    fn default() -> Self {
+        LaunchWatcher::new()
+    }
+}
+impl LaunchWatcher {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        LaunchWatcher {
+            watchers: HashMap::new(),
+            sender,
+            receiver,
+            last_checks: HashMap::new(),
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Make sure that we're watching the session bus of `user`, if they have one.
+    ///
+    /// A watcher that has stopped, e.g. because the user logged out, is restarted.
+    pub fn watch(&mut self, uid: Uid, user: &str) {
+        if self.watchers.get(&uid).is_some_and(|watcher| watcher.is_finished().not()) {
+            return;
+        }
+        let bus = format!("/run/user/{}/bus", uid.0);
+        if Path::new(&bus).exists().not() {
+            // No session, nothing to watch.
+            return;
+        }
+        let mut command = super::command("runuser");
+        command
+            .args(["-u", user, "--", "dbus-monitor", "--address"])
+            .arg(format!("unix:path={bus}"))
+            .arg(LAUNCHED_MATCH)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                debug!("cannot watch app launches of {user}: {err}");
+                return;
+            }
+        };
+        info!("watching app launches of {user}");
+        let sender = self.sender.clone();
+        let user = user.to_string();
+        let watcher = std::thread::spawn(move || {
+            if let Some(stdout) = child.stdout.take() {
+                for line in BufReader::new(stdout).lines() {
+                    let Ok(line) = line else { break };
+                    if is_launch(&line) {
+                        debug!("{user} launched an app");
+                        if sender.send(uid).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+            debug!("stopped watching app launches of {user}");
+        });
+        self.watchers.insert(uid, watcher);
+    }
+
+    /// Wait until `deadline` for a user to launch an app.
+    ///
+    /// Launches are coalesced: a user is returned at most once every `MIN_CHECK_INTERVAL`,
+    /// however many apps they launch meanwhile.
+    pub fn wait_until(&mut self, deadline: Instant) -> Option<Uid> {
+        loop {
+            let now = Instant::now();
+            if let Some(uid) = self.pending.iter().copied().find(|uid| self.may_check(*uid, now)) {
+                self.pending.remove(&uid);
+                self.last_checks.insert(uid, now);
+                return Some(uid);
+            }
+            if now >= deadline {
+                // The sweep that follows checks everybody anyway.
+                self.pending.clear();
+                return None;
+            }
+            // Wake up when the next user held back may be checked.
+            let wake_up = self
+                .pending
+                .iter()
+                .filter_map(|uid| self.last_checks.get(uid))
+                .map(|last| *last + MIN_CHECK_INTERVAL)
+                .min()
+                .map_or(deadline, |wake_up| wake_up.min(deadline));
+            if let Ok(uid) = self.receiver.recv_timeout(wake_up.saturating_duration_since(now)) {
+                self.pending.insert(uid);
+            }
+        }
+    }
+
+    /// Whether the launches of `uid` may trigger a check at `now`.
+    fn may_check(&self, uid: Uid, now: Instant) -> bool {
+        self.last_checks
+            .get(&uid)
+            .is_none_or(|last| now.saturating_duration_since(*last) >= MIN_CHECK_INTERVAL)
+    }
+}
+
+/// Whether a line printed by `dbus-monitor` is the header of a `Launched` signal.
+fn is_launch(line: &str) -> bool {
+    line.starts_with("signal ") && line.contains("member=Launched")
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::unix::uid_resolver::Uid;
+
+    use super::{is_launch, LaunchWatcher, MIN_CHECK_INTERVAL};
+
+    #[test]
+    fn test_is_launch() {
+        assert!(is_launch("signal time=1700000000.123 sender=:1.42 -> destination=(null destination) serial=12 path=/org/gtk/gio/DesktopAppInfo; interface=org.gtk.gio.DesktopAppInfo; member=Launched"));
+        assert!(!is_launch("signal time=1700000000.123 sender=org.freedesktop.DBus -> destination=:1.43 serial=2 path=/org/freedesktop/DBus; interface=org.freedesktop.DBus; member=NameAcquired"));
+        assert!(!is_launch("   string \"member=Launched\""));
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let mut watcher = LaunchWatcher::new();
+        let start = Instant::now();
+        assert_eq!(watcher.wait_until(start + Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Launches in a row trigger a single check.
+        let alice = Uid(1000);
+        for _ in 0..10 {
+            watcher.sender.send(alice).unwrap();
+        }
+        assert_eq!(watcher.wait_until(Instant::now() + Duration::from_millis(50)), Some(alice));
+        let start = Instant::now();
+        assert_eq!(watcher.wait_until(start + Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Until `MIN_CHECK_INTERVAL` has elapsed, launches wait for the next sweep.
+        watcher.sender.send(alice).unwrap();
+        assert_eq!(watcher.wait_until(Instant::now() + Duration::from_millis(50)), None);
+        watcher.last_checks.insert(alice, Instant::now() - MIN_CHECK_INTERVAL);
+        watcher.sender.send(alice).unwrap();
+        assert_eq!(watcher.wait_until(Instant::now() + Duration::from_millis(50)), Some(alice));
+    }
+}
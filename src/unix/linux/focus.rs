@@ -0,0 +1,105 @@
+//! Finding out which process owns the focused window.
+//!
+//! Best effort: this relies on `xdotool`, hence on X11 sessions. There is no standard
+//! way to query the focused window under Wayland.
+
+use std::{ops::Not, path::Path};
+
+use anyhow::{anyhow, Context};
+use log::debug;
+use procfs::process::Process;
+
+use super::procfs::session_description;
+
+/// The pid of the process owning the focused window of the logind session `session`.
+///
+/// The display is the one logind recorded for the session, rather than one found in the
+/// environment of a process, which its user may change, and `xdotool` runs as the user of the
+/// session, rather than as root.
+pub fn focused_pid(session: &str) -> Result<i32, anyhow::Error> {
+    let description = session_description(session)?;
+    let Some(user) = description.get("USER") else {
+        return Err(anyhow!("session {session} doesn't have a user"));
+    };
+    let Some(display) = description.get("DISPLAY") else {
+        if description.get("TYPE").is_some_and(|kind| kind == "wayland") {
+            return Err(anyhow!("session {session} runs under Wayland, which doesn't expose the focused window"));
+        }
+        return Err(anyhow!("session {session} doesn't run on a display"));
+    };
+    let mut command = super::command("runuser");
+    command
+        .args(["-u", user, "--", "xdotool", "getactivewindow", "getwindowpid"])
+        .env("DISPLAY", display)
+        .env_remove("XAUTHORITY");
+    // Some display managers (e.g. GDM) keep the authority of the display in the runtime
+    // directory of the user, otherwise xdotool looks for `~/.Xauthority`.
+    if let Some(uid) = description.get("UID") {
+        let authority = Path::new("/run/user").join(uid).join("gdm/Xauthority");
+        if authority.exists() {
+            command.env("XAUTHORITY", authority);
+        }
+    }
+    let output = command.output().context("Failed to launch xdotool")?;
+    if output.status.success().not() {
+        return Err(anyhow!(
+            "{command:?} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_pid(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Whether `process` owns the window of `focused`, i.e. it is `focused` or a child of
+/// `focused` running the same binary (e.g. one of the processes of a browser).
+pub fn is_focused(process: &Process, focused: i32) -> bool {
+    let Ok(exe) = process.exe() else {
+        return false;
+    };
+    let mut pid = process.pid;
+    loop {
+        if pid == focused {
+            return true;
+        }
+        let Ok(stat) = Process::new(pid).and_then(|proc| proc.stat()) else {
+            break;
+        };
+        let parent = stat.ppid;
+        if parent <= 1 || Process::new(parent).and_then(|proc| proc.exe()).is_ok_and(|parent_exe| parent_exe == exe).not() {
+            break;
+        }
+        pid = parent;
+    }
+    debug!("process {} doesn't own the focused window (owned by {focused})", process.pid);
+    false
+}
+
+fn parse_pid(output: &str) -> Result<i32, anyhow::Error> {
+    output
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid pid '{}'", output.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use procfs::process::Process;
+
+    use super::{is_focused, parse_pid};
+
+    #[test]
+    fn test_parse_pid() {
+        assert_eq!(parse_pid("1234\n").unwrap(), 1234);
+        assert!(parse_pid("").is_err());
+    }
+
+    #[test]
+    fn test_is_focused() {
+        let me = Process::myself().unwrap();
+        assert!(is_focused(&me, me.pid));
+        // Our parent (the test harness or a shell) doesn't run the same binary.
+        let parent = me.stat().unwrap().ppid;
+        assert!(is_focused(&me, parent) == (Process::new(parent).unwrap().exe().ok() == me.exe().ok()));
+    }
+}
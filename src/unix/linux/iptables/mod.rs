@@ -1,13 +1,12 @@
 use std::{
-    io::{BufRead, BufReader, Cursor},
+    io::ErrorKind,
     ops::Not,
+    path::{Path, PathBuf},
     process::Command,
     rc::Rc,
 };
 
-use anyhow::Context;
 use itertools::Itertools;
-use lazy_regex::lazy_regex;
 use log::{debug, warn};
 
 use crate::{
@@ -15,12 +14,47 @@ use crate::{
     uid_resolver::Uid,
 };
 
-const IP_TABLES_PREFIX: &str = "KEEP-IT-FOCUSED";
+pub const IP_TABLES_PREFIX: &str = "KEEP-IT-FOCUSED";
+
+pub use super::DEFAULT_IP_TABLES_BINARY;
+
+/// An error while executing iptables.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The iptables binary doesn't exist, or isn't in `PATH`.
+    #[error("iptables binary {} not found, please install iptables or specify the path to the binary", binary.display())]
+    NotFound { binary: PathBuf },
+
+    /// iptables could not be launched, e.g. because it is not executable.
+    #[error("failed to launch iptables command {} {args:?}", binary.display())]
+    Launch {
+        binary: PathBuf,
+        args: Vec<String>,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// iptables was interrupted by a signal.
+    #[error("iptables command {args:?} interrupted by signal: {status}")]
+    Interrupted { args: Vec<String>, status: String },
+
+    /// iptables returned an error, e.g. because we're not root.
+    #[error("error ({code}) executing iptables command {args:?}: {stderr}")]
+    Failed {
+        args: Vec<String>,
+        code: i32,
+        stderr: String,
+    },
+}
 
 #[derive(typed_builder::TypedBuilder)]
 pub struct IPTable {
     #[builder(default=Rc::new("filter".to_string()))]
     table: Rc<String>,
+
+    /// The iptables binary, e.g. `iptables`, `/usr/sbin/iptables` or `iptables-legacy`.
+    #[builder(default=Rc::new(PathBuf::from(DEFAULT_IP_TABLES_BINARY)), setter(transform = |binary: &Path| Rc::new(binary.to_path_buf())))]
+    binary: Rc<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -38,34 +72,72 @@ pub enum Filter<'a> {
     Destination {
         domain: &'a str,
     },
+    /// Packets sent by the processes of a cgroup v2.
+    Cgroup {
+        path: &'a str,
+    },
+}
+
+/// Make sure that `binary` exists, either as a path or in `PATH`, returning its path.
+pub fn find_binary(binary: &Path) -> Result<PathBuf, Error> {
+    let not_found = || Error::NotFound {
+        binary: binary.to_path_buf(),
+    };
+    if binary.components().count() > 1 {
+        return binary.is_file().then(|| binary.to_path_buf()).ok_or_else(not_found);
+    }
+    let path = std::env::var_os("PATH").ok_or_else(not_found)?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(binary))
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(not_found)
+}
+
+/// Extract the names of user-defined chains from rules in the format of `iptables --list-rules`
+/// (`-N <chain>`) or `iptables-save` (`:<chain> - [0:0]`), keeping those starting with `prefix`,
+/// if specified.
+fn parse_chains(rules: &str, prefix: Option<&str>) -> Vec<String> {
+    rules
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if let Some(chain) = line.strip_prefix("-N ") {
+                return Some(chain.trim());
+            }
+            // In `iptables-save`, built-in chains have a policy, user-defined chains have `-`.
+            let mut fields = line.strip_prefix(':')?.split_whitespace();
+            let chain = fields.next()?;
+            (fields.next() == Some("-")).then_some(chain)
+        })
+        .filter(|chain| prefix.is_none_or(|prefix| chain.starts_with(prefix)))
+        .inspect(|chain| debug!("we're interested in chain {chain:?}"))
+        .map(str::to_string)
+        .collect()
 }
 
-fn iptables() -> Command {
-    Command::new("iptables")
+fn iptables(binary: &Path) -> Command {
+    super::command(binary)
 }
-fn run(mut command: Command) -> Result<Vec<u8>, anyhow::Error> {
+fn run(mut command: Command) -> Result<Vec<u8>, Error> {
+    let binary = PathBuf::from(command.get_program());
     let args = command
         .get_args()
         .map(|s| s.to_string_lossy().to_string())
         .collect_vec();
-    let output = command
-        .output()
-        .with_context(|| format!("failed to launch iptables command {:?}", args))?;
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(source) if source.kind() == ErrorKind::NotFound => return Err(Error::NotFound { binary }),
+        Err(source) => return Err(Error::Launch { binary, args, source }),
+    };
     if output.status.success().not() {
-        let err = String::from_utf8_lossy(&output.stderr);
-        warn!("iptables failed {}", err);
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        warn!("iptables failed {}", stderr);
         let err = match output.status.code() {
-            None => anyhow::anyhow!(
-                "iptables command interrupted by signal {:?}: {}",
-                args,
-                output.status.to_string()
-            ),
-            Some(code) => anyhow::anyhow!(
-                "error ({code}: {}) executing iptables command {:?}: {}",
-                errno::Errno(code),
+            None => Error::Interrupted {
                 args,
-                output.status.to_string()
-            ),
+                status: output.status.to_string(),
+            },
+            Some(code) => Error::Failed { args, code, stderr },
         };
         return Err(err);
     }
@@ -73,54 +145,41 @@ fn run(mut command: Command) -> Result<Vec<u8>, anyhow::Error> {
 }
 
 impl IPTable {
-    pub fn list(self, zero: bool, prefix: Option<&str>) -> Result<Vec<String>, anyhow::Error> {
-        let mut command = iptables();
-        command.args(["--table", &self.table, "--list"]);
+    /// The user-defined chains of the table whose name starts with `prefix`, if specified.
+    ///
+    /// If `zero`, also reset the packet and byte counters of the table.
+    pub fn list(self, zero: bool, prefix: Option<&str>) -> Result<Vec<String>, Error> {
         if zero {
-            command.arg("--zero");
+            let mut command = iptables(&self.binary);
+            command.args(["--table", &self.table, "--zero"]);
+            run(command)?;
         }
+        // Unlike `--list`, the output of `--list-rules` is meant for machines, so it isn't
+        // translated and doesn't depend on column widths.
+        let mut command = iptables(&self.binary);
+        command.args(["--table", &self.table, "--list-rules"]);
         let out = String::from_utf8_lossy(&run(command)?).to_string();
-        let mut instances = vec![];
-        let mut by_line = BufReader::new(Cursor::new(out));
-        loop {
-            let mut line: String = String::new();
-            if let Ok(0) = by_line.read_line(&mut line) {
-                return Ok(instances);
-            }
-            debug!("reading {:?}", line);
-            let re = lazy_regex!("Chain ([A-Za-z0-9-]+) ");
-            let Some(captures) = re.captures(&line) else {
-                continue;
-            };
-            debug!("captures {:?}", captures);
-            let (_, [chain_name]) = captures.extract();
-            if let Some(prefix) = prefix {
-                if chain_name.starts_with(prefix).not() {
-                    continue;
-                };
-            }
-            debug!("we're interested in chain {:?}", chain_name);
-            instances.push(chain_name.to_string());
-        }
+        Ok(parse_chains(&out, prefix))
     }
-    pub fn flush(self, chain: &str) -> Result<(), anyhow::Error> {
-        let mut command = iptables();
+    pub fn flush(self, chain: &str) -> Result<(), Error> {
+        let mut command = iptables(&self.binary);
         command.args(["--table", &self.table, "--flush", chain]);
         run(command)?;
         Ok(())
     }
-    pub fn delete(self, chain: &str) -> Result<(), anyhow::Error> {
-        let mut command = iptables();
+    pub fn delete(self, chain: &str) -> Result<(), Error> {
+        let mut command = iptables(&self.binary);
         command.args(["--table", &self.table, "--delete-chain", chain]);
         run(command)?;
         Ok(())
     }
-    pub fn create(self, chain: &str) -> Result<Chain, anyhow::Error> {
-        let mut command = iptables();
+    pub fn create(self, chain: &str) -> Result<Chain<'_>, Error> {
+        let mut command = iptables(&self.binary);
         command.args(["--table", &self.table, "--new-chain", chain]);
         run(command)?;
         Ok(Chain {
             table: self.table.clone(),
+            binary: self.binary.clone(),
             name: chain,
         })
     }
@@ -132,11 +191,12 @@ pub enum Finish {
 
 pub struct Chain<'a> {
     table: Rc<String>,
+    binary: Rc<PathBuf>,
     name: &'a str,
 }
 impl Chain<'_> {
-    pub fn append(&mut self, filter: Filter) -> Result<(), anyhow::Error> {
-        let mut command = iptables();
+    pub fn append(&mut self, filter: Filter) -> Result<(), Error> {
+        let mut command = iptables(&self.binary);
         command.args(["--table", &self.table, "--append", self.name]);
         match filter {
             Filter::Time {
@@ -166,15 +226,18 @@ impl Chain<'_> {
             Filter::Destination { domain } => {
                 command.args(["--destination", domain]);
             }
+            Filter::Cgroup { path } => {
+                command.args(["--match", "cgroup", "--path", path]);
+            }
         }
         run(command)?;
         Ok(())
     }
-    pub fn finish(self, finish: Finish) -> Result<(), anyhow::Error> {
+    pub fn finish(self, finish: Finish) -> Result<(), Error> {
         let jump = match finish {
             Finish::Drop => "DROP",
         };
-        let mut command = iptables();
+        let mut command = iptables(&self.binary);
         command.args([
             "--table",
             &self.table,
@@ -187,3 +250,77 @@ impl Chain<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{find_binary, parse_chains, Error};
+
+    /// Captured from `iptables-save --table filter`, while rules were applied.
+    const SAVED: &str = "\
+# Generated by iptables-save v1.8.10 (nf_tables) on Mon Nov  4 17:02:11 2024
+*filter
+:INPUT ACCEPT [0:0]
+:FORWARD DROP [0:0]
+:OUTPUT ACCEPT [1523:180422]
+:DOCKER - [0:0]
+:KEEP-IT-FOCUSED0 - [0:0]
+:KEEP-IT-FOCUSED1 - [0:0]
+:KEEP-IT-FOCUSED_v2.x - [0:0]
+-A FORWARD -j DOCKER
+-A KEEP-IT-FOCUSED0 -m time --timestart 17:00:00 --timestop 18:00:00 --kerneltz -j RETURN
+-A KEEP-IT-FOCUSED0 -m owner --uid-owner 1000 -j RETURN
+-A KEEP-IT-FOCUSED0 -d 142.250.179.110/32 -j RETURN
+-A KEEP-IT-FOCUSED0 -j DROP
+COMMIT
+# Completed on Mon Nov  4 17:02:11 2024
+";
+
+    /// Captured from `iptables --table filter --list-rules`, with the same rules.
+    const LISTED: &str = "\
+-P INPUT ACCEPT
+-P FORWARD DROP
+-P OUTPUT ACCEPT
+-N DOCKER
+-N KEEP-IT-FOCUSED0
+-N KEEP-IT-FOCUSED1
+-N KEEP-IT-FOCUSED_v2.x
+-A FORWARD -j DOCKER
+-A KEEP-IT-FOCUSED0 -m time --timestart 17:00:00 --timestop 18:00:00 --kerneltz -j RETURN
+-A KEEP-IT-FOCUSED0 -j DROP
+";
+
+    #[test]
+    fn test_parse_chains() {
+        for rules in [SAVED, LISTED] {
+            // Built-in chains are not listed, other characters are accepted.
+            assert_eq!(
+                parse_chains(rules, None),
+                vec!["DOCKER", "KEEP-IT-FOCUSED0", "KEEP-IT-FOCUSED1", "KEEP-IT-FOCUSED_v2.x"]
+            );
+            assert_eq!(
+                parse_chains(rules, Some("KEEP-IT-FOCUSED")),
+                vec!["KEEP-IT-FOCUSED0", "KEEP-IT-FOCUSED1", "KEEP-IT-FOCUSED_v2.x"]
+            );
+        }
+        assert!(parse_chains("", None).is_empty());
+    }
+
+    #[test]
+    fn test_find_binary() {
+        // Looked up in `PATH`.
+        let sh = find_binary(Path::new("sh")).unwrap();
+        assert!(sh.is_absolute());
+        // Or used as is.
+        assert_eq!(find_binary(&sh).unwrap(), sh);
+        assert!(matches!(
+            find_binary(Path::new("keep-it-focused-no-such-iptables")),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(matches!(
+            find_binary(Path::new("/nonexistent/iptables")),
+            Err(Error::NotFound { .. })
+        ));
+    }
+}
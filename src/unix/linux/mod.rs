@@ -1,4 +1,38 @@
+use std::{ffi::OsStr, process::Command};
+
 #[cfg(feature = "ip_tables")]
 pub mod iptables;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod focus;
 pub mod notify;
-pub mod procfs;
\ No newline at end of file
+pub mod procfs;
+
+/// The iptables binary used by default, looked up in `PATH`.
+///
+/// Defined here rather than in `iptables`, so that options may refer to it regardless of
+/// features.
+pub const DEFAULT_IP_TABLES_BINARY: &str = "iptables";
+
+/// A command running in the C locale, so that the output we parse and the errors we log
+/// don't depend on the language of the system.
+pub fn command(program: impl AsRef<OsStr>) -> Command {
+    let mut command = Command::new(program);
+    command.env("LC_ALL", "C").env("LANG", "C");
+    command
+}
+
+#[cfg(test)]
+mod test {
+    use super::command;
+
+    #[test]
+    fn test_command_locale() {
+        let output = command("sh")
+            .args(["-c", "echo $LC_ALL $LANG"])
+            .env("LANGUAGE", "fr")
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "C C");
+    }
+}
@@ -1,5 +1,7 @@
-use anyhow::Context;
-use log::info;
+use std::{collections::HashSet, ops::Not};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info};
 
 #[allow(dead_code)]
 pub enum Urgency {
@@ -18,17 +20,82 @@ impl std::fmt::Display for Urgency {
     }
 }
 
-pub fn notify(user: &str, message: &str, urgency: Urgency) -> Result<(), anyhow::Error> {
-    info!("attempting to notify {user} of message {message}");
-    let _ = std::process::Command::new("systemd-run")
+/// The command used to show a notification to a user.
+pub fn notify_command(user: &str, message: &str, urgency: Urgency) -> std::process::Command {
+    // The locale only applies to `systemd-run`, whose errors we log. The notification itself
+    // runs in the environment of the user.
+    let mut command = super::command("systemd-run");
+    command
         .arg("--user")
         .arg(format!("--machine={user}@.host"))
         .arg("notify-send")
         .arg(format!("--urgency={urgency}"))
         .arg("--app-name='Let\'s take a break'")
-        .arg(message)
+        .arg(message);
+    command
+}
+
+/// Show a notification to a user.
+///
+/// Fails with the command and its stderr if the notification could not be shown, e.g. if the
+/// user has no session bus.
+pub fn notify(user: &str, message: &str, urgency: Urgency) -> Result<(), anyhow::Error> {
+    info!("attempting to notify {user} of message {message}");
+    run(notify_command(user, message, urgency))
+}
+
+/// The notifications sent during a sweep.
+///
+/// A user may run several processes matching the same rule, e.g. a browser with many processes or
+/// the same binary in two sessions, but should only see each notification once.
+#[derive(Default)]
+pub struct Notifications {
+    /// `(user, message)`.
+    sent: HashSet<(String, String)>,
+}
+impl Notifications {
+    /// Show a notification to a user, unless they have already received it during this sweep.
+    pub fn notify(&mut self, user: &str, message: &str, urgency: Urgency) -> Result<(), anyhow::Error> {
+        if self.sent.insert((user.to_string(), message.to_string())).not() {
+            debug!("already notified {user} of message {message}, skipping");
+            return Ok(());
+        }
+        notify(user, message, urgency)
+    }
+}
+
+/// Run a command, failing with the command and its stderr if it doesn't succeed.
+fn run(mut command: std::process::Command) -> Result<(), anyhow::Error> {
+    let output = command
         .output()
         .context("Failed to launch systemd-run or notify-send")?;
-
+    if output.status.success().not() {
+        return Err(anyhow!(
+            "{command:?} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::run;
+
+    #[test]
+    fn test_run_failure() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo 'no session bus' >&2; exit 3");
+        let error = format!("{:#}", run(command).unwrap_err());
+        assert!(error.contains("\"sh\" \"-c\""), "{error}");
+        assert!(error.contains("exit status: 3"), "{error}");
+        assert!(error.contains("no session bus"), "{error}");
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("true");
+        run(command).unwrap();
+    }
+}
@@ -1,10 +1,18 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsStr,
+    net::{IpAddr, SocketAddr},
+    ops::Not,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
 use anyhow::{anyhow, Context};
-use log::debug;
-use procfs::process::FDTarget;
+use log::{debug, warn};
+use procfs::process::{FDTarget, Process};
 
-use crate::unix::uid_resolver::Uid;
+use crate::{config::Binary, unix::uid_resolver::Uid};
 
 /// Find the user owning a peer currently opened locally.
 pub fn find_peer_owner(peer: SocketAddr) -> Result<Uid, anyhow::Error> {
@@ -35,7 +43,7 @@ pub fn find_peer_owner(peer: SocketAddr) -> Result<Uid, anyhow::Error> {
             if let FDTarget::Socket(inode) = fd.target {
                 if inode_local == inode {
                     debug!("found process {} for local inode, with owner {:?}", exe.display(), process.exe());
-                    let Ok(uid) = process.uid() else { continue };
+                    let Ok(uid) = process_owner(&process) else { continue };
                     owner = Some(uid);
                     break;
                 }
@@ -43,7 +51,496 @@ pub fn find_peer_owner(peer: SocketAddr) -> Result<Uid, anyhow::Error> {
         }
     }
     match owner {
-        Some(owner) => Ok(Uid(owner)),
+        Some(owner) => Ok(owner),
         None => Err(anyhow!("No owner found")) 
     }
+}
+
+/// The uid used by the kernel for uids that have no mapping in the current user namespace.
+const DEFAULT_OVERFLOW_UID: u32 = 65534;
+
+/// Find the user owning a process, as a uid of our own user namespace.
+///
+/// We use the real uid from `/proc/<pid>/status` rather than the owner of `/proc/<pid>`,
+/// as the latter is root for non-dumpable processes. The kernel reports this uid relative
+/// to the user namespace of the reader, so a process running in a user namespace (e.g. a
+/// rootless container or a sandboxed browser) is reported with its uid on the host, as
+/// given by `/proc/<pid>/uid_map`.
+///
+/// If the uid has no mapping in our namespace, the kernel reports the overflow uid instead.
+/// In this case, we fall back to the owner of the closest ancestor running in our own user
+/// namespace, i.e. the user who launched the container.
+///
+/// Limitation: if there is no such ancestor (e.g. the process was reparented to a process
+/// within the container), we return the overflow uid, which won't match any configuration.
+///
+/// To also consider the effective uid, use `watched_owner`.
+pub fn process_owner(process: &Process) -> Result<Uid, anyhow::Error> {
+    owners(process).map(|(real, _)| real)
+}
+
+/// Find the real uid of a process, as given by `process_owner`, and its effective uid, from a
+/// single read of `/proc/<pid>/status`.
+fn owners(process: &Process) -> Result<(Uid, Uid), anyhow::Error> {
+    let status = process.status().context("Could not read process status")?;
+    let effective = Uid(status.euid);
+    if status.ruid != overflow_uid() || same_user_namespace(process.pid) {
+        return Ok((Uid(status.ruid), effective));
+    }
+    let mut ppid = status.ppid;
+    while ppid > 0 {
+        let Ok(parent) = Process::new(ppid) else { break };
+        let Ok(parent_status) = parent.status() else { break };
+        if same_user_namespace(parent.pid) {
+            debug!("process {} has no uid in our namespace, attributing it to ancestor {} with owner {}", process.pid, parent.pid, parent_status.ruid);
+            return Ok((Uid(parent_status.ruid), effective));
+        }
+        ppid = parent_status.ppid;
+    }
+    debug!("process {} has no uid in our namespace and no ancestor in our namespace", process.pid);
+    Ok((Uid(status.ruid), effective))
+}
+
+/// Find the user whose rules apply to a process, among the users watched today.
+///
+/// This is normally the real uid, as given by `process_owner`, i.e. the user who launched the
+/// process: a setuid binary only changes the effective uid, so it remains attributed to the user
+/// who launched it. However, a process may also act on behalf of another user by changing only
+/// its effective uid, e.g. a privileged helper. So if the real uid isn't watched (per
+/// `is_watched`) but the effective uid is, we use the effective uid.
+pub fn watched_owner(process: &Process, is_watched: impl Fn(Uid) -> bool) -> Result<Uid, anyhow::Error> {
+    let (real, effective) = owners(process)?;
+    Ok(pick_owner(real, effective, is_watched))
+}
+
+/// Pick the uid whose rules apply to a process, see `watched_owner`.
+fn pick_owner(real: Uid, effective: Uid, is_watched: impl Fn(Uid) -> bool) -> Uid {
+    if effective != real && effective.0 != overflow_uid() && is_watched(real).not() && is_watched(effective) {
+        debug!("real uid {} isn't watched, using effective uid {}", real.0, effective.0);
+        return effective;
+    }
+    real
+}
+
+/// The uid reported for uids that have no mapping in our user namespace.
+///
+/// Read once, as it is checked for every process during each sweep.
+fn overflow_uid() -> u32 {
+    static OVERFLOW_UID: OnceLock<u32> = OnceLock::new();
+    *OVERFLOW_UID.get_or_init(|| {
+        std::fs::read_to_string("/proc/sys/kernel/overflowuid")
+            .ok()
+            .and_then(|uid| uid.trim().parse().ok())
+            .unwrap_or(DEFAULT_OVERFLOW_UID)
+    })
+}
+
+/// Determine whether process `pid` runs in the same user namespace as we do.
+///
+/// If we can't find out, assume that it does.
+fn same_user_namespace(pid: i32) -> bool {
+    // Our own namespace doesn't change, so read it once.
+    static MINE: OnceLock<Option<(u64, u64)>> = OnceLock::new();
+    let namespace = |path: &str| std::fs::metadata(path).map(|metadata| (metadata.dev(), metadata.ino())).ok();
+    let mine = *MINE.get_or_init(|| namespace("/proc/self/ns/user"));
+    match (mine, namespace(&format!("/proc/{pid}/ns/user"))) {
+        (Some(mine), Some(theirs)) => mine == theirs,
+        _ => true,
+    }
+}
+
+/// The marker appended by the kernel to `/proc/<pid>/exe` if the binary was removed after launch.
+const DELETED_MARKER: &[u8] = b" (deleted)";
+
+/// The path to the binary of a process, as given by `/proc/<pid>/exe`.
+///
+/// If the binary was removed after launch, the kernel appends ` (deleted)` to the path.
+/// Strip it, so that the process can still be matched against its original path.
+pub fn exe_path(exe: PathBuf) -> PathBuf {
+    match exe.as_os_str().as_bytes().strip_suffix(DELETED_MARKER) {
+        Some(original) => PathBuf::from(OsStr::from_bytes(original)),
+        None => exe,
+    }
+}
+
+/// Determine whether process `pid` is running the file at `reference`, whatever
+/// the path through which it was launched.
+///
+/// Files are compared by device and inode, so this detects binaries that have been
+/// renamed, moved within the same filesystem or hard-linked, but not copies.
+pub fn runs_file(pid: i32, reference: &Path) -> Result<bool, anyhow::Error> {
+    let reference = std::fs::metadata(reference)
+        .with_context(|| format!("Could not access {}", reference.display()))?;
+    // `/proc/<pid>/exe` gives access to the file even if it lives in another mount namespace.
+    let exe = std::fs::metadata(format!("/proc/{pid}/exe"))
+        .with_context(|| format!("Could not access binary of process {pid}"))?;
+    Ok(reference.dev() == exe.dev() && reference.ino() == exe.ino())
+}
+
+/// Processes that launch apps on behalf of the user and must never be stopped along with them:
+/// desktop shells, panels, terminals, shells and multiplexers, by `comm` (truncated to 15 bytes).
+const PROTECTED_LAUNCHERS: &[&str] = &[
+    "systemd",
+    // Desktop shells, panels and compositors.
+    "gnome-shell",
+    "plasmashell",
+    "kwin_x11",
+    "kwin_wayland",
+    "xfce4-panel",
+    "xfce4-session",
+    "xfdesktop",
+    "mate-panel",
+    "cinnamon",
+    "budgie-panel",
+    "lxpanel",
+    "lxqt-panel",
+    "sway",
+    "i3",
+    "Hyprland",
+    // Terminals.
+    "gnome-terminal-",
+    "kgx",
+    "konsole",
+    "xterm",
+    "urxvt",
+    "kitty",
+    "alacritty",
+    "foot",
+    "wezterm-gui",
+    "tilix",
+    "terminator",
+    "xfce4-terminal",
+    "mate-terminal",
+    "lxterminal",
+    "qterminal",
+    // Shells and multiplexers.
+    "sh",
+    "bash",
+    "dash",
+    "zsh",
+    "fish",
+    "tmux: server",
+    "screen",
+];
+
+/// Whether a process named `comm` must never be stopped as the launcher of another process.
+fn is_protected_launcher(comm: &str) -> bool {
+    PROTECTED_LAUNCHERS.contains(&comm)
+}
+
+/// The process that launched `process`, if it may be stopped along with `process`.
+///
+/// To avoid ending the session of the user, we only return a parent owned by `uid` that
+/// is neither init, nor a session leader (e.g. a login shell or the desktop session),
+/// nor one of `PROTECTED_LAUNCHERS` (e.g. `systemd --user`, the desktop shell or a terminal).
+pub fn launcher(process: &Process, uid: Uid) -> Option<Process> {
+    let ppid = process.stat().ok()?.ppid;
+    if ppid <= 1 {
+        return None;
+    }
+    let parent = Process::new(ppid).ok()?;
+    let parent_stat = parent.stat().ok()?;
+    if parent_stat.session == parent.pid || is_protected_launcher(&parent_stat.comm) {
+        debug!("process {} was launched by {} ({}), which we shouldn't stop", process.pid, parent.pid, parent_stat.comm);
+        return None;
+    }
+    if process_owner(&parent).ok()? != uid {
+        return None;
+    }
+    Some(parent)
+}
+
+/// Whether `process` is this process or one of its descendants, e.g. a helper we launched.
+pub fn is_self_or_descendant(process: &Process) -> bool {
+    let me = std::process::id() as i32;
+    let mut pid = process.pid;
+    while pid > 1 {
+        if pid == me {
+            return true;
+        }
+        let Ok(stat) = Process::new(pid).and_then(|process| process.stat()) else {
+            return false;
+        };
+        pid = stat.ppid;
+    }
+    false
+}
+
+/// Where logind describes the current login sessions.
+const LOGIND_SESSIONS_DIR: &str = "/run/systemd/sessions";
+
+/// Where logind describes the users currently logged in.
+const LOGIND_USERS_DIR: &str = "/run/systemd/users";
+
+/// Whether `process` belongs to a remote login session (e.g. SSH), according to logind.
+///
+/// Processes that don't belong to any login session (e.g. applications started by
+/// `systemd --user`) are not considered remote.
+pub fn is_remote_session(process: &Process) -> Result<bool, anyhow::Error> {
+    let Some(session) = login_session(process)? else {
+        return Ok(false);
+    };
+    Ok(is_remote(&session_description(&session)?))
+}
+
+/// Whether the description of a logind session is that of a remote session.
+///
+/// Sessions opened from this very machine (e.g. `ssh localhost`) are not considered remote,
+/// otherwise they would let a local user bypass rules that only apply to local sessions.
+fn is_remote(description: &HashMap<String, String>) -> bool {
+    if description.get("REMOTE").is_none_or(|remote| remote != "1") {
+        return false;
+    }
+    let is_loopback = description.get("REMOTE_HOST").is_some_and(|host| {
+        host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.to_canonical().is_loopback())
+    });
+    is_loopback.not()
+}
+
+/// The logind session of `process`, if any, e.g. `3`.
+fn login_session(process: &Process) -> Result<Option<String>, anyhow::Error> {
+    let cgroups = process
+        .cgroups()
+        .with_context(|| format!("Failed to read cgroups of process {}", process.pid))?;
+    Ok(cgroups
+        .0
+        .iter()
+        .find_map(|cgroup| session_from_cgroup(&cgroup.pathname))
+        .map(str::to_string))
+}
+
+/// The logind session whose display `process` of user `uid` runs on: its own session, or
+/// the graphical session of `uid` for processes that don't belong to any login session
+/// (e.g. applications started by `systemd --user`).
+pub fn display_session(process: &Process, uid: Uid) -> Result<String, anyhow::Error> {
+    if let Some(session) = login_session(process)? {
+        return Ok(session);
+    }
+    let path = Path::new(LOGIND_USERS_DIR).join(uid.0.to_string());
+    let description = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read user {}", path.display()))?;
+    parse_description(&description)
+        .remove("DISPLAY")
+        .ok_or_else(|| anyhow!("user {} doesn't have a graphical session", uid.0))
+}
+
+/// The description of a logind session, e.g. `USER=alice`, `DISPLAY=:0`, `REMOTE=1`.
+///
+/// Only root may write these files, so unlike the environment of a process, they cannot be
+/// tampered with by the user.
+pub fn session_description(session: &str) -> Result<HashMap<String, String>, anyhow::Error> {
+    let path = Path::new(LOGIND_SESSIONS_DIR).join(session);
+    let description = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read session {}", path.display()))?;
+    Ok(parse_description(&description))
+}
+
+/// Parse the `KEY=value` lines written by logind, skipping comments.
+fn parse_description(source: &str) -> HashMap<String, String> {
+    source
+        .lines()
+        .filter(|line| line.starts_with('#').not())
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Where cgroups v2 are mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The cgroups v2 (e.g. `/user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox.scope`)
+/// of the processes of `uid` running `binary`.
+///
+/// Cgroups that also contain processes running other binaries are skipped, as blocking them
+/// would also block these other binaries, e.g. an entire terminal session.
+pub fn binary_cgroups(uid: Uid, binary: &Binary) -> Vec<String> {
+    let Ok(processes) = procfs::process::all_processes() else {
+        return vec![];
+    };
+    let mut cgroups = BTreeSet::new();
+    for proc in processes {
+        let Ok(proc) = proc else { continue };
+        if process_owner(&proc).ok() != Some(uid) {
+            continue;
+        }
+        let Ok(exe) = proc.exe() else { continue };
+        if binary.matcher.is_match(exe_path(exe)).not() {
+            continue;
+        }
+        let Ok(proc_cgroups) = proc.cgroups() else { continue };
+        // Under cgroups v2, the only hierarchy is 0.
+        if let Some(cgroup) = proc_cgroups.0.into_iter().find(|cgroup| cgroup.hierarchy == 0) {
+            cgroups.insert(cgroup.pathname);
+        }
+    }
+    cgroups
+        .into_iter()
+        .filter(|cgroup| {
+            let path = Path::new(CGROUP_ROOT).join(cgroup.trim_start_matches('/')).join("cgroup.procs");
+            let Ok(pids) = std::fs::read_to_string(&path) else {
+                return false;
+            };
+            let exes = pids
+                .lines()
+                .filter_map(|pid| Process::new(pid.parse().ok()?).ok()?.exe().ok());
+            let dedicated = is_dedicated(exes, binary);
+            if dedicated.not() {
+                warn!("cgroup {cgroup} runs {binary} alongside other binaries, not blocking its network access");
+            }
+            dedicated
+        })
+        .collect()
+}
+
+/// Whether all the executables of a cgroup match `binary`.
+fn is_dedicated(mut exes: impl Iterator<Item = PathBuf>, binary: &Binary) -> bool {
+    exes.all(|exe| binary.matcher.is_match(exe_path(exe)))
+}
+
+/// Extract the logind session id from a cgroup, e.g. `/user.slice/user-1000.slice/session-3.scope`.
+fn session_from_cgroup(pathname: &str) -> Option<&str> {
+    pathname
+        .split('/')
+        .find_map(|component| component.strip_prefix("session-")?.strip_suffix(".scope"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ops::Not, path::PathBuf};
+
+    use procfs::process::Process;
+
+    use crate::{config::Binary, unix::uid_resolver::Uid};
+
+    use super::{
+        exe_path, is_dedicated, is_protected_launcher, is_remote, is_self_or_descendant, launcher, parse_description, pick_owner,
+        process_owner, runs_file, session_from_cgroup, watched_owner,
+    };
+
+    #[test]
+    fn test_process_owner() {
+        let me = Process::myself().unwrap();
+        assert_eq!(process_owner(&me).unwrap(), Uid::me());
+        assert_eq!(watched_owner(&me, |_| true).unwrap(), Uid::me());
+        assert_eq!(watched_owner(&me, |_| false).unwrap(), Uid::me());
+    }
+
+    #[test]
+    fn test_pick_owner() {
+        let (root, alice, bob) = (Uid(0), Uid(1000), Uid(1001));
+        let watched = |uid: Uid| uid == alice || uid == bob;
+
+        // A setuid binary launched by alice is hers.
+        assert_eq!(pick_owner(alice, root, watched), alice);
+        // Unless we can tell otherwise, the real uid wins.
+        assert_eq!(pick_owner(alice, bob, watched), alice);
+        assert_eq!(pick_owner(root, root, watched), root);
+        assert_eq!(pick_owner(root, Uid(1002), watched), root);
+        // A privileged process acting on behalf of alice is hers.
+        assert_eq!(pick_owner(root, alice, watched), alice);
+    }
+
+    #[test]
+    fn test_is_dedicated() {
+        let firefox = Binary::try_new("firefox").unwrap();
+        let exes = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>().into_iter();
+        assert!(is_dedicated(exes(&["/usr/lib/firefox/firefox", "/usr/lib/firefox/firefox"]), &firefox));
+        assert!(is_dedicated(exes(&["/usr/lib/firefox/firefox (deleted)"]), &firefox));
+        assert!(is_dedicated(exes(&["/usr/lib/firefox/firefox", "/usr/bin/bash"]), &firefox).not());
+    }
+
+    #[test]
+    fn test_session_from_cgroup() {
+        assert_eq!(session_from_cgroup("/user.slice/user-1000.slice/session-3.scope"), Some("3"));
+        assert_eq!(session_from_cgroup("/user.slice/user-1000.slice/session-c2.scope"), Some("c2"));
+        assert_eq!(
+            session_from_cgroup("/user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox.scope"),
+            None
+        );
+        assert_eq!(session_from_cgroup("/"), None);
+    }
+
+    #[test]
+    fn test_parse_description() {
+        let description = parse_description(
+            "# This is private data. Do not parse.\nUID=1000\nUSER=alice\nTYPE=x11\nDISPLAY=:0\nREMOTE=0\n",
+        );
+        assert_eq!(description.get("USER").map(String::as_str), Some("alice"));
+        assert_eq!(description.get("DISPLAY").map(String::as_str), Some(":0"));
+        assert_eq!(description.get("REMOTE").map(String::as_str), Some("0"));
+        assert_eq!(description.len(), 5);
+        assert!(is_remote(&description).not());
+
+        let remote = |host: &str| parse_description(&format!("REMOTE=1\nREMOTE_HOST={host}\n"));
+        assert!(is_remote(&remote("192.168.1.12")));
+        assert!(is_remote(&remote("laptop.example.org")));
+        assert!(is_remote(&parse_description("REMOTE=1\n")));
+        // Sessions opened from this very machine are local.
+        assert!(is_remote(&remote("127.0.0.1")).not());
+        assert!(is_remote(&remote("::1")).not());
+        assert!(is_remote(&remote("::ffff:127.0.0.1")).not());
+        assert!(is_remote(&remote("localhost")).not());
+    }
+
+    #[test]
+    fn test_launcher() {
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let process = Process::new(child.id() as i32).unwrap();
+        let parent = launcher(&process, Uid::me());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(parent.unwrap().pid, std::process::id() as i32);
+        // Processes of other users are not considered.
+        let process = Process::myself().unwrap();
+        assert!(launcher(&process, Uid(Uid::me().0 + 1)).is_none());
+
+        // Desktop shells and terminals are never stopped.
+        assert!(is_protected_launcher("gnome-shell"));
+        assert!(is_protected_launcher("gnome-terminal-"));
+        assert!(is_protected_launcher("bash"));
+        assert!(is_protected_launcher("steam").not());
+    }
+
+    #[test]
+    fn test_is_self_or_descendant() {
+        assert!(is_self_or_descendant(&Process::myself().unwrap()));
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let process = Process::new(child.id() as i32).unwrap();
+        let result = is_self_or_descendant(&process);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(result);
+        assert!(is_self_or_descendant(&Process::new(1).unwrap()).not());
+    }
+
+    #[test]
+    fn test_exe_path() {
+        assert_eq!(
+            exe_path(PathBuf::from("/usr/games/tetris (deleted)")),
+            PathBuf::from("/usr/games/tetris")
+        );
+        assert_eq!(
+            exe_path(PathBuf::from("/usr/games/tetris")),
+            PathBuf::from("/usr/games/tetris")
+        );
+        assert_eq!(
+            exe_path(PathBuf::from("/usr/games/(deleted) tetris")),
+            PathBuf::from("/usr/games/(deleted) tetris")
+        );
+    }
+
+    #[test]
+    fn test_runs_file() {
+        let pid = std::process::id() as i32;
+        let exe = std::env::current_exe().unwrap();
+        assert!(runs_file(pid, &exe).unwrap());
+        assert!(runs_file(pid, "/bin/sh".as_ref()).unwrap().not());
+        assert!(runs_file(pid, "/this/file/does/not/exist".as_ref()).is_err());
+
+        // A hard link is the same file under another name.
+        let link = exe.with_file_name(format!("keep-it-focused-test-link-{pid}"));
+        std::fs::hard_link(&exe, &link).unwrap();
+        assert!(runs_file(pid, &link).unwrap());
+        std::fs::remove_file(&link).unwrap();
+    }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use log::debug;
-use std::collections::HashMap;
+use std::{collections::HashMap, ffi::CStr};
 use uucore::entries::{uid2usr, Locate, Passwd};
 
 use anyhow::{anyhow, Context};
@@ -22,6 +22,10 @@ impl Uid {
 
 pub struct Resolver {
     username_to_uid: HashMap<Username, Uid>,
+
+    /// If true, a name that doesn't match any user exactly may match a user up to case,
+    /// e.g. `Mickey` resolves to `mickey`.
+    case_insensitive: bool,
 }
 
 impl Default for Resolver {
@@ -34,17 +38,97 @@ impl Resolver {
     pub fn new() -> Self {
         Resolver {
             username_to_uid: HashMap::new(),
+            case_insensitive: false,
         }
     }
+
+    /// Fall back to matching names up to case, if `case_insensitive` is true.
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
     pub fn resolve(&mut self, name: &Username) -> Result<Uid, anyhow::Error> {
         if let Some(uid) = self.username_to_uid.get(name) {
             return Ok(*uid);
         }
-        let passwd = Passwd::locate(name.as_str())
-            .with_context(|| format!("Could not find information for user {name}"))?;
+        let passwd = match Passwd::locate(name.as_str()) {
+            Ok(passwd) => passwd,
+            Err(_) if self.case_insensitive => {
+                let Some(folded) = fold_match(name.as_str(), all_user_names())? else {
+                    return Err(anyhow!("Could not find information for user {name}"));
+                };
+                debug!("user {name} matches {folded} up to case");
+                Passwd::locate(folded.as_str())
+                    .with_context(|| format!("Could not find information for user {folded}"))?
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Could not find information for user {name}"))
+            }
+        };
         let uid = Uid(passwd.uid);
         self.username_to_uid.insert(name.clone(), uid);
         debug!("resolved user {name} => {}", uid.0);
         Ok(uid)
     }
 }
+
+/// The names of all the users known to the system.
+fn all_user_names() -> Vec<String> {
+    let mut names = vec![];
+    // Safety: each entry is copied before the next call to `getpwent`, which overwrites it.
+    unsafe {
+        libc::setpwent();
+        loop {
+            let entry = libc::getpwent();
+            if entry.is_null() {
+                break;
+            }
+            names.push(CStr::from_ptr((*entry).pw_name).to_string_lossy().into_owned());
+        }
+        libc::endpwent();
+    }
+    names
+}
+
+/// The only name among `candidates` equal to `name` up to case, if any.
+///
+/// Fails if several candidates match, rather than picking one arbitrarily.
+fn fold_match(name: &str, candidates: impl IntoIterator<Item = String>) -> Result<Option<String>, anyhow::Error> {
+    let folded = name.to_lowercase();
+    let mut matches = candidates
+        .into_iter()
+        .filter(|candidate| candidate.to_lowercase() == folded);
+    let Some(found) = matches.next() else {
+        return Ok(None);
+    };
+    if let Some(other) = matches.next() {
+        return Err(anyhow!("user name {name} is ambiguous, it matches both {found} and {other}"));
+    }
+    Ok(Some(found))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::types::Username;
+
+    use super::{fold_match, Resolver, Uid};
+
+    #[test]
+    fn test_fold_match() {
+        let users = || ["root".to_string(), "mickey".to_string(), "Minnie".to_string(), "minnie".to_string()];
+        assert_eq!(fold_match("Mickey", users()).unwrap(), Some("mickey".to_string()));
+        assert_eq!(fold_match("MICKEY", users()).unwrap(), Some("mickey".to_string()));
+        assert_eq!(fold_match("donald", users()).unwrap(), None);
+        assert!(fold_match("MINNIE", users()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_case_insensitive() {
+        let root = Username("ROOT".to_string());
+        // Exact matching by default.
+        assert!(Resolver::new().resolve(&root).is_err());
+        let mut resolver = Resolver::new().case_insensitive(true);
+        assert_eq!(resolver.resolve(&root).unwrap(), Uid(0));
+        assert_eq!(resolver.resolve(&Username("root".to_string())).unwrap(), Uid(0));
+    }
+}
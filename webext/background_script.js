@@ -55,24 +55,32 @@ let InterdictionManager = {
     // domain => Interdiction
     _interdictionsByDomain: new Map(),
 
+    // The current list of exceptions, i.e. domains permitted even though a parent domain
+    // is interdicted (e.g. "docs.google.com" while "google.com" is interdicted).
+    //
+    // domain => Interdiction
+    _exceptionsByDomain: new Map(),
+
     // The latest version of the declarativeNetRequest rules understood by the browser.
     _rules: [],
     _rulesByDomain: new Map(),
+    _allowRulesByDomain: new Map(),
 
     // A cached array of url filters, used to find whether a tab is breaking a rule.
     _urlFilters: null,
 
-    // From a list of declarativeNetRequest rules, compute a map domain => rule. 
-    _computeRulesByDomain(rules) {
+    // From a list of declarativeNetRequest rules, compute a map domain => rule,
+    // for rules of type `type` ("block" or "allow").
+    _computeRulesByDomain(rules, type) {
         let rulesByDomain = new Map();
         for (let rule of rules) {
-            if (rule.action.type != "block") {
+            if (rule.action.type != type) {
                 continue;
             }
             if (!rule.condition.urlFilter) {
                 continue;
             }
-            let re = /||(.*)/;
+            let re = /^\|\|(.*)/;
             let match = re.exec(rule.condition.urlFilter);
             if (!match) {
                 continue;
@@ -92,7 +100,8 @@ let InterdictionManager = {
             }
         }
         this._rules = rules;
-        this._rulesByDomain = this._computeRulesByDomain(rules);
+        this._rulesByDomain = this._computeRulesByDomain(rules, "block");
+        this._allowRulesByDomain = this._computeRulesByDomain(rules, "allow");
         // Compute an inital (empty) list of url filters.
         this.urlFilters();
     },
@@ -128,10 +137,80 @@ let InterdictionManager = {
                     urlFilter: "||" + interdiction.domain
                 },
                 id: interdiction.id,
+                // More specific domains take precedence, see `addException`.
+                priority: specificity(interdiction.domain),
             });    
         }
     },
 
+    // Add an exception, i.e. permit a domain (and its subdomains) even though a parent domain
+    // is interdicted.
+    //
+    // Rules on more specific domains have a higher priority, so the most specific rule wins,
+    // whether it's an interdiction or an exception.
+    //
+    // Don't forget to call `flush()`!
+    addException(domain) {
+        if (this._exceptionsByDomain.has(domain)) {
+            return;
+        }
+        console.log("keep-it-focused", "InterdictionManager", "adding exception", domain);
+        let exception = new Interdiction(domain, null);
+        this._exceptionsByDomain.set(domain, exception);
+        this._urlFilters = null; // We'll need to recompute url filters.
+        if (this._allowRulesByDomain.get(domain)) {
+            console.log("keep-it-focused", "InterdictionManager", "we already have a rule for this exception, skipping");
+            return;
+        }
+        this._addRules.push({
+            action: {
+                type: "allow"
+            },
+            condition: {
+                urlFilter: "||" + domain
+            },
+            id: exception.id,
+            priority: specificity(domain),
+        });
+    },
+
+    // Remove an exception.
+    //
+    // Don't forget to call `flush()`!
+    removeException(domain) {
+        let exception = this._exceptionsByDomain.get(domain);
+        if (!exception) {
+            return;
+        }
+        console.log("keep-it-focused", "InterdictionManager", "removing exception", domain);
+        this._removeRuleIds.push(exception.id);
+        this._exceptionsByDomain.delete(domain);
+        this._urlFilters = null; // We'll need to recompute url filters.
+    },
+
+    // The current list of domain -> exception. Please do not modify this.
+    exceptions() {
+        return this._exceptionsByDomain;
+    },
+
+    // Check whether a host is currently blocked, i.e. whether the most specific interdiction
+    // or exception covering this host is an interdiction.
+    isBlocked(hostname) {
+        let best = null;
+        for (let [domains, blocked] of [[this._interdictionsByDomain, true], [this._exceptionsByDomain, false]]) {
+            for (let domain of domains.keys()) {
+                if (hostname != domain && !hostname.endsWith("." + domain)) {
+                    continue;
+                }
+                // All these domains are suffixes of `hostname`, so the longest is the most specific.
+                if (!best || domain.length > best.domain.length) {
+                    best = { domain, blocked };
+                }
+            }
+        }
+        return best ? best.blocked : false;
+    },
+
     // Remove an interdiction.
     //
     // Don't forget to call `flush()`!
@@ -156,7 +235,8 @@ let InterdictionManager = {
             console.log("keep-it-focused", "InterdictionManager", "flushing", update);
             await browser.declarativeNetRequest.updateSessionRules(update);
             this._rules = await browser.declarativeNetRequest.getSessionRules();
-            this._rulesByDomain = this._computeRulesByDomain(this._rules);
+            this._rulesByDomain = this._computeRulesByDomain(this._rules, "block");
+            this._allowRulesByDomain = this._computeRulesByDomain(this._rules, "allow");
             console.log("keep-it-focused", "InterdictionManager", "rules after flush", "=>", this._rules);    
         }
 
@@ -213,6 +293,10 @@ let InterdictionManager = {
     },
 
     _tabListener(tabId, change, tab) {
+        if (!InterdictionManager.isBlocked(new URL(tab.url).hostname)) {
+            // The url is covered by an exception.
+            return;
+        }
         // Block from navigating to a forbidden URL.
         console.debug("keep-it-focused", "InterdictionManager", "tab attempting to navigate to unwanted url", change, tab);
         browser.tabs.update(tabId, {
@@ -227,9 +311,9 @@ let InterdictionManager = {
         if (urlFilters.length == 0) {
             return []
         }
-        let currentTabs = await browser.tabs.query({
+        let currentTabs = (await browser.tabs.query({
             url: urlFilters
-        });
+        })).filter((tab) => this.isBlocked(new URL(tab.url).hostname));
         console.debug("keep-it-focused", "InterdictionManager", "offending tabs", currentTabs);
         if (currentTabs.length > 0) {
             console.log("keep-it-focused", "InterdictionManager", "found offending tabs", currentTabs);
@@ -251,14 +335,24 @@ class Interdiction {
     }
 }
 
+// How specific a domain is, i.e. its number of components, e.g. 3 for "docs.google.com".
+//
+// The daemon serves domains from the most specific to the least specific. For a given host,
+// the rule on the longest domain suffix wins.
+function specificity(domain) {
+    return domain.split(".").length;
+}
+
 // An interval of time.
 class Interval {
-    constructor(start, end) {
+    // label: string|undefined - a reason shown to the user, e.g. "homework time"
+    constructor(start, end, label) {
         if (!(start instanceof Date) || !(end instanceof Date)) {
             throw new TypeError();
         }
         this.start = start;
         this.end = end;
+        this.label = label;
     }
     // Check if a date is contained within the interval.
     //
@@ -280,7 +374,7 @@ let ConfigManager = {
     _latestUpdateTS: null,
 
     /**
-     * The latest config
+     * The latest config, most specific domains first.
         config: {
             "website.com": [
                 {"start": "HH:MM"},
@@ -290,6 +384,11 @@ let ConfigManager = {
      */
     _config: null,
 
+    // domain => Interval[], the intervals during which a domain is blocked by an explicit rule.
+    //
+    // Empty unless the daemon serves forbidden intervals.
+    _forbidden: new Map(),
+
     // Promise|null
     //
     // Resolves when `refetchIfNecessary` completes.
@@ -355,10 +454,37 @@ let ConfigManager = {
             if (allowed) {
                 continue;
             }
-            console.debug("keep-it-focused", "ConfigManager", domain, "is currently forbidden");
+            let blockedByRule = (this._forbidden.get(domain) || []).find((interval) => interval.contains(now));
+            console.debug("keep-it-focused", "ConfigManager", domain, "is currently forbidden", blockedByRule ? "blocked by rule" : "outside allowed hours");
+            let label = blockedByRule ? blockedByRule.label : undefined;
+            if (label && !InterdictionManager.interdictions().has(domain)) {
+                browser.notifications.create({
+                    type: "basic",
+                    title: "Keep it Focused",
+                    message: `${domain} is blocked: ${label}`,
+                });
+            }
             InterdictionManager.addInterdiction(domain);
         }
 
+        // Do we need to permit subdomains of interdicted domains?
+        console.debug("keep-it-focused", "ConfigManager", "looking for exceptions");
+        let interdicted = [...InterdictionManager.interdictions().keys()];
+        for (let domain of this._config.keys()) {
+            let isException = !InterdictionManager.interdictions().has(domain)
+                && interdicted.some((parent) => domain.endsWith("." + parent));
+            if (isException) {
+                InterdictionManager.addException(domain);
+            } else {
+                InterdictionManager.removeException(domain);
+            }
+        }
+        for (let domain of [...InterdictionManager.exceptions().keys()]) {
+            if (!this._config.has(domain)) {
+                InterdictionManager.removeException(domain);
+            }
+        }
+
         // Flush interdictions.
         await InterdictionManager.flush();
         console.debug("keep-it-focused", "ConfigManager", "permissions in progress", permissionsInProgress);
@@ -414,8 +540,17 @@ let ConfigManager = {
         }
         console.log("keep-it-focused", "ConfigManager", "update needed");
         try {
+            // On accounts shared by several people, each browser profile may be given a
+            // profile name (stored as `profile` in `browser.storage.local`), so that the
+            // daemon serves the rules of that profile rather than those of the account.
+            let headers = {};
+            let { profile } = await browser.storage.local.get("profile");
+            if (profile) {
+                headers["X-Keep-It-Focused-Profile"] = profile;
+            }
             let response = await fetch("http://localhost:7878", {
                 method: "GET",
+                headers,
             });
             if (!response.ok) {
                 console.error("keep-it-focused", "ConfigManager", "could not get in touch with update server, skipping this update");
@@ -425,10 +560,19 @@ let ConfigManager = {
             console.log("keep-it-focused", "ConfigManager", "obtained update from server", json);
 
             // Convert times in HHMM to Date(), which are simpler to use.
+            //
+            // Each domain maps either to a list of permitted intervals or, if the daemon
+            // serves forbidden intervals, to `{ permitted: [...], forbidden: [...] }`.
             let config = new Map();
+            let forbidden = new Map();
             for (let domain of Object.keys(json)) {
+                let entry = json[domain];
+                let permitted = Array.isArray(entry) ? entry : entry.permitted;
+                if (!Array.isArray(entry)) {
+                    forbidden.set(domain, entry.forbidden.map(({ start, end, label }) => new Interval(hhmmToDate(start), hhmmToDate(end), label)));
+                }
                 let dateIntervals = [];
-                for (let interval of json[domain]) {
+                for (let interval of permitted) {
                     console.debug("keep-it-focused", "ConfigManager", "looking at interval", interval);
                     let { start, end } = interval;
                     let dateStart = hhmmToDate(start);
@@ -438,6 +582,7 @@ let ConfigManager = {
                 config.set(domain, dateIntervals);
             }
             this._config = config;
+            this._forbidden = forbidden;
             this._latestUpdateTS = now;
         } catch (ex) {
             console.error("keep-it-focused", "ConfigManager", "error during update", ex);